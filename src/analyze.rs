@@ -0,0 +1,175 @@
+// analyze: ANALYZE TABLE collects table and column statistics by running an aggregation flow over the table, and stores them with its
+// TableDesc for the optimizer and DESCRIBE TABLE
+
+use std::fs;
+
+use arrow2::temporal_conversions::EPOCH_DAYS_FROM_CE;
+use chrono::{Datelike, NaiveDateTime};
+
+use crate::{
+    env::Env,
+    includes::*,
+    metadata::{ColumnStats, ParquetDesc, TableStats, TableType},
+    pop::POP,
+    qgm::ParserState,
+    run_flow, sqlparser, AST,
+};
+
+// Statistic: What each output column of the ANALYZE query computes. Analyzed columns are identified by their position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Statistic {
+    RowCount,
+    NonNullCount(usize),
+    Min(usize),
+    Max(usize),
+    Ndv(usize),
+    HistogramBound(usize, usize), // Column, bucket boundary
+}
+
+/***************************************************************************************************/
+// analyze_table: Collect statistics on the given columns of a table (all columns by default). Statistics on other columns are kept.
+// Parquet tables take row counts, null counts and min/max from the metadata of their row groups, and only aggregate the rest.
+pub fn analyze_table(env: &mut Env, name: String, colnames: Option<Vec<String>>) -> Result<(), String> {
+    let name = name.to_uppercase();
+    let tabledesc = env.metadata.get_tabledesc(&name).ok_or_else(|| format!("Table {} does not exist.", name))?;
+
+    let fields = match colnames {
+        Some(colnames) => {
+            if has_duplicates(&colnames) {
+                return Err(f!("ANALYZE TABLE {name} lists a column more than once."));
+            }
+            let fields = colnames.iter().map(|colname| tabledesc.get_column(colname).map(|(_, field)| field.clone()).ok_or_else(|| f!("Column {colname} does not exist in table {name}.")));
+            fields.collect::<Result<Vec<_>, _>>()?
+        }
+        None => tabledesc.fields().clone(),
+    };
+    let nbuckets = env.settings.histogram_buckets.unwrap_or(0);
+
+    // Parquet metadata has the row count, and null counts and min/max if the writer recorded them
+    let parquet_stats = if tabledesc.get_type() == TableType::Parquet {
+        let colnames = fields.iter().map(|field| field.name.clone()).collect::<Vec<_>>();
+        Some(ParquetDesc::read_statistics(tabledesc.pathname(), &colnames)?)
+    } else {
+        None
+    };
+    let parquet_column = |colname: &String| parquet_stats.as_ref().and_then(|(_, _, columns)| columns.get(colname)).cloned().unwrap_or_default();
+
+    // Build the query, one aggregate per statistic that's still missing
+    let mut statistics = vec![];
+    if parquet_stats.is_none() {
+        statistics.push((Statistic::RowCount, String::from("COUNT(*)")));
+    }
+    for (ix, field) in fields.iter().enumerate() {
+        let colname = &field.name;
+        if !can_aggregate(field.data_type()) {
+            continue;
+        }
+        let (known, has_range) = (parquet_column(colname), ColumnStats::has_range(field.data_type()));
+        if known.null_count().is_none() {
+            statistics.push((Statistic::NonNullCount(ix), f!("COUNT({colname})")));
+        }
+        if has_range && (known.min().is_none() || known.max().is_none()) {
+            statistics.push((Statistic::Min(ix), f!("MIN({colname})")));
+            statistics.push((Statistic::Max(ix), f!("MAX({colname})")));
+        }
+        statistics.push((Statistic::Ndv(ix), f!("APPROX_COUNT_DISTINCT({colname})")));
+        if has_range && nbuckets > 0 {
+            for bound in 0..=nbuckets {
+                let fraction = bound as f64 / nbuckets as f64;
+                statistics.push((Statistic::HistogramBound(ix, bound), f!("APPROX_PERCENTILE({colname}, {fraction})")));
+            }
+        }
+    }
+    let values = if statistics.is_empty() {
+        vec![]
+    } else {
+        let select_list = statistics.iter().map(|(_, aggregate)| aggregate.as_str()).collect::<Vec<_>>().join(", ");
+        let Some(values) = run_query(env, &f!("SELECT {select_list} FROM {name}"))? else { return Ok(()) };
+        values
+    };
+    if values.len() != statistics.len() {
+        return Err(f!("ANALYZE TABLE {name} did not return any statistics."));
+    }
+    let value_of = |statistic: Statistic| statistics.iter().position(|&(other, _)| other == statistic).and_then(|ix| values[ix]);
+
+    let (nrows, nbytes) = match parquet_stats.as_ref() {
+        Some((nrows, nbytes, _)) => (*nrows, *nbytes),
+        None => {
+            let nrows = value_of(Statistic::RowCount).unwrap_or(0.0) as usize;
+            let nbytes = fs::metadata(tabledesc.pathname()).map_err(|err| stringify1(err, tabledesc.pathname()))?.len() as usize;
+            (nrows, nbytes)
+        }
+    };
+
+    // Columns that weren't analyzed keep their statistics
+    let mut columns = tabledesc.get_stats().map(|table_stats| table_stats.columns().clone()).unwrap_or_default();
+    for (ix, field) in fields.iter().enumerate() {
+        let known = parquet_column(&field.name);
+        let null_count = known.null_count().or_else(|| value_of(Statistic::NonNullCount(ix)).map(|count| nrows.saturating_sub(count as usize)));
+        let min = known.min().or_else(|| value_of(Statistic::Min(ix)));
+        let max = known.max().or_else(|| value_of(Statistic::Max(ix)));
+
+        // Sketches can overestimate, and find no distinct values in columns that are all NULL
+        let ndv = value_of(Statistic::Ndv(ix)).map(|ndv| (ndv as usize).min(nrows)).filter(|&ndv| ndv > 0);
+        let histogram = (0..=nbuckets).map(|bound| value_of(Statistic::HistogramBound(ix, bound))).collect::<Option<Vec<_>>>();
+        let histogram = histogram.filter(|histogram| histogram.len() > 1).map(|mut histogram| {
+            // Sketched quantiles are approximate, unlike the extremes
+            if let (Some(min), Some(max)) = (min, max) {
+                histogram.iter_mut().for_each(|bound| *bound = bound.clamp(min, max));
+                histogram[0] = min;
+                histogram[nbuckets] = max;
+            }
+            histogram
+        });
+
+        columns.insert(field.name.clone(), ColumnStats::new(ndv, null_count, min, max, histogram));
+    }
+
+    let avg_row_size = (nrows > 0).then(|| (nbytes as f64 / nrows as f64).ceil() as usize);
+    env.metadata.set_table_stats(&name, TableStats::new(Some(nrows), avg_row_size, columns))?;
+    info!("Analyzed table {}", &name);
+    Ok(())
+}
+
+// can_aggregate: Datatypes that aggregates accept. Other columns only get statistics from Parquet metadata.
+fn can_aggregate(datatype: &DataType) -> bool {
+    matches!(datatype, DataType::Int32 | DataType::Int64 | DataType::Float64 | DataType::Date32 | DataType::Utf8 | DataType::Boolean | DataType::Decimal(..))
+}
+
+// run_query: Run a query returning a single row of numbers. Nothing is run if PARSE_ONLY is set.
+fn run_query(env: &mut Env, sql: &str) -> Result<Option<Vec<Option<f64>>>, String> {
+    debug!("ANALYZE query: {}", sql);
+    let mut parser_state = ParserState::default();
+    let AST::QGM(mut qgm) = sqlparser::QueryParser::new().parse(&mut parser_state, sql).map_err(stringify)? else { unreachable!() };
+
+    qgm.resolve(env)?;
+    let (lop_graph, lop_key) = qgm.build_logical_plan(env)?;
+    if env.settings.parse_only.unwrap_or(false) {
+        return Ok(None);
+    }
+    let flow = POP::compile_flow(env, &mut qgm, &lop_graph, lop_key)?;
+    run_flow(env, &flow)?;
+
+    // The row gets written by one of the top-level tasks
+    let mut values = vec![];
+    for path in list_files(&get_output_dir(flow.id))? {
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_path(&path).map_err(stringify)?;
+        for record in reader.records() {
+            let record = record.map_err(stringify)?;
+            values = record.iter().zip(flow.schema.fields.iter()).map(|(value, field)| parse_value(value, field.data_type())).collect();
+        }
+    }
+    Ok(Some(values))
+}
+
+// parse_value: Numeric value of an output field. Dates are written out as timestamps, and become days since the epoch.
+fn parse_value(value: &str, datatype: &DataType) -> Option<f64> {
+    match datatype {
+        _ if value.is_empty() => None,
+        DataType::Date32 => {
+            let date = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok()?.date();
+            Some((date.num_days_from_ce() - EPOCH_DAYS_FROM_CE) as f64)
+        }
+        _ => value.parse::<f64>().ok(),
+    }
+}
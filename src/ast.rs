@@ -6,6 +6,7 @@ use crate::{datum::Datum, qgm::QGM};
 pub enum AST {
     CatalogTable { name: String, options: Vec<(String, Datum)> },
     DescribeTable { name: String },
+    AnalyzeTable { name: String, columns: Option<Vec<String>> },
     QGM(QGM),
     SetOption { name: String, value: Datum },
 }
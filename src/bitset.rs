@@ -3,7 +3,7 @@
 use std::{
     cell::RefCell,
     collections::HashMap,
-    hash::Hash,
+    hash::{Hash, Hasher},
     ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign},
     rc::Rc,
 };
@@ -104,6 +104,16 @@ where
     }
 }
 
+// Bitsets sharing a dictionary hash alike whenever they hold the same elements
+impl<T> Hash for Bitset<T>
+where
+    T: Hash + PartialEq + Eq + Copy,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bitmap.hash(state)
+    }
+}
+
 impl<T> Clone for Bitset<T>
 where
     T: Hash + PartialEq + Eq + Copy,
@@ -54,12 +54,8 @@ lazy_static! {
 }
 
 pub fn is_numeric(dt: &DataType) -> bool {
-    let metadata = DATATYPE_PROPS.get(dt);
-    if let Some(metadata) = metadata {
-        metadata.is_numeric
-    } else {
-        panic!("is_numeric() passed invalid datatype")
-    }
+    // Types without metadata, e.g. strings, aren't numeric
+    DATATYPE_PROPS.get(dt).map_or(false, |metadata| metadata.is_numeric)
 }
 
 pub fn to_datatype(name: &str) -> Option<&DataType> {
@@ -133,6 +129,8 @@ pub enum Datum {
     Utf8(String),
     Date32(i32),
     Float64(F64),
+    Decimal(i128, usize, usize), // Unscaled value, precision, scale
+    Binary(Vec<u8>),
 }
 
 impl Datum {
@@ -172,6 +170,46 @@ impl Datum {
         }
     }
 
+    #[inline]
+    pub fn try_as_i128(&self) -> Option<i128> {
+        if let Decimal(val, ..) = self {
+            Some(*val)
+        } else {
+            None
+        }
+    }
+
+    // to_f64: Numeric value of a datum, for aggregates that compute in floating point. Dates count days since the epoch.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Int32(val) | Date32(val) => Some(*val as f64),
+            Int64(val) => Some(*val as f64),
+            Float64(val) => Some(f64::from(*val)),
+            Decimal(val, _, scale) => Some(*val as f64 / 10f64.powi(*scale as i32)),
+            _ => None,
+        }
+    }
+
+    // add: Sum of two numeric datums of the same type
+    pub fn add(&self, other: &Datum) -> Datum {
+        match (self, other) {
+            (Int32(lhs), Int32(rhs)) => Int32(lhs + rhs),
+            (Int64(lhs), Int64(rhs)) => Int64(lhs + rhs),
+            (Float64(lhs), Float64(rhs)) => Float64(F64::from(f64::from(*lhs) + f64::from(*rhs))),
+            (Decimal(lhs, precision, scale), Decimal(rhs, ..)) => Decimal(lhs + rhs, *precision, *scale),
+            _ => panic!("add: Cannot add {:?} and {:?}", self, other),
+        }
+    }
+
+    // compare: Order two datums of the same type. Unlike the derived ordering, floats compare by value rather than by bits.
+    pub fn compare(&self, other: &Datum) -> std::cmp::Ordering {
+        if let (Float64(lhs), Float64(rhs)) = (self, other) {
+            f64::from(*lhs).total_cmp(&f64::from(*rhs))
+        } else {
+            self.cmp(other)
+        }
+    }
+
     #[inline]
     pub fn try_as_str(&self) -> Option<&str> {
         if let Utf8(val) = self {
@@ -181,6 +219,24 @@ impl Datum {
         }
     }
 
+    #[inline]
+    pub fn try_as_bytes(&self) -> Option<&[u8]> {
+        if let Binary(val) = self {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn try_as_bool(&self) -> Option<bool> {
+        if let Boolean(val) = self {
+            Some(*val)
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn try_as_i32(&self) -> Option<i32> {
         match *self {
@@ -199,6 +255,8 @@ impl Datum {
             Utf8(_) => DataType::UInt64,
             Date32(_) => DataType::Date32,
             Float64(_) => DataType::Float64,
+            Decimal(_, precision, scale) => DataType::Decimal(*precision, *scale),
+            Binary(_) => DataType::Binary,
         }
     }
 }
@@ -213,6 +271,13 @@ impl fmt::Display for Datum {
             Utf8(sl) => write!(f, "\"{}\"", sl),
             Date32(d) => write!(f, "\"{}\"", d),
             Float64(value) => write!(f, "{:?}", Into::<F64>::into(*value)),
+            Decimal(value, _, 0) => write!(f, "{}", value),
+            Decimal(value, _, scale) => {
+                let divisor = 10i128.pow(*scale as u32);
+                let sign = if *value < 0 { "-" } else { "" };
+                write!(f, "{}{}.{:0width$}", sign, value.abs() / divisor, value.abs() % divisor, width = *scale)
+            }
+            Binary(bytes) => write!(f, "<{} bytes>", bytes.len()),
         }
     }
 }
@@ -1,12 +1,25 @@
 // env
 
-use crate::{includes::*, logging, metadata::Metadata, scheduler::Scheduler};
+use std::collections::HashMap;
+
+use crate::{
+    includes::*,
+    logging,
+    metadata::Metadata,
+    scalar::{ScalarUdf, ScalarUdfFn},
+    scheduler::{Scheduler, SchedulerMessage},
+};
 
 #[derive(Debug, Default)]
 pub struct EnvSettings {
     pub parallel_degree: Option<usize>,
     pub parse_only: Option<bool>,
     pub trace: Option<String>,
+    pub sort_memory_budget: Option<usize>,
+    pub max_recursion: Option<usize>,
+    pub join_dp_limit: Option<usize>,
+    pub histogram_buckets: Option<usize>,
+    pub broadcast_threshold: Option<usize>,
 }
 
 pub struct Env {
@@ -16,6 +29,7 @@ pub struct Env {
     pub input_pathname: String,
     pub output_dir: String,
     pub settings: EnvSettings,
+    pub scalar_udfs: HashMap<String, ScalarUdf>,
 }
 
 impl Env {
@@ -24,7 +38,7 @@ impl Env {
         let metadata = Metadata::default();
         let options = EnvSettings::default();
 
-        Env { id, scheduler, metadata, input_pathname, output_dir, settings: options }
+        Env { id, scheduler, metadata, input_pathname, output_dir, settings: options, scalar_udfs: HashMap::new() }
     }
 
     pub fn set_option(&mut self, name: String, value: Datum) -> Result<(), String> {
@@ -32,6 +46,11 @@ impl Env {
         let name = name.to_uppercase();
         match name.as_str() {
             "PARALLEL_DEGREE" => self.settings.parallel_degree = Some(Self::get_int_option(name.as_str(), &value)? as usize),
+            "SORT_MEMORY_BUDGET" => self.settings.sort_memory_budget = Some(Self::get_int_option(name.as_str(), &value)? as usize),
+            "MAX_RECURSION" => self.settings.max_recursion = Some(Self::get_int_option(name.as_str(), &value)? as usize),
+            "JOIN_DP_LIMIT" => self.settings.join_dp_limit = Some(Self::get_int_option(name.as_str(), &value)? as usize),
+            "BROADCAST_THRESHOLD" => self.settings.broadcast_threshold = Some(Self::get_int_option(name.as_str(), &value)? as usize),
+            "HISTOGRAM_BUCKETS" => self.settings.histogram_buckets = Some(Self::get_int_option(name.as_str(), &value)? as usize),
             "PARSE_ONLY" => self.settings.parse_only = Some(self.get_boolean_option(name.as_str(), &value)?),
             "TRACE" => {
                 self.settings.trace = Some(Self::get_string_option(name.as_str(), &value)?.to_string());
@@ -42,6 +61,22 @@ impl Env {
         Ok(())
     }
 
+    // register_scalar_udf: Make `func` callable from SQL as `name(...)`. Arguments must match `arg_types` exactly, and columns
+    // passed to `func` hold the rows of one chunk. Only queries of this Env see the function.
+    pub fn register_scalar_udf(&mut self, name: &str, arg_types: Vec<DataType>, return_type: DataType, func: ScalarUdfFn) -> Result<(), String> {
+        let name = name.to_uppercase();
+        ScalarUdf::check_name(&name)?;
+        if self.scalar_udfs.contains_key(&name) {
+            return Err(f!("Function {name}() is already registered."));
+        }
+        let udf = ScalarUdf { arg_types, return_type, func };
+        for tx in self.scheduler.s2t_channels_sx.iter() {
+            tx.send(SchedulerMessage::RegisterUdf { name: name.clone(), udf: udf.clone() }).map_err(stringify)?
+        }
+        self.scalar_udfs.insert(name, udf);
+        Ok(())
+    }
+
     pub fn get_boolean_option(&self, name: &str, value: &Datum) -> Result<bool, String> {
         if let Utf8(s) = value {
             let s = s.to_uppercase();
@@ -7,8 +7,10 @@ use regex::Regex;
 use Expr::*;
 
 use crate::{
+    datum::F64,
     graph::{ExprKey, Graph, QueryBlockKey},
     includes::*,
+    qgm::Ordering,
 };
 
 pub type ExprGraph = Graph<ExprKey, Expr, ExprProp>;
@@ -63,10 +65,22 @@ pub enum RelOp {
     Ge,
     Lt,
     Le,
-    Is,
     Like,
 }
 
+impl RelOp {
+    // reverse: Operator that compares the same way once both operands are swapped
+    pub fn reverse(&self) -> RelOp {
+        match self {
+            RelOp::Gt => RelOp::Lt,
+            RelOp::Ge => RelOp::Le,
+            RelOp::Lt => RelOp::Gt,
+            RelOp::Le => RelOp::Ge,
+            op => *op,
+        }
+    }
+}
+
 impl fmt::Display for RelOp {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -77,7 +91,6 @@ impl fmt::Display for RelOp {
             RelOp::Ge => ">=",
             RelOp::Lt => "<",
             RelOp::Le => "<=",
-            RelOp::Is => "IS",
             RelOp::Like => "LIKE",
         };
         write!(f, "{}", display_str)
@@ -91,6 +104,139 @@ pub enum AggType {
     MAX,
     SUM,
     AVG,
+    STDDEV_SAMP,
+    STDDEV_POP,
+    VAR_SAMP,
+    VAR_POP,
+    MEDIAN,
+    PERCENTILE_CONT(F64),
+    APPROX_COUNT_DISTINCT,
+    APPROX_PERCENTILE(F64),
+    SUM_SQUARES,     // Partial aggregate of the variance family, not available in SQL
+    HLL_SKETCH,      // Partial aggregate of APPROX_COUNT_DISTINCT, not available in SQL
+    QUANTILE_SKETCH, // Partial aggregate of APPROX_PERCENTILE, not available in SQL
+    HLL_MERGE,       // Merges HLL_SKETCH partials, not available in SQL
+    QUANTILE_MERGE,  // Merges QUANTILE_SKETCH partials, not available in SQL
+}
+
+impl AggType {
+    // is_variance: Aggregates computed from the count, sum and sum-of-squares of their input
+    pub fn is_variance(&self) -> bool {
+        matches!(self, AggType::STDDEV_SAMP | AggType::STDDEV_POP | AggType::VAR_SAMP | AggType::VAR_POP)
+    }
+
+    // is_holistic: Aggregates that need all of their input values at once, and so cannot be computed from partial aggregates
+    pub fn is_holistic(&self) -> bool {
+        matches!(self, AggType::MEDIAN | AggType::PERCENTILE_CONT(_))
+    }
+
+    // sketch_aggtype: Approximate aggregates are computed from a sketch of their input, which this partial aggregate builds
+    pub fn sketch_aggtype(&self) -> Option<AggType> {
+        match self {
+            AggType::APPROX_COUNT_DISTINCT => Some(AggType::HLL_SKETCH),
+            AggType::APPROX_PERCENTILE(_) => Some(AggType::QUANTILE_SKETCH),
+            _ => None,
+        }
+    }
+
+    // finish_variance: Compute a variance/standard deviation from its partials. Sample statistics are NULL for less than two values.
+    pub fn finish_variance(&self, count: f64, sum: f64, sum_squares: f64) -> Option<f64> {
+        let divisor = match self {
+            AggType::VAR_SAMP | AggType::STDDEV_SAMP => count - 1.0,
+            AggType::VAR_POP | AggType::STDDEV_POP => count,
+            _ => panic!("finish_variance: {:?} is not a variance aggregate", self),
+        };
+        if divisor <= 0.0 {
+            return None;
+        }
+        // Rounding can make the variance of (nearly) identical values slightly negative
+        let variance = ((sum_squares - sum * sum / count) / divisor).max(0.0);
+        if matches!(self, AggType::STDDEV_SAMP | AggType::STDDEV_POP) {
+            Some(variance.sqrt())
+        } else {
+            Some(variance)
+        }
+    }
+
+    // finish_percentile: Continuous percentile of sorted values, interpolating linearly between the two nearest ones
+    pub fn finish_percentile(&self, sorted_values: &[f64]) -> Option<f64> {
+        let fraction = match self {
+            AggType::MEDIAN => 0.5,
+            AggType::PERCENTILE_CONT(fraction) => f64::from(*fraction),
+            _ => panic!("finish_percentile: {:?} is not a percentile aggregate", self),
+        };
+        if sorted_values.is_empty() {
+            return None;
+        }
+        let position = fraction * (sorted_values.len() - 1) as f64;
+        let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+        Some(sorted_values[lower] + (sorted_values[upper] - sorted_values[lower]) * (position - lower as f64))
+    }
+}
+
+/***************************************************************************************************/
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum WindowFuncType {
+    ROW_NUMBER,
+    RANK,
+    DENSE_RANK,
+    LAG(usize),
+    LEAD(usize),
+    FIRST_VALUE,
+    AGG(AggType), // COUNT, SUM, AVG, MIN, MAX over the frame of each row
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum FrameUnits {
+    ROWS,
+    RANGE,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(usize),
+    CurrentRow,
+    Following(usize),
+    UnboundedFollowing,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct WindowFrame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+impl std::default::Default for WindowFrame {
+    // Without an explicit frame, a row sees everything up to its last peer
+    fn default() -> Self {
+        WindowFrame { units: FrameUnits::RANGE, start: FrameBound::UnboundedPreceding, end: FrameBound::CurrentRow }
+    }
+}
+
+// WindowFunc: Children of a window function are its arguments, followed by its PARTITION BY and ORDER BY keys
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct WindowFunc {
+    pub func_type: WindowFuncType,
+    pub nargs: usize,
+    pub npartition_keys: usize,
+    pub orderings: Vec<Ordering>,
+    pub frame: WindowFrame,
+}
+
+impl WindowFunc {
+    pub fn args<'a>(&self, children: &'a [ExprKey]) -> &'a [ExprKey] {
+        &children[..self.nargs]
+    }
+
+    pub fn partition_keys<'a>(&self, children: &'a [ExprKey]) -> &'a [ExprKey] {
+        &children[self.nargs..self.nargs + self.npartition_keys]
+    }
+
+    pub fn order_keys<'a>(&self, children: &'a [ExprKey]) -> &'a [ExprKey] {
+        &children[self.nargs + self.npartition_keys..]
+    }
 }
 
 /***************************************************************************************************/
@@ -129,7 +275,12 @@ pub enum Expr {
     Subquery(QueryBlockKey),
     AggFunction(AggType, bool),
     ScalarFunction(String),
+    WindowFunction(WindowFunc),
     Cast,
+    CaseExpr { has_operand: bool, has_else: bool }, // Children: [operand] (WHEN, THEN)* [ELSE]
+    CoalesceExpr,
+    NullIfExpr,
+    IsNullExpr { negated: bool },
 }
 
 impl Expr {
@@ -160,7 +311,13 @@ impl Expr {
                 format!("{:?}", aggtype)
             }
             ScalarFunction(name) => format!("{}()", name),
+            WindowFunction(window_func) => format!("{:?} OVER", window_func.func_type),
             Cast => String::from("CAST"),
+            CaseExpr { .. } => String::from("CASE"),
+            CoalesceExpr => String::from("COALESCE"),
+            NullIfExpr => String::from("NULLIF"),
+            IsNullExpr { negated: false } => String::from("IS NULL"),
+            IsNullExpr { negated: true } => String::from("IS NOT NULL"),
         }
     }
 
@@ -204,6 +361,10 @@ impl Expr {
        AggFunction(AggType, bool),
        ScalarFunction(String),
        Cast,
+       CaseExpr { has_operand: bool, has_else: bool },
+       CoalesceExpr,
+       NullIfExpr,
+       IsNullExpr { negated: bool },
     */
     // FIXME: Add all cases!
     pub fn equals(&self, other: &Expr) -> bool {
@@ -217,7 +378,12 @@ impl Expr {
             (NegatedExpr, NegatedExpr) => true,
             (BetweenExpr, BetweenExpr) => true,
             (InListExpr, InListExpr) => true,
+            (CaseExpr { has_operand: o1, has_else: e1 }, CaseExpr { has_operand: o2, has_else: e2 }) => o1 == o2 && e1 == e2,
+            (CoalesceExpr, CoalesceExpr) => true,
+            (NullIfExpr, NullIfExpr) => true,
+            (IsNullExpr { negated: n1 }, IsNullExpr { negated: n2 }) => n1 == n2,
             (AggFunction(aggtype1, distinct1), AggFunction(aggtype2, distinct2)) => aggtype1 == aggtype2 && distinct1 == distinct2,
+            (ScalarFunction(name1), ScalarFunction(name2)) => name1 == name2,
             _ => false,
         }
     }
@@ -306,12 +472,22 @@ impl ExprKey {
                 let (lhs_key, rhs_key) = (children.unwrap()[0], children.unwrap()[1]);
                 format!("{} {} {}", lhs_key.describe(expr_graph, false), op, rhs_key.describe(expr_graph, false),)
             }
+            LogExpr(LogOp::Not) => {
+                let child_key = children.unwrap()[0];
+                format!("NOT ({})", child_key.describe(expr_graph, false))
+            }
             LogExpr(op) => {
                 let (lhs_key, rhs_key) = (children.unwrap()[0], children.unwrap()[1]);
                 format!("{} {} {}", lhs_key.describe(expr_graph, false), op, rhs_key.describe(expr_graph, false),)
             }
-            BetweenExpr => String::from("BETWEEEN"),
-            InListExpr => String::from("IN"),
+            BetweenExpr => {
+                let children = children.unwrap().iter().map(|child_key| child_key.describe(expr_graph, false)).collect::<Vec<_>>();
+                format!("{} BETWEEN {} AND {}", children[0], children[1], children[2])
+            }
+            InListExpr => {
+                let children = children.unwrap().iter().map(|child_key| child_key.describe(expr_graph, false)).collect::<Vec<_>>();
+                format!("{} IN ({})", children[0], children[1..].join(", "))
+            }
             InSubqExpr => String::from("IN_SUBQ"),
             ExistsExpr => String::from("EXISTS"),
             Subquery(_) => String::from("(subquery)"),
@@ -320,12 +496,44 @@ impl ExprKey {
                 format!("{:?}({})", aggtype, child_id.describe(expr_graph, false))
             }
             ScalarFunction(name) => {
-                format!("{}()", name)
+                let args = children.map_or(vec![], |children| children.iter().map(|child_key| child_key.describe(expr_graph, false)).collect());
+                format!("{}({})", name, args.join(", "))
+            }
+            WindowFunction(window_func) => {
+                let children = children.unwrap();
+                let describe_list = |keys: &[ExprKey]| keys.iter().map(|key| key.describe(expr_graph, false)).collect::<Vec<_>>().join(", ");
+                format!(
+                    "{:?}({}) OVER (PARTITION BY {} ORDER BY {})",
+                    window_func.func_type,
+                    describe_list(window_func.args(children)),
+                    describe_list(window_func.partition_keys(children)),
+                    describe_list(window_func.order_keys(children))
+                )
             }
             Cast => {
                 let child_key = children.unwrap()[0];
                 format!("({}) AS {:?}", child_key.describe(expr_graph, false), props.data_type())
             }
+            CaseExpr { has_operand, has_else } => {
+                let mut children = children.unwrap().iter().map(|child_key| child_key.describe(expr_graph, false)).collect::<Vec<_>>();
+                let mut retval = String::from("CASE");
+                if *has_operand {
+                    retval.push_str(&format!(" {}", children.remove(0)));
+                }
+                let else_str = if *has_else { format!(" ELSE {}", children.pop().unwrap()) } else { String::new() };
+                for branch in children.chunks(2) {
+                    retval.push_str(&format!(" WHEN {} THEN {}", branch[0], branch[1]));
+                }
+                format!("{}{} END", retval, else_str)
+            }
+            CoalesceExpr | NullIfExpr => {
+                let args = children.unwrap().iter().map(|child_key| child_key.describe(expr_graph, false)).collect::<Vec<_>>();
+                format!("{}({})", expr.name(), args.join(", "))
+            }
+            IsNullExpr { .. } => {
+                let child_key = children.unwrap()[0];
+                format!("{} {}", child_key.describe(expr_graph, false), expr.name())
+            }
         };
 
         //let retval = format!("{}: {:?}", retval, props.data_type());
@@ -98,8 +98,16 @@ macro_rules! function_name {
 #[allow(unused_imports)]
 pub(crate) use function_name;
 
+pub fn get_pipeline_dir(flow_id: usize, stage_link: StageLink) -> String {
+    format!("{}/flow-{}/pipeline-{}-{}", TEMPDIR, flow_id, stage_link.0, stage_link.1)
+}
+
 pub fn get_partition_dir(flow_id: usize, stage_link: StageLink, pid: PartitionId) -> String {
-    format!("{}/flow-{}/pipeline-{}-{}/consumer-{}", TEMPDIR, flow_id, stage_link.0, stage_link.1, pid)
+    format!("{}/consumer-{}", get_pipeline_dir(flow_id, stage_link), pid)
+}
+
+pub fn get_sort_dir(flow_id: usize, stage_id: StageId, pop_key: crate::graph::POPKey, pid: PartitionId) -> String {
+    format!("{}/flow-{}/sort-{}-{}/partition-{}", TEMPDIR, flow_id, stage_id, pop_key, pid)
 }
 
 pub fn get_output_dir(flow_id: usize) -> String {
@@ -1,6 +1,6 @@
 // lop: Logical operators
 
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 use bimap::BiMap;
 use partitions::PartitionVec;
@@ -10,8 +10,10 @@ use crate::{
     expr::{Expr::*, ExprGraph, *},
     graph::{ExprKey, Graph, LOPKey, QueryBlockKey},
     includes::*,
-    metadata::{PartDesc, PartType},
-    qgm::{Quantifier, QueryBlock, QueryBlockGraph, QueryBlockType},
+    lop_estimate::{LOPEstimate, LOPEstimator},
+    lop_joinorder::JoinTree,
+    metadata::{PartDesc, PartType, TableDesc},
+    qgm::{DistinctProperty, JoinDesc, JoinType, Ordering, Quantifier, QueryBlock, QueryBlockGraph, QueryBlockType},
     QGM,
 };
 
@@ -40,6 +42,16 @@ impl LOPKey {
         Schema::from(fields)
     }
 
+    // get_npartitions: Number of partitions flowing out of a node. A repartition hands its output to its consumers.
+    pub fn get_npartitions(&self, lop_graph: &LOPGraph) -> usize {
+        let (lop, props, _) = lop_graph.get3(*self);
+        if let LOP::Repartition { cpartitions } = lop {
+            *cpartitions
+        } else {
+            props.partdesc.npartitions
+        }
+    }
+
     pub fn get_types(&self, qgm: &mut QGM, lop_graph: &LOPGraph) -> Vec<DataType> {
         let lopprops = &lop_graph.get(*self).properties;
         let expr_graph = &qgm.expr_graph;
@@ -65,10 +77,16 @@ impl LOPKey {
 /***************************************************************************************************/
 #[derive(Debug)]
 pub enum LOP {
-    TableScan { input_projection: Bitset<QunCol> },
-    HashJoin { lhs_join_keys: Vec<ExprKey>, rhs_join_keys: Vec<ExprKey> },
+    TableScan { input_projection: Bitset<QunCol>, limit: Option<usize> },
+    HashJoin { join_type: JoinType, lhs_join_keys: Vec<ExprKey>, rhs_join_keys: Vec<ExprKey>, join_preds: Vec<ExprKey>, null_aware: bool },
     Repartition { cpartitions: usize },
-    Aggregation { key_len: usize },
+    Aggregation { key_len: usize, having: Vec<ExprKey> }, // HAVING predicates filter the aggregated groups
+    Expand { key_len: usize, grouping_sets: Vec<Vec<usize>> }, // One copy of each row per grouping set, tagged with its grouping-id
+    Sort { sort_keys: Vec<(ExprKey, Ordering)>, limit: Option<usize> },
+    Limit { offset: usize, limit: usize },
+    Window { partition_keys: Vec<ExprKey>, order_keys: Vec<(ExprKey, Ordering)>, window_fns: Vec<ExprKey> }, // Appends window functions sharing these keys
+    UnionAll { recursive: bool },
+    RecursiveScan { cte_lop_key: LOPKey }, // Rows produced by the previous iteration of a recursive CTE
 }
 
 /***************************************************************************************************/
@@ -81,11 +99,12 @@ pub struct LOPProps {
     pub virtcols: Option<Vec<VirtCol>>,
     pub preds: Bitset<ExprKey>,
     pub partdesc: PartDesc,
+    pub estimate: LOPEstimate,
 }
 
 impl LOPProps {
     fn new(quns: Bitset<QunId>, cols: Bitset<QunCol>, virtcols: Option<Vec<VirtCol>>, preds: Bitset<ExprKey>, partdesc: PartDesc) -> Self {
-        LOPProps { quns, cols, preds, partdesc, virtcols }
+        LOPProps { quns, cols, preds, partdesc, virtcols, estimate: LOPEstimate::default() }
     }
 }
 
@@ -111,6 +130,9 @@ pub struct APSContext {
     all_quncols: Bitset<QunCol>,
     all_quns: Bitset<QunId>,
     all_preds: Bitset<ExprKey>,
+    cte_plans: HashMap<QueryBlockKey, LOPKey>, // Materialized CTEs
+    recursive_cte: Option<QueryBlockKey>,      // Recursive CTE being planned
+    tabledescs: HashMap<QunId, Rc<dyn TableDesc>>, // Quantifier -> table, for its statistics
 }
 
 impl APSContext {
@@ -139,12 +161,14 @@ impl APSContext {
             panic!("APSContext::new(): Unresolved datatypes in expression graph.");
         }
 
-        APSContext { all_quncols, all_quns, all_preds }
+        let tabledescs = all_quns.elements().into_iter().filter_map(|qunid| qgm.metadata.get_tabledesc(qunid).map(|tabledesc| (qunid, tabledesc))).collect();
+
+        APSContext { all_quncols, all_quns, all_preds, cte_plans: HashMap::new(), recursive_cte: None, tabledescs }
     }
 }
 
 /***************************************************************************************************/
-type PredMap = HashMap<ExprKey, PredDesc>;
+pub type PredMap = HashMap<ExprKey, PredDesc>;
 
 pub struct EqJoinDesc {
     lhs_quns: Bitset<QunId>,
@@ -153,8 +177,9 @@ pub struct EqJoinDesc {
 
 pub struct PredDesc {
     quncols: Bitset<QunCol>,
-    quns: Bitset<QunId>,
-    eqjoin_desc: Option<EqJoinDesc>,
+    pub quns: Bitset<QunId>,
+    pub eqjoin_desc: Option<EqJoinDesc>,
+    pub join_ix: Option<usize>, // Index into qblock.joins for ON-clause predicates
 }
 
 pub struct ExprEqClass {
@@ -200,25 +225,153 @@ impl ExprEqClass {
 
 impl QGM {
     pub fn build_logical_plan(self: &mut QGM, env: &Env) -> Result<(LOPGraph, LOPKey), String> {
+        // Infer predicates and push them into derived tables and aggregations
+        self.qrw_pushdown_preds();
+
         // Construct bitmaps
-        let aps_context = APSContext::new(self);
+        let mut aps_context = APSContext::new(self);
         let mut lop_graph: LOPGraph = Graph::default();
 
+        // CTEs referenced just once get inlined like derived tables. The others are planned once and materialized into a stage of
+        // their own, which all their references read. So are recursive CTEs, whose recursive query block reads back their rows.
+        for cte_key in self.cte_list.clone() {
+            let nrefs = self.iter_qblocks().flat_map(|qbkey| self.qblock_graph.get_value(qbkey).quns.iter()).filter(|qun| qun.get_qblock_key() == Some(cte_key)).count();
+            let recursive = self.qblock_graph.get_value(cte_key).recursive;
+            if nrefs > 1 || recursive {
+                let APSContext { all_quncols, all_quns, all_preds, .. } = &aps_context;
+                let props = LOPProps::new(all_quns.clone_metadata(), all_quncols.clone_metadata(), None, all_preds.clone_metadata(), PartDesc::new(1, PartType::RAW));
+                let materialize_lop_key = lop_graph.add_node_with_props(LOP::Repartition { cpartitions: 1 }, props, None);
+                aps_context.cte_plans.insert(cte_key, materialize_lop_key);
+                aps_context.recursive_cte = recursive.then_some(cte_key);
+
+                let (qblock_graph, expr_graph, _) = self.borrow_parts();
+                let cte_lop_key = Self::build_qblock_logical_plan(qblock_graph, expr_graph, env, cte_key, &aps_context, &mut lop_graph, None)?;
+                aps_context.recursive_cte = None;
+
+                let props = lop_graph.get_properties(cte_lop_key);
+                let partdesc = PartDesc::new(cte_lop_key.get_npartitions(&lop_graph), PartType::RAW);
+                let props = LOPProps::new(props.quns.clone(), props.cols.clone_metadata(), props.virtcols.clone(), props.preds.clone_metadata(), partdesc);
+                let materialize_lop = lop_graph.get_mut(materialize_lop_key);
+                materialize_lop.properties = props;
+                materialize_lop.children = Some(vec![cte_lop_key]);
+            }
+        }
+
         let main_qblock_key = self.main_qblock_key;
         let (qblock_graph, expr_graph, _) = self.borrow_parts();
 
         let lop_key = Self::build_qblock_logical_plan(qblock_graph, expr_graph, env, main_qblock_key, &aps_context, &mut lop_graph, None);
-        if let Ok(lop_key) = lop_key {
-            // Perform any rewrites
-            let lop_key = self.qrw_add_repartitioning_keys_to_projections(&mut lop_graph, lop_key);
-            let lop_key = self.qrw_pushdown_join_keys(&mut lop_graph, lop_key);
-
-            let plan_pathname = format!("{}/{}", env.output_dir, "lop.dot");
-            self.write_logical_plan_to_graphviz(&lop_graph, lop_key, &plan_pathname)?;
-            Ok((lop_graph, lop_key))
+        let lop_key = lop_key.map(|lop_key| Self::build_sort_plan(qblock_graph.get_value(main_qblock_key), &mut lop_graph, lop_key));
+        let lop_key = lop_key.map(|lop_key| Self::build_limit_plan(qblock_graph.get_value(main_qblock_key), expr_graph, &mut lop_graph, lop_key));
+        let lop_key = lop_key?;
+
+        // Perform any rewrites
+        let lop_key = self.qrw_add_repartitioning_keys_to_projections(&mut lop_graph, lop_key);
+        let lop_key = self.qrw_pushdown_join_keys(&mut lop_graph, lop_key);
+
+        LOPEstimator::new(&self.expr_graph, &aps_context.tabledescs).estimate(&mut lop_graph, lop_key);
+
+        let plan_pathname = format!("{}/{}", env.output_dir, "lop.dot");
+        self.write_logical_plan_to_graphviz(&lop_graph, lop_key, &plan_pathname)?;
+        Ok((lop_graph, lop_key))
+    }
+
+    // build_sort_plan: Sort the output of the main query block if it has an ORDER BY clause. Multi-partition inputs are sorted
+    // partition-wise, and the sorted partitions are then merged into a single output partition. With TOP/LIMIT, every sort only
+    // keeps the leading rows it needs.
+    fn build_sort_plan(qblock: &QueryBlock, lop_graph: &mut LOPGraph, lop_key: LOPKey) -> LOPKey {
+        let Some(order_by) = qblock.order_by.as_ref() else { return lop_key };
+
+        let mut props = lop_graph.get_properties(lop_key).clone();
+        props.preds = props.preds.clone_metadata();
+        props.partdesc.npartitions = lop_key.get_npartitions(lop_graph);
+
+        // The plan's output virtual columns line up with the select-list. Sort keys are select-list entries, so map them by position
+        // since the plan may have rewritten them (e.g. two-phase aggregation).
+        let virtcols = props.virtcols.clone().unwrap();
+        let sort_keys = order_by
+            .iter()
+            .map(|&(expr_key, ordering)| {
+                let ix = qblock.select_list.iter().position(|ne| ne.expr_key == expr_key).unwrap();
+                (virtcols[ix], ordering)
+            })
+            .collect::<Vec<_>>();
+
+        // Hidden ORDER BY columns are used for sorting but don't flow out of the final sort
+        let nvisible = qblock.select_list.len() - qblock.nhidden;
+        let output_virtcols = virtcols.into_iter().take(nvisible).collect::<Vec<_>>();
+
+        let npartitions = props.partdesc.npartitions;
+        let limit = qblock.top_n.map(|top_n| top_n + qblock.offset.unwrap_or(0));
+
+        let sort_lop_key = lop_graph.add_node_with_props(LOP::Sort { sort_keys: sort_keys.clone(), limit }, props.clone(), Some(vec![lop_key]));
+        let lop_key = if npartitions > 1 {
+            // Funnel all sorted partitions into one consumer
+            let mut repart_props = props.clone();
+            repart_props.partdesc = PartDesc::new(npartitions, PartType::RAW);
+            let repart_lop_key = lop_graph.add_node_with_props(LOP::Repartition { cpartitions: 1 }, repart_props, Some(vec![sort_lop_key]));
+
+            let mut merge_props = props;
+            merge_props.partdesc = PartDesc::new(1, PartType::RAW);
+            lop_graph.add_node_with_props(LOP::Sort { sort_keys, limit }, merge_props, Some(vec![repart_lop_key]))
         } else {
-            Err("Something bad happened lol".to_string())
+            sort_lop_key
+        };
+        lop_graph.get_mut(lop_key).properties.virtcols = Some(output_virtcols);
+        lop_key
+    }
+
+    // build_limit_plan: Apply TOP/LIMIT/OFFSET to the output of the main query block. The limit is pushed into table scans when
+    // possible, and multi-partition inputs are limited partition-wise before being funneled into a single output partition.
+    fn build_limit_plan(qblock: &QueryBlock, expr_graph: &ExprGraph, lop_graph: &mut LOPGraph, lop_key: LOPKey) -> LOPKey {
+        let Some(limit) = qblock.top_n else { return lop_key };
+        let offset = qblock.offset.unwrap_or(0);
+        let nrows = offset + limit;
+
+        let (lop, props, _) = lop_graph.get3(lop_key);
+        let output_virtcols = props.virtcols.clone();
+        let npartitions = lop_key.get_npartitions(lop_graph);
+
+        // Push the limit below the select-list: compute select-list expressions only for rows that survive the limit.
+        // Aggregations, sorts and window functions define their own outputs, so their select-lists stay put.
+        if !matches!(lop, LOP::Aggregation { .. } | LOP::Sort { .. } | LOP::Window { .. }) {
+            let mut quncols = props.cols.clone_metadata();
+            output_virtcols.iter().flatten().flat_map(|expr_key| expr_key.iter_quncols(expr_graph)).for_each(|quncol| quncols.set(quncol));
+            if !quncols.is_empty() {
+                let props = &mut lop_graph.get_mut(lop_key).properties;
+                props.cols = quncols;
+                props.virtcols = None;
+            }
         }
+
+        let mut props = lop_graph.get_properties(lop_key).clone();
+        props.preds = props.preds.clone_metadata();
+        props.partdesc.npartitions = npartitions;
+
+        // Scans stop reading once they've produced enough rows. Other inputs get limited partition-wise.
+        let lop_key = if let LOP::TableScan { limit: scan_limit, .. } = &mut lop_graph.get_mut(lop_key).value {
+            *scan_limit = Some(nrows);
+            lop_key
+        } else if npartitions > 1 {
+            lop_graph.add_node_with_props(LOP::Limit { offset: 0, limit: nrows }, props.clone(), Some(vec![lop_key]))
+        } else {
+            lop_key
+        };
+
+        let lop_key = if npartitions > 1 {
+            // Funnel all partitions into one consumer
+            let mut repart_props = props.clone();
+            repart_props.partdesc = PartDesc::new(npartitions, PartType::RAW);
+            lop_graph.add_node_with_props(LOP::Repartition { cpartitions: 1 }, repart_props, Some(vec![lop_key]))
+        } else {
+            lop_key
+        };
+
+        let mut limit_props = props;
+        limit_props.cols = limit_props.cols.clone_metadata();
+        limit_props.virtcols = output_virtcols;
+        limit_props.partdesc = PartDesc::new(1, PartType::RAW);
+        lop_graph.add_node_with_props(LOP::Limit { offset, limit }, limit_props, Some(vec![lop_key]))
     }
 
     fn append_virt_cols(lop_graph: &mut LOPGraph, lop_key: LOPKey, newcols: Option<&Vec<VirtCol>>) {
@@ -259,7 +412,7 @@ impl QGM {
         while let Some(lop_key) = iter.next(lop_graph) {
             let lop = lop_graph.get(lop_key);
             match &lop.value {
-                LOP::HashJoin { lhs_join_keys, rhs_join_keys } => {
+                LOP::HashJoin { lhs_join_keys, rhs_join_keys, .. } => {
                     // Only push down projections that are NOT column references. Singleton columns are already a part of the projection.
                     let lhs_has_columns_only = lhs_join_keys.iter().all(|e| e.is_column(&self.expr_graph));
                     let rhs_has_columns_only = rhs_join_keys.iter().all(|e| e.is_column(&self.expr_graph));
@@ -287,12 +440,11 @@ impl QGM {
         expected_partitioning: Option<&PartDesc>,
     ) -> Result<LOPKey, String> {
         let qblock = &qblock_graph.get(qblock_key).value;
+        if matches!(qblock.qbtype, QueryBlockType::Union | QueryBlockType::UnionAll | QueryBlockType::Intersect | QueryBlockType::Except) {
+            return Self::build_setop_logical_plan(qblock_graph, expr_graph, env, qblock_key, aps_context, lop_graph, expected_partitioning);
+        }
         let mut worklist: Vec<LOPKey> = vec![];
 
-        //assert!(self.cte_list.is_empty());
-
-        let all_preds = &aps_context.all_preds;
-
         // Process select-list: Collect all QunCols
         let select_list_quncol = Self::collect_selectlist_quncols(qblock_graph, expr_graph, aps_context, qblock);
 
@@ -302,145 +454,561 @@ impl QGM {
         // Build unary plans first (i.e. baseline single table scans)
         Self::build_unary_plans(qblock_graph, expr_graph, env, aps_context, qblock, lop_graph, &mut pred_map, &eqclass, &select_list_quncol, &mut worklist)?;
 
-        // Run greedy join enumeration
-        let n = qblock.quns.len();
-        for _ix in (2..=n).rev() {
-            let mut join_status = None;
+        // ANSI joins and unnested subqueries fix the shape of the plan around them. Each one is planned as soon as both of its sides have
+        // been planned, and inner joins are only enumerated when none of them can be.
+        let mut pending_joins = (0..qblock.joins.len()).collect::<Vec<_>>();
+
+        while worklist.len() > 1 {
+            let ready_join = pending_joins.iter().position(|&join_ix| Self::find_join_legs(aps_context, &qblock.joins[join_ix], lop_graph, &worklist).is_some());
+            if let Some(pos) = ready_join {
+                let join_ix = pending_joins.remove(pos);
+                let join = &qblock.joins[join_ix];
+                Self::build_ansi_join_plan(qblock_graph, expr_graph, env, aps_context, qblock, join_ix, join, lop_graph, &mut pred_map, &eqclass, &select_list_quncol, &mut worklist)?;
+                continue;
+            }
 
-            // Iterate over pairs of all plans in work-list
-            'outer: for (_ix1, &lhs_plan_key) in worklist.iter().enumerate() {
-                for (_ix2, &rhs_plan_key) in worklist.iter().enumerate() {
-                    if lhs_plan_key == rhs_plan_key {
-                        continue;
-                    }
+            // Enumerate inner joins among the remaining subplans
+            let estimator = LOPEstimator::new(expr_graph, &aps_context.tabledescs);
+            let Some(join_tree) = Self::enumerate_joins(expr_graph, &estimator, env, qblock, lop_graph, &pred_map, &pending_joins, &worklist) else {
+                // Only pending ANSI/semi/anti joins keep the subplans apart
+                let join_type = qblock.joins[pending_joins[0]].join_type;
+                return Err(f!("Cannot find plans for both sides of {} JOIN.", format!("{:?}", join_type).to_uppercase()));
+            };
 
-                    let lhs_props = &lop_graph.get(lhs_plan_key).properties;
-                    let rhs_props = &lop_graph.get(rhs_plan_key).properties;
+            let join_plans = join_tree.plans();
+            let join_lop_key =
+                Self::build_join_tree_plan(qblock_graph, expr_graph, env, aps_context, lop_graph, join_tree, &mut pred_map, &eqclass, &select_list_quncol);
+            worklist.retain(|elem| !join_plans.contains(elem));
+            worklist.insert(0, join_lop_key);
+        }
 
-                    let join_quns = &lhs_props.quns | &rhs_props.quns;
+        if worklist.len() == 1 {
+            let mut root_lop_key = worklist[0];
+            root_lop_key = Self::build_window_plan(qblock_graph, expr_graph, env, qblock, lop_graph, root_lop_key, &eqclass);
+            if matches!(qblock.distinct, DistinctProperty::Distinct) && !Self::is_unique_on_select_list(expr_graph, qblock) {
+                root_lop_key = Self::build_distinct_plan(qblock_graph, expr_graph, env, qblock, lop_graph, root_lop_key, &eqclass);
+            }
+            if let Some(expected_partitioning) = expected_partitioning {
+                root_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, root_lop_key, expected_partitioning, &eqclass);
+            }
 
-                    // Are there any join predicates between two subplans?
-                    // P1.quns should be superset of LHS quns
-                    // P2.quns should be superset of RHS quns
-                    let join_preds = pred_map
-                        .iter()
-                        .filter_map(|(pred_key, pred_desc)| {
-                            let PredDesc { quns, .. } = pred_desc;
-                            let is_subset = (quns & &join_quns) == *quns;
-                            if is_subset {
-                                Some(*pred_key)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
+            if qblock.qbtype == QueryBlockType::GroupBy {
+                // Only the select-list expressions flow out of a queryblock. We can clear the column bitset.
+                let props = &mut lop_graph.get_mut(root_lop_key).properties;
+                props.cols = props.cols.clone_metadata();
+            } else {
+                // Only the select-list expressions flow out of a queryblock. We can clear the column bitset.
+                let props = &mut lop_graph.get_mut(root_lop_key).properties;
+                let virtcols = qblock.select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
+                props.virtcols = Some(virtcols);
+                props.cols = props.cols.clone_metadata();
+            }
 
-                    // Only select equality predicates (hash/merge joins only)
-                    let mut equi_join_preds = join_preds
-                        .iter()
-                        .filter_map(|&pred_key| {
-                            if let Some(eqjoin_desc) = pred_map.get(&pred_key).unwrap().eqjoin_desc.as_ref() {
-                                let join_class = Self::classify_predicate(eqjoin_desc, lhs_props, rhs_props);
-                                if join_class.0 == PredicateType::EquiJoin {
-                                    Some((pred_key, join_class.1))
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>();
+            info!("Created logical plan for qblock id: {}", qblock.id);
 
-                    // Sort preds since the preceding hash-based ordering can be random
-                    equi_join_preds.sort_by(|a, b| a.0.cmp(&b.0));
-                    let eqq = equi_join_preds.iter().map(|e| e.0).collect::<Vec<_>>();
+            Ok(root_lop_key)
+        } else {
+            Err("Cannot find plan for qblock".to_string())
+        }
+    }
 
-                    if !equi_join_preds.is_empty() {
-                        let mut preds = all_preds.clone_metadata();
+    // build_window_plan: Window functions sharing their PARTITION BY and ORDER BY keys are computed by one Window operator. Its input is
+    // sorted on those keys partition-wise, then hash-partitioned on the PARTITION BY keys (or funneled into a single partition without
+    // them), with each consumer merging the sorted runs it receives. Window operators for different keys are stacked, each one passing
+    // on the window functions computed below it.
+    fn build_window_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, qblock: &QueryBlock, lop_graph: &mut LOPGraph, lop_key: LOPKey, eqclass: &ExprEqClass,
+    ) -> LOPKey {
+        let mut window_fns: Vec<ExprKey> = vec![];
+        for ne in qblock.select_list.iter() {
+            for expr_key in expr_graph.true_iter(ne.expr_key) {
+                if matches!(expr_graph.get_value(expr_key), WindowFunction(..)) && !window_fns.contains(&expr_key) {
+                    window_fns.push(expr_key);
+                }
+            }
+        }
 
-                        for pred_key in join_preds.iter() {
-                            // Don't add equijoin preds to the after-join list
-                            if !eqq.contains(pred_key) {
-                                preds.set(*pred_key);
-                            }
-                            pred_map.remove_entry(pred_key);
-                        }
+        // Group window functions by their keys
+        let same_keys = |keys1: &[ExprKey], keys2: &[ExprKey]| keys1.len() == keys2.len() && keys1.iter().zip(keys2).all(|(&k1, &k2)| Expr::isomorphic(expr_graph, k1, k2));
+        let mut specs: Vec<(Vec<ExprKey>, Vec<(ExprKey, Ordering)>, Vec<ExprKey>)> = vec![];
+        for expr_key in window_fns {
+            let (WindowFunction(window_func), _, Some(children)) = expr_graph.get3(expr_key) else { panic!("build_window_plan: Invalid window function") };
+            let partition_keys = window_func.partition_keys(children).to_vec();
+            let order_keys = window_func.order_keys(children).iter().cloned().zip(window_func.orderings.iter().cloned()).collect::<Vec<_>>();
+
+            let spec = specs.iter_mut().find(|(spec_partition_keys, spec_order_keys, _)| {
+                same_keys(spec_partition_keys, &partition_keys)
+                    && spec_order_keys.len() == order_keys.len()
+                    && spec_order_keys.iter().zip(order_keys.iter()).all(|((k1, o1), (k2, o2))| o1 == o2 && Expr::isomorphic(expr_graph, *k1, *k2))
+            });
+            if let Some((_, _, spec_window_fns)) = spec {
+                spec_window_fns.push(expr_key)
+            } else {
+                specs.push((partition_keys, order_keys, vec![expr_key]))
+            }
+        }
 
-                        // Initialize join properties
-                        let quns = &lhs_props.quns | &rhs_props.quns;
-                        let mut cols = &lhs_props.cols | &rhs_props.cols;
+        let mut lop_key = lop_key;
+        let mut computed_window_fns: Vec<ExprKey> = vec![];
+        for (partition_keys, order_keys, window_fns) in specs {
+            let mut props = lop_graph.get_properties(lop_key).clone();
+            props.preds = props.preds.clone_metadata();
+            props.partdesc.npartitions = lop_key.get_npartitions(lop_graph);
+            props.virtcols = (!computed_window_fns.is_empty()).then(|| computed_window_fns.clone());
+
+            let sort_keys = partition_keys.iter().map(|&expr_key| (expr_key, Ordering::Asc)).chain(order_keys.iter().cloned()).collect::<Vec<_>>();
+            let sort_lop_key = if sort_keys.is_empty() {
+                lop_key
+            } else {
+                lop_graph.add_node_with_props(LOP::Sort { sort_keys: sort_keys.clone(), limit: None }, props.clone(), Some(vec![lop_key]))
+            };
 
-                        // Compute cols to flow through. Retain all cols in the select-list + unbound preds
-                        let mut flowcols = select_list_quncol.clone();
-                        for (_, PredDesc { quncols, .. }) in pred_map.iter() {
-                            flowcols |= quncols;
-                        }
-                        cols &= flowcols;
+            let expected_partitioning = if partition_keys.is_empty() {
+                PartDesc::new(1, PartType::RAW)
+            } else {
+                PartDesc::new(env.settings.parallel_degree.unwrap_or(1), PartType::HASHEXPR(partition_keys.clone()))
+            };
+            let repart_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, sort_lop_key, &expected_partitioning, eqclass);
+            let child_lop_key = if repart_lop_key != sort_lop_key && !sort_keys.is_empty() {
+                // Merge the sorted runs of all producers
+                let mut merge_props = props.clone();
+                merge_props.partdesc = expected_partitioning;
+                lop_graph.add_node_with_props(LOP::Sort { sort_keys, limit: None }, merge_props, Some(vec![repart_lop_key]))
+            } else {
+                repart_lop_key
+            };
 
-                        let (new_lhs_plan_key, new_rhs_plan_key, lhs_join_keys, rhs_join_keys, cpartitions) =
-                            Self::repartition_join_legs(qblock_graph, expr_graph, env, lop_graph, lhs_plan_key, rhs_plan_key, &equi_join_preds, &eqclass);
+            computed_window_fns.extend(window_fns.iter().cloned());
+            let mut window_props = lop_graph.get_properties(child_lop_key).clone();
+            window_props.preds = window_props.preds.clone_metadata();
+            window_props.partdesc.npartitions = child_lop_key.get_npartitions(lop_graph);
+            window_props.virtcols = Some(computed_window_fns.clone());
+            lop_key = lop_graph.add_node_with_props(LOP::Window { partition_keys, order_keys, window_fns }, window_props, Some(vec![child_lop_key]));
+        }
+        lop_key
+    }
 
-                        // Join partitioning is identical to partitioning of the LHS.
-                        let lhs_props = &lop_graph.get(new_lhs_plan_key).properties;
-                        let mut partdesc = lhs_props.partdesc.clone();
-                        partdesc.npartitions = cpartitions;
+    // build_distinct_plan: SELECT DISTINCT is a keys-only aggregation on the select-list, hash-partitioned on it if the input has several
+    // partitions.
+    fn build_distinct_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, qblock: &QueryBlock, lop_graph: &mut LOPGraph, lop_key: LOPKey, eqclass: &ExprEqClass,
+    ) -> LOPKey {
+        // Aggregations already produce their select-list
+        let props = &mut lop_graph.get_mut(lop_key).properties;
+        if qblock.qbtype != QueryBlockType::GroupBy {
+            props.virtcols = Some(qblock.select_list.iter().map(|ne| ne.expr_key).collect());
+        }
+        props.cols = props.cols.clone_metadata();
+        let keys = props.virtcols.clone().unwrap();
 
-                        let props = LOPProps::new(quns, cols, None, preds, partdesc);
+        let lop_key = if lop_key.get_npartitions(lop_graph) > 1 {
+            let expected_partitioning = PartDesc::new(env.settings.parallel_degree.unwrap_or(1), PartType::HASHEXPR(keys.clone()));
+            Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, lop_key, &expected_partitioning, eqclass)
+        } else {
+            lop_key
+        };
 
-                        let join_lop_key = lop_graph.add_node_with_props(
-                            LOP::HashJoin { lhs_join_keys, rhs_join_keys },
-                            props,
-                            Some(vec![new_lhs_plan_key, new_rhs_plan_key]),
-                        );
+        let mut props = lop_graph.get_properties(lop_key).clone();
+        props.preds = props.preds.clone_metadata();
+        props.partdesc.npartitions = lop_key.get_npartitions(lop_graph);
+        lop_graph.add_node_with_props(LOP::Aggregation { key_len: keys.len(), having: vec![] }, props, Some(vec![lop_key]))
+    }
 
-                        join_status = Some((lhs_plan_key, rhs_plan_key, join_lop_key));
+    // is_unique_on_select_list: A grouped query block's output is already distinct if its select-list includes every grouping key
+    fn is_unique_on_select_list(expr_graph: &ExprGraph, qblock: &QueryBlock) -> bool {
+        if let Some(group_by) = qblock.group_by.as_ref() {
+            let select_list = &qblock.select_list[..qblock.select_list.len() - qblock.nhidden];
+            (0..group_by.len()).all(|key_ix| select_list.iter().any(|ne| matches!(expr_graph.get_value(ne.expr_key), CID(_, colid) if *colid == key_ix)))
+        } else {
+            false
+        }
+    }
 
-                        // For now, go with the first equi-join
-                        break 'outer;
-                    }
+    // build_setop_logical_plan: UNION ALL concatenates its branches, repartitioning them only if their partition counts differ. The other
+    // set operations hash-partition both branches on all columns and aggregate on all of them. UNION just drops duplicates, while
+    // INTERSECT/EXCEPT tag rows with the branch they come from and keep groups seen in both branches/only in the first one.
+    fn build_setop_logical_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &mut ExprGraph, env: &Env, qblock_key: QueryBlockKey, aps_context: &APSContext, lop_graph: &mut LOPGraph,
+        expected_partitioning: Option<&PartDesc>,
+    ) -> Result<LOPKey, String> {
+        let qblock = &qblock_graph.get(qblock_key).value;
+        let qbtype = qblock.qbtype;
+        let is_tagged = matches!(qbtype, QueryBlockType::Intersect | QueryBlockType::Except);
+        let npartitions = env.settings.parallel_degree.unwrap_or(1);
+        let eqclass = ExprEqClass::default();
+
+        let mut children = vec![];
+        for (branch_ix, qun) in qblock.quns.iter().enumerate() {
+            let branch_key = qun.get_qblock_key().unwrap();
+            let mut child_lop_key = Self::build_qblock_logical_plan(qblock_graph, expr_graph, env, branch_key, aps_context, lop_graph, None)?;
+
+            // Rows that compare equal have to meet in the same partition, so branches are hashed on their outputs by position
+            if qbtype != QueryBlockType::UnionAll {
+                let virtcols = lop_graph.get_properties(child_lop_key).virtcols.clone().unwrap();
+                let partdesc = PartDesc::new(npartitions, PartType::HASHEXPR(virtcols));
+                child_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, child_lop_key, &partdesc, &eqclass);
+            }
+
+            if is_tagged {
+                let tag_key = expr_graph.add_node_with_props(Literal(Datum::Int64(branch_ix as i64)), ExprProp::new(DataType::Int64), None);
+                lop_graph.get_mut(child_lop_key).properties.virtcols.as_mut().unwrap().push(tag_key);
+            }
+            children.push(child_lop_key);
+        }
+
+        // UNION ALL branches only need to agree on partition counts
+        let child_npartitions = children.iter().map(|child_lop_key| child_lop_key.get_npartitions(lop_graph)).collect::<Vec<_>>();
+        if child_npartitions.iter().any(|&n| n != child_npartitions[0]) {
+            for child_lop_key in children.iter_mut() {
+                let virtcols = lop_graph.get_properties(*child_lop_key).virtcols.clone().unwrap();
+                let partdesc = PartDesc::new(npartitions, PartType::HASHEXPR(virtcols));
+                *child_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, *child_lop_key, &partdesc, &eqclass);
+            }
+        }
+        let npartitions = children[0].get_npartitions(lop_graph);
+
+        // Branch outputs are positional: the select-list of a set operation refers to them by column number
+        let qunid = qblock.quns[0].id;
+        let mut quns = aps_context.all_quns.clone_metadata();
+        quns.set(qunid);
+        let cols = aps_context.all_quncols.clone_metadata();
+        let partdesc = PartDesc::new(npartitions, PartType::RAW);
+        let select_list = qblock.select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
+
+        let mut virtcols = select_list.clone();
+        let tag_key = is_tagged.then(|| expr_graph.add_node_with_props(CID(qunid, select_list.len()), ExprProp::new(DataType::Int64), None));
+        virtcols.extend(tag_key);
+        let props = LOPProps::new(quns.clone(), cols.clone(), Some(virtcols), aps_context.all_preds.clone_metadata(), partdesc.clone());
+        let mut root_lop_key = lop_graph.add_node_with_props(LOP::UnionAll { recursive: qblock.recursive }, props, Some(children));
+
+        if qbtype != QueryBlockType::UnionAll {
+            let agg_of_tag = |aggtype: AggType, expr_graph: &mut ExprGraph| {
+                expr_graph.add_node_with_props(AggFunction(aggtype, false), ExprProp::new(DataType::Int64), Some(vec![tag_key.unwrap()]))
+            };
+            let pred_key = match qbtype {
+                QueryBlockType::Intersect => {
+                    let children = vec![agg_of_tag(AggType::MIN, expr_graph), agg_of_tag(AggType::MAX, expr_graph)];
+                    Some(expr_graph.add_node_with_props(RelExpr(RelOp::Lt), ExprProp::new(DataType::Boolean), Some(children)))
+                }
+                QueryBlockType::Except => {
+                    let zero_key = expr_graph.add_node_with_props(Literal(Datum::Int64(0)), ExprProp::new(DataType::Int64), None);
+                    let children = vec![agg_of_tag(AggType::MAX, expr_graph), zero_key];
+                    Some(expr_graph.add_node_with_props(RelExpr(RelOp::Eq), ExprProp::new(DataType::Boolean), Some(children)))
                 }
+                _ => None,
+            };
+            let having = pred_key.into_iter().collect();
+
+            let key_len = select_list.len();
+            let props = LOPProps::new(quns, cols, Some(select_list), aps_context.all_preds.clone_metadata(), partdesc);
+            root_lop_key = lop_graph.add_node_with_props(LOP::Aggregation { key_len, having }, props, Some(vec![root_lop_key]));
+        }
+
+        if let Some(expected_partitioning) = expected_partitioning {
+            root_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, root_lop_key, expected_partitioning, &eqclass);
+        }
+        Ok(root_lop_key)
+    }
+
+    // build_join_plan: Hash join two subplans, repartitioning either leg as needed. `preds` run on the joined rows while `join_preds`
+    // are residual ON-clause predicates of an outer/semi/anti join that decide whether rows match at all. A NOT IN anti-join also
+    // compares one pair of keys NULL-aware; those keys go last. Joins without keys broadcast their build side to every probe partition,
+    // and so do joins whose build side is estimated to be smaller than BROADCAST_THRESHOLD.
+    pub fn build_join_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, aps_context: &APSContext, lop_graph: &mut LOPGraph, lhs_plan_key: LOPKey,
+        rhs_plan_key: LOPKey, join_type: JoinType,
+        equi_join_preds: &[(ExprKey, PredicateAlignment)], null_aware_pred: Option<(ExprKey, PredicateAlignment)>, join_preds: Vec<ExprKey>, preds: Bitset<ExprKey>,
+        pred_map: &PredMap, eqclass: &ExprEqClass, select_list_quncol: &Bitset<QunCol>,
+    ) -> LOPKey {
+        let lhs_props = &lop_graph.get(lhs_plan_key).properties;
+        let rhs_props = &lop_graph.get(rhs_plan_key).properties;
+
+        // Initialize join properties. Semi/anti joins only return LHS rows.
+        let quns = &lhs_props.quns | &rhs_props.quns;
+        let mut cols = if matches!(join_type, JoinType::Semi | JoinType::Anti) { lhs_props.cols.clone() } else { &lhs_props.cols | &rhs_props.cols };
+
+        // Compute cols to flow through. Retain all cols in the select-list + unbound preds
+        let mut flowcols = select_list_quncol.clone();
+        for (_, PredDesc { quncols, .. }) in pred_map.iter() {
+            flowcols |= quncols;
+        }
+        cols &= flowcols;
+
+        // Chunks without columns carry no rows, so some column has to flow through even if nothing above needs it
+        if cols.is_empty() {
+            if let Some(&quncol) = lhs_props.cols.elements().first() {
+                cols.set(quncol);
             }
+        }
 
-            if let Some((plan1_key, plan2_key, join_node)) = join_status {
-                worklist.retain(|&elem| (elem != plan1_key && elem != plan2_key));
-                worklist.insert(0, join_node);
-            } else {
-                panic!("No join found!!!")
+        let lhs_npartitions = lhs_plan_key.get_npartitions(lop_graph);
+        let (new_lhs_plan_key, new_rhs_plan_key, mut lhs_join_keys, mut rhs_join_keys, cpartitions) = if equi_join_preds.is_empty()
+            && matches!(join_type, JoinType::Right | JoinType::Full)
+        {
+            // Unmatched build rows can only be found if all of them meet all probe rows
+            let lhs_plan_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, lhs_plan_key, &PartDesc::new(1, PartType::RAW), eqclass);
+            let rhs_plan_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, rhs_plan_key, &PartDesc::new(1, PartType::RAW), eqclass);
+            (lhs_plan_key, rhs_plan_key, vec![], vec![], 1)
+        } else if equi_join_preds.is_empty()
+            || Self::broadcast_build_side(env, &LOPEstimator::new(expr_graph, &aps_context.tabledescs), lop_graph, rhs_plan_key, join_type)
+        {
+            // Every probe partition gets the entire build side, and the probe side stays put
+            let rhs_plan_key =
+                Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, rhs_plan_key, &PartDesc::new(lhs_npartitions, PartType::BROADCAST), eqclass);
+            let (lhs_join_keys, rhs_join_keys) = Self::compute_join_partitioning_keys(expr_graph, equi_join_preds);
+            (lhs_plan_key, rhs_plan_key, lhs_join_keys, rhs_join_keys, lhs_npartitions)
+        } else {
+            Self::repartition_join_legs(qblock_graph, expr_graph, env, lop_graph, lhs_plan_key, rhs_plan_key, equi_join_preds, eqclass)
+        };
+
+        if let Some(null_aware_pred) = null_aware_pred {
+            let (lhs_keys, rhs_keys) = Self::compute_join_partitioning_keys(expr_graph, &[null_aware_pred]);
+            lhs_join_keys.extend(lhs_keys);
+            rhs_join_keys.extend(rhs_keys);
+        }
+
+        // Join partitioning is identical to partitioning of the LHS, unless NULL-extended LHS rows can show up anywhere.
+        let lhs_props = &lop_graph.get(new_lhs_plan_key).properties;
+        let mut partdesc = lhs_props.partdesc.clone();
+        partdesc.npartitions = cpartitions;
+        if matches!(join_type, JoinType::Right | JoinType::Full) {
+            partdesc.part_type = PartType::RAW;
+        }
+
+        let props = LOPProps::new(quns, cols, None, preds.clone_metadata(), partdesc);
+
+        let join_lop_key = lop_graph.add_node_with_props(
+            LOP::HashJoin { join_type, lhs_join_keys, rhs_join_keys, join_preds, null_aware: null_aware_pred.is_some() },
+            props,
+            Some(vec![new_lhs_plan_key, new_rhs_plan_key]),
+        );
+
+        // Predicates on one side alone can run ahead of the join, unless that side gets NULL-extended
+        for pred_key in preds.elements() {
+            Self::pushdown_pred(expr_graph, lop_graph, join_lop_key, pred_key);
+        }
+        join_lop_key
+    }
+
+    // pushdown_pred: Filter the output of a subplan as early as possible. Joins pass the filter on to a side whose rows they never
+    // NULL-extend, and repartitioning passes it on to its producers. The filter stays put once no input has all the columns it needs.
+    fn pushdown_pred(expr_graph: &ExprGraph, lop_graph: &mut LOPGraph, lop_key: LOPKey, pred_key: ExprKey) {
+        let (lop, props, children) = lop_graph.get3(lop_key);
+        let pred_quns = props.quns.clone_metadata().init(pred_key.iter_quns(expr_graph));
+        let sides = match lop {
+            LOP::HashJoin { join_type: JoinType::Inner, .. } => vec![0, 1],
+            LOP::HashJoin { join_type: JoinType::Left | JoinType::Scalar | JoinType::Semi | JoinType::Anti, .. } => vec![0],
+            LOP::HashJoin { join_type: JoinType::Right, .. } => vec![1],
+            LOP::Repartition { .. } => vec![0],
+            _ => vec![],
+        };
+        let children = children.cloned().unwrap_or_default();
+        let child_lop_key = sides.into_iter().filter_map(|ix| children.get(ix).cloned()).find(|&child_lop_key| {
+            let child_quns = &lop_graph.get_properties(child_lop_key).quns;
+            !pred_quns.is_empty() && pred_quns.is_subset_of(child_quns)
+        });
+        match child_lop_key {
+            Some(child_lop_key) => Self::pushdown_pred(expr_graph, lop_graph, child_lop_key, pred_key),
+            None => lop_graph.get_mut(lop_key).properties.preds.set(pred_key),
+        }
+    }
+
+    // build_join_tree_plan: Build the inner joins of an enumerated join tree bottom-up
+    fn build_join_tree_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, aps_context: &APSContext, lop_graph: &mut LOPGraph, join_tree: JoinTree,
+        pred_map: &mut PredMap, eqclass: &ExprEqClass, select_list_quncol: &Bitset<QunCol>,
+    ) -> LOPKey {
+        match join_tree {
+            JoinTree::Plan(lop_key) => lop_key,
+            JoinTree::Join(lhs, rhs) => {
+                let lhs_plan_key = Self::build_join_tree_plan(qblock_graph, expr_graph, env, aps_context, lop_graph, *lhs, pred_map, eqclass, select_list_quncol);
+                let rhs_plan_key = Self::build_join_tree_plan(qblock_graph, expr_graph, env, aps_context, lop_graph, *rhs, pred_map, eqclass, select_list_quncol);
+                Self::build_inner_join_plan(qblock_graph, expr_graph, env, aps_context, lop_graph, lhs_plan_key, rhs_plan_key, pred_map, eqclass, select_list_quncol)
             }
         }
+    }
 
-        if worklist.len() == 1 {
-            let mut root_lop_key = worklist[0];
-            if let Some(expected_partitioning) = expected_partitioning {
-                root_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, root_lop_key, expected_partitioning, &eqclass);
+    // build_inner_join_plan: Join two subplans on every WHERE predicate that becomes bound by joining them. Equalities across both sides
+    // become join keys. Without any, every row of one side meets every row of the other.
+    fn build_inner_join_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, aps_context: &APSContext, lop_graph: &mut LOPGraph, lhs_plan_key: LOPKey,
+        rhs_plan_key: LOPKey, pred_map: &mut PredMap, eqclass: &ExprEqClass, select_list_quncol: &Bitset<QunCol>,
+    ) -> LOPKey {
+        let lhs_props = &lop_graph.get(lhs_plan_key).properties;
+        let rhs_props = &lop_graph.get(rhs_plan_key).properties;
+        let join_quns = &lhs_props.quns | &rhs_props.quns;
+
+        // Are there any join predicates between two subplans?
+        // P1.quns should be superset of LHS quns
+        // P2.quns should be superset of RHS quns
+        let join_preds = pred_map
+            .iter()
+            .filter_map(|(pred_key, pred_desc)| {
+                let PredDesc { quns, join_ix, .. } = pred_desc;
+                let is_subset = (quns & &join_quns) == *quns;
+                if is_subset && join_ix.is_none() {
+                    Some(*pred_key)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Only select equality predicates (hash/merge joins only)
+        let mut equi_join_preds = join_preds
+            .iter()
+            .filter_map(|&pred_key| {
+                if let Some(eqjoin_desc) = pred_map.get(&pred_key).unwrap().eqjoin_desc.as_ref() {
+                    let join_class = Self::classify_predicate(eqjoin_desc, &lhs_props.quns, &rhs_props.quns);
+                    if join_class.0 == PredicateType::EquiJoin {
+                        Some((pred_key, join_class.1))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Sort preds since the preceding hash-based ordering can be random
+        equi_join_preds.sort_by(|a, b| a.0.cmp(&b.0));
+        let eqq = equi_join_preds.iter().map(|e| e.0).collect::<Vec<_>>();
+
+        let mut preds = aps_context.all_preds.clone_metadata();
+        for pred_key in join_preds.iter() {
+            // Don't add equijoin preds to the after-join list
+            if !eqq.contains(pred_key) {
+                preds.set(*pred_key);
             }
+            pred_map.remove_entry(pred_key);
+        }
 
-            if qblock.qbtype == QueryBlockType::GroupBy {
-                // Only the select-list expressions flow out of a queryblock. We can clear the column bitset.
-                let props = &mut lop_graph.get_mut(root_lop_key).properties;
-                props.cols = props.cols.clone_metadata();
+        Self::build_join_plan(
+            qblock_graph,
+            expr_graph,
+            env,
+            aps_context,
+            lop_graph,
+            lhs_plan_key,
+            rhs_plan_key,
+            JoinType::Inner,
+            &equi_join_preds,
+            None,
+            vec![],
+            preds,
+            pred_map,
+            eqclass,
+            select_list_quncol,
+        )
+    }
+
+    // find_join_legs: Find the plans for both sides of an ANSI or semi/anti join, if they have been built yet
+    fn find_join_legs(aps_context: &APSContext, join: &JoinDesc, lop_graph: &LOPGraph, worklist: &[LOPKey]) -> Option<(LOPKey, LOPKey)> {
+        let lhs_quns = aps_context.all_quns.clone_metadata().init(join.lhs_quns.iter().cloned());
+        let rhs_quns = aps_context.all_quns.clone_metadata().init(join.rhs_quns.iter().cloned());
+
+        let find_plan = |quns: &Bitset<QunId>| worklist.iter().cloned().find(|&lop_key| lop_graph.get_properties(lop_key).quns == *quns);
+        find_plan(&lhs_quns).zip(find_plan(&rhs_quns))
+    }
+
+    // build_ansi_join_plan: Join the plans for both sides of an ANSI or semi/anti join. ON-clause predicates on the side that rows may get
+    // NULL-extended against (or for semi/anti joins, on the subquery side) are applied to that side up front, equalities across both sides
+    // become join keys, and anything else has to be evaluated while matching. WHERE predicates only run after the join, and only if the join
+    // itself isn't nested inside a NULL-producing side.
+    pub fn build_ansi_join_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, env: &Env, aps_context: &APSContext, qblock: &QueryBlock, join_ix: usize, join: &JoinDesc,
+        lop_graph: &mut LOPGraph, pred_map: &mut PredMap, eqclass: &ExprEqClass, select_list_quncol: &Bitset<QunCol>, worklist: &mut Vec<LOPKey>,
+    ) -> Result<(), String> {
+        let join_type = join.join_type;
+        let join_name = format!("{:?}", join_type).to_uppercase();
+        let lhs_quns = aps_context.all_quns.clone_metadata().init(join.lhs_quns.iter().cloned());
+        let rhs_quns = aps_context.all_quns.clone_metadata().init(join.rhs_quns.iter().cloned());
+
+        // Both sides have been planned already
+        let Some((lhs_plan_key, rhs_plan_key)) = Self::find_join_legs(aps_context, join, lop_graph, worklist) else {
+            return Err(f!("Cannot find plans for both sides of {} JOIN.", join_name));
+        };
+
+        // Classify ON-clause predicates
+        let mut on_preds = pred_map.iter().filter(|(_, pred_desc)| pred_desc.join_ix == Some(join_ix)).map(|(&pred_key, _)| pred_key).collect::<Vec<_>>();
+        on_preds.sort();
+
+        let mut equi_join_preds = vec![];
+        let mut null_aware_pred = None;
+        let mut join_preds = vec![];
+        for pred_key in on_preds {
+            let PredDesc { quns, eqjoin_desc, .. } = pred_map.remove(&pred_key).unwrap();
+            let (lhs_props, rhs_props) = (lop_graph.get_properties(lhs_plan_key), lop_graph.get_properties(rhs_plan_key));
+            let join_class = eqjoin_desc.map(|eqjoin_desc| Self::classify_predicate(&eqjoin_desc, &lhs_props.quns, &rhs_props.quns));
+
+            if join.null_aware_pred == Some(pred_key) {
+                // NOT IN compares an expression of the outer query with the subquery's column
+                let Some((PredicateType::EquiJoin, alignment)) = join_class else {
+                    return Err("NOT IN requires an expression over the outer query compared against one over the subquery.".to_string());
+                };
+                null_aware_pred = Some((pred_key, alignment));
+            } else if let Some((PredicateType::EquiJoin, alignment)) = join_class {
+                equi_join_preds.push((pred_key, alignment));
+            } else if !quns.is_empty() && quns.is_subset_of(&lhs_quns) && matches!(join_type, JoinType::Inner | JoinType::Right | JoinType::Semi) {
+                Self::pushdown_pred(expr_graph, lop_graph, lhs_plan_key, pred_key);
+            } else if !quns.is_empty() && quns.is_subset_of(&rhs_quns) && matches!(join_type, JoinType::Inner | JoinType::Left | JoinType::Semi | JoinType::Anti) {
+                Self::pushdown_pred(expr_graph, lop_graph, rhs_plan_key, pred_key);
             } else {
-                // Only the select-list expressions flow out of a queryblock. We can clear the column bitset.
-                let props = &mut lop_graph.get_mut(root_lop_key).properties;
-                let virtcols = qblock.select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
-                props.virtcols = Some(virtcols);
-                props.cols = props.cols.clone_metadata();
+                join_preds.push(pred_key);
             }
+        }
 
-            info!("Created logical plan for qblock id: {}", qblock.id);
+        // Collect WHERE predicates that can run right after this join
+        let mut preds = aps_context.all_preds.clone_metadata();
+        let join_quns = &lhs_quns | &rhs_quns;
+        let is_nullable = qblock.joins[join_ix + 1..].iter().any(|outer_join| outer_join.null_producing_quns().contains(&join.lhs_quns[0]));
+        if !is_nullable {
+            let where_preds = pred_map.iter().filter(|(_, pred_desc)| pred_desc.quns.is_subset_of(&join_quns)).map(|(&pred_key, _)| pred_key).collect::<Vec<_>>();
+            for pred_key in where_preds {
+                preds.set(pred_key);
+                pred_map.remove(&pred_key);
+            }
+        }
 
-            Ok(root_lop_key)
-        } else {
-            Err("Cannot find plan for qblock".to_string())
+        // Inner joins don't need to distinguish residual ON-clause predicates from WHERE predicates
+        if join_type == JoinType::Inner {
+            for pred_key in join_preds.drain(..) {
+                preds.set(pred_key);
+            }
         }
+
+        let join_lop_key = Self::build_join_plan(
+            qblock_graph,
+            expr_graph,
+            env,
+            aps_context,
+            lop_graph,
+            lhs_plan_key,
+            rhs_plan_key,
+            join_type,
+            &equi_join_preds,
+            null_aware_pred,
+            join_preds,
+            preds,
+            pred_map,
+            eqclass,
+            select_list_quncol,
+        );
+
+        worklist.retain(|&elem| (elem != lhs_plan_key && elem != rhs_plan_key));
+        worklist.insert(0, join_lop_key);
+        Ok(())
     }
 
     pub fn build_unary_plans(
         qblock_graph: &QueryBlockGraph, expr_graph: &mut ExprGraph, env: &Env, aps_context: &APSContext, qblock: &QueryBlock, lop_graph: &mut LOPGraph,
         pred_map: &mut PredMap, eqclass: &ExprEqClass, select_list_quncol: &Bitset<QunCol>, worklist: &mut Vec<LOPKey>,
     ) -> Result<(), String> {
-        let APSContext { all_quncols, all_quns, all_preds } = aps_context;
+        let APSContext { all_quncols, all_quns, all_preds, cte_plans, recursive_cte, .. } = aps_context;
+
+        // WHERE predicates cannot be pushed below an outer join into its NULL-producing side
+        let nullable_quns = all_quns.clone_metadata().init(qblock.joins.iter().flat_map(|join| join.null_producing_quns()));
 
         // Build unary POPs first
         for qun in qblock.quns.iter() {
@@ -456,19 +1024,27 @@ impl QGM {
             let mut unbound_quncols = select_list_quncol.clone();
 
             let mut preds = all_preds.clone_metadata();
-            pred_map.iter().for_each(|(&pred_key, PredDesc { quncols, quns, .. })| {
+            pred_map.iter().for_each(|(&pred_key, PredDesc { quncols, quns, join_ix, .. })| {
                 if quns.get(qun.id) {
-                    if quns.len() == 1 {
+                    if quns.len() == 1 && join_ix.is_none() && !nullable_quns.get(qun.id) {
                         // Set preds: find local preds that refer to this qun
                         preds.set(pred_key);
                     } else {
-                        // Set output columns: Only project cols in the select-list + unbound join preds
+                        // Set output columns: Only project cols in the select-list + unbound join preds (incl. ON-clause preds)
                         unbound_quncols |= quncols;
                     }
                 }
             });
 
-            let output_quncols = &unbound_quncols & &input_quncols;
+            let mut output_quncols = &unbound_quncols & &input_quncols;
+
+            // Chunks without columns carry no rows, so a qun that's only needed for its row count (e.g. the subquery of an uncorrelated EXISTS)
+            // still has to produce some column
+            if output_quncols.is_empty() && qun.tabledesc.is_some() {
+                let quncol = input_quncols.elements().first().cloned().unwrap_or(QunCol(qun.id, 0));
+                input_quncols.set(quncol);
+                output_quncols.set(quncol);
+            }
 
             // Remove all preds that will run on this tablescan as they've been bound already
             for pred_key in preds.elements().iter() {
@@ -483,39 +1059,88 @@ impl QGM {
 
                 // Build Scan POP
                 let child_lop_key = Self::build_qblock_logical_plan(qblock_graph, expr_graph, env, child_qblock_key, aps_context, lop_graph, None)?;
+
+                // Grouping sets: Expand the input into every set, so that a single aggregation computes all of them. The expanded rows
+                // no longer follow the child's partitioning.
+                let child_lop_key = if let Some(grouping_sets) = qblock.grouping_sets.as_ref() {
+                    let mut props = lop_graph.get_properties(child_lop_key).clone();
+                    props.preds = props.preds.clone_metadata();
+                    props.partdesc = PartDesc::new(child_lop_key.get_npartitions(lop_graph), PartType::RAW);
+                    let lop = LOP::Expand { key_len: key_len - 1, grouping_sets: grouping_sets.clone() };
+                    lop_graph.add_node_with_props(lop, props, Some(vec![child_lop_key]))
+                } else {
+                    child_lop_key
+                };
                 let (_, child_props, _) = lop_graph.get3(child_lop_key);
                 let child_props = child_props.clone();
 
-                // Build Aggregation POP
-                if child_props.partdesc.npartitions == 1 {
+                // Build Aggregation POP. The predicates of a GROUP-BY query block come from its HAVING clause, and filter the final groups.
+                let having = preds.elements();
+                let preds = preds.clone_metadata();
+                let agg_exprs = qblock.select_list.iter().map(|ne| ne.expr_key).chain(having.iter().cloned()).collect::<Vec<_>>();
+                let has_distinct_aggs =
+                    agg_exprs.iter().any(|&expr_key| expr_graph.true_iter(expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), AggFunction(_, true))));
+                let has_holistic_aggs = agg_exprs
+                    .iter()
+                    .any(|&expr_key| expr_graph.true_iter(expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), AggFunction(aggtype, _) if aggtype.is_holistic())));
+                if has_distinct_aggs {
+                    Self::build_distinct_agg_plan(qblock_graph, expr_graph, env, aps_context, qblock, lop_graph, child_lop_key, quns, output_quncols, preds, &having, eqclass)?
+                } else if child_lop_key.get_npartitions(lop_graph) == 1 {
                     // Underlying aggregation input has one partition. Aggregate directly. No pre-agg needed.
                     let expected_partitioning = PartDesc { npartitions: 1, part_type: PartType::RAW };
 
                     let children = Some(vec![child_lop_key]);
                     let virtcols = qblock.select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
                     let mut props = LOPProps::new(quns, output_quncols, Some(virtcols), preds, expected_partitioning);
-                    lop_graph.add_node_with_props(LOP::Aggregation { key_len }, props, children)
+                    lop_graph.add_node_with_props(LOP::Aggregation { key_len, having }, props, children)
+                } else if has_holistic_aggs {
+                    // Holistic aggregates (e.g. MEDIAN) cannot be pre-aggregated. Repartition the input on the grouping keys and aggregate once.
+                    let expected_partitioning = if key_len == 0 {
+                        PartDesc::new(1, PartType::RAW)
+                    } else {
+                        let keys = child_qblock
+                            .select_list
+                            .iter()
+                            .take(key_len)
+                            .enumerate()
+                            .map(|(cid, ne)| {
+                                let props = expr_graph.get_properties(ne.expr_key).clone();
+                                expr_graph.add_node_with_props(Expr::CID(qun.id, cid), props, None)
+                            })
+                            .collect::<Vec<_>>();
+                        PartDesc::new(env.settings.parallel_degree.unwrap_or(1), PartType::HASHEXPR(keys))
+                    };
+                    let repart_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, child_lop_key, &expected_partitioning, eqclass);
+
+                    let virtcols = qblock.select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
+                    let props = LOPProps::new(quns, output_quncols, Some(virtcols), preds, expected_partitioning);
+                    lop_graph.add_node_with_props(LOP::Aggregation { key_len, having }, props, Some(vec![repart_lop_key]))
                 } else {
                     // Underlying aggregation input has multiple partitions. We aggregate in two steps.
-                    let (pre_exprs, post_exprs) = Self::build_pre_and_post_aggs_virt_cols(env, qblock_graph, expr_graph, lop_graph, aps_context, qblock)?;
+                    let (pre_exprs, _, post_exprs, post_having) =
+                        Self::build_pre_and_post_aggs_virt_cols(env, qblock_graph, expr_graph, lop_graph, aps_context, qblock, &having, key_len)?;
                     let expected_partitioning_expr = pre_exprs.iter().take(key_len).cloned().collect::<Vec<_>>();
 
                     // Build pre-aggregation POP
-                    let preagg_lop = LOP::Aggregation { key_len };
+                    let preagg_lop = LOP::Aggregation { key_len, having: vec![] };
                     let mut preagg_props = child_props.clone();
                     preagg_props.virtcols = Some(pre_exprs);
                     preagg_props.cols = child_props.cols.clone_metadata();
+                    preagg_props.preds = child_props.preds.clone_metadata();
                     let preagg_children = Some(vec![child_lop_key]);
                     let preagg_lop_key = lop_graph.add_node_with_props(preagg_lop, preagg_props, preagg_children);
 
-                    // Repartition: partition keys <= aggregation keys
-                    let expected_partitioning =
-                        PartDesc { npartitions: env.settings.parallel_degree.unwrap_or(1), part_type: PartType::HASHEXPR(expected_partitioning_expr) };
+                    // Repartition: partition keys <= aggregation keys. Without keys, everything gets aggregated in a single partition.
+                    let expected_partitioning = if key_len == 0 {
+                        PartDesc::new(1, PartType::RAW)
+                    } else {
+                        PartDesc { npartitions: env.settings.parallel_degree.unwrap_or(1), part_type: PartType::HASHEXPR(expected_partitioning_expr) }
+                    };
                     let repart_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, preagg_lop_key, &expected_partitioning, &eqclass);
                     let (repart_lop, repart_props, _) = lop_graph.get3(repart_lop_key);
 
                     // Build post-aggregation POP
-                    let postagg_lop = LOP::Aggregation { key_len };
+                    let postagg_lop = LOP::Aggregation { key_len, having: post_having };
                     let mut postagg_props = repart_props.clone();
                     postagg_props.virtcols = Some(post_exprs);
                     postagg_props.cols = repart_props.cols.clone_metadata();
@@ -525,6 +1150,26 @@ impl QGM {
                     let postagg_lop_key = lop_graph.add_node_with_props(postagg_lop, postagg_props, postagg_children);
                     postagg_lop_key
                 }
+            } else if qun.get_qblock_key().is_some() && qun.get_qblock_key() == *recursive_cte {
+                // Self-reference of a recursive CTE
+                let cte_lop_key = cte_plans[&qun.get_qblock_key().unwrap()];
+                let partdesc = PartDesc::new(1, PartType::RAW);
+                let props = LOPProps::new(quns, output_quncols, None, preds, partdesc);
+                lop_graph.add_node_with_props(LOP::RecursiveScan { cte_lop_key }, props, None)
+            } else if let Some(child_qblock_key) = qun.get_qblock_key() {
+                // Derived table: Its query block is planned into a stage of its own, whose output columns line up with the select-list.
+                // Each partition of the child feeds the same partition of the derived table. Materialized CTEs are read from the stage
+                // that computes them.
+                let child_lop_key = if let Some(&cte_lop_key) = cte_plans.get(&child_qblock_key) {
+                    cte_lop_key
+                } else {
+                    Self::build_qblock_logical_plan(qblock_graph, expr_graph, env, child_qblock_key, aps_context, lop_graph, None)?
+                };
+                let npartitions = child_lop_key.get_npartitions(lop_graph);
+
+                let partdesc = PartDesc::new(npartitions, PartType::RAW);
+                let props = LOPProps::new(quns, output_quncols, None, preds, partdesc);
+                lop_graph.add_node_with_props(LOP::Repartition { cpartitions: npartitions }, props, Some(vec![child_lop_key]))
             } else {
                 let npartitions = if let Some(tabledesc) = qun.tabledesc.as_ref() {
                     tabledesc.get_part_desc().unwrap().npartitions
@@ -534,7 +1179,7 @@ impl QGM {
                 // Build Scan POP
                 let partdesc = PartDesc::new(npartitions, PartType::RAW);
                 let props = LOPProps::new(quns, output_quncols, None, preds, partdesc);
-                lop_graph.add_node_with_props(LOP::TableScan { input_projection: input_quncols }, props, None)
+                lop_graph.add_node_with_props(LOP::TableScan { input_projection: input_quncols, limit: None }, props, None)
             };
             //debug!("Build TableScan: key={:?} {:?} id={}", lopkey, qun.display(), qun.id);
             worklist.push(lopkey);
@@ -542,14 +1187,58 @@ impl QGM {
         Ok(())
     }
 
+    // build_distinct_agg_plan: DISTINCT aggregates take two levels of aggregation. The first one groups on the GROUP-BY keys plus the
+    // DISTINCT argument (which follows them in the child's output), and computes partial non-distinct aggregates. The second one groups
+    // on the GROUP-BY keys, aggregating the now-unique DISTINCT argument and merging the partial aggregates. Inputs with several
+    // partitions are pre-aggregated on the first level keys and repartitioned on the GROUP-BY keys before the first level completes.
+    fn build_distinct_agg_plan(
+        qblock_graph: &QueryBlockGraph, expr_graph: &mut ExprGraph, env: &Env, aps_context: &APSContext, qblock: &QueryBlock, lop_graph: &mut LOPGraph,
+        child_lop_key: LOPKey, quns: Bitset<QunId>, output_quncols: Bitset<QunCol>, preds: Bitset<ExprKey>, having: &[ExprKey], eqclass: &ExprEqClass,
+    ) -> Result<LOPKey, String> {
+        let key_len = qblock.group_by.as_ref().unwrap().len();
+        let (pre_exprs, merge_exprs, post_exprs, post_having) =
+            Self::build_pre_and_post_aggs_virt_cols(env, qblock_graph, expr_graph, lop_graph, aps_context, qblock, having, key_len + 1)?;
+
+        // First level, over the child
+        let mut props = lop_graph.get_properties(child_lop_key).clone();
+        props.virtcols = Some(pre_exprs.clone());
+        props.cols = props.cols.clone_metadata();
+        props.preds = props.preds.clone_metadata();
+        let mut lop_key = lop_graph.add_node_with_props(LOP::Aggregation { key_len: key_len + 1, having: vec![] }, props.clone(), Some(vec![child_lop_key]));
+
+        if child_lop_key.get_npartitions(lop_graph) > 1 {
+            let expected_partitioning = if key_len == 0 {
+                PartDesc::new(1, PartType::RAW)
+            } else {
+                PartDesc::new(env.settings.parallel_degree.unwrap_or(1), PartType::HASHEXPR(pre_exprs.iter().take(key_len).cloned().collect()))
+            };
+            let repart_lop_key = Self::repartition_if_needed(qblock_graph, expr_graph, lop_graph, lop_key, &expected_partitioning, eqclass);
+
+            // Merge the partial aggregates. The output has the same layout as theirs, which is what the second level refers to.
+            let mut merge_props = lop_graph.get_properties(repart_lop_key).clone();
+            merge_props.virtcols = Some(merge_exprs);
+            merge_props.cols = merge_props.cols.clone_metadata();
+            merge_props.partdesc = expected_partitioning;
+            lop_key = lop_graph.add_node_with_props(LOP::Aggregation { key_len: key_len + 1, having: vec![] }, merge_props, Some(vec![repart_lop_key]));
+        }
+
+        // Second level
+        let mut partdesc = lop_graph.get_properties(lop_key).partdesc.clone();
+        partdesc.npartitions = lop_key.get_npartitions(lop_graph);
+        let props = LOPProps::new(quns, output_quncols, Some(post_exprs), preds, partdesc);
+        Ok(lop_graph.add_node_with_props(LOP::Aggregation { key_len, having: post_having }, props, Some(vec![lop_key])))
+    }
+
+    // build_pre_and_post_aggs_virt_cols: Split the select-list of an aggregation into pre-aggregation expressions over the child, their
+    // merge expressions over the pre-aggregation output, and post-aggregation expressions. The first `key_len` child columns are grouped on.
     fn build_pre_and_post_aggs_virt_cols(
         env: &Env, qblock_graph: &QueryBlockGraph, expr_graph: &mut ExprGraph, lop_graph: &mut LOPGraph, aps_context: &APSContext, qblock: &QueryBlock,
-    ) -> Result<(Vec<ExprKey>, Vec<ExprKey>), String> {
+        having: &[ExprKey], key_len: usize,
+    ) -> Result<(Vec<ExprKey>, Vec<ExprKey>, Vec<ExprKey>, Vec<ExprKey>), String> {
         let mut preaggs: Vec<(ExprKey, ExprKey)> = vec![]; // orig -> pre map
         let mut postaggs: Vec<ExprKey> = vec![];
 
         // Prime pre-agg list by adding grouping columns.
-        let key_len = qblock.group_by.as_ref().unwrap().len();
         let child_qun = &qblock.quns[0];
         let qid = child_qun.id;
         let Some(child_qblock) = child_qun.get_qblock(qblock_graph) else { return Err("Bad!".to_string()) };
@@ -566,8 +1255,15 @@ impl QGM {
             let postagge = Self::build_one_pre_and_post_virt_col(qblock_graph, expr_graph, expr_key, &mut preaggs)?;
             postaggs.push(postagge);
         }
+
+        // HAVING predicates get translated the same way. Aggregates they refer to are pre-aggregated even if the select-list lacks them.
+        let mut post_having = vec![];
+        for &expr_key in having {
+            post_having.push(Self::build_one_pre_and_post_virt_col(qblock_graph, expr_graph, expr_key, &mut preaggs)?);
+        }
         let preagg = preaggs.iter().map(|&(e, _)| e).collect::<Vec<_>>();
-        Ok((preagg, postaggs))
+        let mergeagg = preaggs.iter().map(|&(_, e)| e).collect::<Vec<_>>();
+        Ok((preagg, mergeagg, postaggs, post_having))
     }
 
     fn build_one_pre_and_post_virt_col(
@@ -592,13 +1288,62 @@ impl QGM {
                     );
                     post_expr_key
                 }
+                Expr::AggFunction(aggtype, true) => {
+                    // DISTINCT aggregates run after the pre-aggregation made their argument unique, which is grouped on
+                    let cur_child_expr_key = cur_children.unwrap()[0];
+                    let Some(post_child_expr_key) = Self::find(expr_graph, preaggs, cur_child_expr_key) else {
+                        return Err("Bad child of distinct agg-function, expecting grouping column".to_string());
+                    };
+                    let post_expr = Expr::AggFunction(aggtype, false);
+                    expr_graph.add_node_with_props(post_expr, cur_props, Some(vec![post_child_expr_key]))
+                }
+                Expr::AggFunction(aggtype, _) if aggtype.is_holistic() => {
+                    return Err(f!("{aggtype:?} cannot be computed alongside DISTINCT aggregates."));
+                }
+                Expr::AggFunction(aggtype, _) if aggtype.is_variance() => {
+                    // Variances are computed from the count, sum and sum-of-squares of their argument, each of which is pre-aggregated
+                    let cur_child_expr_key = cur_children.unwrap()[0];
+                    let child_data_type = expr_graph.get_properties(cur_child_expr_key).data_type().clone();
+                    let partials = vec![(AggType::COUNT, DataType::Int64), (AggType::SUM, child_data_type), (AggType::SUM_SQUARES, DataType::Float64)];
+                    let post_children = partials
+                        .into_iter()
+                        .map(|(partial_aggtype, data_type)| {
+                            let partial_expr = Expr::AggFunction(partial_aggtype, false);
+                            let partial_expr_key = expr_graph.add_node_with_props(partial_expr, ExprProp::new(data_type), Some(vec![cur_child_expr_key]));
+                            Self::build_one_pre_and_post_virt_col(qblock_graph, expr_graph, partial_expr_key, preaggs)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let post_expr = Expr::ScalarFunction(f!("{aggtype:?}"));
+                    expr_graph.add_node_with_props(post_expr, cur_props, Some(post_children))
+                }
+                Expr::AggFunction(aggtype, _) if aggtype.sketch_aggtype().is_some() => {
+                    // Approximate aggregates pre-aggregate their argument into a sketch. Sketches get merged and only turned into a result at the end.
+                    let cur_child_expr_key = cur_children.unwrap()[0];
+                    let sketch_expr = Expr::AggFunction(aggtype.sketch_aggtype().unwrap(), false);
+                    let sketch_expr_key = expr_graph.add_node_with_props(sketch_expr, ExprProp::new(DataType::Binary), Some(vec![cur_child_expr_key]));
+                    let mut post_children = vec![Self::build_one_pre_and_post_virt_col(qblock_graph, expr_graph, sketch_expr_key, preaggs)?];
+                    let name = match aggtype {
+                        AggType::APPROX_PERCENTILE(fraction) => {
+                            let fraction_expr = Expr::Literal(Datum::Float64(fraction));
+                            post_children.push(expr_graph.add_node_with_props(fraction_expr, ExprProp::new(DataType::Float64), None));
+                            "APPROX_PERCENTILE"
+                        }
+                        _ => "APPROX_COUNT_DISTINCT",
+                    };
+                    expr_graph.add_node_with_props(Expr::ScalarFunction(name.to_string()), cur_props, Some(post_children))
+                }
                 Expr::AggFunction(aggtype, distinct) => {
                     let cur_child_expr_key = cur_children.unwrap()[0];
                     let cur_child = expr_graph.get_value(cur_child_expr_key);
                     let Expr::CID(qid, cid) = cur_child else { return Err("Bad child of agg-function, expecting CID".to_string()) };
                     let post_child_expr = Expr::CID(*qid, preaggs.len());
                     let post_child_expr_key = expr_graph.add_node_with_props(post_child_expr, cur_props.clone(), None);
-                    let postaggtype = if aggtype == AggType::COUNT { AggType::SUM } else { aggtype };
+                    let postaggtype = match aggtype {
+                        AggType::COUNT | AggType::SUM_SQUARES => AggType::SUM,
+                        AggType::HLL_SKETCH => AggType::HLL_MERGE,
+                        AggType::QUANTILE_SKETCH => AggType::QUANTILE_MERGE,
+                        _ => aggtype,
+                    };
                     let post_expr = Expr::AggFunction(postaggtype, distinct);
                     let post_expr_key = expr_graph.add_node_with_props(post_expr, cur_props, Some(vec![post_child_expr_key]));
                     preaggs.push((pre_expr_key, post_expr_key));
@@ -609,7 +1354,22 @@ impl QGM {
                     );
                     post_expr_key
                 }
-                Expr::BinaryExpr(ArithOp::Div) | Expr::Cast => {
+                Expr::Literal(_) => {
+                    // Literals are computed as-is after the post-aggregation
+                    pre_expr_key
+                }
+                Expr::BinaryExpr(_)
+                | Expr::NegatedExpr
+                | Expr::Cast
+                | Expr::ScalarFunction(_)
+                | Expr::RelExpr(_)
+                | Expr::LogExpr(_)
+                | Expr::BetweenExpr
+                | Expr::InListExpr
+                | Expr::CaseExpr { .. }
+                | Expr::CoalesceExpr
+                | Expr::NullIfExpr
+                | Expr::IsNullExpr { .. } => {
                     let post_children = cur_children
                         .unwrap()
                         .iter()
@@ -635,13 +1395,13 @@ impl QGM {
         None
     }
 
-    pub fn classify_predicate(eqjoin_desc: &EqJoinDesc, lhs_props: &LOPProps, rhs_props: &LOPProps) -> (PredicateType, PredicateAlignment) {
+    pub fn classify_predicate(eqjoin_desc: &EqJoinDesc, lhs_quns: &Bitset<QunId>, rhs_quns: &Bitset<QunId>) -> (PredicateType, PredicateAlignment) {
         let (lhs_pred_quns, rhs_pred_quns) = (&eqjoin_desc.lhs_quns, &eqjoin_desc.rhs_quns);
 
         // pred-quns must be subset of plan quns
-        if lhs_pred_quns.is_subset_of(&lhs_props.quns) && rhs_pred_quns.is_subset_of(&rhs_props.quns) {
+        if lhs_pred_quns.is_subset_of(lhs_quns) && rhs_pred_quns.is_subset_of(rhs_quns) {
             (PredicateType::EquiJoin, PredicateAlignment::Aligned)
-        } else if lhs_pred_quns.is_subset_of(&rhs_props.quns) && rhs_pred_quns.is_subset_of(&lhs_props.quns) {
+        } else if lhs_pred_quns.is_subset_of(rhs_quns) && rhs_pred_quns.is_subset_of(lhs_quns) {
             // Swapped scenario
             (PredicateType::EquiJoin, PredicateAlignment::Reversed)
         } else {
@@ -661,39 +1421,46 @@ impl QGM {
         let mut eqclass = ExprEqClass::default();
         let mut eqpred_legs = vec![];
 
-        if let Some(pred_list) = qblock.pred_list.as_ref() {
-            for &pred_key in pred_list.iter() {
-                // Collect quns and quncols for each predicate
-                let mut quncols = aps_context.all_quncols.clone_metadata();
-                let mut quns = aps_context.all_quns.clone_metadata();
+        // WHERE predicates followed by ON-clause predicates of every ANSI join
+        let where_preds = qblock.pred_list.iter().flatten().map(|&pred_key| (pred_key, None));
+        let on_preds = qblock.joins.iter().enumerate().flat_map(|(join_ix, join)| join.on_clause.iter().map(move |&pred_key| (pred_key, Some(join_ix))));
 
-                for quncol in pred_key.iter_quncols(expr_graph) {
-                    quncols.set(quncol);
-                    quns.set(quncol.0);
-                }
+        for (pred_key, join_ix) in where_preds.chain(on_preds) {
+            // Collect quns and quncols for each predicate
+            let mut quncols = aps_context.all_quncols.clone_metadata();
+            let mut quns = aps_context.all_quns.clone_metadata();
 
-                // For equijoin candidates, collect lhs and rhs quns
-                let expr = expr_graph.get(pred_key);
-                let eqjoin_desc = if let RelExpr(RelOp::Eq) = expr.value {
-                    let children = expr.children.as_ref().unwrap();
-                    let (lhs_child_key, rhs_child_key) = (children[0], children[1]);
-                    let lhs_quns = aps_context.all_quns.clone_metadata().init(lhs_child_key.iter_quns(expr_graph));
-                    let rhs_quns = aps_context.all_quns.clone_metadata().init(rhs_child_key.iter_quns(expr_graph));
+            for quncol in pred_key.iter_quncols(expr_graph) {
+                quncols.set(quncol);
+                quns.set(quncol.0);
+            }
 
-                    if !lhs_quns.is_empty() && !rhs_quns.is_empty() {
+            // Outer join equalities don't hold across NULL-extended rows, so they don't contribute to eq-classes
+            let is_outer_join_pred = join_ix.map(|join_ix| qblock.joins[join_ix].join_type != JoinType::Inner).unwrap_or(false);
+
+            // For equijoin candidates, collect lhs and rhs quns
+            let expr = expr_graph.get(pred_key);
+            let eqjoin_desc = if let RelExpr(RelOp::Eq) = expr.value {
+                let children = expr.children.as_ref().unwrap();
+                let (lhs_child_key, rhs_child_key) = (children[0], children[1]);
+                let lhs_quns = aps_context.all_quns.clone_metadata().init(lhs_child_key.iter_quns(expr_graph));
+                let rhs_quns = aps_context.all_quns.clone_metadata().init(rhs_child_key.iter_quns(expr_graph));
+
+                if !lhs_quns.is_empty() && !rhs_quns.is_empty() {
+                    if !is_outer_join_pred {
                         let (lhs_hash, rhs_hash) = (lhs_child_key.hash(expr_graph), rhs_child_key.hash(expr_graph));
                         eqpred_legs.push((lhs_hash, lhs_child_key));
                         eqpred_legs.push((rhs_hash, rhs_child_key));
                         eqclass.set_eq(lhs_child_key, rhs_child_key);
-                        Some(EqJoinDesc { lhs_quns, rhs_quns })
-                    } else {
-                        None
                     }
+                    Some(EqJoinDesc { lhs_quns, rhs_quns })
                 } else {
                     None
-                };
-                pred_map.insert(pred_key, PredDesc { quncols, quns, eqjoin_desc });
-            }
+                }
+            } else {
+                None
+            };
+            pred_map.insert(pred_key, PredDesc { quncols, quns, eqjoin_desc, join_ix });
         }
 
         // SELECT expressions are also added to eq-class. These come into play when determining partitioning.
@@ -0,0 +1,290 @@
+// lop_estimate: Cardinality and row width estimates of logical operators
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    expr::{Expr::*, ExprGraph, LogOp, RelOp},
+    graph::{ExprKey, LOPKey},
+    includes::*,
+    lop::{LOPGraph, LOPProps, LOP},
+    metadata::{ColumnStats, TableDesc, TableStats, TableType},
+    qgm::JoinType,
+};
+
+// Estimates for tables and columns without statistics
+const DEFAULT_NROWS: f64 = 1000.0;
+const DEFAULT_COLUMN_WIDTH: f64 = 16.0;
+
+// Predicate selectivities without column statistics
+const EQ_SELECTIVITY: f64 = 0.1;
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+const BETWEEN_SELECTIVITY: f64 = 0.25;
+const LIKE_SELECTIVITY: f64 = 0.25;
+const NULL_SELECTIVITY: f64 = 0.1;
+const SEMIJOIN_SELECTIVITY: f64 = 0.5;
+const GROUP_SELECTIVITY: f64 = 0.1; // Fraction of rows that are distinct on the GROUP-BY keys
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+
+/***************************************************************************************************/
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LOPEstimate {
+    pub nrows: f64,    // # of rows
+    pub row_size: f64, // Average row width, in bytes
+}
+
+impl LOPEstimate {
+    pub fn describe(&self) -> String {
+        format!("rows = {:.0}, width = {:.0}", self.nrows, self.row_size)
+    }
+}
+
+/***************************************************************************************************/
+// LOPEstimator: Derives estimates bottom-up, from the statistics cataloged with each table (NROWS, AVG_ROW_SIZE, COLUMN_NDV, COLUMN_MIN,
+// COLUMN_MAX) or collected by ANALYZE TABLE, and from the selectivity of the predicates applied on the way up.
+pub struct LOPEstimator<'a> {
+    expr_graph: &'a ExprGraph,
+    tabledescs: &'a HashMap<QunId, Rc<dyn TableDesc>>,
+}
+
+impl<'a> LOPEstimator<'a> {
+    pub fn new(expr_graph: &'a ExprGraph, tabledescs: &'a HashMap<QunId, Rc<dyn TableDesc>>) -> Self {
+        LOPEstimator { expr_graph, tabledescs }
+    }
+
+    // estimate: Estimate a subplan bottom-up, recording the estimate of every operator in its properties
+    pub fn estimate(&self, lop_graph: &mut LOPGraph, lop_key: LOPKey) -> LOPEstimate {
+        let children = lop_graph.get(lop_key).children.clone().unwrap_or_default();
+        let child_estimates = children.iter().map(|&child_key| self.estimate(lop_graph, child_key)).collect::<Vec<_>>();
+
+        let (lop, props, _) = lop_graph.get3(lop_key);
+        let nrows = match lop {
+            LOP::TableScan { .. } => self.table_nrows(props.quns.elements()[0]),
+            LOP::HashJoin { join_type, lhs_join_keys, rhs_join_keys, join_preds, .. } => {
+                let (lhs, rhs) = (child_estimates[0], child_estimates[1]);
+                let join_keys = lhs_join_keys.iter().cloned().zip(rhs_join_keys.iter().cloned()).collect::<Vec<_>>();
+                let nrows = self.join_cardinality(lhs, rhs, &join_keys) * self.conjunct_selectivity(join_preds.iter());
+                match join_type {
+                    JoinType::Inner => nrows,
+                    JoinType::Left | JoinType::Scalar => nrows.max(lhs.nrows),
+                    JoinType::Right => nrows.max(rhs.nrows),
+                    JoinType::Full => nrows.max(lhs.nrows).max(rhs.nrows),
+                    JoinType::Semi | JoinType::Anti => lhs.nrows * SEMIJOIN_SELECTIVITY,
+                }
+            }
+            LOP::Aggregation { key_len: 0, .. } => 1.0,
+            LOP::Aggregation { key_len, having } => {
+                let input_nrows = child_estimates[0].nrows;
+                let keys = props.virtcols.as_ref().map_or(&[][..], |virtcols| &virtcols[..*key_len]);
+                let ndvs = keys.iter().map(|&key| self.column_stats(key).and_then(|stats| stats.ndv())).collect::<Option<Vec<_>>>();
+                let ngroups = ndvs.map_or(input_nrows * GROUP_SELECTIVITY, |ndvs| ndvs.iter().map(|&ndv| ndv as f64).product());
+                ngroups.min(input_nrows).max(1.0) * self.conjunct_selectivity(having.iter())
+            }
+            LOP::Expand { grouping_sets, .. } => child_estimates[0].nrows * grouping_sets.len() as f64,
+            LOP::Sort { limit: Some(limit), .. } | LOP::Limit { limit, .. } => child_estimates[0].nrows.min(*limit as f64),
+            LOP::UnionAll { .. } => child_estimates.iter().map(|estimate| estimate.nrows).sum(),
+            LOP::RecursiveScan { .. } => DEFAULT_NROWS,
+            LOP::Repartition { .. } | LOP::Sort { .. } | LOP::Window { .. } => child_estimates.first().map_or(DEFAULT_NROWS, |estimate| estimate.nrows),
+        };
+        let nrows = nrows * self.conjunct_selectivity(props.preds.elements().iter());
+
+        // Scan limits apply after predicates
+        let nrows = match lop {
+            LOP::TableScan { limit: Some(limit), .. } => nrows.min(*limit as f64),
+            _ => nrows,
+        };
+
+        let props = &mut lop_graph.get_mut(lop_key).properties;
+        props.estimate = LOPEstimate { nrows: nrows.max(1.0), row_size: self.row_size(props) };
+        props.estimate
+    }
+
+    // row_size: Sum of the widths of the columns flowing out of an operator. Table columns take an equal share of AVG_ROW_SIZE.
+    fn row_size(&self, props: &LOPProps) -> f64 {
+        let col_widths = props.cols.elements().into_iter().map(|QunCol(qunid, colid)| {
+            let tabledesc = self.tabledescs.get(&qunid);
+            let avg_row_size = tabledesc.and_then(|tabledesc| tabledesc.get_stats()).and_then(|stats| stats.avg_row_size());
+            match (tabledesc, avg_row_size) {
+                (Some(tabledesc), Some(avg_row_size)) => avg_row_size as f64 / tabledesc.fields().len() as f64,
+                (Some(tabledesc), None) => tabledesc.fields().get(colid).map_or(DEFAULT_COLUMN_WIDTH, |field| Self::datatype_width(field.data_type())),
+                _ => DEFAULT_COLUMN_WIDTH,
+            }
+        });
+        let virtcol_widths = props.virtcols.iter().flatten().map(|&expr_key| Self::datatype_width(expr_key.get_data_type(self.expr_graph)));
+        col_widths.chain(virtcol_widths).sum()
+    }
+
+    fn datatype_width(datatype: &DataType) -> f64 {
+        match datatype {
+            DataType::Boolean | DataType::Int8 | DataType::UInt8 => 1.0,
+            DataType::Int16 | DataType::UInt16 | DataType::Float16 => 2.0,
+            DataType::Int32 | DataType::UInt32 | DataType::Float32 | DataType::Date32 => 4.0,
+            DataType::Int64 | DataType::UInt64 | DataType::Float64 | DataType::Date64 => 8.0,
+            DataType::Decimal(..) => 16.0,
+            _ => DEFAULT_COLUMN_WIDTH,
+        }
+    }
+
+    // join_cardinality: Each equi-join key divides the cross product by the larger of its NDVs on either side. Table columns without an NDV
+    // take their table's NROWS, as if they were keys. Keys that are neither are assumed to match foreign keys against keys, so the first
+    // one yields as many rows as the larger side.
+    pub fn join_cardinality(&self, lhs: LOPEstimate, rhs: LOPEstimate, join_keys: &[(ExprKey, ExprKey)]) -> f64 {
+        if join_keys.is_empty() {
+            return lhs.nrows * rhs.nrows;
+        }
+
+        let mut nrows = lhs.nrows * rhs.nrows;
+        let mut nunknown = 0;
+        for &(lhs_key, rhs_key) in join_keys.iter() {
+            let lhs_ndv = self.column_ndv(lhs_key, lhs.nrows);
+            let rhs_ndv = self.column_ndv(rhs_key, rhs.nrows);
+            match lhs_ndv.into_iter().chain(rhs_ndv).reduce(f64::max) {
+                Some(ndv) => nrows /= ndv,
+                None => nunknown += 1,
+            }
+        }
+        if nunknown == join_keys.len() {
+            nrows /= lhs.nrows.min(rhs.nrows).max(1.0);
+            nunknown -= 1;
+        }
+        nrows * EQ_SELECTIVITY.powi(nunknown as i32)
+    }
+
+    // column_ndv: NDV of a base table column, capped by the # of rows it's drawn from. Without a COLUMN_NDV, every row of the table is assumed
+    // to be distinct.
+    fn column_ndv(&self, expr_key: ExprKey, nrows: f64) -> Option<f64> {
+        let (qunid, _) = self.table_column(expr_key)?;
+        let ndv = match self.column_stats(expr_key).and_then(|stats| stats.ndv()) {
+            Some(ndv) => ndv as f64,
+            None => self.table_nrows(qunid),
+        };
+        Some(ndv.min(nrows).max(1.0))
+    }
+
+    // table_nrows: NROWS of the table scanned by a quantifier, or a default if it's unknown
+    fn table_nrows(&self, qunid: QunId) -> f64 {
+        let nrows = self.tabledescs.get(&qunid).and_then(|tabledesc| tabledesc.get_stats()).and_then(|stats| stats.nrows());
+        nrows.map_or(DEFAULT_NROWS, |nrows| nrows as f64)
+    }
+
+    // table_column: (quantifier, column) of an expression that refers to a column of a base table, not of a derived table
+    fn table_column(&self, expr_key: ExprKey) -> Option<(QunId, ColId)> {
+        let (qunid, colid) = match self.expr_graph.get_value(expr_key) {
+            CID(qunid, colid) | Column { qunid, colid, .. } => (*qunid, *colid),
+            _ => return None,
+        };
+        let tabledesc = self.tabledescs.get(&qunid)?;
+        (tabledesc.get_type() != TableType::Query && colid < tabledesc.fields().len()).then_some((qunid, colid))
+    }
+
+    fn column_stats(&self, expr_key: ExprKey) -> Option<&'a ColumnStats> {
+        self.table_column_stats(expr_key).map(|(_, column_stats)| column_stats)
+    }
+
+    fn table_column_stats(&self, expr_key: ExprKey) -> Option<(&'a TableStats, &'a ColumnStats)> {
+        let (qunid, colid) = self.table_column(expr_key)?;
+        let tabledesc = self.tabledescs.get(&qunid)?;
+        let field = tabledesc.fields().get(colid)?;
+        let table_stats = tabledesc.get_stats()?;
+        Some((table_stats, table_stats.column(&field.name)?))
+    }
+
+    // null_fraction: Fraction of a table's rows where a column is NULL
+    fn null_fraction(&self, expr_key: ExprKey) -> Option<f64> {
+        let (table_stats, column_stats) = self.table_column_stats(expr_key)?;
+        let (nrows, null_count) = (table_stats.nrows()?, column_stats.null_count()?);
+        (nrows > 0).then(|| (null_count as f64 / nrows as f64).min(1.0))
+    }
+
+    // literal_value: Numeric value of a literal, looking through casts
+    fn literal_value(&self, expr_key: ExprKey) -> Option<f64> {
+        let (expr, _, children) = self.expr_graph.get3(expr_key);
+        match expr {
+            Literal(datum) => datum.to_f64(),
+            Cast => self.literal_value(children?[0]),
+            _ => None,
+        }
+    }
+
+    // range_fraction: Fraction of a column's values that lie between `lo` and `hi`. Histograms give the fraction of rows in every bucket
+    // crossed; otherwise values are assumed to be spread evenly over [min, max].
+    fn range_fraction(stats: &ColumnStats, lo: Option<f64>, hi: Option<f64>) -> Option<f64> {
+        if let Some(histogram) = stats.histogram().filter(|histogram| histogram.len() > 1) {
+            let lo = lo.map_or(0.0, |lo| Self::histogram_fraction(histogram, lo));
+            let hi = hi.map_or(1.0, |hi| Self::histogram_fraction(histogram, hi));
+            return Some((hi - lo).clamp(0.0, 1.0));
+        }
+
+        let (min, max) = (stats.min()?, stats.max()?);
+        if max <= min {
+            return None;
+        }
+        let (lo, hi) = (lo.unwrap_or(min).max(min), hi.unwrap_or(max).min(max));
+        Some(((hi - lo) / (max - min)).clamp(0.0, 1.0))
+    }
+
+    // histogram_fraction: Fraction of values below `value`, interpolating within the bucket that holds it
+    fn histogram_fraction(histogram: &[f64], value: f64) -> f64 {
+        let nbuckets = histogram.len() - 1;
+        let ix = histogram.partition_point(|&bound| bound <= value);
+        if ix == 0 {
+            return 0.0;
+        } else if ix > nbuckets {
+            return 1.0;
+        }
+        let (lo, hi) = (histogram[ix - 1], histogram[ix]);
+        ((ix - 1) as f64 + (value - lo) / (hi - lo)) / nbuckets as f64
+    }
+
+    fn conjunct_selectivity<'b>(&self, pred_keys: impl Iterator<Item = &'b ExprKey>) -> f64 {
+        pred_keys.map(|&pred_key| self.selectivity(pred_key)).product()
+    }
+
+    // selectivity: Fraction of rows a predicate is expected to retain. Comparisons of a column against literals go by its NDV and range.
+    pub fn selectivity(&self, pred_key: ExprKey) -> f64 {
+        let (expr, _, children) = self.expr_graph.get3(pred_key);
+        let children = children.map_or(&[][..], |children| children.as_slice());
+        let child_selectivities = children.iter().map(|&child_key| self.selectivity(child_key));
+
+        match expr {
+            RelExpr(op) => {
+                // Column on the left, literal on the right
+                let (column, literal, op) = match (self.column_stats(children[0]), self.column_stats(children[1])) {
+                    (Some(stats), _) => (Some(stats), self.literal_value(children[1]), *op),
+                    (None, Some(stats)) => (Some(stats), self.literal_value(children[0]), op.reverse()),
+                    (None, None) => (None, None, *op),
+                };
+                let eq_selectivity = column.and_then(|stats| stats.ndv()).map_or(EQ_SELECTIVITY, |ndv| 1.0 / ndv as f64);
+                let range_selectivity = |lo, hi| column.zip(literal).and_then(|(stats, _)| Self::range_fraction(stats, lo, hi)).unwrap_or(RANGE_SELECTIVITY);
+                match op {
+                    RelOp::Eq => eq_selectivity,
+                    RelOp::Ne => 1.0 - eq_selectivity,
+                    RelOp::Lt | RelOp::Le => range_selectivity(None, literal),
+                    RelOp::Gt | RelOp::Ge => range_selectivity(literal, None),
+                    RelOp::Like => LIKE_SELECTIVITY,
+                }
+            }
+            BetweenExpr => {
+                let (lo, hi) = (self.literal_value(children[1]), self.literal_value(children[2]));
+                let stats = self.column_stats(children[0]).filter(|_| lo.is_some() && hi.is_some());
+                stats.and_then(|stats| Self::range_fraction(stats, lo, hi)).unwrap_or(BETWEEN_SELECTIVITY)
+            }
+            InListExpr => {
+                let nitems = children.len().saturating_sub(1) as f64;
+                let eq_selectivity = self.column_stats(children[0]).and_then(|stats| stats.ndv()).map_or(EQ_SELECTIVITY, |ndv| 1.0 / ndv as f64);
+                (eq_selectivity * nitems).min(1.0)
+            }
+            IsNullExpr { negated } => {
+                let null_fraction = self.null_fraction(children[0]).unwrap_or(NULL_SELECTIVITY);
+                if *negated {
+                    1.0 - null_fraction
+                } else {
+                    null_fraction
+                }
+            }
+            LogExpr(LogOp::And) => child_selectivities.product(),
+            LogExpr(LogOp::Or) => 1.0 - child_selectivities.map(|selectivity| 1.0 - selectivity).product::<f64>(),
+            LogExpr(LogOp::Not) => 1.0 - child_selectivities.product::<f64>(),
+            _ => DEFAULT_SELECTIVITY,
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// lop_joinorder: Cost-based enumeration of inner joins
+
+use std::collections::HashMap;
+
+use crate::{
+    bitset::Bitset,
+    env::Env,
+    expr::ExprGraph,
+    graph::LOPKey,
+    includes::*,
+    lop::{LOPGraph, PredMap, PredicateAlignment, PredicateType},
+    lop_estimate::{LOPEstimate, LOPEstimator},
+    qgm::QueryBlock,
+    QGM,
+};
+
+pub const JOIN_DP_LIMIT: usize = 10; // Subplans beyond which joins are enumerated greedily
+
+/***************************************************************************************************/
+// JoinTree: Inner joins between subplans. The RHS of every join is its build side.
+#[derive(Debug, Clone)]
+pub enum JoinTree {
+    Plan(LOPKey),
+    Join(Box<JoinTree>, Box<JoinTree>),
+}
+
+impl JoinTree {
+    pub fn plans(&self) -> Vec<LOPKey> {
+        match self {
+            JoinTree::Plan(lop_key) => vec![*lop_key],
+            JoinTree::Join(lhs, rhs) => {
+                let mut plans = lhs.plans();
+                plans.extend(rhs.plans());
+                plans
+            }
+        }
+    }
+}
+
+// JoinCandidate: Join tree over a set of quantifiers along with its estimated output and cost
+#[derive(Debug, Clone)]
+struct JoinCandidate {
+    tree: JoinTree,
+    quns: Bitset<QunId>,
+    estimate: LOPEstimate,
+    cost: f64,
+}
+
+/***************************************************************************************************/
+impl QGM {
+    // enumerate_joins: Pick the next inner joins to build among the subplans in the worklist. Up to JOIN_DP_LIMIT subplans, join trees are
+    // costed bottom-up over every set of quantifiers (DPsize), and the cheapest one joining the most subplans wins. Beyond that, the cheapest
+    // join of two subplans is picked. Subplans without join predicates between them are only joined if nothing else can be. None means
+    // that only pending ANSI/semi/anti joins keep the subplans apart.
+    pub fn enumerate_joins(
+        expr_graph: &ExprGraph, estimator: &LOPEstimator<'_>, env: &Env, qblock: &QueryBlock, lop_graph: &mut LOPGraph, pred_map: &PredMap, pending_joins: &[usize],
+        worklist: &[LOPKey],
+    ) -> Option<JoinTree> {
+        let plans = worklist
+            .iter()
+            .map(|&lop_key| {
+                let estimate = estimator.estimate(lop_graph, lop_key);
+                let quns = lop_graph.get_properties(lop_key).quns.clone();
+                JoinCandidate { tree: JoinTree::Plan(lop_key), quns, estimate, cost: 0.0 }
+            })
+            .collect::<Vec<_>>();
+
+        let enumerator = JoinEnumerator { expr_graph, estimator, qblock, pred_map, pending_joins };
+        let dp_limit = env.settings.join_dp_limit.unwrap_or(JOIN_DP_LIMIT);
+        let best = if plans.len() <= dp_limit { enumerator.enumerate_dp(&plans) } else { enumerator.enumerate_greedy(&plans, false) };
+        best.or_else(|| enumerator.enumerate_greedy(&plans, true)).map(|candidate| candidate.tree)
+    }
+}
+
+/***************************************************************************************************/
+struct JoinEnumerator<'a> {
+    expr_graph: &'a ExprGraph,
+    estimator: &'a LOPEstimator<'a>,
+    qblock: &'a QueryBlock,
+    pred_map: &'a PredMap,
+    pending_joins: &'a [usize],
+}
+
+impl<'a> JoinEnumerator<'a> {
+    // enumerate_dp: Cheapest join tree over every set of quantifiers made of N subplans is built from the cheapest trees over its subsets
+    fn enumerate_dp(&self, plans: &[JoinCandidate]) -> Option<JoinCandidate> {
+        let mut best_plans: HashMap<Bitset<QunId>, JoinCandidate> = plans.iter().map(|plan| (plan.quns.clone(), plan.clone())).collect();
+
+        // levels[N-1]: Sets of quantifiers made of N subplans, in the order they were found
+        let mut levels: Vec<Vec<Bitset<QunId>>> = vec![plans.iter().map(|plan| plan.quns.clone()).collect()];
+
+        for nplans in 2..=plans.len() {
+            let mut level = vec![];
+            for lhs_nplans in 1..nplans {
+                for lhs_quns in levels[lhs_nplans - 1].iter() {
+                    for rhs_quns in levels[nplans - lhs_nplans - 1].iter() {
+                        let Some(candidate) = self.join(&best_plans[lhs_quns], &best_plans[rhs_quns], false) else { continue };
+                        match best_plans.get(&candidate.quns) {
+                            Some(best_plan) if best_plan.cost <= candidate.cost => {}
+                            best_plan => {
+                                if best_plan.is_none() {
+                                    level.push(candidate.quns.clone());
+                                }
+                                best_plans.insert(candidate.quns.clone(), candidate);
+                            }
+                        }
+                    }
+                }
+            }
+            levels.push(level);
+        }
+
+        let level = levels.iter().skip(1).rev().find(|level| !level.is_empty())?;
+        level.iter().map(|quns| &best_plans[quns]).min_by(|a, b| a.cost.total_cmp(&b.cost)).cloned()
+    }
+
+    // enumerate_greedy: Cheapest join of two subplans
+    fn enumerate_greedy(&self, plans: &[JoinCandidate], allow_cross_products: bool) -> Option<JoinCandidate> {
+        plans
+            .iter()
+            .flat_map(|lhs| plans.iter().filter_map(move |rhs| self.join(lhs, rhs, allow_cross_products)))
+            .min_by(|a, b| a.cost.total_cmp(&b.cost))
+    }
+
+    // join: Estimate the join of two candidates, if it can be built
+    fn join(&self, lhs: &JoinCandidate, rhs: &JoinCandidate, allow_cross_products: bool) -> Option<JoinCandidate> {
+        if !lhs.quns.is_disjoint(&rhs.quns) {
+            return None;
+        }
+        let quns = &lhs.quns | &rhs.quns;
+
+        // Both sides have to be on the same side of every pending ANSI/semi/anti join
+        let crosses_pending_join = self.pending_joins.iter().any(|&join_ix| {
+            let join = &self.qblock.joins[join_ix];
+            join.lhs_quns.iter().any(|&qunid| quns.get(qunid)) && join.rhs_quns.iter().any(|&qunid| quns.get(qunid))
+        });
+        if crosses_pending_join {
+            return None;
+        }
+
+        // WHERE predicates bound by this join. Those within either side have been accounted for by its estimate.
+        let mut join_keys = vec![];
+        let mut selectivity = 1.0;
+        for (&pred_key, pred_desc) in self.pred_map.iter() {
+            let pred_quns = &pred_desc.quns;
+            if pred_desc.join_ix.is_some() || !pred_quns.is_subset_of(&quns) || pred_quns.is_subset_of(&lhs.quns) || pred_quns.is_subset_of(&rhs.quns) {
+                continue;
+            }
+            let join_class = pred_desc.eqjoin_desc.as_ref().map(|eqjoin_desc| QGM::classify_predicate(eqjoin_desc, &lhs.quns, &rhs.quns));
+            if let Some((PredicateType::EquiJoin, alignment)) = join_class {
+                let children = self.expr_graph.get(pred_key).children.as_ref().unwrap();
+                if alignment == PredicateAlignment::Aligned {
+                    join_keys.push((children[0], children[1]));
+                } else {
+                    join_keys.push((children[1], children[0]));
+                }
+            } else {
+                selectivity *= self.estimator.selectivity(pred_key);
+            }
+        }
+        if join_keys.is_empty() && !allow_cross_products {
+            return None;
+        }
+
+        // Joins pay for hashing (or broadcasting) their build side and for producing their output
+        let nrows = self.estimator.join_cardinality(lhs.estimate, rhs.estimate, &join_keys) * selectivity;
+        let estimate = LOPEstimate { nrows, row_size: lhs.estimate.row_size + rhs.estimate.row_size };
+        let cost = lhs.cost + rhs.cost + rhs.estimate.nrows * rhs.estimate.row_size + estimate.nrows * estimate.row_size;
+
+        let tree = JoinTree::Join(Box::new(lhs.tree.clone()), Box::new(rhs.tree.clone()));
+        Some(JoinCandidate { tree, quns, estimate, cost })
+    }
+}
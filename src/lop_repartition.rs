@@ -5,26 +5,41 @@ use crate::{
     graph::{ExprKey, Graph, LOPKey},
     includes::*,
     lop::{ExprEqClass, LOPGraph, LOPProps, PredicateAlignment, VirtCol, LOP},
+    lop_estimate::LOPEstimator,
     metadata::{PartDesc, PartType},
     QGM,
-    qgm::{QueryBlockGraph}
+    qgm::{JoinType, QueryBlockGraph}
 };
 
+pub const BROADCAST_THRESHOLD: usize = 1 << 20; // Largest build side, in estimated bytes, that joins broadcast instead of repartitioning
+
 impl QGM {
     pub fn repartition_if_needed(qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, lop_graph: &mut LOPGraph, lop_key: LOPKey, expected_partitioning: &PartDesc, eqclass: &ExprEqClass) -> LOPKey {
-        let props = lop_graph.get_properties(lop_key);
-        let actual_partitioning = &props.partdesc;
+        let (lop, props, _) = lop_graph.get3(lop_key);
+
+        // A repartition's output is spread across its consumers
+        let actual_partitioning = if let LOP::Repartition { cpartitions } = lop {
+            &PartDesc::new(*cpartitions, props.partdesc.part_type.clone())
+        } else {
+            &props.partdesc
+        };
 
         if (expected_partitioning.npartitions == 1 && actual_partitioning.npartitions == 1)
             || Self::compare_part_descs(expr_graph, expected_partitioning, actual_partitioning, eqclass)
         {
             lop_key
+        } else if matches!(lop, LOP::Repartition { .. }) {
+            // Retarget the existing repartition instead of stacking another one atop it
+            let lop = lop_graph.get_mut(lop_key);
+            lop.value = LOP::Repartition { cpartitions: expected_partitioning.npartitions };
+            lop.properties.partdesc.part_type = expected_partitioning.part_type.clone();
+            lop_key
         } else {
             let mut partdesc = expected_partitioning.clone();
             let cpartitions = partdesc.npartitions;
             partdesc.npartitions = actual_partitioning.npartitions;
 
-            let props = LOPProps { quns: props.quns.clone(), cols: props.cols.clone(), virtcols: props.virtcols.clone(), preds: props.preds.clone_metadata(), partdesc };
+            let props = LOPProps { quns: props.quns.clone(), cols: props.cols.clone(), virtcols: props.virtcols.clone(), preds: props.preds.clone_metadata(), partdesc, estimate: props.estimate };
             lop_graph.add_node_with_props(LOP::Repartition { cpartitions }, props, Some(vec![lop_key]))
         }
     }
@@ -42,11 +57,11 @@ impl QGM {
 
         let lhs_repart_props = lhs_partdesc.map(|partdesc| {
             let virtcols = None;
-            LOPProps { quns: lhs_props.quns.clone(), cols: lhs_props.cols.clone(), preds: lhs_props.preds.clone_metadata(), partdesc, virtcols }
+            LOPProps { quns: lhs_props.quns.clone(), cols: lhs_props.cols.clone(), preds: lhs_props.preds.clone_metadata(), partdesc, virtcols, estimate: lhs_props.estimate }
         });
         let rhs_repart_props = rhs_partdesc.map(|partdesc| {
             let virtcols = None;
-            LOPProps { quns: rhs_props.quns.clone(), cols: rhs_props.cols.clone(), preds: rhs_props.preds.clone_metadata(), partdesc, virtcols }
+            LOPProps { quns: rhs_props.quns.clone(), cols: rhs_props.cols.clone(), preds: rhs_props.preds.clone_metadata(), partdesc, virtcols, estimate: rhs_props.estimate }
         });
 
         //let (lhs_partitions, rhs_partitions) = (npartitions, npartitions);
@@ -67,6 +82,17 @@ impl QGM {
         (new_lhs_plan_key, new_rhs_plan_key, lhs_join_keys, rhs_join_keys, cpartitions)
     }
 
+    // broadcast_build_side: Should a join copy its entire build side to every probe partition rather than repartition both legs on the
+    // join keys? Only if the build side is small enough, and unless unmatched build rows have to be returned.
+    pub fn broadcast_build_side(env: &Env, estimator: &LOPEstimator<'_>, lop_graph: &mut LOPGraph, rhs_plan_key: LOPKey, join_type: JoinType) -> bool {
+        if matches!(join_type, JoinType::Right | JoinType::Full) {
+            return false;
+        }
+        let threshold = env.settings.broadcast_threshold.unwrap_or(BROADCAST_THRESHOLD);
+        let estimate = estimator.estimate(lop_graph, rhs_plan_key);
+        estimate.nrows * estimate.row_size <= threshold as f64
+    }
+
     pub(crate) fn partdesc_to_virtcols(expr_graph: &ExprGraph, partdesc: &PartDesc) -> Option<Vec<VirtCol>> {
         // Only return virtual columns that are composite expressions (i.e. not plain columns)
         if let PartType::HASHEXPR(exprs) = &partdesc.part_type {
@@ -89,7 +115,7 @@ impl QGM {
     pub fn compare_part_descs(expr_graph: &ExprGraph, expected_desc: &PartDesc, actual_desc: &PartDesc, eqclass: &ExprEqClass) -> bool {
         if expected_desc.npartitions == actual_desc.npartitions {
             match (&expected_desc.part_type, &actual_desc.part_type) {
-                (PartType::RAW, PartType::RAW) => true,
+                (PartType::RAW, PartType::RAW) | (PartType::BROADCAST, PartType::BROADCAST) => true,
                 (PartType::HASHEXPR(keys1), PartType::HASHEXPR(keys2)) => Self::compare_part_keys(expr_graph, keys1, keys2, eqclass),
                 _ => false,
             }
@@ -29,6 +29,7 @@ lalrpop_mod!(pub sqlparser); // synthesized by LALRPOP
 #[macro_use]
 extern crate derivative;
 
+pub mod analyze;
 pub mod bitset;
 pub mod env;
 pub mod graph;
@@ -43,7 +44,10 @@ pub mod qgm;
 pub mod qgmiter;
 
 pub mod lop;
+pub mod lop_estimate;
+pub mod lop_joinorder;
 pub mod lop_repartition;
+pub mod qrw;
 pub mod qst;
 
 pub mod flow;
@@ -51,14 +55,21 @@ pub mod pcode;
 pub mod pop;
 pub mod pop_compile;
 pub mod pop_csv;
+pub mod pop_expand;
 pub mod pop_hash;
 pub mod pop_hashagg;
 pub mod pop_hashmatch;
+pub mod pop_limit;
 pub mod pop_parquet;
 pub mod pop_repartition;
 pub mod pop_run;
+pub mod pop_sort;
+pub mod pop_union;
+pub mod pop_window;
+pub mod scalar;
 
 pub mod datum;
+pub mod sketch;
 pub mod scheduler;
 pub mod stage;
 pub mod task;
@@ -122,6 +133,8 @@ fn run_job(env: &mut Env, run_trace: bool) -> Result<(), String> {
 
     let qgm_raw_pathname = format!("{}/{}", env.output_dir, "qgm_raw.dot");
     let qgm_resolved_pathname = format!("{}/{}", env.output_dir, "qgm_resolved.dot");
+    let results_pathname = format!("{}/{}", env.output_dir, "results.txt");
+    fs::remove_file(&results_pathname).unwrap_or_default();
 
     let mut astlist: Vec<AST> = sqlparser::JobParser::new().parse(&mut parser_state, &contents).unwrap();
 
@@ -134,7 +147,11 @@ fn run_job(env: &mut Env, run_trace: bool) -> Result<(), String> {
                 env.metadata.catalog_table(name, options)?;
             }
             AST::DescribeTable { name } => {
-                env.metadata.describe_table(name)?;
+                let lines = env.metadata.describe_table(name)?;
+                append_lines(&lines, &results_pathname)?;
+            }
+            AST::AnalyzeTable { name, columns } => {
+                analyze::analyze_table(env, name, columns)?;
             }
             AST::SetOption { name, value } => {
                 env.set_option(name, value)?;
@@ -155,6 +172,9 @@ fn run_job(env: &mut Env, run_trace: bool) -> Result<(), String> {
                     run_flow(env, &flow)?;
 
                     display_output_dir(&flow);
+
+                    let ordered = qgm.qblock_graph.get(qgm.main_qblock_key).value.order_by.is_some();
+                    append_results(&flow, &results_pathname, ordered)?;
                 }
             }
         }
@@ -203,6 +223,7 @@ fn main() -> Result<(), String> {
 
     Ok(())
 }
+
 /*
 ********************************** run_unit_tests *********************************************************
 */
@@ -215,27 +236,43 @@ fn run_unit_tests() -> Result<(), String> {
     //let diffcmd = "/Applications/DiffMerge.app/Contents/MacOS/DiffMerge";
     let diffcmd = "diff";
 
-    for (id, test) in vec!["rst", "repartition", "groupby", "spja"].iter().enumerate() {
+    // Plans only, or plans and query results
+    let plan_tests = vec!["rst", "repartition", "groupby", "spja"];
+    let result_tests = vec![
+        "orderby", "multistage", "hashjoin", "taskerror", "aggstrings", "derived", "union", "arith", "cte", "recursive_cte", "distinct", "nulls",
+        "statistics", "approx", "grouping_sets", "window", "having", "conditional", "predicates", "functions", "udf", "joinorder", "estimates", "analyze",
+        "broadcast", "pushdown", "subquery", "outerjoin", "scalar_subquery", "limit",
+    ];
+    let tests = plan_tests.iter().map(|test| (test, true)).chain(result_tests.iter().map(|test| (test, false)));
+
+    for (id, (test, parse_only)) in tests.enumerate() {
         let input_pathname = f!("{TOPDIR}/sql/{test}.fsql");
         let output_dir = f!("{TOPDIR}/tests/output/{test}/");
 
         println!("---------- Running subtest {}", input_pathname);
-        std::fs::remove_dir_all(&output_dir).map_err(stringify)?;
+        std::fs::remove_dir_all(&output_dir).unwrap_or_default();
         std::fs::create_dir_all(&output_dir).map_err(stringify)?;
 
         ntotal = ntotal + 1;
         let mut env = Env::new(id, 12, input_pathname, output_dir.clone());
-        env.set_option("PARSE_ONLY".to_string(), Utf8(String::from("true"))).unwrap();
+        if *test == "udf" {
+            register_example_udfs(&mut env)?;
+        }
+        if parse_only {
+            env.set_option("PARSE_ONLY".to_string(), Utf8(String::from("true"))).unwrap();
+        }
 
         let jobres = run_job(&mut env, false);
         if let Err(errstr) = jobres {
+            // Jobs stop at their first error, which is compared with gold like any other output
             let errstr = format!("{}", &errstr);
             error!("{}", errstr);
+            fs::write(f!("{output_dir}/error.txt"), errstr + "\n").map_err(stringify)?;
         }
-        // Compare with gold output
+        // Compare with gold output. The .jpg renderings depend on the local Graphviz install and aren't compared.
         let gold_dir = f!("{TOPDIR}/tests/gold/{test}/");
 
-        let output = Command::new(diffcmd).arg(gold_dir).arg(output_dir).output().expect("failed to execute process");
+        let output = Command::new(diffcmd).arg("-x").arg("*.jpg").arg(gold_dir).arg(output_dir).output().expect("failed to execute process");
 
         let mut mismatch = false;
         for (tag, buf) in vec![("out", output.stdout), ("err", output.stderr)].iter() {
@@ -251,9 +288,24 @@ fn run_unit_tests() -> Result<(), String> {
     }
 
     println!("---------- Completed: {}/{} subtests passed", npassed, ntotal);
+    if npassed < ntotal {
+        let nfailed = ntotal - npassed;
+        return Err(f!("{nfailed} subtests did not match their gold output"));
+    }
     Ok(())
 }
 
+// register_example_udfs: UDFs used by sql/udf.fsql
+#[cfg(test)]
+fn register_example_udfs(env: &mut Env) -> Result<(), String> {
+    fn hash_string(args: &[Box<dyn Array>]) -> Box<dyn Array> {
+        let strings = args[0].as_any().downcast_ref::<arrow2::array::Utf8Array<i32>>().unwrap();
+        let hashes = strings.values_iter().map(|s| fasthash::xx::hash64(s) as i64).collect::<Vec<_>>();
+        Box::new(PrimitiveArray::<i64>::from_vec(hashes).with_validity(strings.validity().cloned()))
+    }
+    env.register_scalar_udf("HASH_STRING", vec![DataType::Utf8], DataType::Int64, hash_string)
+}
+
 fn display_output_dir(flow: &Flow) {
     println!("---------- output ----------");
     let output_dir = get_output_dir(flow.id);
@@ -272,6 +324,37 @@ fn display_output_dir(flow: &Flow) {
     println!("");
 }
 
+// append_results: Add the rows returned by a query to the results file of its job. Rows are sorted unless the query orders them, since
+// partitions finish in no particular order.
+fn append_results(flow: &Flow, results_pathname: &str, ordered: bool) -> Result<(), String> {
+    let output_dir = get_output_dir(flow.id);
+    let mut files = list_files(&output_dir)?;
+    files.sort();
+
+    let mut rows = vec![];
+    for file_path in files.iter() {
+        let contents = fs::read_to_string(file_path).map_err(stringify)?;
+        rows.extend(contents.lines().filter(|line| !line.is_empty()).map(String::from));
+    }
+    if !ordered {
+        rows.sort();
+    }
+
+    append_lines(&rows, results_pathname)
+}
+
+// append_lines: Append a "rows" header and the given lines to the results file. DESCRIBE TABLE output goes there too, so gold files cover it.
+fn append_lines(lines: &[String], results_pathname: &str) -> Result<(), String> {
+    use std::io::Write;
+
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(results_pathname).map_err(stringify)?;
+    writeln!(file, "---------- {} rows", lines.len()).map_err(stringify)?;
+    for line in lines.iter() {
+        writeln!(file, "{}", line).map_err(stringify)?;
+    }
+    Ok(())
+}
+
 /*
 fn test(from: PrimitiveArray<i128>) {
     cast::integer_to_decimal(&from, 5, 2);
@@ -1,10 +1,14 @@
 // metadata
 
-use std::{collections::HashMap, fs::File, rc::Rc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    rc::Rc,
+};
 
 use arrow2::io::csv::read;
 
-use crate::{expr::ExprGraph, graph::ExprKey, includes::*, Datum};
+use crate::{expr::ExprGraph, graph::ExprKey, includes::*, pop_hashagg::array_to_datum, Datum};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TableType {
@@ -17,6 +21,7 @@ pub enum TableType {
 pub enum PartType {
     RAW,
     HASHEXPR(Vec<ExprKey>),
+    BROADCAST, // Every consumer gets all rows
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +38,7 @@ impl PartDesc {
     pub fn describe(&self, expr_graph: &ExprGraph, do_escape: bool) -> String {
         let part_type_str = match &self.part_type {
             PartType::RAW => String::from("RAW"),
+            PartType::BROADCAST => String::from("BROADCAST"),
             PartType::HASHEXPR(exprs) => {
                 let mut exprstr = String::from("");
                 for (ix, expr_key) in exprs.iter().enumerate() {
@@ -48,11 +54,99 @@ impl PartDesc {
     }
 }
 
-#[allow(dead_code)] // FIXME
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+pub struct ColumnStats {
+    ndv: Option<usize>, // # of distinct values
+    null_count: Option<usize>,
+    min: Option<f64>,
+    max: Option<f64>,
+    histogram: Option<Vec<f64>>, // Boundaries of equi-depth buckets, from the minimum to the maximum
+}
+
+impl ColumnStats {
+    pub fn new(ndv: Option<usize>, null_count: Option<usize>, min: Option<f64>, max: Option<f64>, histogram: Option<Vec<f64>>) -> Self {
+        ColumnStats { ndv, null_count, min, max, histogram }
+    }
+
+    // has_range: Datatypes whose values are kept as numbers, for min/max and histograms. Dates count days since the epoch.
+    pub fn has_range(datatype: &DataType) -> bool {
+        matches!(datatype, DataType::Int32 | DataType::Int64 | DataType::Float64 | DataType::Date32)
+    }
+
+    pub fn ndv(&self) -> Option<usize> {
+        self.ndv
+    }
+
+    pub fn null_count(&self) -> Option<usize> {
+        self.null_count
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.max
+    }
+
+    pub fn histogram(&self) -> Option<&Vec<f64>> {
+        self.histogram.as_ref()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if let Some(ndv) = self.ndv {
+            parts.push(format!("NDV = {}", ndv));
+        }
+        if let Some(null_count) = self.null_count {
+            parts.push(format!("NULLS = {}", null_count));
+        }
+        if let Some(min) = self.min {
+            parts.push(format!("MIN = {}", min));
+        }
+        if let Some(max) = self.max {
+            parts.push(format!("MAX = {}", max));
+        }
+        if let Some(histogram) = self.histogram.as_ref() {
+            let bounds = histogram.iter().map(|bound| ((bound * 100.0).round() / 100.0).to_string()).collect::<Vec<_>>();
+            parts.push(format!("HISTOGRAM = [{}]", bounds.join(", ")));
+        }
+        parts.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct TableStats {
-    nrows: usize,
-    avg_row_size: usize,
+    nrows: Option<usize>,
+    avg_row_size: Option<usize>,
+    columns: BTreeMap<String, ColumnStats>, // Column name -> statistics
+}
+
+impl TableStats {
+    pub fn new(nrows: Option<usize>, avg_row_size: Option<usize>, columns: BTreeMap<String, ColumnStats>) -> Self {
+        TableStats { nrows, avg_row_size, columns }
+    }
+
+    pub fn nrows(&self) -> Option<usize> {
+        self.nrows
+    }
+
+    pub fn avg_row_size(&self) -> Option<usize> {
+        self.avg_row_size
+    }
+
+    pub fn column(&self, name: &str) -> Option<&ColumnStats> {
+        self.columns.get(name)
+    }
+
+    pub fn columns(&self) -> &BTreeMap<String, ColumnStats> {
+        &self.columns
+    }
+
+    pub fn describe(&self) -> String {
+        let describe_option = |value: Option<usize>| value.map_or(String::from("?"), |value| value.to_string());
+        format!("NROWS = {}, AVG_ROW_SIZE = {}", describe_option(self.nrows), describe_option(self.avg_row_size))
+    }
 }
 
 pub trait TableDesc {
@@ -67,9 +161,10 @@ pub trait TableDesc {
     fn get_column(&self, colname: &str) -> Option<(usize, &Field)>;
     fn get_part_desc(&self) -> Option<&PartDesc>;
     fn get_stats(&self) -> Option<&TableStats>;
+    fn with_stats(&self, table_stats: TableStats) -> Rc<dyn TableDesc>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CSVDesc {
     typ: TableType,
     pathname: Rc<String>,
@@ -139,6 +234,9 @@ impl TableDesc for CSVDesc {
     fn get_stats(&self) -> Option<&TableStats> {
         Some(&self.table_stats)
     }
+    fn with_stats(&self, table_stats: TableStats) -> Rc<dyn TableDesc> {
+        Rc::new(CSVDesc { table_stats, ..self.clone() })
+    }
 }
 
 #[derive(Default)]
@@ -164,6 +262,7 @@ impl Metadata {
             let datatype = match datatype {
                 "STRING" => DataType::Utf8,
                 "INT" => DataType::Int64,
+                "DATE" => DataType::Date32,
                 _ => return Err(f!("Invalid datatype {datatype} in COLUMN specification")),
             };
             let field = Field::new(name, datatype, false);
@@ -215,30 +314,64 @@ impl Metadata {
         let nrows = match hm.get("NROWS") {
             Some(Int64(nrows)) => {
                 if *nrows > 0 {
-                    *nrows as usize
+                    Some(*nrows as usize)
                 } else {
                     return Err(String::from("Invalid value for option NROWS"));
                 }
             }
-            None => 1usize,
+            None => None,
             _ => return Err(String::from("Invalid value for option NROWS")),
         };
 
         let avg_row_size = match hm.get("AVG_ROW_SIZE") {
             Some(Int64(avg_row_size)) => {
                 if *avg_row_size > 0 {
-                    *avg_row_size as usize
+                    Some(*avg_row_size as usize)
                 } else {
                     return Err(String::from("Invalid value for option AVG_ROW_SIZE"));
                 }
             }
-            None => 1usize,
+            None => None,
             _ => return Err(String::from("Invalid value for option AVG_ROW_SIZE")),
         };
-        let table_stats = TableStats { nrows, avg_row_size };
+        let columns = Self::get_column_stats(hm)?;
+        let table_stats = TableStats { nrows, avg_row_size, columns };
         Ok(table_stats)
     }
 
+    fn get_column_stats(hm: &HashMap<String, Datum>) -> Result<BTreeMap<String, ColumnStats>, String> {
+        // Parse: COLUMN_NDV = "C_CUSTKEY=1500,C_NATIONKEY=25", COLUMN_MIN = "C_NATIONKEY=0", COLUMN_MAX = "C_NATIONKEY=24"
+        let mut columns: BTreeMap<String, ColumnStats> = BTreeMap::new();
+        for option in ["COLUMN_NDV", "COLUMN_MIN", "COLUMN_MAX"] {
+            let colstr = match hm.get(option) {
+                Some(Utf8(colstr)) => colstr,
+                None => continue,
+                _ => return Err(f!("Invalid value for option {option}")),
+            };
+            for part in colstr.split(',') {
+                let mut colname_and_value = part.split('=');
+                let err = f!("Cannot parse {option} specification");
+                let (name, value) = (colname_and_value.next().ok_or(err.clone())?.trim().to_uppercase(), colname_and_value.next().ok_or(err)?.trim());
+                let value = value.parse::<f64>().map_err(|_| f!("Invalid value {value} for column {name} in {option} specification"))?;
+                let column_stats = columns.entry(name).or_default();
+                match option {
+                    "COLUMN_NDV" if value >= 1.0 => column_stats.ndv = Some(value as usize),
+                    "COLUMN_NDV" => return Err(f!("Invalid value {value} in COLUMN_NDV specification")),
+                    "COLUMN_MIN" => column_stats.min = Some(value),
+                    _ => column_stats.max = Some(value),
+                }
+            }
+        }
+        Ok(columns)
+    }
+
+    fn check_column_stats(table_stats: &TableStats, columns: &[Field], name: &String) -> Result<(), String> {
+        match table_stats.columns.keys().find(|colname| !columns.iter().any(|field| field.name == **colname)) {
+            Some(colname) => Err(f!("Statistics specified for column {colname} that table {name} does not have.")),
+            None => Ok(()),
+        }
+    }
+
     fn get_part_desc(hm: &HashMap<String, Datum>) -> Result<PartDesc, String> {
         let npartitions = match hm.get("PARTITIONS") {
             Some(Int64(npartitions)) => {
@@ -283,6 +416,7 @@ impl Metadata {
                 } else {
                     unimplemented!()
                 };
+                Self::check_column_stats(&table_stats, &columns, &name)?;
 
                 let csvdesc = Rc::new(CSVDesc::new(typ, path, columns, separator, header, part_desc, table_stats)?);
                 self.tables.insert(name.to_string(), csvdesc);
@@ -307,6 +441,7 @@ impl Metadata {
                 let table_stats = Self::get_table_stats(&hm)?;
 
                 let columns = ParquetDesc::infer_metadata(&path)?;
+                Self::check_column_stats(&table_stats, &columns, &name)?;
                 let csvdesc = Rc::new(ParquetDesc::new(typ, path, columns, part_desc, table_stats)?);
                 self.tables.insert(name.to_string(), csvdesc);
                 info!("Cataloged table {}", &name);
@@ -316,25 +451,41 @@ impl Metadata {
         Ok(())
     }
 
-    pub fn describe_table(&self, name: String) -> Result<(), String> {
+    // describe_table: Return the definition of a table, along with any statistics cataloged or collected for it, one line per item
+    pub fn describe_table(&self, name: String) -> Result<Vec<String>, String> {
         let name = name.to_uppercase();
         let tbldesc = self.tables.get(&name);
         if tbldesc.is_none() {
             return Err(format!("Table {} does not exist.", name));
         }
         let tbldesc = tbldesc.unwrap();
-        println!("Table {}", name);
-        println!("  pathname = \"{}\"", tbldesc.pathname());
+        let mut lines = vec![format!("Table {}", name)];
+        lines.push(format!("  pathname = \"{}\"", tbldesc.pathname()));
         if tbldesc.get_type() == TableType::CSV {
-            info!("  HEADER = {}", tbldesc.header());
-            info!("  SEPARATOR = '{}'", tbldesc.separator());
+            lines.push(format!("  HEADER = {}", tbldesc.header()));
+            lines.push(format!("  SEPARATOR = '{}'", tbldesc.separator()));
         }
-        println!("  PARTITIONS = {:?}", tbldesc.get_part_desc());
-        println!("  STATS = {:?}", tbldesc.get_stats());
-        println!("  {} COLUMNS", tbldesc.fields().len());
+        lines.push(format!("  PARTITIONS = {:?}", tbldesc.get_part_desc()));
+        let table_stats = tbldesc.get_stats();
+        if let Some(table_stats) = table_stats {
+            lines.push(format!("  STATS = {}", table_stats.describe()));
+        }
+        lines.push(format!("  {} COLUMNS", tbldesc.fields().len()));
         for cd in tbldesc.fields() {
-            println!("      {} {:?}", cd.name, cd.data_type);
+            match table_stats.and_then(|table_stats| table_stats.column(&cd.name)) {
+                Some(column_stats) => lines.push(format!("      {} {:?} ({})", cd.name, cd.data_type, column_stats.describe())),
+                None => lines.push(format!("      {} {:?}", cd.name, cd.data_type)),
+            }
         }
+        Ok(lines)
+    }
+
+    // set_table_stats: Replace the statistics of a table, e.g. with those collected by ANALYZE TABLE
+    pub fn set_table_stats(&mut self, name: &str, table_stats: TableStats) -> Result<(), String> {
+        let name = name.to_uppercase();
+        let tbldesc = self.tables.get(&name).ok_or_else(|| format!("Table {} does not exist.", name))?;
+        let tbldesc = tbldesc.with_stats(table_stats);
+        self.tables.insert(name, tbldesc);
         Ok(())
     }
 
@@ -344,7 +495,7 @@ impl Metadata {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ParquetDesc {
     typ: TableType,
     pathname: Rc<String>,
@@ -372,6 +523,37 @@ impl ParquetDesc {
 
         Ok(fields)
     }
+
+    // read_statistics: Row count, uncompressed size and per-column null counts and min/max as recorded in the metadata of every row group.
+    // Row groups without min/max don't contribute to them.
+    pub fn read_statistics(pathname: &str, colnames: &[String]) -> Result<(usize, usize, BTreeMap<String, ColumnStats>), String> {
+        use arrow2::io::parquet::read;
+
+        let mut reader = File::open(pathname).map_err(stringify)?;
+        let metadata = read::read_metadata(&mut reader).map_err(stringify)?;
+        let schema = read::infer_schema(&metadata).map_err(stringify)?;
+        let nbytes = metadata.row_groups.iter().map(|row_group| row_group.total_byte_size()).sum::<usize>();
+
+        let mut columns = BTreeMap::new();
+        for field in schema.fields.iter() {
+            let name = field.name.to_uppercase();
+            if !colnames.contains(&name) {
+                continue;
+            }
+            let statistics = read::statistics::deserialize(field, &metadata.row_groups).map_err(stringify)?;
+            let null_counts = statistics.null_count.as_any().downcast_ref::<PrimitiveArray<u64>>();
+            let null_count = null_counts.and_then(|null_counts| null_counts.iter().map(|null_count| null_count.map(|&null_count| null_count as usize)).sum());
+
+            let values = |array: &Box<dyn Array>| (0..array.len()).filter_map(|ix| array_to_datum(array, ix)?.to_f64()).collect::<Vec<_>>();
+            let (min, max) = if ColumnStats::has_range(field.data_type()) {
+                (values(&statistics.min_value).into_iter().reduce(f64::min), values(&statistics.max_value).into_iter().reduce(f64::max))
+            } else {
+                (None, None)
+            };
+            columns.insert(name, ColumnStats::new(None, null_count, min, max, None));
+        }
+        Ok((metadata.num_rows, nbytes, columns))
+    }
 }
 
 impl TableDesc for ParquetDesc {
@@ -410,6 +592,9 @@ impl TableDesc for ParquetDesc {
     fn get_stats(&self) -> Option<&TableStats> {
         Some(&self.table_stats)
     }
+    fn with_stats(&self, table_stats: TableStats) -> Rc<dyn TableDesc> {
+        Rc::new(ParquetDesc { table_stats, ..self.clone() })
+    }
 }
 
 #[derive(Debug)]
@@ -459,4 +644,7 @@ impl TableDesc for QueryDesc {
     fn get_stats(&self) -> Option<&TableStats> {
         None
     }
+    fn with_stats(&self, _table_stats: TableStats) -> Rc<dyn TableDesc> {
+        panic!("Queries have no statistics")
+    }
 }
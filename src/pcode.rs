@@ -2,16 +2,28 @@
 
 #![allow(clippy::borrowed_box)]
 
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
-use arrow2::{scalar::{PrimitiveScalar, Scalar, Utf8Scalar}, compute::cast::{CastOptions, self}};
+use arrow2::{
+    array::{new_null_array, BinaryArray, Utf8Array},
+    bitmap::Bitmap,
+    compute::{
+        boolean_kleene,
+        cast::{self, CastOptions},
+        if_then_else::if_then_else,
+    },
+    scalar::{PrimitiveScalar, Scalar, Utf8Scalar},
+};
+use regex::Regex;
 
 use crate::{
-    datum::Datum,
-    expr::{ArithOp, Expr, ExprGraph, LogOp, RelOp},
+    datum::{Datum, F64},
+    expr::{AggType, ArithOp, Expr, ExprGraph, LogOp, RelOp},
     graph::ExprKey,
     includes::*,
     pop::{Projection, ProjectionMap},
+    scalar::ScalarFunc,
+    sketch::{HyperLogLog, QuantileSketch},
 };
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -35,6 +47,124 @@ pub enum PInstruction {
     LogExpr(LogOp),
     ControlOp(ControlOp),
     Cast(DataType),
+    ScalarFunction(String, usize), // Function name, # of arguments
+    Call(ScalarFunc, usize),       // Built-in function, # of arguments
+    CaseExpr { has_operand: bool, has_else: bool, nargs: usize, data_type: DataType },
+    CoalesceExpr(usize, DataType), // # of arguments, result type
+    NullIfExpr,
+    IsNullExpr { negated: bool },
+    LikeExpr(LikePattern),
+    BetweenExpr,
+    InListExpr(InList),
+}
+
+// LikePattern: LIKE patterns without `_` and with `%` only at their ends are matched without a regex
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LikePattern {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+    Regex(String), // Anchored regular expression, compiled when evaluated
+}
+
+impl LikePattern {
+    pub fn new(pattern: &str) -> Self {
+        // Consecutive wildcards match the same strings as a single one
+        let mut collapsed = String::new();
+        for ch in pattern.chars() {
+            if !(ch == '%' && collapsed.ends_with('%')) {
+                collapsed.push(ch);
+            }
+        }
+
+        let (leading, trailing) = (collapsed.starts_with('%'), collapsed.len() > 1 && collapsed.ends_with('%'));
+        let inner = collapsed.trim_start_matches('%').trim_end_matches('%').to_string();
+        if inner.contains(['%', '_']) {
+            let regex = collapsed
+                .chars()
+                .map(|ch| match ch {
+                    '%' => String::from(".*"),
+                    '_' => String::from("."),
+                    _ => regex::escape(&ch.to_string()),
+                })
+                .collect::<String>();
+            return LikePattern::Regex(format!("(?s)^{}$", regex));
+        }
+        match (leading, trailing) {
+            (false, false) => LikePattern::Exact(inner),
+            (false, true) => LikePattern::Prefix(inner),
+            (true, false) => LikePattern::Suffix(inner),
+            (true, true) => LikePattern::Contains(inner),
+        }
+    }
+
+    fn eval(&self, array: &dyn Array) -> BooleanArray {
+        let array = array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
+        let values = array.values_iter();
+        let matches: Bitmap = match self {
+            LikePattern::Exact(s) => values.map(|value| value == s).collect(),
+            LikePattern::Prefix(s) => values.map(|value| value.starts_with(s.as_str())).collect(),
+            LikePattern::Suffix(s) => values.map(|value| value.ends_with(s.as_str())).collect(),
+            LikePattern::Contains(s) => values.map(|value| value.contains(s.as_str())).collect(),
+            LikePattern::Regex(regex) => {
+                let regex = Regex::new(regex).unwrap();
+                values.map(|value| regex.is_match(value)).collect()
+            }
+        };
+        BooleanArray::new(DataType::Boolean, matches, array.validity().cloned())
+    }
+}
+
+// InList: Values of an IN-list, hashed by their physical type
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InListValues {
+    Int32(HashSet<i32>),
+    Int64(HashSet<i64>),
+    Float64(HashSet<F64>),
+    Decimal(HashSet<i128>),
+    Utf8(HashSet<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InList {
+    values: InListValues,
+    has_null: bool, // Rows without a match are NULL rather than FALSE
+}
+
+impl InList {
+    pub fn new(data_type: &DataType, datums: Vec<&Datum>) -> Self {
+        let has_null = datums.iter().any(|datum| matches!(datum, Datum::Null));
+        let datums = datums.into_iter().filter(|datum| !matches!(datum, Datum::Null));
+        let values = match data_type {
+            DataType::Int32 | DataType::Date32 => InListValues::Int32(datums.map(|datum| datum.try_as_i32().unwrap()).collect()),
+            DataType::Int64 => InListValues::Int64(datums.map(|datum| datum.try_as_i64().unwrap()).collect()),
+            DataType::Float64 => InListValues::Float64(datums.map(|datum| F64::from(datum.try_as_f64().unwrap())).collect()),
+            DataType::Decimal(..) => InListValues::Decimal(datums.map(|datum| datum.try_as_i128().unwrap()).collect()),
+            DataType::Utf8 => InListValues::Utf8(datums.map(|datum| datum.try_as_str().unwrap().to_string()).collect()),
+            _ => panic!("IN-lists of {:?} values are not supported yet", data_type),
+        };
+        InList { values, has_null }
+    }
+
+    fn eval(&self, array: &dyn Array) -> BooleanArray {
+        if *array.data_type() == DataType::Null {
+            return BooleanArray::new_null(DataType::Boolean, array.len());
+        }
+        let matches: Bitmap = match &self.values {
+            InListValues::Int32(set) => primitive_values::<i32>(array).iter().map(|value| set.contains(value)).collect(),
+            InListValues::Int64(set) => primitive_values::<i64>(array).iter().map(|value| set.contains(value)).collect(),
+            InListValues::Float64(set) => primitive_values::<f64>(array).iter().map(|value| set.contains(&F64::from(*value))).collect(),
+            InListValues::Decimal(set) => primitive_values::<i128>(array).iter().map(|value| set.contains(value)).collect(),
+            InListValues::Utf8(set) => array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap().values_iter().map(|value| set.contains(value)).collect(),
+        };
+        let validity = match (array.validity(), self.has_null) {
+            (Some(validity), true) => Some(validity & &matches),
+            (None, true) => Some(matches.clone()),
+            (validity, false) => validity.cloned(),
+        };
+        BooleanArray::new(DataType::Boolean, matches, validity)
+    }
 }
 
 impl ExprKey {
@@ -49,10 +179,16 @@ impl ExprKey {
         } else {
             let (expr, props, children) = expr_graph.get3(*self);
 
-            // Post-order traversal (i.e. children before parents except when compiling aggs)
+            // Post-order traversal (i.e. children before parents except when compiling aggs). LIKE patterns, IN-list values and date parts
+            // are part of their instructions instead.
             if !matches!(expr, Expr::AggFunction(..)) {
                 if let Some(children) = children {
-                    for &child_expr_key in children {
+                    let children = match expr {
+                        Expr::RelExpr(RelOp::Like) | Expr::InListExpr => &children[..1],
+                        Expr::ScalarFunction(name) if ScalarFunc::takes_date_part(name) => &children[1..],
+                        _ => &children[..],
+                    };
+                    for &child_expr_key in children.iter() {
                         child_expr_key.compile(expr_graph, pcode, proj_map)
                     }
                 }
@@ -67,7 +203,22 @@ impl ExprKey {
                     PInstruction::Column(colid)
                 }
                 Expr::BinaryExpr(op) => PInstruction::BinaryExpr(*op),
+                Expr::RelExpr(RelOp::Like) => {
+                    let Expr::Literal(Datum::Utf8(pattern)) = expr_graph.get_value(children.unwrap()[1]) else {
+                        panic!("LIKE pattern is not a string literal")
+                    };
+                    PInstruction::LikeExpr(LikePattern::new(pattern))
+                }
                 Expr::RelExpr(op) => PInstruction::RelExpr(*op),
+                Expr::BetweenExpr => PInstruction::BetweenExpr,
+                Expr::InListExpr => {
+                    let children = children.unwrap();
+                    let datums = children.iter().skip(1).map(|&child_key| match expr_graph.get_value(child_key) {
+                        Expr::Literal(datum) => datum,
+                        _ => panic!("IN-list value is not a literal"),
+                    });
+                    PInstruction::InListExpr(InList::new(children[0].get_data_type(expr_graph), datums.collect()))
+                }
                 Expr::LogExpr(op) => PInstruction::LogExpr(*op),
                 Expr::NegatedExpr => PInstruction::NegatedExpr,
                 Expr::AggFunction(agg_type, _) => {
@@ -80,6 +231,22 @@ impl ExprKey {
                     }
                 }
                 Expr::Cast => PInstruction::Cast(props.data_type.clone()),
+                Expr::ScalarFunction(name) => {
+                    let children = children.map_or(&[][..], |children| &children[..]);
+                    // Resolve has checked the name already, so anything that isn't built-in or planned internally is a UDF
+                    if ScalarFunc::is_internal(name) {
+                        PInstruction::ScalarFunction(name.clone(), children.len())
+                    } else {
+                        let func = ScalarFunc::new(expr_graph, name, children).unwrap_or_else(|_| ScalarFunc::UDF(name.clone()));
+                        PInstruction::Call(func, children.len() - ScalarFunc::takes_date_part(name) as usize)
+                    }
+                }
+                Expr::CaseExpr { has_operand, has_else } => {
+                    PInstruction::CaseExpr { has_operand: *has_operand, has_else: *has_else, nargs: children.unwrap().len(), data_type: props.data_type.clone() }
+                }
+                Expr::CoalesceExpr => PInstruction::CoalesceExpr(children.unwrap().len(), props.data_type.clone()),
+                Expr::NullIfExpr => PInstruction::NullIfExpr,
+                Expr::IsNullExpr { negated } => PInstruction::IsNullExpr { negated: *negated },
                 _ => panic!("Expression not compilable yet: {:?}", expr),
             }
         };
@@ -108,6 +275,22 @@ enum PCodeStack<'a> {
     Column(Column<'a>),
 }
 
+impl<'a> PCodeStack<'a> {
+    // into_array: Column of `len` values. NULL constants and NULL-typed columns become NULLs of `data_type`.
+    fn into_array(self, data_type: &DataType, len: usize) -> Box<dyn Array> {
+        let array = match self {
+            PCodeStack::Column(Column::Owned(array)) => array,
+            PCodeStack::Column(Column::Ref(array)) => array.clone(),
+            PCodeStack::Datum(datum) => broadcast_datum(&datum, len),
+        };
+        if *array.data_type() == DataType::Null && *data_type != DataType::Null {
+            new_null_array(data_type.clone(), len)
+        } else {
+            array
+        }
+    }
+}
+
 impl<'a> fmt::Debug for PCodeStack<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         let display_str = match self {
@@ -170,6 +353,7 @@ impl PCode {
                             };
                             stack.push(PCodeStack::Column(Column::Owned(array)));
                         }
+                        (PCodeStack::Datum(lhs), arithop, PCodeStack::Datum(rhs)) => stack.push(PCodeStack::Datum(eval_arith_datums(&lhs, *arithop, &rhs))),
                         (lhs, op, rhs) => {
                             todo!("Not yet implemented: {:?} {:?} {:?}", lhs, op, rhs)
                         }
@@ -240,15 +424,21 @@ impl PCode {
                         }
                     }
                 }
+                PInstruction::LogExpr(LogOp::Not) => {
+                    let arg = stack.pop().unwrap().into_array(&DataType::Boolean, input.len());
+                    let arg = arg.as_any().downcast_ref::<BooleanArray>().unwrap();
+                    stack.push(PCodeStack::Column(Column::Owned(Box::new(boolean::not(arg)))));
+                }
                 PInstruction::LogExpr(op) => {
                     let (rhs, lhs) = (stack.pop().unwrap(), stack.pop().unwrap());
                     match (lhs, op, rhs) {
                         (PCodeStack::Column(lhs), relop, PCodeStack::Column(rhs)) => {
                             let lhs = lhs.get().as_any().downcast_ref::<BooleanArray>().unwrap();
                             let rhs = rhs.get().as_any().downcast_ref::<BooleanArray>().unwrap();
+                            // Three-valued logic: NULL AND FALSE is FALSE, NULL OR TRUE is TRUE
                             let array: Box<dyn Array> = match relop {
-                                LogOp::And => Box::new(boolean::and(lhs, rhs)),
-                                LogOp::Or => Box::new(boolean::or(lhs, rhs)),
+                                LogOp::And => Box::new(boolean_kleene::and(lhs, rhs)),
+                                LogOp::Or => Box::new(boolean_kleene::or(lhs, rhs)),
                                 _ => todo!(),
                             };
                             stack.push(PCodeStack::Column(Column::Owned(array)));
@@ -279,6 +469,80 @@ impl PCode {
                         _ => todo!(),
                     }
                 }
+                PInstruction::ScalarFunction(name, nargs) => {
+                    let args = stack.split_off(stack.len() - nargs);
+                    let args = args
+                        .iter()
+                        .map(|arg| match arg {
+                            PCodeStack::Column(col) => col.get().clone(),
+                            PCodeStack::Datum(datum) => broadcast_datum(datum, input.len()),
+                        })
+                        .collect::<Vec<_>>();
+                    let array = eval_scalar_function(name, &args, input.len());
+                    stack.push(PCodeStack::Column(Column::Owned(array)));
+                }
+                PInstruction::Call(func, nargs) => {
+                    let args = stack.split_off(stack.len() - nargs);
+                    let args = args.into_iter().map(|arg| arg.into_array(&DataType::Null, input.len())).collect::<Vec<_>>();
+                    stack.push(PCodeStack::Column(Column::Owned(func.eval(&args))));
+                }
+                PInstruction::CaseExpr { has_operand, has_else, nargs, data_type } => {
+                    let args = stack.split_off(stack.len() - nargs);
+                    let array = eval_case(args, *has_operand, *has_else, data_type, input.len());
+                    stack.push(PCodeStack::Column(Column::Owned(array)));
+                }
+                PInstruction::CoalesceExpr(nargs, data_type) => {
+                    // Walk the arguments backwards, each one overriding the result of the ones after it wherever it is not NULL
+                    let args = stack.split_off(stack.len() - nargs);
+                    let mut args = args.into_iter().map(|arg| arg.into_array(data_type, input.len())).collect::<Vec<_>>();
+                    let mut array = args.pop().unwrap();
+                    for arg in args.into_iter().rev() {
+                        array = if_then_else(&boolean::is_not_null(&*arg), &*arg, &*array).unwrap();
+                    }
+                    stack.push(PCodeStack::Column(Column::Owned(array)));
+                }
+                PInstruction::NullIfExpr => {
+                    let (rhs, lhs) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    let lhs = lhs.into_array(&DataType::Null, input.len());
+                    let array = if let PCodeStack::Datum(Datum::Null) = rhs {
+                        // Nothing equals NULL
+                        lhs
+                    } else {
+                        let rhs = rhs.into_array(lhs.data_type(), input.len());
+                        let equal = true_values(&comparison::eq(&*lhs, &*rhs));
+                        let validity = match lhs.validity() {
+                            Some(validity) => validity & &!&equal,
+                            None => !&equal,
+                        };
+                        lhs.with_validity(Some(validity))
+                    };
+                    stack.push(PCodeStack::Column(Column::Owned(array)));
+                }
+                PInstruction::LikeExpr(pattern) => {
+                    let arg = stack.pop().unwrap().into_array(&DataType::Utf8, input.len());
+                    stack.push(PCodeStack::Column(Column::Owned(Box::new(pattern.eval(&*arg)))));
+                }
+                PInstruction::BetweenExpr => {
+                    let (hi, lo, arg) = (stack.pop().unwrap(), stack.pop().unwrap(), stack.pop().unwrap());
+                    let arg = arg.into_array(&DataType::Null, input.len());
+                    let array = match (lo, hi) {
+                        (PCodeStack::Datum(lo), PCodeStack::Datum(hi)) => eval_between(&*arg, &lo, &hi),
+                        (lo, hi) => {
+                            let (lo, hi) = (lo.into_array(arg.data_type(), input.len()), hi.into_array(arg.data_type(), input.len()));
+                            boolean_kleene::and(&comparison::gt_eq(&*arg, &*lo), &comparison::lt_eq(&*arg, &*hi))
+                        }
+                    };
+                    stack.push(PCodeStack::Column(Column::Owned(Box::new(array))));
+                }
+                PInstruction::InListExpr(in_list) => {
+                    let arg = stack.pop().unwrap().into_array(&DataType::Null, input.len());
+                    stack.push(PCodeStack::Column(Column::Owned(Box::new(in_list.eval(&*arg)))));
+                }
+                PInstruction::IsNullExpr { negated } => {
+                    let arg = stack.pop().unwrap().into_array(&DataType::Null, input.len());
+                    let array = if *negated { boolean::is_not_null(&*arg) } else { boolean::is_null(&*arg) };
+                    stack.push(PCodeStack::Column(Column::Owned(Box::new(array))));
+                }
                 _ => {
                     debug!("Instruction inst: {:?} not implemented yet. Possibly invalid?", inst);
                     todo!()
@@ -289,7 +553,166 @@ impl PCode {
         match array {
             PCodeStack::Column(Column::Owned(array)) => array,
             PCodeStack::Column(Column::Ref(array)) => array.clone(),
-            _ => panic!("unexpected value"),
+            PCodeStack::Datum(datum) => broadcast_datum(&datum, input.len()),
+        }
+    }
+}
+
+// eval_case: Arguments are laid out as in Expr::CaseExpr. Branches are applied from last to first, so that the first matching one wins.
+// Rows that match no branch get the ELSE value, or NULL.
+fn eval_case(args: Vec<PCodeStack<'_>>, has_operand: bool, has_else: bool, data_type: &DataType, len: usize) -> Box<dyn Array> {
+    let mut args = args.into_iter();
+    let operand = if has_operand { Some(args.next().unwrap().into_array(&DataType::Null, len)) } else { None };
+    let mut args = args.collect::<Vec<_>>();
+    let mut array = if has_else { args.pop().unwrap().into_array(data_type, len) } else { new_null_array(data_type.clone(), len) };
+
+    let mut args = args.into_iter();
+    let mut branches = vec![];
+    while let (Some(when), Some(then)) = (args.next(), args.next()) {
+        branches.push((when, then));
+    }
+    for (when, then) in branches.into_iter().rev() {
+        // A condition that is NULL doesn't hold
+        let condition = match operand.as_ref() {
+            Some(operand) => {
+                let when = when.into_array(operand.data_type(), len);
+                comparison::eq(&**operand, &*when)
+            }
+            None => when.into_array(&DataType::Boolean, len).as_any().downcast_ref::<BooleanArray>().unwrap().clone(),
+        };
+        let condition = BooleanArray::new(DataType::Boolean, true_values(&condition), None);
+        let then = then.into_array(data_type, len);
+        array = if_then_else(&condition, &*then, &*array).unwrap();
+    }
+    array
+}
+
+// eval_arith_datums: Constant arithmetic, e.g. `1 + 10` in `L_QUANTITY <= 1 + 10`. Division is done in floating point.
+fn eval_arith_datums(lhs: &Datum, op: ArithOp, rhs: &Datum) -> Datum {
+    if let (Datum::Int64(lhs), Datum::Int64(rhs), false) = (lhs, rhs, op == ArithOp::Div) {
+        let value = match op {
+            ArithOp::Add => lhs + rhs,
+            ArithOp::Sub => lhs - rhs,
+            ArithOp::Mul => lhs * rhs,
+            ArithOp::Div => unreachable!(),
+        };
+        return Datum::Int64(value);
+    }
+    let (lhs, rhs) = (lhs.to_f64().unwrap(), rhs.to_f64().unwrap());
+    let value = match op {
+        ArithOp::Add => lhs + rhs,
+        ArithOp::Sub => lhs - rhs,
+        ArithOp::Mul => lhs * rhs,
+        ArithOp::Div => lhs / rhs,
+    };
+    Datum::Float64(F64::from(value))
+}
+
+// eval_between: Fused `lo <= arg AND arg <= hi` for constant bounds
+fn eval_between(arg: &dyn Array, lo: &Datum, hi: &Datum) -> BooleanArray {
+    fn between<T: PartialOrd>(values: impl Iterator<Item = T>, lo: T, hi: T) -> Bitmap {
+        values.map(|value| lo <= value && value <= hi).collect()
+    }
+
+    let matches = match (lo, hi) {
+        (Datum::Int32(lo) | Datum::Date32(lo), Datum::Int32(hi) | Datum::Date32(hi)) => between(primitive_values::<i32>(arg).iter(), lo, hi),
+        (Datum::Int64(lo), Datum::Int64(hi)) => between(primitive_values::<i64>(arg).iter(), lo, hi),
+        (Datum::Float64(lo), Datum::Float64(hi)) => between(primitive_values::<f64>(arg).iter().cloned(), f64::from(*lo), f64::from(*hi)),
+        (Datum::Decimal(lo, ..), Datum::Decimal(hi, ..)) => between(primitive_values::<i128>(arg).iter(), lo, hi),
+        (Datum::Utf8(lo), Datum::Utf8(hi)) => between(arg.as_any().downcast_ref::<Utf8Array<i32>>().unwrap().values_iter(), lo.as_str(), hi.as_str()),
+        _ => unreachable!("BETWEEN {:?} AND {:?}: bounds are cast to one of the supported types during resolve", lo, hi),
+    };
+    BooleanArray::new(DataType::Boolean, matches, arg.validity().cloned())
+}
+
+fn primitive_values<T: arrow2::types::NativeType>(array: &dyn Array) -> &[T] {
+    array.as_any().downcast_ref::<PrimitiveArray<T>>().unwrap().values()
+}
+
+// true_values: Bitmap of the rows that are TRUE, as opposed to FALSE or NULL. Filters only keep those rows.
+pub fn true_values(array: &BooleanArray) -> Bitmap {
+    match array.validity() {
+        Some(validity) => array.values() & validity,
+        None => array.values().clone(),
+    }
+}
+
+// eval_scalar_function: Functions outside the ScalarFunc registry. These are planned internally: the grouping-id placeholder, GROUPING(),
+// and finishing a variance or an approximate aggregate from its merged partial aggregates. Resolve rejects any other name.
+fn eval_scalar_function(name: &str, args: &[Box<dyn Array>], len: usize) -> Box<dyn Array> {
+    match name {
+        // Placeholder for the grouping-id, filled in when rows get expanded into grouping sets
+        "$GROUPING_ID" => return broadcast_datum(&Datum::Int64(0), len),
+        "GROUPING" => return eval_grouping(args),
+        "APPROX_COUNT_DISTINCT" | "APPROX_PERCENTILE" => return eval_sketch(name, args),
+        _ => {}
+    }
+
+    let aggtype = match name {
+        "STDDEV_SAMP" => AggType::STDDEV_SAMP,
+        "STDDEV_POP" => AggType::STDDEV_POP,
+        "VAR_SAMP" => AggType::VAR_SAMP,
+        "VAR_POP" => AggType::VAR_POP,
+        _ => unreachable!("Scalar function {}() is neither registered nor planned internally", name),
+    };
+
+    // Arguments: count, sum, sum-of-squares
+    let args = args.iter().map(|arg| cast::cast(&**arg, &DataType::Float64, CastOptions::default()).unwrap()).collect::<Vec<_>>();
+    let args = args.iter().map(|arg| arg.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap()).collect::<Vec<_>>();
+    let array = args[0]
+        .iter()
+        .zip(args[1].iter())
+        .zip(args[2].iter())
+        .map(|((count, sum), sum_squares)| match (count, sum, sum_squares) {
+            (Some(count), Some(sum), Some(sum_squares)) => aggtype.finish_variance(*count, *sum, *sum_squares),
+            _ => None,
+        })
+        .collect::<PrimitiveArray<f64>>();
+    Box::new(array)
+}
+
+// eval_sketch: Finish an approximate aggregate from its merged sketch. APPROX_PERCENTILE() also takes the fraction it returns.
+fn eval_sketch(name: &str, args: &[Box<dyn Array>]) -> Box<dyn Array> {
+    let sketches = args[0].as_any().downcast_ref::<BinaryArray<i32>>().unwrap();
+    if name == "APPROX_COUNT_DISTINCT" {
+        let array = sketches.iter().map(|bytes| bytes.map(|bytes| HyperLogLog::from_bytes(bytes).estimate())).collect::<PrimitiveArray<i64>>();
+        Box::new(array)
+    } else {
+        let fractions = args[1].as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap();
+        let array = (sketches.iter().zip(fractions.iter()))
+            .map(|(bytes, fraction)| QuantileSketch::from_bytes(bytes?).quantile(*fraction?))
+            .collect::<PrimitiveArray<f64>>();
+        Box::new(array)
+    }
+}
+
+// eval_grouping: Arguments are the grouping-id followed by the bit# of every GROUPING() argument. The first argument yields the
+// most significant bit of the result.
+fn eval_grouping(args: &[Box<dyn Array>]) -> Box<dyn Array> {
+    let args = args.iter().map(|arg| arg.as_any().downcast_ref::<PrimitiveArray<i64>>().unwrap()).collect::<Vec<_>>();
+    let (grouping_ids, bits) = (args[0], &args[1..]);
+    let array = (0..grouping_ids.len())
+        .map(|ix| {
+            let grouping_id = grouping_ids.value(ix);
+            bits.iter().fold(0, |grouping, bit| (grouping << 1) | ((grouping_id >> bit.value(ix)) & 1))
+        })
+        .collect::<Vec<i64>>();
+    Box::new(PrimitiveArray::<i64>::from_vec(array))
+}
+
+// broadcast_datum: Expand a constant into a column, e.g. for literals in a select-list
+fn broadcast_datum(datum: &Datum, len: usize) -> Box<dyn Array> {
+    match datum {
+        Datum::Null => new_null_array(DataType::Null, len),
+        Datum::Boolean(bvalue) => Box::new(BooleanArray::from_trusted_len_values_iter(std::iter::repeat(*bvalue).take(len))),
+        Datum::Int32(ivalue) => Box::new(PrimitiveArray::<i32>::from_trusted_len_values_iter(std::iter::repeat(*ivalue).take(len))),
+        Datum::Int64(ivalue) => Box::new(PrimitiveArray::<i64>::from_trusted_len_values_iter(std::iter::repeat(*ivalue).take(len))),
+        Datum::Utf8(svalue) => Box::new(Utf8Array::<i32>::from_trusted_len_values_iter(std::iter::repeat(svalue).take(len))),
+        Datum::Date32(ivalue) => Box::new(PrimitiveArray::<i32>::from_trusted_len_values_iter(std::iter::repeat(*ivalue).take(len)).to(DataType::Date32)),
+        Datum::Float64(fvalue) => Box::new(PrimitiveArray::<f64>::from_trusted_len_values_iter(std::iter::repeat(f64::from(*fvalue)).take(len))),
+        Datum::Decimal(value, precision, scale) => {
+            Box::new(PrimitiveArray::<i128>::from_trusted_len_values_iter(std::iter::repeat(*value).take(len)).to(DataType::Decimal(*precision, *scale)))
         }
+        Datum::Binary(bvalue) => Box::new(BinaryArray::<i32>::from_trusted_len_values_iter(std::iter::repeat(bvalue).take(len))),
     }
 }
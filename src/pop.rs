@@ -14,10 +14,15 @@ use crate::{
     includes::*,
     pcode::PCode,
     pop_csv::CSV,
+    pop_expand::Expand,
     pop_hashagg::HashAgg,
     pop_hashmatch::HashMatch,
+    pop_limit::Limit,
     pop_parquet::Parquet,
     pop_repartition::{RepartitionRead, RepartitionWrite},
+    pop_sort::Sort,
+    pop_union::UnionAll,
+    pop_window::Window,
     stage::Stage,
 };
 
@@ -66,7 +71,7 @@ impl ProjectionMap {
 
     pub fn set_agg(&mut self, agg_type: AggType, input_colid: ColId, output_data_type: DataType) -> ColId {
         let prj = Projection::AggCol(Agg { agg_type, input_colid, output_data_type });
-        let next_colid = self.hashmap.len();
+        let next_colid = self.hashmap.values().max().map_or(0, |&colid| colid + 1);
         let retval = self.hashmap.entry(prj).or_insert_with_key(|k| {
             debug!("ProjectionMap:set_agg(): Assigned {:?} -> {}", k, next_colid);
             next_colid
@@ -97,8 +102,13 @@ pub enum POP {
     Parquet(Parquet),
     HashMatch(HashMatch),
     HashAgg(HashAgg),
+    Expand(Expand),
     RepartitionWrite(RepartitionWrite),
     RepartitionRead(RepartitionRead),
+    Sort(Sort),
+    Limit(Limit),
+    UnionAll(UnionAll),
+    Window(Window),
 }
 
 /***************************************************************************************************/
@@ -117,7 +127,8 @@ impl VecWriter {
     }
 
     fn into_string(self) -> String {
-        String::from_utf8(self.buffer).unwrap()
+        // Binary columns (e.g. partial sketches) need not be valid UTF-8
+        String::from_utf8_lossy(&self.buffer).into_owned()
     }
 }
 
@@ -132,6 +143,12 @@ impl Write for VecWriter {
     }
 }
 
+// slice_chunk: Return up to `len` rows starting at `offset`
+pub fn slice_chunk(chunk: &ChunkBox, offset: usize, len: usize) -> ChunkBox {
+    let len = len.min(chunk.len() - offset);
+    Chunk::new(chunk.arrays().iter().map(|array| array.sliced(offset, len)).collect())
+}
+
 pub fn chunk_to_string(chunk: &ChunkBox, header: &str) -> String {
     // Only display a maximum of 10 rows
     let len = chunk.len();
@@ -172,13 +189,13 @@ pub fn chunk_to_tabularstring(chunk: &ChunkBox, header: &str) -> String {
             for _ in 0..len {
                 writer.write_fmt(format_args!("{:10}", "+----------")).unwrap();
             }
-            writer.write(b"+\n").unwrap();
+            writer.write_all(b"+\n").unwrap();
         }
         if rx < rows.len() - 1 {
             for col in cols.iter() {
                 writer.write_fmt(format_args!("|{:10}", col)).unwrap();
             }
-            writer.write(b"|\n").unwrap();
+            writer.write_all(b"|\n").unwrap();
         }
     }
     writer.into_string()
@@ -13,10 +13,10 @@ use crate::{
     pcode::PCode,
     pop::{Agg, POPProps, Projection, ProjectionMap, POP},
     pop_csv::CSV,
-    pop_hashagg, pop_hashmatch,
+    pop_expand, pop_hashagg, pop_hashmatch, pop_limit,
     pop_parquet::Parquet,
-    pop_repartition,
-    qgm::QGM,
+    pop_repartition, pop_sort, pop_union, pop_window,
+    qgm::{JoinType, QGM},
     stage::{StageGraph, StageLink},
 };
 
@@ -26,7 +26,7 @@ impl POP {
         let mut stage_graph = StageGraph::default();
 
         let root_stage_id = stage_graph.add_stage(lop_key, None);
-        let root_pop_key = Self::compile_lop(qgm, lop_graph, lop_key, &mut stage_graph, root_stage_id)?;
+        let root_pop_key = Self::compile_lop(env, qgm, lop_graph, lop_key, &mut stage_graph, root_stage_id)?;
         stage_graph.set_root_pop_key(root_stage_id, root_pop_key);
 
         // Diagnostics
@@ -45,9 +45,18 @@ impl POP {
     }
 
     #[tracing::instrument(fields(lop = %lop_key), skip_all, parent = None)]
-    pub fn compile_lop(qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId) -> Result<POPKey, String> {
+    pub fn compile_lop(env: &Env, qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId) -> Result<POPKey, String> {
         let (lop, _, lop_children) = lop_graph.get3(lop_key);
 
+        // A CTE referenced more than once is materialized by a single stage. Every consumer after the first one just reads its output.
+        if let (LOP::Repartition { cpartitions }, Some(cte_stage)) = (lop, stage_graph.find_child_stage(lop_key)) {
+            let (cte_stage_id, stage_link) = (cte_stage.stage_id, StageLink(cte_stage.stage_id, cte_stage.parent_stage_ids[0]));
+            let schema = Rc::new(lop_key.get_schema(qgm, lop_graph));
+            let read_pop_key = Self::compile_repartition_read(qgm, lop_graph, lop_key, stage_graph, stage_id, stage_link, schema, *cpartitions)?;
+            stage_graph.add_parent_stage(cte_stage_id, stage_id, read_pop_key);
+            return Ok(read_pop_key);
+        }
+
         // Do we have a new stage?
         let (effective_stage_id, stage_link) = if matches!(lop, LOP::Repartition { .. }) {
             let child_stage_id = stage_graph.add_stage(lop_key, Some(stage_id));
@@ -60,9 +69,15 @@ impl POP {
         // Compile children first
         let mut pop_children = vec![];
         if let Some(lop_children) = lop_children {
-            for lop_child_key in lop_children {
-                let pop_key = Self::compile_lop(qgm, lop_graph, *lop_child_key, stage_graph, effective_stage_id)?;
+            for (child_ix, lop_child_key) in lop_children.iter().enumerate() {
+                let first_stage_id = stage_graph.stages.len();
+                let pop_key = Self::compile_lop(env, qgm, lop_graph, *lop_child_key, stage_graph, effective_stage_id)?;
                 pop_children.push(pop_key);
+
+                // Stages below the recursive query block of a recursive CTE get rerun on every iteration
+                if matches!(lop, LOP::UnionAll { recursive: true }) && child_ix == 1 {
+                    stage_graph.stages[effective_stage_id].recursive_stages = Some((first_stage_id..stage_graph.stages.len()).collect());
+                }
             }
         }
 
@@ -78,6 +93,12 @@ impl POP {
                 Self::compile_repartition_write(qgm, lop_graph, lop_key, stage_graph, stage_link.unwrap(), pop_children, schema.clone().unwrap(), *cpartitions)?
             }
             LOP::Aggregation { .. } => Self::compile_aggregation(qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::Expand { .. } => Self::compile_expand(qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::Sort { .. } => Self::compile_sort(env, qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::Window { .. } => Self::compile_window(qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::Limit { .. } => Self::compile_limit(qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::UnionAll { .. } => Self::compile_union_all(qgm, lop_graph, lop_key, stage_graph, effective_stage_id, pop_children)?,
+            LOP::RecursiveScan { .. } => Self::compile_recursive_scan(qgm, lop_graph, lop_key, stage_graph, effective_stage_id)?,
         };
 
         debug!("[{:?}] compiled to {:?} in stage {}", lop_key, pop_key, effective_stage_id);
@@ -85,7 +106,7 @@ impl POP {
 
         // Add RepartionRead
         if let LOP::Repartition { cpartitions } = lop {
-            let read_pop_key: POPKey = Self::compile_repartition_read(qgm, lop_graph, lop_key, stage_graph, stage_link.unwrap(), schema.unwrap(), *cpartitions)?;
+            let read_pop_key: POPKey = Self::compile_repartition_read(qgm, lop_graph, lop_key, stage_graph, stage_id, stage_link.unwrap(), schema.unwrap(), *cpartitions)?;
             debug!("[{:?}] compiled to {:?} in stage {}", lop_key, read_pop_key, stage_id);
 
             stage_graph.set_root_pop_key(effective_stage_id, pop_key);
@@ -104,10 +125,10 @@ impl POP {
         let tbldesc = qgm.metadata.get_tabledesc(qunid).unwrap();
 
         // Build input map
-        let (input_projection, mut input_proj_map) = if let LOP::TableScan { input_projection } = lop {
+        let (input_projection, mut input_proj_map, limit) = if let LOP::TableScan { input_projection, limit } = lop {
             let proj_map: ProjectionMap = Self::compute_projection_map(input_projection, None);
             let input_projection = input_projection.elements().iter().map(|&quncol| quncol.1).collect::<Vec<ColId>>();
-            (input_projection, proj_map)
+            (input_projection, proj_map, *limit)
         } else {
             return Err(String::from("Internal error: compile_scan() received a POP that isn't a TableScan"));
         };
@@ -120,11 +141,11 @@ impl POP {
         let pop = match tbldesc.get_type() {
             TableType::CSV => {
                 let inner =
-                    CSV::new(tbldesc.pathname().clone(), tbldesc.fields().clone(), tbldesc.header(), tbldesc.separator(), lopprops.partdesc.npartitions, input_projection);
+                    CSV::new(tbldesc.pathname().clone(), tbldesc.fields().clone(), tbldesc.header(), tbldesc.separator(), lopprops.partdesc.npartitions, input_projection, limit);
                 POP::CSV(inner)
             }
             TableType::Parquet => {
-                let inner = Parquet::new(tbldesc.pathname().clone(), tbldesc.fields().clone(), lopprops.partdesc.npartitions, input_projection);
+                let inner = Parquet::new(tbldesc.pathname().clone(), tbldesc.fields().clone(), lopprops.partdesc.npartitions, input_projection, limit);
                 POP::Parquet(inner)
             }
             unexpected => panic!("Unexpected type: {:?}", unexpected),
@@ -234,30 +255,44 @@ impl POP {
         let stage_id = stage_link.0;
         let (_, lopprops, children) = lop_graph.get3(lop_key);
 
-        // We shouldn't have any predicates
-        let predicates = None;
-        assert!(lopprops.preds.is_empty());
-
         // Build projection map of child. This will be used to resolve any column references in this LOP
         let child_lop_key = children.unwrap()[0];
         let child_lopprops = lop_graph.get_properties(child_lop_key);
         let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
 
+        // A derived table's quantifier exposes the select-list of its query block, i.e. the output of the child, by position
+        if lopprops.quns != child_lopprops.quns {
+            let qunid = lopprops.quns.elements()[0];
+            let ncols = child_lopprops.virtcols.as_ref().map_or(0, |virtcols| virtcols.len());
+            for colid in 0..ncols {
+                proj_map.set(Projection::QunCol(QunCol(qunid, colid)), colid);
+            }
+        }
+
+        // Only derived tables have predicates here
+        let predicates = Self::compile_predicates(qgm, &lopprops.preds, &mut proj_map);
+
         // Compile real + virt columns
         let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
         let props = POPProps::new(predicates, cols, virtcols, lopprops.partdesc.npartitions);
 
-        let repart_key = if let PartType::HASHEXPR(partkey) = &lopprops.partdesc.part_type {
-            debug!("Compile pkey start");
-            Self::compile_exprs(qgm, partkey, &mut proj_map).unwrap()
-        } else {
-            panic!("Invalid partitioning type")
+        let repart_key = match &lopprops.partdesc.part_type {
+            PartType::HASHEXPR(partkey) => {
+                debug!("Compile pkey start");
+                Self::compile_exprs(qgm, partkey, &mut proj_map).unwrap()
+            }
+            // Unkeyed repartitioning either keeps rows in their partition (funneling them into a single consumer, if that's all there is)
+            // or copies them to every consumer
+            PartType::RAW if cpartitions == 1 || cpartitions == lopprops.partdesc.npartitions => vec![],
+            PartType::BROADCAST => vec![],
+            _ => panic!("Invalid partitioning type"),
         };
+        let broadcast = matches!(lopprops.partdesc.part_type, PartType::BROADCAST);
         debug!("Compile pkey end");
 
         debug!("[{:?}] compile_repartition_write: schema = {:?}", lop_key, &schema);
 
-        let pop_inner = pop_repartition::RepartitionWrite::new(repart_key, schema, stage_link, cpartitions);
+        let pop_inner = pop_repartition::RepartitionWrite::new(repart_key, schema, stage_link, cpartitions, broadcast);
         let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
         let pop_key = pop_graph.add_node_with_props(POP::RepartitionWrite(pop_inner), props, Some(pop_children));
 
@@ -268,11 +303,11 @@ impl POP {
 
     #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
     pub fn compile_repartition_read(
-        _qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_link: StageLink, schema: Rc<Schema>, npartitions: usize,
+        _qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, stage_link: StageLink, schema: Rc<Schema>,
+        npartitions: usize,
     ) -> Result<POPKey, String> {
         debug!("[{:?}] compile_repartition_read: schema = {:?}", lop_key, &schema);
 
-        let stage_id = stage_link.1;
         let lopprops = &lop_graph.get(lop_key).properties;
 
         // No predicates
@@ -287,7 +322,7 @@ impl POP {
 
         let props = POPProps::new(predicates, cols, virtcols, npartitions);
 
-        let pop_inner = pop_repartition::RepartitionRead::new(schema, stage_link);
+        let pop_inner = pop_repartition::RepartitionRead::new(schema, stage_link, false);
         let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
 
         let pop_key = pop_graph.add_node_with_props(POP::RepartitionRead(pop_inner), props, None);
@@ -297,12 +332,45 @@ impl POP {
         Ok(pop_key)
     }
 
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_recursive_scan(qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId) -> Result<POPKey, String> {
+        let (lop, lopprops, ..) = lop_graph.get3(lop_key);
+
+        if let LOP::RecursiveScan { cte_lop_key } = lop {
+            // Read back what the CTE's stage wrote during its previous iteration
+            let cte_stage = stage_graph.find_child_stage(*cte_lop_key).unwrap();
+            let stage_link = StageLink(cte_stage.stage_id, cte_stage.parent_stage_ids[0]);
+            let schema = Rc::new(cte_lop_key.get_schema(qgm, lop_graph));
+
+            // The CTE's quantifier exposes its select-list by position
+            let qunid = lopprops.quns.elements()[0];
+            let mut proj_map = ProjectionMap::default();
+            for colid in 0..schema.fields.len() {
+                proj_map.set(Projection::QunCol(QunCol(qunid, colid)), colid);
+            }
+
+            let predicates = Self::compile_predicates(qgm, &lopprops.preds, &mut proj_map);
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(predicates, cols, virtcols, lopprops.partdesc.npartitions);
+
+            let pop_inner = pop_repartition::RepartitionRead::new(schema, stage_link, true);
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let pop_key = pop_graph.add_node_with_props(POP::RepartitionRead(pop_inner), props, None);
+
+            debug!("[{:?}] end compile_recursive_scan", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
     #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
     pub fn compile_join(
         qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
     ) -> Result<POPKey, String> {
         let (lop, lopprops, children) = lop_graph.get3(lop_key);
-        if let LOP::HashJoin { lhs_join_keys, rhs_join_keys } = lop {
+        if let LOP::HashJoin { join_type, lhs_join_keys, rhs_join_keys, join_preds, null_aware } = lop {
             let keyexprs = [lhs_join_keys, rhs_join_keys];
             let keycols = [0, 1]
                 .iter()
@@ -338,7 +406,17 @@ impl POP {
             let left_child_proj_map = Self::compute_projection_map(&left_child_lop_props.cols, left_child_lop_props.virtcols.as_ref());
             let right_child_lop_props = lop_graph.get_properties(children.unwrap()[1]);
             let right_child_proj_map = Self::compute_projection_map(&right_child_lop_props.cols, right_child_lop_props.virtcols.as_ref());
-            let mut proj_map = left_child_proj_map.append(right_child_proj_map);
+            let mut join_proj_map = left_child_proj_map.append(right_child_proj_map);
+
+            let join_preds = Self::compile_exprs(qgm, join_preds, &mut join_proj_map);
+            debug!("[{:?}] join predicates {:?}", lop_key, join_preds);
+
+            // Semi/anti joins only return LHS rows, so RHS columns are only visible to join predicates
+            let mut proj_map = if matches!(join_type, JoinType::Semi | JoinType::Anti) {
+                Self::compute_projection_map(&left_child_lop_props.cols, left_child_lop_props.virtcols.as_ref())
+            } else {
+                join_proj_map
+            };
 
             // Compile real + virt columns
             let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
@@ -350,7 +428,7 @@ impl POP {
 
             let children_data_types = children.unwrap().iter().map(|child_lop_key| child_lop_key.get_types(qgm, lop_graph)).collect::<Vec<_>>();
 
-            let pop_inner = pop_hashmatch::HashMatch { keycols, children_data_types };
+            let pop_inner = pop_hashmatch::HashMatch { join_type: *join_type, keycols, children_data_types, join_preds, null_aware: *null_aware };
             let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
 
             let pop_key = pop_graph.add_node_with_props(POP::HashMatch(pop_inner), props, Some(pop_children));
@@ -367,20 +445,27 @@ impl POP {
         qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
     ) -> Result<POPKey, String> {
         let (lop, lopprops, children) = lop_graph.get3(lop_key);
-        if let LOP::Aggregation { key_len } = lop {
+        if let LOP::Aggregation { key_len, having } = lop {
             let qunid = lopprops.quns.elements()[0];
 
-            // Populate internal projection-map with key columns.
+            // Populate internal projection-map with key columns. Keys can also be referred to by the child expressions that compute them.
             let mut internal_proj_map = Self::compute_initial_agg_projection_map(qunid, *key_len);
+            let child_lopprops = lop_graph.get_properties(children.unwrap()[0]);
+            if let (true, Some(child_virtcols)) = (child_lopprops.cols.is_empty(), child_lopprops.virtcols.as_ref()) {
+                for (colid, &virtcol) in child_virtcols.iter().take(*key_len).enumerate() {
+                    internal_proj_map.set(Projection::VirtCol(virtcol), colid);
+                }
+            }
 
-            // Compile real + virt columns + predicates. As aggregate expressions (e.g. SUM(col) are visited, they are added to the projection map
-            // at column offsets beyond the # of key columns.
+            // Compile real + virt columns + HAVING predicates. As aggregate expressions (e.g. SUM(col) are visited, they are added to the projection map
+            // at column offsets beyond the # of key columns. Aggregates only referenced by HAVING thus get accumulated too.
             let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut internal_proj_map);
             assert!(cols.is_none());
-            let predicates = Self::compile_predicates(qgm, &lopprops.preds, &mut internal_proj_map);
-            debug!("[{:?}] predicates {:?}", lop_key, predicates);
+            assert!(lopprops.preds.is_empty());
+            let having = Self::compile_exprs(qgm, having, &mut internal_proj_map);
+            debug!("[{:?}] having {:?}", lop_key, having);
 
-            let props = POPProps::new(predicates, cols, virtcols, lopprops.partdesc.npartitions);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
 
             let aggs = Self::build_ordered_agg_list(&internal_proj_map);
             debug!("aggs = {:?}", &aggs);
@@ -391,7 +476,7 @@ impl POP {
             let child_lop_key = children.unwrap()[0];
             let child_data_types = child_lop_key.get_types(qgm, lop_graph);
 
-            let pop_inner = pop_hashagg::HashAgg::new(keycols, child_data_types, aggs);
+            let pop_inner = pop_hashagg::HashAgg::new(keycols, child_data_types, aggs, having);
 
             let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
             let pop_key = pop_graph.add_node_with_props(POP::HashAgg(pop_inner), props, Some(pop_children));
@@ -404,6 +489,170 @@ impl POP {
         }
     }
 
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_sort(
+        env: &Env, qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
+    ) -> Result<POPKey, String> {
+        let (lop, lopprops, children) = lop_graph.get3(lop_key);
+        if let LOP::Sort { sort_keys, limit } = lop {
+            // Build projection map of child. This will be used to resolve sort keys and output columns
+            let child_lop_key = children.unwrap()[0];
+            let child_lopprops = lop_graph.get_properties(child_lop_key);
+            let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
+
+            let sort_exprs = sort_keys.iter().map(|(expr_key, _)| *expr_key).collect::<Vec<_>>();
+            let sort_code = Self::compile_exprs(qgm, &sort_exprs, &mut proj_map).unwrap();
+            let sort_keys = sort_code.into_iter().zip(sort_keys.iter()).map(|(pcode, (_, ordering))| (pcode, *ordering)).collect::<Vec<_>>();
+
+            // Compile real + virt columns
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
+
+            // A sort sitting atop a repartition merges the sorted runs written by each producer
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let merge_link = if let POP::RepartitionRead(rpr) = pop_graph.get_value(pop_children[0]) { Some(*rpr.stage_link()) } else { None };
+            let memory_budget = env.settings.sort_memory_budget.unwrap_or(pop_sort::SORT_MEMORY_BUDGET);
+
+            let pop_inner = pop_sort::Sort::new(sort_keys, merge_link, memory_budget, *limit);
+            let pop_key = pop_graph.add_node_with_props(POP::Sort(pop_inner), props, Some(pop_children));
+
+            debug!("[{:?}] end compile_sort", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_window(
+        qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
+    ) -> Result<POPKey, String> {
+        let (lop, lopprops, children) = lop_graph.get3(lop_key);
+        if let LOP::Window { partition_keys, order_keys, window_fns } = lop {
+            // Build projection map of child. This will be used to resolve keys, window function arguments and output columns
+            let child_lop_key = children.unwrap()[0];
+            let child_lopprops = lop_graph.get_properties(child_lop_key);
+            let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
+            let nchildcols = child_lopprops.cols.len() + child_lopprops.virtcols.as_ref().map_or(0, |virtcols| virtcols.len());
+
+            let partition_code = Self::compile_exprs(qgm, partition_keys, &mut proj_map).unwrap_or_default();
+            let order_exprs = order_keys.iter().map(|(expr_key, _)| *expr_key).collect::<Vec<_>>();
+            let order_code = Self::compile_exprs(qgm, &order_exprs, &mut proj_map).unwrap_or_default();
+            let order_keys = order_code.into_iter().zip(order_keys.iter()).map(|(pcode, (_, ordering))| (pcode, *ordering)).collect::<Vec<_>>();
+
+            let mut window_cols = vec![];
+            for &expr_key in window_fns.iter() {
+                let (expr, props, children) = qgm.expr_graph.get3(expr_key);
+                let Expr::WindowFunction(window_func) = expr else { panic!("compile_window: Invalid window function") };
+                let (func_type, frame, data_type) = (window_func.func_type, window_func.frame, props.data_type().clone());
+                let args = window_func.args(children.unwrap()).to_vec();
+                let args = Self::compile_exprs(qgm, &args, &mut proj_map).unwrap_or_default();
+                window_cols.push(pop_window::WindowCol { func_type, frame, args, data_type });
+            }
+
+            // Window functions are appended to the child's columns
+            for (ix, &expr_key) in window_fns.iter().enumerate() {
+                proj_map.set(Projection::VirtCol(expr_key), nchildcols + ix);
+            }
+
+            // Compile real + virt columns
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
+
+            let pop_inner = pop_window::Window::new(partition_code, order_keys, window_cols);
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let pop_key = pop_graph.add_node_with_props(POP::Window(pop_inner), props, Some(pop_children));
+
+            debug!("[{:?}] end compile_window", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_expand(
+        qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
+    ) -> Result<POPKey, String> {
+        let (lop, lopprops, children) = lop_graph.get3(lop_key);
+        if let LOP::Expand { key_len, grouping_sets } = lop {
+            // Build projection map of child. This will be used to resolve output columns
+            let child_lop_key = children.unwrap()[0];
+            let child_lopprops = lop_graph.get_properties(child_lop_key);
+            let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
+
+            // Compile real + virt columns
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
+
+            let pop_inner = pop_expand::Expand::new(*key_len, grouping_sets.clone());
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let pop_key = pop_graph.add_node_with_props(POP::Expand(pop_inner), props, Some(pop_children));
+
+            debug!("[{:?}] end compile_expand", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_limit(
+        qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
+    ) -> Result<POPKey, String> {
+        let (lop, lopprops, children) = lop_graph.get3(lop_key);
+        if let LOP::Limit { offset, limit } = lop {
+            // Build projection map of child. This will be used to resolve output columns
+            let child_lop_key = children.unwrap()[0];
+            let child_lopprops = lop_graph.get_properties(child_lop_key);
+            let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
+
+            // Compile real + virt columns
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
+
+            let pop_inner = pop_limit::Limit::new(*offset, *limit);
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let pop_key = pop_graph.add_node_with_props(POP::Limit(pop_inner), props, Some(pop_children));
+
+            debug!("[{:?}] end compile_limit", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
+    #[tracing::instrument(fields(lop = lop_key.to_string()), skip_all, parent = None)]
+    pub fn compile_union_all(
+        qgm: &mut QGM, lop_graph: &LOPGraph, lop_key: LOPKey, stage_graph: &mut StageGraph, stage_id: StageId, pop_children: Vec<POPKey>,
+    ) -> Result<POPKey, String> {
+        let (lop, lopprops, children) = lop_graph.get3(lop_key);
+        if let LOP::UnionAll { recursive } = lop {
+            // All children produce the same layout, so the first one's projection map serves for all of them
+            let child_lop_key = children.unwrap()[0];
+            let child_lopprops = lop_graph.get_properties(child_lop_key);
+            let mut proj_map: ProjectionMap = Self::compute_projection_map(&child_lopprops.cols, child_lopprops.virtcols.as_ref());
+
+            // Compile real + virt columns
+            let (cols, virtcols) = Self::compile_projection(qgm, lop_key, lopprops, &mut proj_map);
+            let props = POPProps::new(None, cols, virtcols, lopprops.partdesc.npartitions);
+
+            let pop_inner = pop_union::UnionAll::new(*recursive);
+            let pop_graph = &mut stage_graph.stages[stage_id].pop_graph;
+            let pop_key = pop_graph.add_node_with_props(POP::UnionAll(pop_inner), props, Some(pop_children));
+
+            debug!("[{:?}] end compile_union_all", lop_key);
+
+            Ok(pop_key)
+        } else {
+            panic!("Bad LOP")
+        }
+    }
+
     pub fn compute_initial_agg_projection_map(qunid: QunId, key_len: usize) -> ProjectionMap {
         let mut proj_map = ProjectionMap::default();
         for colid in 0..key_len {
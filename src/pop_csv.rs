@@ -15,7 +15,7 @@ use crate::{
     flow::Flow,
     graph::POPKey,
     includes::*,
-    pop::{chunk_to_string, POPContext},
+    pop::{chunk_to_string, slice_chunk, POPContext},
     stage::Stage,
 };
 
@@ -29,6 +29,7 @@ pub struct CSVContext {
     rows: Vec<ByteRecord>,
     partition_id: PartitionId,
     partition: TextFilePartition,
+    nrows_left: Option<usize>, // Rows still to be produced if the scan is limited
 }
 
 impl CSVContext {
@@ -48,7 +49,16 @@ impl CSVContext {
 
         let rows = vec![ByteRecord::default(); CHUNK_SIZE];
 
-        let csvctx = CSVContext { pop_key, fields: csv.fields.clone(), projection: csv.input_projection.clone(), reader, rows, partition_id, partition };
+        let csvctx = CSVContext {
+            pop_key,
+            fields: csv.fields.clone(),
+            projection: csv.input_projection.clone(),
+            reader,
+            rows,
+            partition_id,
+            partition,
+            nrows_left: csv.limit,
+        };
 
         Ok(Box::new(csvctx))
     }
@@ -89,6 +99,11 @@ impl POPContext for CSVContext {
         let pop_key = self.pop_key;
         let props = stage.pop_graph.get_properties(pop_key);
 
+        // Stop reading once a limited scan has produced enough rows
+        if self.nrows_left == Some(0) {
+            return Ok(None);
+        }
+
         let mut chunk = self.next0()?;
 
         //debug!("Before preds: \n{}", chunk_to_string(&chunk, "Before preds"));
@@ -98,6 +113,11 @@ impl POPContext for CSVContext {
             chunk = POPKey::eval_predicates(props, chunk);
             //debug!("After preds: \n{}", chunk_to_string(&chunk, "After preds"));
 
+            if let Some(nrows_left) = self.nrows_left.as_mut() {
+                chunk = slice_chunk(&chunk, 0, *nrows_left);
+                *nrows_left -= chunk.len();
+            }
+
             let projection_chunk = POPKey::eval_projection(props, &chunk);
             let headerstr = format!("CSVContext::next Stage = {}, {:?}, Partition = {}", stage.stage_id, pop_key, self.partition_id);
             debug!("{}", chunk_to_string(&projection_chunk, &headerstr));
@@ -117,13 +137,16 @@ pub struct CSV {
     pub separator: char,
     pub partitions: Vec<TextFilePartition>,
     pub input_projection: Vec<ColId>,
+    pub limit: Option<usize>,
 }
 
 impl CSV {
-    pub fn new(pathname: String, fields: Vec<Field>, header: bool, separator: char, npartitions: usize, input_projection: Vec<ColId>) -> CSV {
+    pub fn new(
+        pathname: String, fields: Vec<Field>, header: bool, separator: char, npartitions: usize, input_projection: Vec<ColId>, limit: Option<usize>,
+    ) -> CSV {
         let partitions = Self::compute_partitions(&pathname, npartitions as u64).unwrap();
 
-        CSV { pathname, fields, header, separator, partitions, input_projection }
+        CSV { pathname, fields, header, separator, partitions, input_projection, limit }
     }
 
     fn compute_partitions(pathname: &str, nsplits: u64) -> Result<Vec<TextFilePartition>, String> {
@@ -0,0 +1,103 @@
+// pop_expand
+
+use arrow2::array::new_null_array;
+
+use crate::{
+    flow::Flow,
+    graph::POPKey,
+    includes::*,
+    pop::{chunk_to_string, POPContext, POP},
+    stage::Stage,
+};
+
+/***************************************************************************************************/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Expand {
+    pub key_len: usize,                 // # of GROUP-BY keys. The grouping-id follows them.
+    pub grouping_sets: Vec<Vec<usize>>, // Keys grouped on by each set
+}
+
+impl Expand {
+    pub fn new(key_len: usize, grouping_sets: Vec<Vec<usize>>) -> Self {
+        Expand { key_len, grouping_sets }
+    }
+
+    // grouping_id: Keys left out of a set have their bits set, the first key being the most significant one. Repeats of a set are
+    // numbered in the bits above the keys, so that they don't get aggregated together.
+    fn grouping_id(&self, set_ix: usize) -> i64 {
+        let set = &self.grouping_sets[set_ix];
+        let repeat = self.grouping_sets[..set_ix].iter().filter(|&prev_set| prev_set == set).count() as i64;
+        let key_bits: i64 = (0..self.key_len).filter(|key_ix| !set.contains(key_ix)).map(|key_ix| 1 << (self.key_len - 1 - key_ix)).sum();
+        (repeat << self.key_len) | key_bits
+    }
+
+    // expand_chunk: Copy of `chunk` for one grouping set. Keys outside the set are NULL.
+    fn expand_chunk(&self, chunk: &ChunkBox, set_ix: usize) -> ChunkBox {
+        let len = chunk.len();
+        let set = &self.grouping_sets[set_ix];
+        let grouping_id = self.grouping_id(set_ix);
+        let arrays = chunk
+            .arrays()
+            .iter()
+            .enumerate()
+            .map(|(colid, array)| {
+                if colid < self.key_len && !set.contains(&colid) {
+                    new_null_array(array.data_type().clone(), len)
+                } else if colid == self.key_len {
+                    Box::new(PrimitiveArray::<i64>::from_vec(vec![grouping_id; len]))
+                } else {
+                    array.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        Chunk::new(arrays)
+    }
+}
+
+/***************************************************************************************************/
+pub struct ExpandContext {
+    pop_key: POPKey,
+    children: Vec<Box<dyn POPContext>>,
+    partition_id: PartitionId,
+    chunk: Option<ChunkBox>, // Child chunk being expanded
+    set_ix: usize,           // Next grouping set to expand it into
+}
+
+impl ExpandContext {
+    pub fn try_new(pop_key: POPKey, _: &Expand, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+        Ok(Box::new(ExpandContext { pop_key, children, partition_id, chunk: None, set_ix: 0 }))
+    }
+}
+
+impl POPContext for ExpandContext {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next(&mut self, flow: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
+        let pop_key = self.pop_key;
+        let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
+
+        if let POP::Expand(expand) = pop {
+            loop {
+                if let Some(chunk) = self.chunk.as_ref() {
+                    if self.set_ix < expand.grouping_sets.len() {
+                        let chunk = expand.expand_chunk(chunk, self.set_ix);
+                        self.set_ix += 1;
+                        debug!("{}", chunk_to_string(&chunk, &format!("ExpandContext::next partition = {}", self.partition_id)));
+                        return Ok(Some(chunk));
+                    }
+                }
+
+                // Every set is done with the current chunk, move on to the next one
+                let Some(chunk) = self.children[0].next(flow, stage)? else { break };
+                self.chunk = Some(POPKey::eval_projection(props, &chunk));
+                self.set_ix = 0;
+            }
+        } else {
+            panic!("ugh")
+        }
+        Ok(None)
+    }
+}
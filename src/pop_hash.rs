@@ -19,15 +19,15 @@ macro_rules! hash_array {
         let array_inner: &$array_type = $array.as_any().downcast_ref().unwrap();
         for (ix, elem) in array_inner.values_iter().enumerate() {
             let hv = $state.hash_one(elem);
-            $hash_array[ix] += hv;
-            $hash_array[ix] = 999;
+            $hash_array[ix] = $hash_array[ix].wrapping_mul(31).wrapping_add(hv);
         }
     }};
 }
 
-pub fn hash_chunk(chunk: &ChunkBox, state: &RandomState) -> (Vec<HashValue>, Vec<SplitId>) {
-    // Initialize hash array
-    let mut hash_array = vec![0; chunk.len()];
+#[allow(clippy::modulo_one)]
+pub fn hash_chunk(chunk: &ChunkBox, nrows: usize, state: &RandomState) -> (Vec<HashValue>, Vec<SplitId>) {
+    // Initialize hash array. Rows of a chunk without any (key) columns all hash to 0.
+    let mut hash_array: Vec<HashValue> = vec![0; nrows];
 
     for array in chunk.arrays() {
         match array.data_type().to_physical_type() {
@@ -3,12 +3,14 @@
 #![allow(warnings)]
 
 use std::{
+    cmp::Ordering,
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
 };
 
 use arrow2::{
-    array::{MutableArray, MutableBooleanArray, MutablePrimitiveArray, MutableUtf8Array, Utf8Array},
+    array::{BinaryArray, MutableArray, MutableBinaryArray, MutableBooleanArray, MutablePrimitiveArray, MutableUtf8Array, Utf8Array},
+    compute::filter::filter_chunk,
     datatypes::PhysicalType,
     types::PrimitiveType,
 };
@@ -19,15 +21,29 @@ use crate::{
     expr::AggType,
     flow::Flow,
     graph::POPKey,
+    pcode::{true_values, PCode},
     includes::*,
     pop::{chunk_to_string, Agg, POPContext, POP},
     pop_hash::NSPLITS,
+    sketch::{HyperLogLog, QuantileSketch},
     stage::Stage,
     Datum,
 };
 
 type DataRow = Vec<Option<Datum>>;
 
+const NULL_HASH: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Accumulator: Running state of one aggregate of a group
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Value(Option<Datum>),                               // COUNT, SUM, SUM_SQUARES, MIN, MAX
+    Moments { count: i64, sum: f64, sum_squares: f64 }, // Variances and standard deviations
+    Values(Vec<f64>),                                   // Holistic aggregates, e.g. MEDIAN
+    CountDistinct(HyperLogLog),                         // APPROX_COUNT_DISTINCT and its partials
+    Quantiles(QuantileSketch),                          // APPROX_PERCENTILE and its partials
+}
+
 /***************************************************************************************************/
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HashAgg {
@@ -35,12 +51,13 @@ pub struct HashAgg {
     pub child_data_types: Vec<DataType>,
     pub child_physical_types: Vec<PhysicalType>,
     pub aggs: Vec<(Agg, ColId)>, // ColId represents the ordering of each aggregator and starts at keylen
+    pub having: Option<Vec<PCode>>, // HAVING predicates, evaluated over the keys and finalized aggregates
 }
 
 impl HashAgg {
-    pub fn new(keycols: Vec<Vec<ColId>>, child_data_types: Vec<DataType>, aggs: Vec<(Agg, ColId)>) -> Self {
+    pub fn new(keycols: Vec<Vec<ColId>>, child_data_types: Vec<DataType>, aggs: Vec<(Agg, ColId)>, having: Option<Vec<PCode>>) -> Self {
         let child_physical_types = child_data_types.iter().map(|typ| typ.to_physical_type()).collect::<Vec<_>>();
-        HashAgg { keycols, child_data_types, child_physical_types, aggs }
+        HashAgg { keycols, child_data_types, child_physical_types, aggs, having }
     }
 
     pub fn keylen(&self) -> usize {
@@ -50,7 +67,7 @@ impl HashAgg {
 
 /***************************************************************************************************/
 struct HashAggSplit {
-    hash_map: MyHashTable<DataRow, DataRow>, // Hash-of-keys -> Accumulators
+    hash_map: MyHashTable<DataRow, Vec<Accumulator>>, // Hash-of-keys -> Accumulators
 }
 
 impl HashAggSplit {
@@ -106,23 +123,41 @@ impl HashAggContext {
         let mut hasharr = vec![0u64; chunk.len()];
         let keylen = hash_agg.keycols[0].len();
 
+        // NULL keys hash to a fixed value so that they land in the same group
         for array in chunk.arrays().iter().take(keylen) {
             match array.data_type().to_physical_type() {
                 PhysicalType::Primitive(PrimitiveType::Int64) => {
                     let primarr = array.as_any().downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval = *hashval ^ *newval.unwrap() as u64);
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, |v| *v as u64));
+                }
+                PhysicalType::Primitive(PrimitiveType::Int32) => {
+                    let primarr = array.as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, |v| *v as u64));
+                }
+                PhysicalType::Primitive(PrimitiveType::Float64) => {
+                    let primarr = array.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap();
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, |v| v.to_bits()));
+                }
+                PhysicalType::Primitive(PrimitiveType::Int128) => {
+                    let primarr = array.as_any().downcast_ref::<PrimitiveArray<i128>>().unwrap();
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, |v| (*v as u64) ^ ((*v >> 64) as u64)));
+                }
+                PhysicalType::Boolean => {
+                    let primarr = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, |v| v as u64));
                 }
                 PhysicalType::Utf8 => {
                     let primarr = array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
-                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval = *hashval ^ xx::hash64(newval.unwrap()));
+                    hasharr.iter_mut().zip(primarr.iter()).for_each(|(hashval, newval)| *hashval ^= newval.map_or(NULL_HASH, xx::hash64));
                 }
-                _ => todo!(),
+                typ => todo!("hash_chunk(), todo: {:?}", typ),
             }
         }
         hasharr
     }
 
     //#[tracing::instrument(fields(key, value), skip_all, parent = None)]
+    #[allow(clippy::modulo_one)]
     fn upsert(&mut self, hash_agg: &HashAgg, chunk: ChunkBox) -> Result<(), String> {
         let keycols = &hash_agg.keycols[0];
         let keylen = hash_agg.keylen();
@@ -137,130 +172,107 @@ impl HashAggContext {
             // Find/init the accumulators
             let cmp_key = |key: &DataRow| -> bool { compare_key(&chunk, row_ix, key) };
             let gen_key = || -> DataRow { build_key(&chunk, keylen, row_ix) };
-            let entry = split.hash_map.find(hash_value as usize, cmp_key, gen_key, || Self::init_accumulators(hash_agg, &chunk, row_ix));
-            let do_init = matches!(entry, MyEntry::Inserted(_));
-            if do_init {
-                continue;
-            }
+            let entry = split.hash_map.find(hash_value as usize, cmp_key, gen_key, || Self::init_accumulators(hash_agg));
 
             let bucket = entry.bucket();
             let mut key_value = split.hash_map.key_value_mut(bucket);
             let (key, accumulators) = &mut key_value.as_mut().unwrap();
 
-            //for (accnum, &(Agg { agg_type, input_colid, .. }, _)) in hash_agg.aggs.iter().enumerate() {
             for (acc, &(Agg { agg_type, input_colid, .. }, _)) in accumulators.iter_mut().zip(hash_agg.aggs.iter()) {
-                let array = &chunk.arrays()[input_colid].as_any();
-                let input_type = &hash_agg.child_physical_types[input_colid];
-                match (agg_type, input_type) {
-                    (AggType::COUNT, _) => {
-                        acc.as_mut().unwrap().add_i64(1);
-                    }
-                    (AggType::SUM, PhysicalType::Primitive(PrimitiveType::Int64)) => {
-                        let primarr = array.downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                        let cur_value = primarr.get(row_ix).unwrap();
-                        let old_sum = acc.as_ref().map_or(0, |e| e.try_as_i64().unwrap());
-                        *acc = Some(Int64(old_sum + cur_value));
-                    }
-                    (AggType::SUM, PhysicalType::Primitive(PrimitiveType::Float64)) => {
-                        let primarr = array.downcast_ref::<PrimitiveArray<f64>>().unwrap();
-                        let cur_value = primarr.get(row_ix).unwrap();
-                        acc.as_mut().unwrap().add_f64(cur_value);
-                    }
-                    (AggType::MAX | AggType::MIN, PhysicalType::Primitive(PrimitiveType::Int64)) => {
-                        let primarr = array.downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                        let cur_datum = primarr.get(row_ix).map(|ival| Int64(ival));
-                        if let Some(Int64(cur_int)) = cur_datum {
-                            if let Some(Int64(acc_int)) = acc {
-                                if (agg_type == AggType::MAX) && (cur_int > *acc_int) {
-                                    *acc = cur_datum;
-                                } else if (agg_type == AggType::MIN) && (cur_int < *acc_int) {
-                                    *acc = cur_datum;
-                                }
-                            } else {
-                                // Accumulator is NULL
-                                todo!("What to do here?")
-                            }
-                        } else {
-                            // Current value is NULL
-                            todo!("What to do here?")
-                        }
-                    }
-                    (AggType::MAX | AggType::MIN, PhysicalType::Utf8) => {
-                        let primarr = array.downcast_ref::<Utf8Array<i32>>().unwrap();
-                        let cur_datum = primarr.get(row_ix).map(|sval| Utf8(sval.to_string()));
-                        if let Some(Utf8(cur_str)) = cur_datum.as_ref() {
-                            if let Some(Utf8(acc_str)) = acc {
-                                let cur_str = cur_str.as_str();
-                                let acc_str = acc_str.as_str();
-                                if (agg_type == AggType::MAX) && (cur_str > acc_str) {
-                                    *acc = cur_datum;
-                                } else if (agg_type == AggType::MIN) && (cur_str < acc_str) {
-                                    *acc = cur_datum;
-                                }
-                            } else {
-                                // Accumulator is NULL
-                                todo!("What to do here?")
-                            }
-                        } else {
-                            // Current value is NULL
-                            todo!("What to do here?")
-                        }
-                    }
-                    _ => panic!("HashAggContext::insert(): Combination of {:?} not yet supported", (agg_type, input_type)),
-                }
+                let cur_datum = array_to_datum(&chunk.arrays()[input_colid], row_ix);
+                Self::accumulate(agg_type, acc, cur_datum);
             }
         }
 
         Ok(())
     }
 
-    fn init_accumulators(hash_agg: &HashAgg, chunk: &ChunkBox, ix: usize) -> DataRow {
-        let mut accumulators = vec![];
-        for &(Agg { agg_type, input_colid, .. }, _) in hash_agg.aggs.iter() {
-            let array = &chunk.arrays()[input_colid].as_any();
-            let input_type = &hash_agg.child_physical_types[input_colid];
+    fn init_accumulators(hash_agg: &HashAgg) -> Vec<Accumulator> {
+        hash_agg.aggs.iter().map(|(agg, _)| Self::init_accumulator(agg.agg_type)).collect()
+    }
 
-            match (agg_type, input_type) {
-                (AggType::COUNT, _) => {
-                    accumulators.push(Some(Int64(1)));
-                }
-                (AggType::SUM, PhysicalType::Primitive(PrimitiveType::Int64)) => {
-                    let primarr = array.downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                    let cur_value = primarr.get(ix).unwrap();
-                    accumulators.push(Some(Int64(cur_value)));
-                }
-                (AggType::SUM, PhysicalType::Primitive(PrimitiveType::Float64)) => {
-                    let primarr = array.downcast_ref::<PrimitiveArray<f64>>().unwrap();
-                    let cur_value = primarr.get(ix).unwrap();
-                    accumulators.push(Some(Float64(F64::from(cur_value))));
-                }
-                (AggType::MAX | AggType::MIN, PhysicalType::Primitive(PrimitiveType::Int64)) => {
-                    let primarr = array.downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                    let cur_datum = primarr.get(ix).map(|ival| Int64(ival));
-                    if cur_datum.is_some() {
-                        accumulators.push(cur_datum);
-                    } else {
-                        // Current value is NULL
-                        accumulators.push(None);
+    // init_accumulator: COUNT starts at zero, every other aggregate starts out as NULL until it sees its first non-NULL input
+    pub fn init_accumulator(agg_type: AggType) -> Accumulator {
+        match agg_type {
+            AggType::COUNT => Accumulator::Value(Some(Int64(0))),
+            agg_type if agg_type.is_variance() => Accumulator::Moments { count: 0, sum: 0.0, sum_squares: 0.0 },
+            agg_type if agg_type.is_holistic() => Accumulator::Values(vec![]),
+            AggType::APPROX_COUNT_DISTINCT | AggType::HLL_SKETCH | AggType::HLL_MERGE => Accumulator::CountDistinct(HyperLogLog::new()),
+            AggType::APPROX_PERCENTILE(_) | AggType::QUANTILE_SKETCH | AggType::QUANTILE_MERGE => Accumulator::Quantiles(QuantileSketch::new()),
+            _ => Accumulator::Value(None),
+        }
+    }
+
+    pub fn accumulate(agg_type: AggType, acc: &mut Accumulator, cur_datum: Option<Datum>) {
+        // NULL inputs are ignored by all aggregates
+        let Some(cur_datum) = cur_datum else { return };
+
+        match acc {
+            Accumulator::Moments { count, sum, sum_squares } => {
+                let value = cur_datum.to_f64().unwrap();
+                *count += 1;
+                *sum += value;
+                *sum_squares += value * value;
+            }
+            Accumulator::Values(values) => values.push(cur_datum.to_f64().unwrap()),
+            // Merge aggregates see partial sketches, all others see values
+            Accumulator::CountDistinct(sketch) if agg_type == AggType::HLL_MERGE => {
+                sketch.merge(&HyperLogLog::from_bytes(cur_datum.try_as_bytes().unwrap()))
+            }
+            Accumulator::CountDistinct(sketch) => sketch.add(&cur_datum),
+            Accumulator::Quantiles(sketch) if agg_type == AggType::QUANTILE_MERGE => {
+                sketch.merge(&QuantileSketch::from_bytes(cur_datum.try_as_bytes().unwrap()))
+            }
+            Accumulator::Quantiles(sketch) => sketch.add(cur_datum.to_f64().unwrap()),
+            Accumulator::Value(acc) => {
+                let cur_datum = if agg_type == AggType::SUM_SQUARES {
+                    let value = cur_datum.to_f64().unwrap();
+                    Float64(F64::from(value * value))
+                } else {
+                    cur_datum
+                };
+                match (agg_type, acc.as_mut()) {
+                    (AggType::COUNT, Some(acc_datum)) => acc_datum.add_i64(1),
+                    (AggType::SUM | AggType::SUM_SQUARES | AggType::MIN | AggType::MAX, None) => *acc = Some(cur_datum),
+                    (AggType::SUM | AggType::SUM_SQUARES, Some(acc_datum)) => *acc_datum = acc_datum.add(&cur_datum),
+                    (AggType::MIN, Some(acc_datum)) => {
+                        if cur_datum.compare(acc_datum) == Ordering::Less {
+                            *acc_datum = cur_datum
+                        }
                     }
-                }
-                (AggType::MAX | AggType::MIN, PhysicalType::Utf8) => {
-                    let primarr = array.downcast_ref::<Utf8Array<i32>>().unwrap();
-                    let cur_datum = primarr.get(ix).map(|sval| Utf8(sval.to_string()));
-                    if cur_datum.is_some() {
-                        accumulators.push(cur_datum);
-                    } else {
-                        // Current value is NULL
-                        accumulators.push(None);
+                    (AggType::MAX, Some(acc_datum)) => {
+                        if cur_datum.compare(acc_datum) == Ordering::Greater {
+                            *acc_datum = cur_datum
+                        }
                     }
+                    _ => panic!("HashAggContext::accumulate(): Combination of {:?} not yet supported", (agg_type, acc)),
                 }
-                _ => panic!("HashAggContext::init_accumulators(): Combination of {:?} not yet supported", (agg_type, input_type)),
             }
         }
-        accumulators
     }
 
-    fn init_mutable_array(data_type: &DataType, len: usize) -> Box<dyn MutableArray> {
+    // finalize: Result of an aggregate from its accumulated state
+    pub fn finalize(agg_type: AggType, acc: &Accumulator) -> Option<Datum> {
+        match acc {
+            Accumulator::Value(datum) => datum.clone(),
+            Accumulator::Moments { count, sum, sum_squares } => agg_type.finish_variance(*count as f64, *sum, *sum_squares).map(|value| Float64(F64::from(value))),
+            Accumulator::Values(values) => {
+                let mut values = values.clone();
+                values.sort_by(f64::total_cmp);
+                agg_type.finish_percentile(&values).map(|value| Float64(F64::from(value)))
+            }
+            // Partial aggregates pass their sketches on
+            Accumulator::CountDistinct(sketch) if matches!(agg_type, AggType::HLL_SKETCH | AggType::HLL_MERGE) => Some(Binary(sketch.to_bytes())),
+            Accumulator::Quantiles(sketch) if matches!(agg_type, AggType::QUANTILE_SKETCH | AggType::QUANTILE_MERGE) => Some(Binary(sketch.to_bytes())),
+            Accumulator::CountDistinct(sketch) => Some(Int64(sketch.estimate())),
+            Accumulator::Quantiles(sketch) => {
+                let AggType::APPROX_PERCENTILE(fraction) = agg_type else { panic!("HashAggContext::finalize(): Unexpected {:?}", agg_type) };
+                sketch.quantile(f64::from(fraction)).map(|value| Float64(F64::from(value)))
+            }
+        }
+    }
+
+    pub fn init_mutable_array(data_type: &DataType, len: usize) -> Box<dyn MutableArray> {
         match data_type {
             DataType::Int32 => Box::new(MutablePrimitiveArray::<i32>::with_capacity(len)),
             DataType::Date32 => Box::new(MutablePrimitiveArray::<i32>::with_capacity(len).to(DataType::Date32)),
@@ -268,11 +280,13 @@ impl HashAggContext {
             DataType::Utf8 => Box::new(MutableUtf8Array::<i32>::with_capacity(len)),
             DataType::Boolean => Box::new(MutableBooleanArray::with_capacity(len)),
             DataType::Float64 => Box::new(MutablePrimitiveArray::<f64>::with_capacity(len)),
+            DataType::Decimal(..) => Box::new(MutablePrimitiveArray::<i128>::with_capacity(len).to(data_type.clone())),
+            DataType::Binary => Box::new(MutableBinaryArray::<i32>::with_capacity(len)),
             typ => todo!("not implemented: {:?}", typ),
         }
     }
 
-    fn append_mutable_array(mutarr: &mut Box<dyn MutableArray>, datum: Option<&Datum>) {
+    pub fn append_mutable_array(mutarr: &mut Box<dyn MutableArray>, datum: Option<&Datum>) {
         match mutarr.data_type().to_physical_type() {
             PhysicalType::Primitive(PrimitiveType::Int32) => {
                 let mutarr = mutarr.as_mut_any().downcast_mut::<MutablePrimitiveArray<i32>>().unwrap();
@@ -286,17 +300,28 @@ impl HashAggContext {
                 let mutarr = mutarr.as_mut_any().downcast_mut::<MutablePrimitiveArray<f64>>().unwrap();
                 mutarr.push(datum.map(|ivalue| ivalue.try_as_f64().unwrap()));
             }
+            PhysicalType::Primitive(PrimitiveType::Int128) => {
+                let mutarr = mutarr.as_mut_any().downcast_mut::<MutablePrimitiveArray<i128>>().unwrap();
+                mutarr.push(datum.map(|ivalue| ivalue.try_as_i128().unwrap()));
+            }
+            PhysicalType::Boolean => {
+                let mutarr = mutarr.as_mut_any().downcast_mut::<MutableBooleanArray>().unwrap();
+                mutarr.push(datum.map(|bvalue| bvalue.try_as_bool().unwrap()));
+            }
             PhysicalType::Utf8 => {
                 let mutarr = mutarr.as_mut_any().downcast_mut::<MutableUtf8Array<i32>>().unwrap();
-                let a = datum.map(|ivalue| ivalue.to_string());
-                mutarr.push(a);
+                mutarr.push(datum.map(|ivalue| ivalue.try_as_str().unwrap()));
+            }
+            PhysicalType::Binary => {
+                let mutarr = mutarr.as_mut_any().downcast_mut::<MutableBinaryArray<i32>>().unwrap();
+                mutarr.push(datum.map(|bvalue| bvalue.try_as_bytes().unwrap()));
             }
 
             _ => todo!(),
         }
     }
 
-    fn convert_mutarr_to_immutable(mutarrays: Vec<Box<dyn MutableArray>>) -> Vec<Box<dyn Array>> {
+    pub fn convert_mutarr_to_immutable(mutarrays: Vec<Box<dyn MutableArray>>) -> Vec<Box<dyn Array>> {
         mutarrays
             .into_iter()
             .map(|mutarr| {
@@ -324,6 +349,19 @@ impl HashAggContext {
                         let arr: Box<dyn Array> = Box::new(arr);
                         arr
                     }
+                    DataType::Decimal(..) => {
+                        let mutarr = mutarr.as_any().downcast_ref::<MutablePrimitiveArray<i128>>().unwrap().clone();
+                        let iter = mutarr.iter().map(|i| i.cloned());
+                        let arr = PrimitiveArray::<i128>::from_trusted_len_iter(iter).to(data_type.clone());
+                        let arr: Box<dyn Array> = Box::new(arr);
+                        arr
+                    }
+                    DataType::Boolean => {
+                        let mutarr = mutarr.as_any().downcast_ref::<MutableBooleanArray>().unwrap().clone();
+                        let arr = BooleanArray::from_trusted_len_iter(mutarr.iter());
+                        let arr: Box<dyn Array> = Box::new(arr);
+                        arr
+                    }
                     DataType::Utf8 => {
                         let mutarr = mutarr.as_any().downcast_ref::<MutableUtf8Array<i32>>().unwrap().clone();
                         let iter = mutarr.iter().map(|s| s.to_owned());
@@ -331,6 +369,12 @@ impl HashAggContext {
                         let arr: Box<dyn Array> = Box::new(arr);
                         arr
                     }
+                    DataType::Binary => {
+                        let mutarr = mutarr.as_any().downcast_ref::<MutableBinaryArray<i32>>().unwrap().clone();
+                        let arr: BinaryArray<i32> = mutarr.into();
+                        let arr: Box<dyn Array> = Box::new(arr);
+                        arr
+                    }
                     _ => todo!(),
                 }
             })
@@ -366,9 +410,9 @@ impl HashAggContext {
                         let mutarr = &mut arrays[kx];
                         Self::append_mutable_array(mutarr, key.as_ref());
                     }
-                    for (ax, acc) in accumulators.iter().enumerate() {
+                    for (ax, (acc, (agg, _))) in accumulators.iter().zip(hash_agg.aggs.iter()).enumerate() {
                         let mutarr = &mut arrays[ax + keylen];
-                        Self::append_mutable_array(mutarr, acc.as_ref());
+                        Self::append_mutable_array(mutarr, Self::finalize(agg.agg_type, acc).as_ref());
                     }
                 }
 
@@ -376,8 +420,16 @@ impl HashAggContext {
                 let chunk = Chunk::new(arrays);
                 chunk_to_string(&chunk, "Aggregation internal output");
 
-                // Run predicates, if any
-                let chunk = POPKey::eval_predicates(props, chunk);
+                // Run HAVING predicates, if any
+                let mut chunk = chunk;
+                if let Some(having) = hash_agg.having.as_ref() {
+                    for pred in having.iter() {
+                        let bool_array = pred.eval(&chunk);
+                        let bool_array = bool_array.as_any().downcast_ref::<BooleanArray>().unwrap();
+                        let bool_array = BooleanArray::new(DataType::Boolean, true_values(bool_array), None);
+                        chunk = filter_chunk(&chunk, &bool_array).map_err(stringify)?;
+                    }
+                }
 
                 let projection_chunk = POPKey::eval_projection(props, &chunk);
                 debug!("hash_agg projection: \n{}", chunk_to_string(&projection_chunk, "hash_agg projection"));
@@ -403,49 +455,77 @@ fn compare_key(chunk: &ChunkBox, row_ix: usize, key: &DataRow) -> bool {
                     _ => false,
                 }
             }
-            typ => panic!("array_to_iter(), todo: {:?}", typ),
-        };
-        if !cmpstat {
-            // Comparison failed ... bail out
-            return false;
-        }
-    }
-    true
-}
-
-fn build_key(chunk: &ChunkBox, keylen: usize, ix: usize) -> Vec<Option<Datum>> {
-    let mut key = Vec::with_capacity(keylen);
-    for array in chunk.arrays().iter().take(keylen) {
-        match array.data_type() {
-            DataType::Date32 => {
-                let basearr = array.as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
-                let e = basearr.get(ix).map(|e| Date32(e));
-                key.push(e)
-            }
             DataType::Int64 => {
                 let basearr = array.as_any().downcast_ref::<PrimitiveArray<i64>>().unwrap();
-                let e = basearr.get(ix).map(|e| Int64(e));
-                key.push(e)
+                basearr.get(row_ix).map(Int64).as_ref() == keydatum
             }
-            DataType::Utf8 => {
-                let basearr = array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
-                let e = basearr.get(ix).map(|e| Utf8(e.to_string()));
-                key.push(e)
+            DataType::Date32 => {
+                let basearr = array.as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+                basearr.get(row_ix).map(Date32).as_ref() == keydatum
             }
             DataType::Boolean => {
                 let basearr = array.as_any().downcast_ref::<BooleanArray>().unwrap();
-                let e = basearr.get(ix).map(|e| Boolean(e));
-                key.push(e)
+                basearr.get(row_ix).map(Boolean).as_ref() == keydatum
             }
             DataType::Float64 => {
                 let basearr = array.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap();
-                let e = basearr.get(ix).map(|e| Float64(F64::from(e)));
-                key.push(e)
+                basearr.get(row_ix).map(|e| Float64(F64::from(e))).as_ref() == keydatum
+            }
+            DataType::Decimal(precision, scale) => {
+                let basearr = array.as_any().downcast_ref::<PrimitiveArray<i128>>().unwrap();
+                basearr.get(row_ix).map(|e| Decimal(e, *precision, *scale)).as_ref() == keydatum
             }
             typ => panic!("array_to_iter(), todo: {:?}", typ),
+        };
+        if !cmpstat {
+            // Comparison failed ... bail out
+            return false;
+        }
+    }
+    true
+}
+
+fn build_key(chunk: &ChunkBox, keylen: usize, ix: usize) -> Vec<Option<Datum>> {
+    chunk.arrays().iter().take(keylen).map(|array| array_to_datum(array, ix)).collect()
+}
+
+// array_to_datum: Extract a single value from an array, NULL becoming None
+pub fn array_to_datum(array: &Box<dyn Array>, ix: usize) -> Option<Datum> {
+    match array.data_type() {
+        DataType::Date32 => {
+            let basearr = array.as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+            basearr.get(ix).map(Date32)
+        }
+        DataType::Int32 => {
+            let basearr = array.as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+            basearr.get(ix).map(Int32)
+        }
+        DataType::Int64 => {
+            let basearr = array.as_any().downcast_ref::<PrimitiveArray<i64>>().unwrap();
+            basearr.get(ix).map(Int64)
+        }
+        DataType::Utf8 => {
+            let basearr = array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap();
+            basearr.get(ix).map(|e| Utf8(e.to_string()))
+        }
+        DataType::Boolean => {
+            let basearr = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+            basearr.get(ix).map(Boolean)
+        }
+        DataType::Float64 => {
+            let basearr = array.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap();
+            basearr.get(ix).map(|e| Float64(F64::from(e)))
+        }
+        DataType::Decimal(precision, scale) => {
+            let basearr = array.as_any().downcast_ref::<PrimitiveArray<i128>>().unwrap();
+            basearr.get(ix).map(|e| Decimal(e, *precision, *scale))
+        }
+        DataType::Binary => {
+            let basearr = array.as_any().downcast_ref::<BinaryArray<i32>>().unwrap();
+            basearr.get(ix).map(|e| Binary(e.to_vec()))
         }
+        typ => panic!("array_to_datum(), todo: {:?}", typ),
     }
-    key
 }
 
 impl POPContext for HashAggContext {
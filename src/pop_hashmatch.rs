@@ -4,8 +4,8 @@ use std::collections::HashMap;
 
 use ahash::RandomState;
 use arrow2::{
-    array::{MutableArray, MutableBooleanArray, MutablePrimitiveArray, MutableUtf8Array, Utf8Array},
-    compute::{filter::filter_chunk, take},
+    array::{new_null_array, MutableArray, MutableBooleanArray, MutablePrimitiveArray, MutableUtf8Array, Utf8Array},
+    compute::{concatenate::concatenate, filter::filter_chunk, take},
     datatypes::PhysicalType,
     types::PrimitiveType,
 };
@@ -15,16 +15,38 @@ use crate::{
     flow::Flow,
     graph::POPKey,
     includes::*,
+    pcode::{true_values, PCode},
     pop::{chunk_to_string, POPContext, POP},
     pop_hash::*,
+    qgm::JoinType,
     stage::Stage,
 };
 
 /***************************************************************************************************/
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HashMatch {
+    pub join_type: JoinType,
     pub keycols: Vec<Vec<ColId>>, // Maintain a list of key columns for each child. len() == 2 for joins
     pub children_data_types: Vec<Vec<DataType>>,
+    pub join_preds: Option<Vec<PCode>>, // Residual ON-clause predicates. Evaluated as part of matching, before NULL-extension.
+    pub null_aware: bool,               // Last key pair is a NOT IN comparison: a NULL on either side counts as a match
+}
+
+impl HashMatch {
+    // Unmatched probe (LHS) rows are NULL-extended
+    fn preserves_probe_rows(&self) -> bool {
+        matches!(self.join_type, JoinType::Left | JoinType::Full | JoinType::Scalar)
+    }
+
+    // Unmatched build (RHS) rows are NULL-extended
+    fn preserves_build_rows(&self) -> bool {
+        matches!(self.join_type, JoinType::Right | JoinType::Full)
+    }
+
+    // Semi/anti joins return each probe row at most once, and only its own columns
+    fn is_semi_or_anti(&self) -> bool {
+        matches!(self.join_type, JoinType::Semi | JoinType::Anti)
+    }
 }
 
 /***************************************************************************************************/
@@ -32,30 +54,27 @@ struct HashMatchSplit {
     id: SplitId,
     mut_arrays: Vec<Box<dyn MutableArray>>,
     arrays: Vec<Box<dyn Array>>,
+    nrows: usize,
     hash_map: HashMap<HashValue, Vec<BuildRowId>>, // Hash-of-keys -> {Row-Id}*
+    matched: Vec<bool>,                            // Build rows that found at least one match
+    null_key_rids: Vec<BuildRowId>,                // Null-aware joins: Build rows with a NULL NOT IN key. These are candidates for every probe row.
 }
 
 impl HashMatchSplit {
     fn new(id: SplitId) -> Self {
-        HashMatchSplit { id, mut_arrays: vec![], arrays: vec![], hash_map: HashMap::new() }
+        HashMatchSplit { id, mut_arrays: vec![], arrays: vec![], nrows: 0, hash_map: HashMap::new(), matched: vec![], null_key_rids: vec![] }
     }
 }
 
 macro_rules! copy_to_build_array {
-    ($from_array_typ:ty, $from_array:expr, $to_array_typ:ty, $to_array:expr, $split_ids:expr, $cur_split_id:expr, $hash_array:expr, $hash_map:expr) => {{
+    ($from_array_typ:ty, $from_array:expr, $to_array_typ:ty, $to_array:expr, $split_ids:expr, $cur_split_id:expr) => {{
         let primarr = $from_array.as_any().downcast_ref::<$from_array_typ>().unwrap();
         let mutarr = $to_array.as_mut_any().downcast_mut::<$to_array_typ>().unwrap();
-        let hash_map = $hash_map;
-        let mutarr_height = mutarr.len();
 
         $split_ids.iter().enumerate().filter(|(_, &split_id)| split_id == $cur_split_id).for_each(|(rid, _)| {
             let value = primarr.get(rid);
             mutarr.push(value);
             debug!("copy_to_build_array: inserted {:?} into split {}", value, $cur_split_id);
-
-            let hash_value = $hash_array[rid];
-            let rids = hash_map.entry(hash_value).or_insert(vec![]);
-            rids.push(rid + mutarr_height)
         });
     }};
 }
@@ -85,6 +104,7 @@ pub struct HashMatchContext {
     partition_id: PartitionId,
     state: RandomState,
     splits: Vec<HashMatchSplit>,
+    unmatched_build_rows_done: bool,
 }
 
 impl POPContext for HashMatchContext {
@@ -108,7 +128,7 @@ impl HashMatchContext {
     pub fn try_new(pop_key: POPKey, _: &HashMatch, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
         let state = RandomState::with_seeds(97, 31, 45, 21);
 
-        Ok(Box::new(HashMatchContext { pop_key, children, partition_id, state, splits: vec![] }))
+        Ok(Box::new(HashMatchContext { pop_key, children, partition_id, state, splits: vec![], unmatched_build_rows_done: false }))
     }
 
     fn next_join(&mut self, flow: &Flow, stage: &Stage, hash_match: &HashMatch) -> Result<Option<ChunkBox>, String> {
@@ -118,11 +138,26 @@ impl HashMatchContext {
         }
 
         // Probe
-        let child = &mut self.children[0];
-        while let Some(chunk) = child.next(flow, stage)? {
+        while let Some(chunk) = self.children[0].next(flow, stage)? {
             if !chunk.is_empty() {
-                let chunk = self.process_join_probe_input(flow, stage, hash_match, chunk)?;
-                debug!("HashMatchContext::next \n{}", chunk_to_string(&chunk, "HashMatchContext::next"));
+                let chunk = if hash_match.is_semi_or_anti() {
+                    self.process_semi_join_probe_input(stage, hash_match, chunk)?
+                } else {
+                    self.process_join_probe_input(flow, stage, hash_match, chunk)?
+                };
+                if !chunk.is_empty() {
+                    debug!("HashMatchContext::next \n{}", chunk_to_string(&chunk, "HashMatchContext::next"));
+                    return Ok(Some(chunk));
+                }
+            }
+        }
+
+        // Probe side is exhausted. Emit build rows that never found a match.
+        if hash_match.preserves_build_rows() && !self.unmatched_build_rows_done {
+            self.unmatched_build_rows_done = true;
+            let chunk = self.process_unmatched_build_rows(stage, hash_match)?;
+            if !chunk.is_empty() {
+                debug!("HashMatchContext::next \n{}", chunk_to_string(&chunk, "HashMatchContext::next unmatched build rows"));
                 return Ok(Some(chunk));
             }
         }
@@ -140,21 +175,27 @@ impl HashMatchContext {
             // Compute hash + split-# for each row in the chunk
             let keycols = &hash_match.keycols[1];
             let keys = eval_cols(keycols, &chunk);
-            let (hash_array, split_ids) = hash_chunk(&keys, &self.state);
+            let (hash_array, split_ids) = hash_chunk(&keys, chunk.len(), &self.state);
+            let null_keys = Self::null_aware_key_nulls(hash_match, &keys);
 
             for split in self.splits.iter_mut() {
-                Self::insert(hash_match, split, &chunk, &hash_array, &split_ids);
+                Self::insert(hash_match, split, &chunk, &hash_array, &split_ids, null_keys.as_deref());
             }
         }
 
         for split in self.splits.iter_mut() {
             Self::demut_build_arrays(split);
+            split.matched = vec![false; split.nrows];
+        }
+
+        if hash_match.join_type == JoinType::Scalar && self.splits.iter().map(|split| split.nrows).sum::<usize>() > 1 {
+            return Err("Scalar subquery returned more than one row.".to_string());
         }
 
         Ok(())
     }
 
-    fn insert(hash_match: &HashMatch, split: &mut HashMatchSplit, build_chunk: &ChunkBox, hash_array: &[u64], split_ids: &[SplitId]) {
+    fn insert(hash_match: &HashMatch, split: &mut HashMatchSplit, build_chunk: &ChunkBox, hash_array: &[u64], split_ids: &[SplitId], null_keys: Option<&[bool]>) {
         if split.mut_arrays.is_empty() {
             Self::alloc_build_arrays(hash_match, split, build_chunk);
         }
@@ -163,17 +204,37 @@ impl HashMatchContext {
         for (from_array, to_array, typ) in izip!(build_chunk.arrays(), split.mut_arrays.iter_mut(), &hash_match.children_data_types[1]) {
             match typ.to_physical_type() {
                 PhysicalType::Primitive(PrimitiveType::Int64) => {
-                    copy_to_build_array!(PrimitiveArray<i64>, from_array, MutablePrimitiveArray<i64>, to_array, split_ids, cur_split_id, hash_array, &mut split.hash_map)
-                }
-                PhysicalType::Utf8 => {
-                    copy_to_build_array!(Utf8Array<i32>, from_array, MutableUtf8Array<i32>, to_array, split_ids, cur_split_id, hash_array, &mut split.hash_map)
+                    copy_to_build_array!(PrimitiveArray<i64>, from_array, MutablePrimitiveArray<i64>, to_array, split_ids, cur_split_id)
                 }
-                PhysicalType::Boolean => {
-                    copy_to_build_array!(BooleanArray, from_array, MutableBooleanArray, to_array, split_ids, cur_split_id, hash_array, &mut split.hash_map)
+                PhysicalType::Primitive(PrimitiveType::Float64) => {
+                    copy_to_build_array!(PrimitiveArray<f64>, from_array, MutablePrimitiveArray<f64>, to_array, split_ids, cur_split_id)
                 }
+                PhysicalType::Utf8 => copy_to_build_array!(Utf8Array<i32>, from_array, MutableUtf8Array<i32>, to_array, split_ids, cur_split_id),
+                PhysicalType::Boolean => copy_to_build_array!(BooleanArray, from_array, MutableBooleanArray, to_array, split_ids, cur_split_id),
                 typ => panic!("insert(), todo: {:?}", typ),
             }
         }
+
+        // Hash each row once, irrespective of the number of columns
+        for (rid, _) in split_ids.iter().enumerate().filter(|(_, &split_id)| split_id == cur_split_id) {
+            if null_keys.map_or(false, |null_keys| null_keys[rid]) {
+                split.null_key_rids.push(split.nrows);
+            } else {
+                let rids = split.hash_map.entry(hash_array[rid]).or_insert(vec![]);
+                rids.push(split.nrows);
+            }
+            split.nrows += 1;
+        }
+    }
+
+    // null_aware_key_nulls: For null-aware joins, flag rows whose NOT IN key (always the last key) is NULL
+    fn null_aware_key_nulls(hash_match: &HashMatch, keys: &ChunkBox) -> Option<Vec<bool>> {
+        if hash_match.null_aware {
+            let array = keys.arrays().last().unwrap();
+            Some((0..keys.len()).map(|rid| array.is_null(rid)).collect())
+        } else {
+            None
+        }
     }
 
     #[allow(unused_variables)]
@@ -188,6 +249,7 @@ impl HashMatchContext {
             .map(|typ| {
                 let mut_array: Box<dyn MutableArray> = match typ.to_physical_type() {
                     PhysicalType::Primitive(PrimitiveType::Int64) => Box::new(MutablePrimitiveArray::<i64>::new()),
+                    PhysicalType::Primitive(PrimitiveType::Float64) => Box::new(MutablePrimitiveArray::<f64>::new()),
                     PhysicalType::Utf8 => Box::new(MutableUtf8Array::<i32>::new()),
                     PhysicalType::Boolean => Box::new(MutableBooleanArray::new()),
                     typ => panic!("alloc_build_arrays(), todo: {:?}", typ),
@@ -210,7 +272,7 @@ impl HashMatchContext {
 
         // Hash input keys
         let keys = eval_cols(keycols, &chunk);
-        let (hash_array, split_ids) = hash_chunk(&keys, &self.state);
+        let (hash_array, split_ids) = hash_chunk(&keys, chunk.len(), &self.state);
 
         debug!(
             "HashMatchContext {:?} partition = {}, hash = {:?}{}{}",
@@ -223,24 +285,164 @@ impl HashMatchContext {
 
         let rids = self.find_matches(hash_array, split_ids, false);
 
+        let mut chunks = vec![];
+        let mut probe_matched = vec![false; chunk.len()];
         if !rids.is_empty() {
             let probe_chunk = self.contruct_probe_output(&rids, &chunk)?;
             let build_chunk = self.contruct_build_output(hash_match, &rids)?;
 
-            let chunk = Self::contruct_joined_chunk(hash_match, build_chunk, probe_chunk)?;
+            let (chunk, filter) = Self::contruct_joined_chunk(hash_match, build_chunk, probe_chunk)?;
 
-            // Run predicates, if any
-            let chunk = POPKey::eval_predicates(props, chunk);
-            //debug!("After join preds: \n{}", chunk_to_string(&chunk, "After join preds"));
+            // Remember which rows on either side found a match
+            for (&(probe_rid, build_rid), is_match) in rids.iter().zip(filter.iter()) {
+                if is_match == Some(true) {
+                    probe_matched[probe_rid] = true;
+                    if let Some((split_id, build_rid)) = build_rid {
+                        self.splits[split_id].matched[build_rid] = true;
+                    }
+                }
+            }
+            chunks.push(filter_chunk(&chunk, &filter).map_err(stringify)?);
+        }
+
+        // NULL-extend probe rows without a match
+        if hash_match.preserves_probe_rows() {
+            let unmatched_rids: PrimitiveArray<u64> = probe_matched.iter().enumerate().filter(|(_, &matched)| !matched).map(|(rid, _)| Some(rid as u64)).collect();
+            if !unmatched_rids.is_empty() {
+                let mut arrays = Self::take_chunk(&chunk, unmatched_rids)?;
+                let nrows = arrays[0].len();
+                arrays.extend(hash_match.children_data_types[1].iter().map(|typ| new_null_array(typ.clone(), nrows)));
+                chunks.push(Chunk::new(arrays));
+            }
+        }
+
+        let chunk = match chunks.len() {
+            0 => return Ok(Chunk::new(vec![])),
+            1 => chunks.pop().unwrap(),
+            _ => Self::concat_chunks(&chunks)?,
+        };
 
-            let projection_chunk = POPKey::eval_projection(props, &chunk);
-            debug!("hash_join_projection: \n{}", chunk_to_string(&projection_chunk, "hash_join_projection"));
-            Ok(projection_chunk)
+        // Run predicates, if any
+        let chunk = POPKey::eval_predicates(props, chunk);
+        //debug!("After join preds: \n{}", chunk_to_string(&chunk, "After join preds"));
+
+        let projection_chunk = POPKey::eval_projection(props, &chunk);
+        debug!("hash_join_projection: \n{}", chunk_to_string(&projection_chunk, "hash_join_projection"));
+        Ok(projection_chunk)
+    }
+
+    // process_semi_join_probe_input: Semi joins keep probe rows with at least one match, anti joins keep those without any.
+    // Candidate build rows are checked in rounds of doubling width, and a probe row stops probing as soon as it finds its first match.
+    fn process_semi_join_probe_input(&mut self, stage: &Stage, hash_match: &HashMatch, chunk: ChunkBox) -> Result<ChunkBox, String> {
+        let props = stage.pop_graph.get_properties(self.pop_key);
+
+        // Hash input keys
+        let keys = eval_cols(&hash_match.keycols[0], &chunk);
+        let (hash_array, split_ids) = hash_chunk(&keys, chunk.len(), &self.state);
+        let null_keys = Self::null_aware_key_nulls(hash_match, &keys);
+
+        let is_null_key = |probe_rid: usize| null_keys.as_ref().map_or(false, |null_keys| null_keys[probe_rid]);
+
+        // Without other join conditions, a NULL NOT IN key matches as soon as there is any build row
+        let uncorrelated = hash_match.keycols[0].len() == 1 && hash_match.join_preds.is_none();
+        let has_build_rows = self.splits.iter().any(|split| split.nrows > 0);
+        let mut probe_matched = (0..chunk.len()).map(|probe_rid| uncorrelated && has_build_rows && is_null_key(probe_rid)).collect::<Vec<_>>();
+
+        let (mut offset, mut width) = (0, 1);
+        loop {
+            let splits = &self.splits;
+            let rids = (0..chunk.len())
+                .filter(|&probe_rid| !probe_matched[probe_rid])
+                .flat_map(|probe_rid| {
+                    let build_rids = Self::candidate_build_rids(splits, hash_array[probe_rid], split_ids[probe_rid], is_null_key(probe_rid));
+                    build_rids.skip(offset).take(width).map(move |build_rid| (probe_rid, Some(build_rid)))
+                })
+                .collect::<MatchRIDList>();
+            if rids.is_empty() {
+                break;
+            }
+
+            let probe_chunk = self.contruct_probe_output(&rids, &chunk)?;
+            let build_chunk = self.contruct_build_output(hash_match, &rids)?;
+            let (_, filter) = Self::contruct_joined_chunk(hash_match, build_chunk, probe_chunk)?;
+            for (&(probe_rid, _), is_match) in rids.iter().zip(filter.iter()) {
+                if is_match == Some(true) {
+                    probe_matched[probe_rid] = true;
+                }
+            }
+            offset += width;
+            width *= 2;
+        }
+
+        let keep_semi = hash_match.join_type == JoinType::Semi;
+        let filter = probe_matched.iter().map(|&matched| Some(matched == keep_semi)).collect::<BooleanArray>();
+        let chunk = filter_chunk(&chunk, &filter).map_err(stringify)?;
+        if chunk.is_empty() {
+            return Ok(chunk);
+        }
+
+        // Run predicates, if any
+        let chunk = POPKey::eval_predicates(props, chunk);
+
+        let projection_chunk = POPKey::eval_projection(props, &chunk);
+        debug!("semi_join_projection: \n{}", chunk_to_string(&projection_chunk, "semi_join_projection"));
+        Ok(projection_chunk)
+    }
+
+    // candidate_build_rids: Build rows a probe row may match. A NULL NOT IN key on the probe side could match any build row, so these
+    // are enumerated again in every round rather than collected upfront.
+    fn candidate_build_rids(
+        splits: &[HashMatchSplit], hash_value: HashValue, split_id: SplitId, is_null_key: bool,
+    ) -> Box<dyn Iterator<Item = (SplitId, BuildRowId)> + '_> {
+        if is_null_key {
+            Box::new(splits.iter().flat_map(|split| (0..split.nrows).map(move |build_rid| (split.id, build_rid))))
         } else {
-            Ok(Chunk::new(vec![]))
+            let split = &splits[split_id];
+            let matches = split.hash_map.get(&hash_value).into_iter().flatten();
+            Box::new(matches.chain(split.null_key_rids.iter()).map(move |&build_rid| (split.id, build_rid)))
         }
     }
 
+    fn process_unmatched_build_rows(&mut self, stage: &Stage, hash_match: &HashMatch) -> Result<ChunkBox, String> {
+        let props = stage.pop_graph.get_properties(self.pop_key);
+
+        // Collect unmatched rows from every split
+        let mut chunks = vec![];
+        for split in self.splits.iter().filter(|split| split.nrows > 0) {
+            let unmatched_rids: PrimitiveArray<u64> = split.matched.iter().enumerate().filter(|(_, &matched)| !matched).map(|(rid, _)| Some(rid as u64)).collect();
+            if !unmatched_rids.is_empty() {
+                let arrays = split.arrays.iter().map(|array| take::take(&**array, &unmatched_rids).map_err(stringify)).collect::<Result<Vec<_>, String>>()?;
+                chunks.push(Chunk::new(arrays));
+            }
+        }
+
+        let build_chunk = match chunks.len() {
+            0 => return Ok(Chunk::new(vec![])),
+            1 => chunks.pop().unwrap(),
+            _ => Self::concat_chunks(&chunks)?,
+        };
+
+        // NULL-extend them with probe columns
+        let nrows = build_chunk.len();
+        let mut arrays = hash_match.children_data_types[0].iter().map(|typ| new_null_array(typ.clone(), nrows)).collect::<Vec<_>>();
+        arrays.append(&mut build_chunk.into_arrays());
+        let chunk = Chunk::new(arrays);
+
+        let chunk = POPKey::eval_predicates(props, chunk);
+        Ok(POPKey::eval_projection(props, &chunk))
+    }
+
+    fn concat_chunks(chunks: &[ChunkBox]) -> Result<ChunkBox, String> {
+        let ncols = chunks[0].arrays().len();
+        let arrays = (0..ncols)
+            .map(|colid| {
+                let arrays = chunks.iter().map(|chunk| &*chunk.arrays()[colid]).collect::<Vec<_>>();
+                concatenate(&arrays).map_err(stringify)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Chunk::new(arrays))
+    }
+
     fn find_matches(&mut self, hash_array: Vec<u64>, split_ids: Vec<SplitId>, include_missing: bool) -> MatchRIDList {
         // Build rid-list based on hash-match: Probe-RowId -> SplitId + BuildRowId
         let mut rid_matches = vec![];
@@ -270,6 +472,7 @@ impl HashMatchContext {
             .enumerate()
             .map(|(colid, typ)| match typ.to_physical_type() {
                 PhysicalType::Primitive(PrimitiveType::Int64) => copy_from_build_array!(PrimitiveArray<i64>, self, rids, colid),
+                PhysicalType::Primitive(PrimitiveType::Float64) => copy_from_build_array!(PrimitiveArray<f64>, self, rids, colid),
                 PhysicalType::Utf8 => copy_from_build_array!(Utf8Array<i32>, self, rids, colid),
                 PhysicalType::Boolean => copy_from_build_array!(BooleanArray, self, rids, colid),
                 typ => panic!("contruct_build_output(), todo: {:?}", typ),
@@ -312,7 +515,8 @@ impl HashMatchContext {
         Ok(probe_chunk)
     }
 
-    fn contruct_joined_chunk(hash_match: &HashMatch, build_chunk: ChunkBox, probe_chunk: ChunkBox) -> Result<ChunkBox, String> {
+    // contruct_joined_chunk: Glue candidate probe/build rows together. Returns the joined chunk along with a filter of actual matches.
+    fn contruct_joined_chunk(hash_match: &HashMatch, build_chunk: ChunkBox, probe_chunk: ChunkBox) -> Result<(ChunkBox, BooleanArray), String> {
         // So far, we've only matched build/probe based on hash-values. Make sure the actual keys match.
         assert!(build_chunk.len() == probe_chunk.len());
 
@@ -325,20 +529,29 @@ impl HashMatchContext {
         let mut build_arrays = build_chunk.into_arrays();
         let mut probe_arrays = probe_chunk.into_arrays();
 
-        let build_keys = build_arrays.iter().enumerate().filter_map(|(ix, array)| if build_cols.contains(&ix) { Some(&**array) } else { None }).collect::<Vec<_>>();
-        let probe_keys = probe_arrays.iter().enumerate().filter_map(|(ix, array)| if probe_cols.contains(&ix) { Some(&**array) } else { None }).collect::<Vec<_>>();
-
-        // Compare key columns
+        // Compare key columns. The NOT IN key of a null-aware join isn't known to differ if either side is NULL.
         let mut filter = BooleanArray::from(vec![Some(true); chunk_height]);
-        for (&build_keycol, &probe_keycol) in build_keys.iter().zip(probe_keys.iter()) {
-            let filter2 = comparison::eq(build_keycol, probe_keycol);
+        for (ix, (&build_keycol, &probe_keycol)) in build_cols.iter().zip(probe_cols.iter()).enumerate() {
+            let mut filter2 = comparison::eq(&*build_arrays[build_keycol], &*probe_arrays[probe_keycol]);
+            if hash_match.null_aware && ix == build_cols.len() - 1 {
+                filter2 = filter2.iter().map(|is_match| Some(is_match.unwrap_or(true))).collect::<BooleanArray>();
+            }
             filter = boolean::and(&filter, &filter2);
         }
 
-        // Join and filter final chunk
         probe_arrays.append(&mut build_arrays);
         let chunk = Chunk::new(probe_arrays);
-        filter_chunk(&chunk, &filter).map_err(stringify)
+
+        // Residual ON-clause predicates are part of the match condition
+        if let Some(join_preds) = hash_match.join_preds.as_ref() {
+            for pred in join_preds.iter() {
+                let bool_array = pred.eval(&chunk);
+                let bool_array = bool_array.as_any().downcast_ref::<BooleanArray>().unwrap();
+                filter = boolean::and(&filter, bool_array);
+            }
+        }
+        let filter = BooleanArray::new(DataType::Boolean, true_values(&filter), None);
+        Ok((chunk, filter))
     }
 
     fn take_chunk(chunk: &ChunkBox, rids: PrimitiveArray<u64>) -> Result<Vec<Box<dyn Array>>, String> {
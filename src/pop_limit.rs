@@ -0,0 +1,73 @@
+// pop_limit
+
+use crate::{
+    flow::Flow,
+    graph::POPKey,
+    includes::*,
+    pop::{chunk_to_string, slice_chunk, POPContext, POP},
+    stage::Stage,
+};
+
+/***************************************************************************************************/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Limit {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Limit {
+    pub fn new(offset: usize, limit: usize) -> Self {
+        Limit { offset, limit }
+    }
+}
+
+/***************************************************************************************************/
+pub struct LimitContext {
+    pop_key: POPKey,
+    children: Vec<Box<dyn POPContext>>,
+    partition_id: PartitionId,
+    nskipped: usize,
+    nreturned: usize,
+}
+
+impl LimitContext {
+    pub fn try_new(pop_key: POPKey, _: &Limit, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+        Ok(Box::new(LimitContext { pop_key, children, partition_id, nskipped: 0, nreturned: 0 }))
+    }
+}
+
+impl POPContext for LimitContext {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next(&mut self, flow: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
+        let pop_key = self.pop_key;
+        let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
+
+        if let POP::Limit(limit) = pop {
+            // Stop pulling from our child as soon as the limit is reached
+            while self.nreturned < limit.limit {
+                let Some(chunk) = self.children[0].next(flow, stage)? else { break };
+
+                // Skip past OFFSET rows, then trim to the remaining limit
+                let nskip = (limit.offset - self.nskipped).min(chunk.len());
+                self.nskipped += nskip;
+                let len = (chunk.len() - nskip).min(limit.limit - self.nreturned);
+                if len == 0 {
+                    continue;
+                }
+                self.nreturned += len;
+
+                let chunk = slice_chunk(&chunk, nskip, len);
+                let chunk = POPKey::eval_projection(props, &chunk);
+                debug!("{}", chunk_to_string(&chunk, &format!("LimitContext::next partition = {}", self.partition_id)));
+                return Ok(Some(chunk));
+            }
+        } else {
+            panic!("ugh")
+        }
+        Ok(None)
+    }
+}
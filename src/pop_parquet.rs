@@ -8,7 +8,7 @@ use crate::{
     flow::Flow,
     graph::POPKey,
     includes::*,
-    pop::{chunk_to_string, POPContext},
+    pop::{chunk_to_string, slice_chunk, POPContext},
     stage::Stage,
 };
 
@@ -20,6 +20,7 @@ pub struct ParquetContext {
     pop_key: POPKey,
     input_projection_final_ordering: Vec<usize>,
     partition_id: PartitionId,
+    nrows_left: Option<usize>, // Rows still to be produced if the scan is limited
 
     #[derivative(Debug = "ignore")]
     file_reader: FileReader<File>,
@@ -60,7 +61,7 @@ impl ParquetContext {
 
         let input_projection_final_ordering: Vec<usize> = input_projection_pairs.iter().map(|e| e.0).collect();
 
-        let pqctx = ParquetContext { pop_key, input_projection_final_ordering, file_reader, partition_id };
+        let pqctx = ParquetContext { pop_key, input_projection_final_ordering, file_reader, partition_id, nrows_left: pq.limit };
         debug!("input_projection {:?}", pq.input_projection);
 
         debug!("{:?}", pqctx);
@@ -79,6 +80,11 @@ impl POPContext for ParquetContext {
         let pop_key = self.pop_key;
         let props = stage.pop_graph.get_properties(pop_key);
 
+        // Stop reading once a limited scan has produced enough rows
+        if self.nrows_left == Some(0) {
+            return Ok(None);
+        }
+
         let chunk = self.file_reader.next();
 
         if let Some(chunk) = chunk {
@@ -100,7 +106,12 @@ impl POPContext for ParquetContext {
             }
 
             // Compute predicates, if any
-            let chunk = POPKey::eval_predicates(props, chunk);
+            let mut chunk = POPKey::eval_predicates(props, chunk);
+
+            if let Some(nrows_left) = self.nrows_left.as_mut() {
+                chunk = slice_chunk(&chunk, 0, *nrows_left);
+                *nrows_left -= chunk.len();
+            }
 
             #[cfg(debug_assertions)]
             if !chunk.is_empty() {
@@ -128,11 +139,12 @@ pub struct Parquet {
     pub pathname: String,
     pub fields: Vec<Field>,
     pub input_projection: Vec<ColId>,
+    pub limit: Option<usize>,
 }
 
 impl Parquet {
-    pub fn new(pathname: String, fields: Vec<Field>, _npartitions: usize, input_projection: Vec<ColId>) -> Parquet {
-        Parquet { pathname, fields, input_projection }
+    pub fn new(pathname: String, fields: Vec<Field>, _npartitions: usize, input_projection: Vec<ColId>, limit: Option<usize>) -> Parquet {
+        Parquet { pathname, fields, input_projection, limit }
     }
 }
 
@@ -21,19 +21,24 @@ use crate::{
     stage::{Stage, StageLink},
 };
 
+const NULL_HASH: u64 = 0x9e37_79b9_7f4a_7c15;
+
 /***************************************************************************************************/
 pub struct RepartitionWriteContext {
     pop_key: POPKey,
     children: Vec<Box<dyn POPContext>>,
     partition_id: PartitionId,
+    iteration: usize,
     writers: Vec<Option<FileWriter<File>>>,
 }
 
 impl RepartitionWriteContext {
-    pub fn try_new(pop_key: POPKey, rpw: &RepartitionWrite, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+    pub fn try_new(
+        pop_key: POPKey, rpw: &RepartitionWrite, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId, iteration: usize,
+    ) -> Result<Box<dyn POPContext>, String> {
         let writers = (0..rpw.cpartitions).map(|_| None).collect();
 
-        Ok(Box::new(RepartitionWriteContext { pop_key, children, partition_id, writers }))
+        Ok(Box::new(RepartitionWriteContext { pop_key, children, partition_id, iteration, writers }))
     }
 
     fn eval_repart_keys(repart_code: &[PCode], input: &ChunkBox) -> ChunkBox {
@@ -42,10 +47,23 @@ impl RepartitionWriteContext {
     }
 
     fn hash_chunk(chunk: ChunkBox) -> Result<PrimitiveArray<u64>, String> {
-        // FIXME: We hash the first column only. Need to include all columns.
-        let last_ix = chunk.arrays().len() - 1;
-        let arr0 = &chunk.arrays()[last_ix];
-        hash(&**arr0).map_err(stringify)
+        // Combine the hashes of all key columns. NULLs hash alike regardless of what their slots hold.
+        let mut hashed = vec![0u64; chunk.len()];
+        for array in chunk.arrays() {
+            let array_hashes = if let DataType::Float64 = array.data_type() {
+                // arrow2 doesn't hash floats, so hash their bit patterns instead
+                let primarr = array.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap();
+                let bits = PrimitiveArray::<u64>::from_vec(primarr.values_iter().map(|v| v.to_bits()).collect());
+                hash(&bits).map_err(stringify)?
+            } else {
+                hash(&**array).map_err(stringify)?
+            };
+            for (row_ix, (hashval, newval)) in hashed.iter_mut().zip(array_hashes.values_iter()).enumerate() {
+                let newval = if array.is_null(row_ix) { NULL_HASH } else { *newval };
+                *hashval = hashval.wrapping_mul(31).wrapping_add(newval);
+            }
+        }
+        Ok(PrimitiveArray::from_vec(hashed))
     }
 
     fn compute_partitions(hashed: PrimitiveArray<u64>, npartitions: PartitionId) -> PrimitiveArray<u64> {
@@ -55,7 +73,7 @@ impl RepartitionWriteContext {
     fn get_writer(&mut self, flow_id: usize, rpw: &RepartitionWrite, cpartition: PartitionId) -> Result<&mut FileWriter<File>, String> {
         if self.writers[cpartition].is_none() {
             let dirname = get_partition_dir(flow_id, rpw.stage_link, cpartition);
-            let path = format!("{}/producer-{}.arrow", dirname, self.partition_id);
+            let path = get_producer_file(&dirname, self.partition_id, self.iteration);
             std::fs::create_dir_all(dirname).map_err(stringify)?;
 
             let file = File::create(path).map_err(stringify)?;
@@ -118,26 +136,40 @@ impl POPContext for RepartitionWriteContext {
             let repart_key_code = &rpw.repart_key;
 
             while let Some(chunk) = self.children[0].next(flow, stage)? {
+                let chunk = POPKey::eval_predicates(props, chunk);
                 if !chunk.is_empty() {
                     let chunk = POPKey::eval_projection(props, &chunk);
 
-                    // Compute partitioning keys
-                    let repart_keys = Self::eval_repart_keys(repart_key_code, &chunk);
-
-                    // Compute hash
-                    let repart_hash = Self::hash_chunk(repart_keys)?;
-
-                    // Compute partitions
-                    let part_array = Self::compute_partitions(repart_hash, rpw.cpartitions);
-                    /*
-                    debug!(
-                        "[{:?}] RepartitionWriteContext partition = {}::cpartitions: \n{:?}",
-                        self.pop_key, self.partition_id, part_array
-                    );
-                    */
-
-                    // Write partitions
-                    self.write_partitions(flow.id, rpw, chunk, part_array)?;
+                    if repart_key_code.is_empty() && rpw.broadcast {
+                        // Every consumer gets everything
+                        for cpartition in 0..rpw.cpartitions {
+                            let writer = self.get_writer(flow.id, rpw, cpartition)?;
+                            writer.write(&chunk, None).map_err(stringify)?;
+                        }
+                    } else if repart_key_code.is_empty() {
+                        // No partitioning keys: rows stay in their partition, or all go to the one consumer
+                        let cpartition = self.partition_id % rpw.cpartitions;
+                        let writer = self.get_writer(flow.id, rpw, cpartition)?;
+                        writer.write(&chunk, None).map_err(stringify)?;
+                    } else {
+                        // Compute partitioning keys
+                        let repart_keys = Self::eval_repart_keys(repart_key_code, &chunk);
+
+                        // Compute hash
+                        let repart_hash = Self::hash_chunk(repart_keys)?;
+
+                        // Compute partitions
+                        let part_array = Self::compute_partitions(repart_hash, rpw.cpartitions);
+                        /*
+                        debug!(
+                            "[{:?}] RepartitionWriteContext partition = {}::cpartitions: \n{:?}",
+                            self.pop_key, self.partition_id, part_array
+                        );
+                        */
+
+                        // Write partitions
+                        self.write_partitions(flow.id, rpw, chunk, part_array)?;
+                    }
                 }
             }
             self.finish_writers(rpw)?;
@@ -162,14 +194,44 @@ pub struct RepartitionWrite {
 
     #[getset(get = "pub")]
     stage_link: StageLink,
+
+    #[getset(get = "pub")]
+    broadcast: bool,
 }
 
 impl RepartitionWrite {
-    pub fn new(repart_key: Vec<PCode>, schema: Rc<Schema>, stage_link: StageLink, cpartitions: PartitionId) -> Self {
-        RepartitionWrite { repart_key, schema, stage_link, cpartitions }
+    pub fn new(repart_key: Vec<PCode>, schema: Rc<Schema>, stage_link: StageLink, cpartitions: PartitionId, broadcast: bool) -> Self {
+        RepartitionWrite { repart_key, schema, stage_link, cpartitions, broadcast }
+    }
+}
+
+// get_producer_file: Every iteration of a recursive stage adds its own files
+fn get_producer_file(dirname: &String, partition_id: PartitionId, iteration: usize) -> String {
+    if iteration == 0 {
+        format!("{}/producer-{}.arrow", dirname, partition_id)
+    } else {
+        format!("{}/producer-{}.iteration-{}.arrow", dirname, partition_id, iteration)
     }
 }
 
+fn get_file_iteration(path: &str) -> usize {
+    let iteration = path.strip_suffix(".arrow").and_then(|path| path.rsplit_once(".iteration-")).map(|(_, iteration)| iteration);
+    iteration.map_or(0, |iteration| iteration.parse().unwrap())
+}
+
+// has_iteration_output: Did an iteration of a recursive stage produce any rows?
+pub fn has_iteration_output(flow_id: usize, stage_link: StageLink, cpartitions: PartitionId, iteration: usize) -> Result<bool, String> {
+    for cpartition in 0..cpartitions {
+        let dirname = get_partition_dir(flow_id, stage_link, cpartition);
+        if let Ok(files) = list_files(&dirname) {
+            if files.iter().any(|path| get_file_iteration(path) == iteration) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
 /***************************************************************************************************/
 #[derive(Debug, Serialize, Deserialize, Getters)]
 pub struct RepartitionRead {
@@ -178,11 +240,14 @@ pub struct RepartitionRead {
 
     #[getset(get = "pub")]
     stage_link: StageLink,
+
+    #[getset(get = "pub")]
+    delta: bool, // Only read the rows that the previous iteration of a recursive stage produced
 }
 
 impl RepartitionRead {
-    pub fn new(schema: Rc<Schema>, stage_link: StageLink) -> Self {
-        RepartitionRead { schema, stage_link }
+    pub fn new(schema: Rc<Schema>, stage_link: StageLink, delta: bool) -> Self {
+        RepartitionRead { schema, stage_link, delta }
     }
 }
 
@@ -211,11 +276,11 @@ impl RepartitionReadCell {
 }
 
 impl RepartitionReadContext {
-    pub fn try_new(flow_id: usize, pop_key: POPKey, rpw: &RepartitionRead, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+    pub fn try_new(flow_id: usize, pop_key: POPKey, rpw: &RepartitionRead, partition_id: PartitionId, iteration: usize) -> Result<Box<dyn POPContext>, String> {
         // Enumerate directory
         let dirname = get_partition_dir(flow_id, rpw.stage_link, partition_id);
         let files = list_files(&dirname);
-        let files = if let Err(errstr) = files {
+        let mut files = if let Err(errstr) = files {
             if !errstr.contains("kind: NotFound") {
                 return Err(errstr);
             }
@@ -223,6 +288,9 @@ impl RepartitionReadContext {
         } else {
             files.unwrap()
         };
+        if rpw.delta {
+            files.retain(|path| iteration > 0 && get_file_iteration(path) == iteration - 1);
+        }
         debug!("[{:?}] RepartitionReadContext::new, partition = {}, files = {:?}", pop_key, partition_id, &files);
 
         let cell = RepartitionReadCell::new(files, |files| {
@@ -249,13 +317,18 @@ impl POPContext for RepartitionReadContext {
     fn next(&mut self, _: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
         let pop_key = self.pop_key;
         let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
 
         if let POP::RepartitionRead(_) = pop {
-            let chunk = self.cell.next();
-            if let Some(chunk) = chunk {
-                let headerstr = format!("RepartitionReadContext::next Stage = {}, {:?}, Partition = {}", stage.stage_id, pop_key, self.partition_id);
-                debug!("{}", chunk_to_string(&chunk, &headerstr));
-                return Ok(Some(chunk));
+            while let Some(chunk) = self.cell.next() {
+                // Only reads of recursive CTEs have predicates
+                let chunk = POPKey::eval_predicates(props, chunk);
+                if !chunk.is_empty() {
+                    let chunk = POPKey::eval_projection(props, &chunk);
+                    let headerstr = format!("RepartitionReadContext::next Stage = {}, {:?}, Partition = {}", stage.stage_id, pop_key, self.partition_id);
+                    debug!("{}", chunk_to_string(&chunk, &headerstr));
+                    return Ok(Some(chunk));
+                }
             }
         } else {
             panic!("ugh")
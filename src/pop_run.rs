@@ -2,7 +2,7 @@
 
 use arrow2::compute::filter::filter_chunk;
 
-use crate::{graph::POPKey, includes::*, pop::POPProps};
+use crate::{graph::POPKey, includes::*, pcode::true_values, pop::POPProps};
 
 impl POPKey {
     pub fn eval_predicates(props: &POPProps, input: ChunkBox) -> ChunkBox {
@@ -11,8 +11,9 @@ impl POPKey {
             for pred in preds.iter() {
                 let bool_chunk = pred.eval(&filtered_chunk);
                 let bool_array = bool_chunk.as_any().downcast_ref::<BooleanArray>().unwrap();
+                let bool_array = BooleanArray::new(DataType::Boolean, true_values(bool_array), None);
 
-                filtered_chunk = filter_chunk(&filtered_chunk, bool_array).unwrap();
+                filtered_chunk = filter_chunk(&filtered_chunk, &bool_array).unwrap();
             }
         }
         filtered_chunk
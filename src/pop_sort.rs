@@ -0,0 +1,418 @@
+// pop_sort
+
+use std::{collections::BinaryHeap, fs::File};
+
+use arrow2::{
+    array::growable::make_growable,
+    compute::{
+        aggregate::estimated_bytes_size,
+        concatenate::concatenate,
+        sort::{
+            lexsort_to_indices,
+            row::{RowConverter, Rows, SortField},
+            SortColumn, SortOptions,
+        },
+        take::take,
+    },
+    io::ipc::{
+        read::{read_file_metadata, FileReader},
+        write::{FileWriter, WriteOptions},
+    },
+};
+
+use crate::{
+    flow::Flow,
+    graph::POPKey,
+    includes::*,
+    pcode::PCode,
+    pop::{chunk_to_string, slice_chunk, POPContext, POP},
+    qgm::Ordering,
+    stage::{Stage, StageLink},
+};
+
+pub const SORT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+const TOP_N_MAX_CHUNKS: usize = 16; // Top-N sorts compact their retained input chunks beyond this
+
+/***************************************************************************************************/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sort {
+    pub sort_keys: Vec<(PCode, Ordering)>,
+    pub merge_link: Option<StageLink>, // If set, the input is a set of sorted runs written by the producers of this link
+    pub memory_budget: usize,          // Input buffered beyond this many bytes is spilled to disk as a sorted run
+    pub limit: Option<usize>,          // If set, only the leading `limit` rows are produced
+}
+
+impl Sort {
+    pub fn new(sort_keys: Vec<(PCode, Ordering)>, merge_link: Option<StageLink>, memory_budget: usize, limit: Option<usize>) -> Self {
+        Sort { sort_keys, merge_link, memory_budget, limit }
+    }
+
+    fn sort_options(ordering: Ordering) -> SortOptions {
+        // NULLs compare high: last in ascending order, first in descending order
+        let descending = ordering == Ordering::Desc;
+        SortOptions { descending, nulls_first: descending }
+    }
+
+    fn eval_sort_keys(&self, chunk: &ChunkBox) -> Vec<Box<dyn Array>> {
+        self.sort_keys.iter().map(|(pcode, _)| pcode.eval(chunk)).collect()
+    }
+
+    // convert_sort_keys: Encode sort keys in row format. Encoded rows compare bytewise in sort order.
+    fn convert_sort_keys(&self, chunk: &ChunkBox, converter: &mut Option<RowConverter>) -> Result<Rows, String> {
+        let keys = self.eval_sort_keys(chunk);
+        let converter = converter.get_or_insert_with(|| {
+            let fields = keys.iter().zip(self.sort_keys.iter()).map(|(key, (_, ordering))| SortField::new_with_options(key.data_type().clone(), Sort::sort_options(*ordering)));
+            RowConverter::new(fields.collect())
+        });
+        converter.convert_columns(&keys).map_err(stringify)
+    }
+}
+
+/***************************************************************************************************/
+struct SortedRun {
+    reader: FileReader<File>,
+    chunk: Option<ChunkBox>,
+    rows: Option<Rows>, // Sort keys of `chunk` in row format
+    cursor: usize,
+}
+
+impl SortedRun {
+    fn try_new(path: &str) -> Result<Self, String> {
+        let mut file = File::open(path).map_err(|err| stringify1(err, path))?;
+        let metadata = read_file_metadata(&mut file).map_err(stringify)?;
+        let reader = FileReader::new(file, metadata, None, None);
+        Ok(SortedRun { reader, chunk: None, rows: None, cursor: 0 })
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.chunk.as_ref().map_or(true, |chunk| self.cursor >= chunk.len())
+    }
+
+    fn load_next_chunk(&mut self, sort: &Sort, converter: &mut Option<RowConverter>) -> Result<(), String> {
+        self.chunk = None;
+        self.rows = None;
+        self.cursor = 0;
+
+        for chunk in self.reader.by_ref() {
+            let chunk = chunk.map_err(stringify)?;
+            if !chunk.is_empty() {
+                self.rows = Some(sort.convert_sort_keys(&chunk, converter)?);
+                self.chunk = Some(chunk);
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/***************************************************************************************************/
+pub struct SortContext {
+    pop_key: POPKey,
+    children: Vec<Box<dyn POPContext>>,
+    partition_id: PartitionId,
+    is_initialized: bool,
+
+    // In-memory sort
+    buffer: Vec<ChunkBox>,
+    buffer_size: usize,
+    sorted_chunk: Option<ChunkBox>,
+    offset: usize,
+    nreturned: usize,
+
+    // Top-N sort: (sort key in row format, buffer index, row index). The largest retained key sits on top.
+    heap: BinaryHeap<(Vec<u8>, usize, usize)>,
+
+    // External sort
+    run_paths: Vec<String>,
+    runs: Vec<SortedRun>,
+    converter: Option<RowConverter>,
+}
+
+impl SortContext {
+    pub fn try_new(pop_key: POPKey, _: &Sort, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+        Ok(Box::new(SortContext {
+            pop_key,
+            children,
+            partition_id,
+            is_initialized: false,
+            buffer: vec![],
+            buffer_size: 0,
+            sorted_chunk: None,
+            offset: 0,
+            nreturned: 0,
+            heap: BinaryHeap::new(),
+            run_paths: vec![],
+            runs: vec![],
+            converter: None,
+        }))
+    }
+
+    fn consume_input(&mut self, flow: &Flow, stage: &Stage, sort: &Sort) -> Result<(), String> {
+        if let Some(merge_link) = sort.merge_link {
+            // Every producer has written a sorted run for us. Merge them.
+            let dirname = get_partition_dir(flow.id, merge_link, self.partition_id);
+            let run_paths = match list_files(&dirname) {
+                Ok(run_paths) => run_paths,
+                Err(errstr) if errstr.contains("kind: NotFound") => vec![],
+                Err(errstr) => return Err(errstr),
+            };
+            return self.open_runs(sort, &run_paths);
+        }
+
+        if let Some(limit) = sort.limit {
+            return self.consume_top_n(flow, stage, sort, limit);
+        }
+
+        while let Some(chunk) = self.children[0].next(flow, stage)? {
+            if !chunk.is_empty() {
+                self.buffer_size += chunk.arrays().iter().map(|array| estimated_bytes_size(&**array)).sum::<usize>();
+                self.buffer.push(chunk);
+                if self.buffer_size > sort.memory_budget {
+                    self.spill(flow, stage, sort)?;
+                }
+            }
+        }
+
+        if self.run_paths.is_empty() {
+            // Everything fit in memory
+            self.sorted_chunk = self.sort_buffer(sort)?;
+            Ok(())
+        } else {
+            self.spill(flow, stage, sort)?;
+            let run_paths = std::mem::take(&mut self.run_paths);
+            self.open_runs(sort, &run_paths)
+        }
+    }
+
+    // consume_top_n: Retain only the `limit` smallest rows seen so far in a bounded heap
+    fn consume_top_n(&mut self, flow: &Flow, stage: &Stage, sort: &Sort, limit: usize) -> Result<(), String> {
+        while let Some(chunk) = self.children[0].next(flow, stage)? {
+            if chunk.is_empty() || limit == 0 {
+                continue;
+            }
+
+            let rows = sort.convert_sort_keys(&chunk, &mut self.converter)?;
+            let buffer_ix = self.buffer.len();
+            let mut is_retained = false;
+            for (row_ix, row) in rows.iter().enumerate() {
+                if self.heap.len() < limit {
+                    self.heap.push((row.as_ref().to_vec(), buffer_ix, row_ix));
+                } else if row.as_ref() < self.heap.peek().unwrap().0.as_slice() {
+                    self.heap.pop();
+                    self.heap.push((row.as_ref().to_vec(), buffer_ix, row_ix));
+                } else {
+                    continue;
+                }
+                is_retained = true;
+            }
+
+            if is_retained {
+                self.buffer.push(chunk);
+                if self.buffer.len() > TOP_N_MAX_CHUNKS {
+                    // Gather retained rows into a single chunk so that evicted rows can be freed
+                    let entries = std::mem::take(&mut self.heap).into_sorted_vec();
+                    let chunk = Self::gather_rows(&self.buffer, entries.iter().map(|&(_, buffer_ix, row_ix)| (buffer_ix, row_ix)));
+                    self.buffer = vec![chunk];
+                    self.heap = entries.into_iter().enumerate().map(|(row_ix, (key, ..))| (key, 0, row_ix)).collect();
+                }
+            }
+        }
+
+        let entries = std::mem::take(&mut self.heap).into_sorted_vec();
+        if !entries.is_empty() {
+            self.sorted_chunk = Some(Self::gather_rows(&self.buffer, entries.iter().map(|&(_, buffer_ix, row_ix)| (buffer_ix, row_ix))));
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // gather_rows: Build a chunk out of (chunk index, row index) pairs
+    fn gather_rows(chunks: &[ChunkBox], rows: impl Iterator<Item = (usize, usize)> + Clone) -> ChunkBox {
+        let ncols = chunks[0].arrays().len();
+        let arrays = (0..ncols)
+            .map(|colid| {
+                let arrays = chunks.iter().map(|chunk| &*chunk.arrays()[colid]).collect::<Vec<_>>();
+                let mut growable = make_growable(&arrays, true, 0);
+                for (chunk_ix, row_ix) in rows.clone() {
+                    growable.extend(chunk_ix, row_ix, 1);
+                }
+                growable.as_box()
+            })
+            .collect::<Vec<_>>();
+        Chunk::new(arrays)
+    }
+
+    fn sort_buffer(&mut self, sort: &Sort) -> Result<Option<ChunkBox>, String> {
+        let chunks = std::mem::take(&mut self.buffer);
+        self.buffer_size = 0;
+
+        let chunk = match chunks.len() {
+            0 => return Ok(None),
+            1 => chunks.into_iter().next().unwrap(),
+            _ => {
+                let ncols = chunks[0].arrays().len();
+                let arrays = (0..ncols)
+                    .map(|colid| {
+                        let arrays = chunks.iter().map(|chunk| &*chunk.arrays()[colid]).collect::<Vec<_>>();
+                        concatenate(&arrays).map_err(stringify)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Chunk::new(arrays)
+            }
+        };
+
+        let keys = sort.eval_sort_keys(&chunk);
+        let sort_columns = keys
+            .iter()
+            .zip(sort.sort_keys.iter())
+            .map(|(key, (_, ordering))| SortColumn { values: &**key, options: Some(Sort::sort_options(*ordering)) })
+            .collect::<Vec<_>>();
+        let indices = lexsort_to_indices::<u64>(&sort_columns, None).map_err(stringify)?;
+
+        let arrays = chunk.arrays().iter().map(|array| take(&**array, &indices).map_err(stringify)).collect::<Result<Vec<_>, String>>()?;
+        Ok(Some(Chunk::new(arrays)))
+    }
+
+    fn spill(&mut self, flow: &Flow, stage: &Stage, sort: &Sort) -> Result<(), String> {
+        if let Some(chunk) = self.sort_buffer(sort)? {
+            let dirname = get_sort_dir(flow.id, stage.stage_id, self.pop_key, self.partition_id);
+            std::fs::create_dir_all(&dirname).map_err(stringify)?;
+            let path = format!("{}/run-{}.arrow", dirname, self.run_paths.len());
+
+            debug!("[{:?}] SortContext partition = {}, spilling {} rows to {}", self.pop_key, self.partition_id, chunk.len(), &path);
+
+            let fields = chunk.arrays().iter().enumerate().map(|(colid, array)| Field::new(format!("col_{}", colid), array.data_type().clone(), true)).collect::<Vec<_>>();
+            let schema = Schema::from(fields);
+
+            let file = File::create(&path).map_err(stringify)?;
+            let mut writer = FileWriter::new(file, schema, None, WriteOptions { compression: None });
+            writer.start().map_err(stringify)?;
+            for offset in (0..chunk.len()).step_by(CHUNK_SIZE) {
+                let slice = slice_chunk(&chunk, offset, CHUNK_SIZE);
+                writer.write(&slice, None).map_err(stringify)?;
+            }
+            writer.finish().map_err(stringify)?;
+
+            self.run_paths.push(path);
+        }
+        Ok(())
+    }
+
+    fn open_runs(&mut self, sort: &Sort, run_paths: &[String]) -> Result<(), String> {
+        for path in run_paths.iter() {
+            let mut run = SortedRun::try_new(path)?;
+            run.load_next_chunk(sort, &mut self.converter)?;
+            self.runs.push(run);
+        }
+        Ok(())
+    }
+
+    fn next_in_memory(&mut self) -> Option<ChunkBox> {
+        let sorted_chunk = self.sorted_chunk.as_ref()?;
+        if self.offset < sorted_chunk.len() {
+            let chunk = slice_chunk(sorted_chunk, self.offset, CHUNK_SIZE);
+            self.offset += chunk.len();
+            Some(chunk)
+        } else {
+            None
+        }
+    }
+
+    fn next_merged(&mut self, sort: &Sort) -> Result<Option<ChunkBox>, String> {
+        // Pick rows in sort order: (run, row-id)*
+        let mut picks: Vec<(usize, usize)> = vec![];
+        while picks.len() < CHUNK_SIZE {
+            let mut min_ix: Option<usize> = None;
+            for (ix, run) in self.runs.iter().enumerate() {
+                if let Some(rows) = run.rows.as_ref() {
+                    let is_smaller = min_ix.map_or(true, |min_ix| {
+                        let min_run = &self.runs[min_ix];
+                        rows.row(run.cursor) < min_run.rows.as_ref().unwrap().row(min_run.cursor)
+                    });
+                    if is_smaller {
+                        min_ix = Some(ix);
+                    }
+                }
+            }
+
+            let Some(min_ix) = min_ix else { break };
+            let run = &mut self.runs[min_ix];
+            picks.push((min_ix, run.cursor));
+            run.cursor += 1;
+
+            // Flush output before this run moves on to its next chunk
+            if run.is_exhausted() {
+                break;
+            }
+        }
+
+        if picks.is_empty() {
+            return Ok(None);
+        }
+
+        // Gather picked rows across runs
+        let live_runs = self.runs.iter().enumerate().filter(|(_, run)| run.chunk.is_some()).map(|(ix, _)| ix).collect::<Vec<_>>();
+        let mut growable_ix = vec![0; self.runs.len()];
+        for (ix, &run_ix) in live_runs.iter().enumerate() {
+            growable_ix[run_ix] = ix;
+        }
+
+        let ncols = self.runs[picks[0].0].chunk.as_ref().unwrap().arrays().len();
+        let arrays = (0..ncols)
+            .map(|colid| {
+                let arrays = live_runs.iter().map(|&run_ix| &*self.runs[run_ix].chunk.as_ref().unwrap().arrays()[colid]).collect::<Vec<_>>();
+                let mut growable = make_growable(&arrays, true, picks.len());
+                for &(run_ix, row_ix) in picks.iter() {
+                    growable.extend(growable_ix[run_ix], row_ix, 1);
+                }
+                growable.as_box()
+            })
+            .collect::<Vec<_>>();
+
+        // Refill exhausted runs
+        for run in self.runs.iter_mut() {
+            if run.chunk.is_some() && run.is_exhausted() {
+                run.load_next_chunk(sort, &mut self.converter)?;
+            }
+        }
+
+        Ok(Some(Chunk::new(arrays)))
+    }
+}
+
+impl POPContext for SortContext {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next(&mut self, flow: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
+        let pop_key = self.pop_key;
+        let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
+
+        if let POP::Sort(sort) = pop {
+            if !self.is_initialized {
+                self.consume_input(flow, stage, sort)?;
+                self.is_initialized = true;
+            }
+
+            let chunk = if self.runs.is_empty() { self.next_in_memory() } else { self.next_merged(sort)? };
+
+            // Sorted runs may hold more rows than a limited sort produces
+            let chunk = match (chunk, sort.limit) {
+                (Some(chunk), Some(limit)) if self.nreturned < limit => Some(slice_chunk(&chunk, 0, limit - self.nreturned)),
+                (_, Some(_)) => None,
+                (chunk, None) => chunk,
+            };
+            if let Some(chunk) = chunk {
+                self.nreturned += chunk.len();
+                let chunk = POPKey::eval_projection(props, &chunk);
+                debug!("{}", chunk_to_string(&chunk, &format!("SortContext::next partition = {}", self.partition_id)));
+                return Ok(Some(chunk));
+            }
+        } else {
+            panic!("ugh")
+        }
+        Ok(None)
+    }
+}
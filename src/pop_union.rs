@@ -0,0 +1,70 @@
+// pop_union
+
+use crate::{
+    flow::Flow,
+    graph::POPKey,
+    includes::*,
+    pop::{chunk_to_string, POPContext, POP},
+    stage::Stage,
+};
+
+/***************************************************************************************************/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnionAll {
+    recursive: bool, // Anchor query block on the first iteration, recursive query block on the following ones
+}
+
+impl UnionAll {
+    pub fn new(recursive: bool) -> Self {
+        UnionAll { recursive }
+    }
+}
+
+/***************************************************************************************************/
+pub struct UnionAllContext {
+    pop_key: POPKey,
+    children: Vec<Box<dyn POPContext>>,
+    partition_id: PartitionId,
+    child_ix: usize,
+    end_ix: usize,
+}
+
+impl UnionAllContext {
+    pub fn try_new(
+        pop_key: POPKey, union_all: &UnionAll, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId, iteration: usize,
+    ) -> Result<Box<dyn POPContext>, String> {
+        let (child_ix, end_ix) = match (union_all.recursive, iteration) {
+            (false, _) => (0, children.len()),
+            (true, 0) => (0, 1),
+            (true, _) => (1, 2),
+        };
+        Ok(Box::new(UnionAllContext { pop_key, children, partition_id, child_ix, end_ix }))
+    }
+}
+
+impl POPContext for UnionAllContext {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next(&mut self, flow: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
+        let pop_key = self.pop_key;
+        let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
+
+        if let POP::UnionAll(_) = pop {
+            // Drain children one after another. Their outputs have identical layouts.
+            while self.child_ix < self.end_ix {
+                if let Some(chunk) = self.children[self.child_ix].next(flow, stage)? {
+                    let chunk = POPKey::eval_projection(props, &chunk);
+                    debug!("{}", chunk_to_string(&chunk, &format!("UnionAllContext::next partition = {}", self.partition_id)));
+                    return Ok(Some(chunk));
+                }
+                self.child_ix += 1;
+            }
+        } else {
+            panic!("ugh")
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,303 @@
+// pop_window
+
+use arrow2::compute::concatenate::concatenate;
+
+use crate::{
+    datum::F64,
+    expr::{AggType, FrameBound, FrameUnits, WindowFrame, WindowFuncType},
+    flow::Flow,
+    graph::POPKey,
+    includes::*,
+    pcode::PCode,
+    pop::{chunk_to_string, slice_chunk, POPContext, POP},
+    pop_hashagg::{array_to_datum, Accumulator, HashAggContext},
+    qgm::Ordering,
+    stage::Stage,
+};
+
+type Row = Vec<Option<Datum>>;
+
+/***************************************************************************************************/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WindowCol {
+    pub func_type: WindowFuncType,
+    pub frame: WindowFrame,
+    pub args: Vec<PCode>,
+    pub data_type: DataType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Window {
+    pub partition_keys: Vec<PCode>,
+    pub order_keys: Vec<(PCode, Ordering)>,
+    pub window_cols: Vec<WindowCol>,
+}
+
+// Peers: Rows of one window partition, split into groups of peers, i.e. rows with the same ORDER BY keys
+struct Peers {
+    start: usize,
+    end: usize,
+    non_nulls: (usize, usize), // Rows whose RANGE key isn't NULL. NULL keys sort together at one end of the partition.
+    peer_starts: Vec<usize>,   // For each row of the partition
+    peer_ends: Vec<usize>,
+}
+
+impl Window {
+    pub fn new(partition_keys: Vec<PCode>, order_keys: Vec<(PCode, Ordering)>, window_cols: Vec<WindowCol>) -> Self {
+        Window { partition_keys, order_keys, window_cols }
+    }
+
+    fn eval_rows<'a>(pcodes: impl Iterator<Item = &'a PCode>, chunk: &ChunkBox) -> Vec<Row> {
+        let arrays = pcodes.map(|pcode| pcode.eval(chunk)).collect::<Vec<_>>();
+        (0..chunk.len()).map(|ix| arrays.iter().map(|array| array_to_datum(array, ix)).collect()).collect()
+    }
+
+    // eval_window_cols: Compute every window function over `chunk`, whose rows are sorted on the partition and order keys
+    fn eval_window_cols(&self, chunk: &ChunkBox) -> Vec<Box<dyn Array>> {
+        let len = chunk.len();
+        let partition_keys = Self::eval_rows(self.partition_keys.iter(), chunk);
+        let order_keys = Self::eval_rows(self.order_keys.iter().map(|(pcode, _)| pcode), chunk);
+
+        // RANGE offsets apply to the value of the single ORDER BY key
+        let range_values = order_keys
+            .iter()
+            .map(|keys| keys.first().cloned().flatten().map(|datum| datum.try_as_i32().map_or_else(|| datum.to_f64().unwrap(), |value| value as f64)))
+            .collect::<Vec<_>>();
+        let direction = if self.order_keys.first().map(|(_, ordering)| *ordering) == Some(Ordering::Desc) { -1.0 } else { 1.0 };
+
+        let mut partitions = vec![];
+        let mut start = 0;
+        while start < len {
+            let end = (start + 1..len).find(|&ix| partition_keys[ix] != partition_keys[start]).unwrap_or(len);
+            let mut peers = Peers { start, end, non_nulls: (start, end), peer_starts: vec![], peer_ends: vec![] };
+            let mut peer_start = start;
+            while peer_start < end {
+                let peer_end = (peer_start + 1..end).find(|&ix| order_keys[ix] != order_keys[peer_start]).unwrap_or(end);
+                peers.peer_starts.extend(std::iter::repeat(peer_start).take(peer_end - peer_start));
+                peers.peer_ends.extend(std::iter::repeat(peer_end).take(peer_end - peer_start));
+                peer_start = peer_end;
+            }
+            let mut non_nulls = (start..end).filter(|&ix| range_values[ix].is_some());
+            if let Some(nn_start) = non_nulls.next() {
+                peers.non_nulls = (nn_start, non_nulls.last().map_or(nn_start + 1, |ix| ix + 1));
+            }
+            partitions.push(peers);
+            start = end;
+        }
+
+        self.window_cols
+            .iter()
+            .map(|window_col| {
+                let args = Self::eval_rows(window_col.args.iter(), chunk);
+                let mut values = vec![];
+                for peers in partitions.iter() {
+                    Self::eval_window_col(window_col, &args, peers, &range_values, direction, &mut values);
+                }
+                let mut mutarr = HashAggContext::init_mutable_array(&window_col.data_type, len);
+                values.iter().for_each(|value| HashAggContext::append_mutable_array(&mut mutarr, value.as_ref()));
+                HashAggContext::convert_mutarr_to_immutable(vec![mutarr]).pop().unwrap()
+            })
+            .collect()
+    }
+
+    fn eval_window_col(window_col: &WindowCol, args: &[Row], peers: &Peers, range_values: &[Option<f64>], direction: f64, values: &mut Vec<Option<Datum>>) {
+        let arg = |ix: usize| args[ix][0].clone();
+
+        // LAG/LEAD fall back to their default (or NULL) past either end of the partition
+        let offset_value = |ix: usize, other_ix: Option<usize>| match other_ix {
+            Some(other_ix) if other_ix >= peers.start && other_ix < peers.end => arg(other_ix),
+            _ => args[ix].get(1).cloned().flatten(),
+        };
+        let mut dense_rank = 0;
+        let mut acc_frame: Option<(usize, usize, Accumulator)> = None;
+
+        for ix in peers.start..peers.end {
+            let (peer_start, peer_end) = (peers.peer_starts[ix - peers.start], peers.peer_ends[ix - peers.start]);
+            if ix == peer_start {
+                dense_rank += 1;
+            }
+            let value = match window_col.func_type {
+                WindowFuncType::ROW_NUMBER => Some(Int64((ix - peers.start + 1) as i64)),
+                WindowFuncType::RANK => Some(Int64((peer_start - peers.start + 1) as i64)),
+                WindowFuncType::DENSE_RANK => Some(Int64(dense_rank)),
+                WindowFuncType::LAG(offset) => offset_value(ix, ix.checked_sub(offset)),
+                WindowFuncType::LEAD(offset) => offset_value(ix, Some(ix + offset)),
+                WindowFuncType::FIRST_VALUE => {
+                    let (lo, hi) = Self::frame_bounds(&window_col.frame, ix, peers, peer_start, peer_end, range_values, direction);
+                    if lo < hi {
+                        arg(lo)
+                    } else {
+                        None
+                    }
+                }
+                WindowFuncType::AGG(agg_type) => {
+                    let (lo, hi) = Self::frame_bounds(&window_col.frame, ix, peers, peer_start, peer_end, range_values, direction);
+                    let hi = hi.max(lo);
+
+                    // Frames that keep their start only grow, so the previous row's accumulator gets extended instead of being rebuilt
+                    let (acc_lo, acc_hi, mut acc) = match acc_frame.take() {
+                        Some((acc_lo, acc_hi, acc)) if acc_lo == lo && acc_hi <= hi => (acc_lo, acc_hi, acc),
+                        _ => (lo, lo, Self::init_accumulator(agg_type)),
+                    };
+                    (acc_hi..hi).for_each(|frame_ix| HashAggContext::accumulate(agg_type, &mut acc, arg(frame_ix)));
+                    let value = Self::finalize(agg_type, &acc);
+                    acc_frame = Some((acc_lo, hi, acc));
+                    value
+                }
+            };
+            values.push(value);
+        }
+    }
+
+    // frame_bounds: Rows [lo, hi) of the frame of row `ix`. The frame is empty if hi <= lo.
+    fn frame_bounds(
+        frame: &WindowFrame, ix: usize, peers: &Peers, peer_start: usize, peer_end: usize, range_values: &[Option<f64>], direction: f64,
+    ) -> (usize, usize) {
+        let (start, end) = (peers.start, peers.end);
+        match frame.units {
+            FrameUnits::ROWS => {
+                let lo = match frame.start {
+                    FrameBound::UnboundedPreceding => start,
+                    FrameBound::Preceding(n) => ix.saturating_sub(n).max(start),
+                    FrameBound::CurrentRow => ix,
+                    FrameBound::Following(n) => (ix + n).min(end),
+                    FrameBound::UnboundedFollowing => end,
+                };
+                let hi = match frame.end {
+                    FrameBound::UnboundedPreceding => start,
+                    FrameBound::Preceding(n) => (ix + 1).saturating_sub(n).max(start),
+                    FrameBound::CurrentRow => ix + 1,
+                    FrameBound::Following(n) => (ix + n + 1).min(end),
+                    FrameBound::UnboundedFollowing => end,
+                };
+                (lo, hi)
+            }
+            FrameUnits::RANGE => {
+                // Offsets span the rows whose key lies within that distance of the current row's key. NULL keys are only ever
+                // peers of each other.
+                let (nn_start, nn_end) = peers.non_nulls;
+                let distance_bound = |delta: f64, inclusive: bool| match range_values[ix] {
+                    Some(value) => {
+                        let distances = &range_values[nn_start..nn_end];
+                        nn_start
+                            + distances.partition_point(|other| {
+                                let distance = (other.unwrap() - value) * direction;
+                                if inclusive {
+                                    distance <= delta
+                                } else {
+                                    distance < delta
+                                }
+                            })
+                    }
+                    None if inclusive => peer_end,
+                    None => peer_start,
+                };
+                let lo = match frame.start {
+                    FrameBound::UnboundedPreceding => start,
+                    FrameBound::Preceding(n) => distance_bound(-(n as f64), false),
+                    FrameBound::CurrentRow => peer_start,
+                    FrameBound::Following(n) => distance_bound(n as f64, false),
+                    FrameBound::UnboundedFollowing => end,
+                };
+                let hi = match frame.end {
+                    FrameBound::UnboundedPreceding => start,
+                    FrameBound::Preceding(n) => distance_bound(-(n as f64), true),
+                    FrameBound::CurrentRow => peer_end,
+                    FrameBound::Following(n) => distance_bound(n as f64, true),
+                    FrameBound::UnboundedFollowing => end,
+                };
+                (lo, hi)
+            }
+        }
+    }
+
+    // AVG over a frame is computed from the count and sum of its values
+    fn init_accumulator(agg_type: AggType) -> Accumulator {
+        if agg_type == AggType::AVG {
+            Accumulator::Moments { count: 0, sum: 0.0, sum_squares: 0.0 }
+        } else {
+            HashAggContext::init_accumulator(agg_type)
+        }
+    }
+
+    fn finalize(agg_type: AggType, acc: &Accumulator) -> Option<Datum> {
+        match acc {
+            Accumulator::Moments { count, sum, .. } if agg_type == AggType::AVG => (*count > 0).then(|| Float64(F64::from(sum / *count as f64))),
+            _ => HashAggContext::finalize(agg_type, acc),
+        }
+    }
+}
+
+/***************************************************************************************************/
+pub struct WindowContext {
+    pop_key: POPKey,
+    children: Vec<Box<dyn POPContext>>,
+    partition_id: PartitionId,
+    is_initialized: bool,
+    chunk: Option<ChunkBox>,     // All input, followed by the window columns
+    slices: Vec<(usize, usize)>, // Input chunks as (offset, len) within `chunk`. Output follows the same boundaries.
+    slice_ix: usize,
+}
+
+impl WindowContext {
+    pub fn try_new(pop_key: POPKey, _: &Window, children: Vec<Box<dyn POPContext>>, partition_id: PartitionId) -> Result<Box<dyn POPContext>, String> {
+        Ok(Box::new(WindowContext { pop_key, children, partition_id, is_initialized: false, chunk: None, slices: vec![], slice_ix: 0 }))
+    }
+
+    // compute: Window partitions can span input chunks, so all of the input is gathered before any window function is computed
+    fn compute(&mut self, flow: &Flow, stage: &Stage, window: &Window) -> Result<(), String> {
+        let mut chunks = vec![];
+        while let Some(chunk) = self.children[0].next(flow, stage)? {
+            if !chunk.is_empty() {
+                self.slices.push((self.slices.last().map_or(0, |(offset, len)| offset + len), chunk.len()));
+                chunks.push(chunk);
+            }
+        }
+        if chunks.is_empty() {
+            return Ok(());
+        }
+
+        let ncols = chunks[0].arrays().len();
+        let arrays = (0..ncols)
+            .map(|colid| {
+                let arrays = chunks.iter().map(|chunk| &*chunk.arrays()[colid]).collect::<Vec<_>>();
+                concatenate(&arrays).map_err(stringify)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let chunk = Chunk::new(arrays);
+
+        let window_arrays = window.eval_window_cols(&chunk);
+        let arrays = chunk.into_arrays().into_iter().chain(window_arrays).collect();
+        self.chunk = Some(Chunk::new(arrays));
+        Ok(())
+    }
+}
+
+impl POPContext for WindowContext {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn next(&mut self, flow: &Flow, stage: &Stage) -> Result<Option<ChunkBox>, String> {
+        let pop_key = self.pop_key;
+        let pop = stage.pop_graph.get_value(pop_key);
+        let props = stage.pop_graph.get_properties(pop_key);
+
+        if let POP::Window(window) = pop {
+            if !self.is_initialized {
+                self.compute(flow, stage, window)?;
+                self.is_initialized = true;
+            }
+
+            if let (Some(chunk), Some(&(offset, len))) = (self.chunk.as_ref(), self.slices.get(self.slice_ix)) {
+                self.slice_ix += 1;
+                let chunk = POPKey::eval_projection(props, &slice_chunk(chunk, offset, len));
+                debug!("{}", chunk_to_string(&chunk, &format!("WindowContext::next partition = {}", self.partition_id)));
+                return Ok(Some(chunk));
+            }
+        } else {
+            panic!("ugh")
+        }
+        Ok(None)
+    }
+}
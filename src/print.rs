@@ -9,7 +9,7 @@ use crate::{
     includes::*,
     lop::{LOPGraph, VirtCol, LOP},
     pop::{POPGraph, POP},
-    qgm::QueryBlock,
+    qgm::{JoinType, Ordering, QueryBlock},
     stage::{Stage, StageGraph},
     QGM,
 };
@@ -82,30 +82,67 @@ impl QGM {
         let predstring = props.preds.describe(self, true);
 
         let (label, extrastr) = match &lop {
-            LOP::TableScan { input_projection } => {
+            LOP::TableScan { input_projection, limit } => {
                 let input_projection = input_projection.describe(self);
-                let extrastr = format!("(input = {})", input_projection);
+                let mut extrastr = format!("(input = {})", input_projection);
+                if let Some(limit) = limit {
+                    extrastr.push_str(&format!(", limit = {}", limit));
+                }
                 (String::from("TableScan"), extrastr)
             }
-            LOP::HashJoin { lhs_join_keys, rhs_join_keys } => {
+            LOP::HashJoin { join_type, lhs_join_keys, rhs_join_keys, join_preds, null_aware } => {
                 let lhsstr = describe_preds(lhs_join_keys, self, true, false);
                 let rhsstr = describe_preds(rhs_join_keys, self, true, false);
-                let extrastr = format!("{} = {}", lhsstr, rhsstr);
-                (String::from("HashJoin"), extrastr)
+                let mut extrastr = format!("{} = {}", lhsstr, rhsstr);
+                if !join_preds.is_empty() {
+                    extrastr.push_str(&format!(", on = {}", describe_preds(join_preds, self, true, false)));
+                }
+                let label = match (join_type, null_aware) {
+                    (JoinType::Inner, _) => String::from("HashJoin"),
+                    (_, true) => format!("HashJoin ({:?}, null-aware)", join_type),
+                    _ => format!("HashJoin ({:?})", join_type),
+                };
+                (label, extrastr)
             }
             LOP::Repartition { cpartitions } => {
                 let extrastr = format!("c = {}", cpartitions);
                 (String::from("Repartition"), extrastr)
             }
-            LOP::Aggregation { key_len } => {
-                let extrastr = format!("key_len = {}", key_len);
+            LOP::Aggregation { key_len, having } => {
+                let mut extrastr = format!("key_len = {}", key_len);
+                if !having.is_empty() {
+                    let having = having.iter().map(|&expr_key| expr_key.describe(&self.expr_graph, true)).collect::<Vec<_>>().join(" AND ");
+                    extrastr.push_str(&format!(", having = {}", having));
+                }
                 (String::from("Aggregation"), extrastr)
             }
+            LOP::Expand { key_len, grouping_sets } => {
+                let extrastr = format!("key_len = {}, sets = {:?}", key_len, grouping_sets);
+                (String::from("Expand"), extrastr)
+            }
+            LOP::Sort { sort_keys, limit } => {
+                let mut extrastr = describe_sort_keys(sort_keys, self);
+                if let Some(limit) = limit {
+                    extrastr.push_str(&format!(", limit = {}", limit));
+                }
+                (String::from("Sort"), extrastr)
+            }
+            LOP::Limit { offset, limit } => {
+                let extrastr = format!("offset = {}, limit = {}", offset, limit);
+                (String::from("Limit"), extrastr)
+            }
+            LOP::Window { partition_keys, order_keys, .. } => {
+                let extrastr = format!("partition by = {}, order by = {}", describe_preds(partition_keys, self, true, false), describe_sort_keys(order_keys, self));
+                (String::from("Window"), extrastr)
+            }
+            LOP::UnionAll { recursive: false } => (String::from("UnionAll"), String::new()),
+            LOP::UnionAll { recursive: true } => (String::from("UnionAll"), String::from("recursive")),
+            LOP::RecursiveScan { cte_lop_key } => (String::from("RecursiveScan"), format!("cte = {:?}", cte_lop_key.id())),
         };
 
         fprint!(
             file,
-            "    lopkey{}[label=\"{}-{}|{:?}|{}|{}|{}|{}\"];\n",
+            "    lopkey{}[label=\"{}-{}|{:?}|{}|{}|{}|{}|{}\"];\n",
             id,
             label,
             lop_key.id(),
@@ -113,7 +150,8 @@ impl QGM {
             colstring,
             predstring,
             props.partdesc.describe(&self.expr_graph, true),
-            extrastr
+            extrastr,
+            props.estimate.describe()
         );
 
         Ok(())
@@ -138,12 +176,11 @@ impl QGM {
             //fprint!(file, "    color = \"red\"\n");
             fprint!(file, "}}\n");
 
-            // For every non-root stage, point to POP in parent stage
-            if let Some(to_stage_id) = stage.parent_stage_id {
+            // For every non-root stage, point to POPs in parent stages
+            for (&to_stage_id, &to_pop_key) in stage.parent_stage_ids.iter().zip(stage.parent_pop_keys.iter()) {
                 let from_stage_id = stage.stage_id;
                 let from_pop_key = stage.root_pop_key.unwrap();
                 let from_name = from_pop_key.full_id(from_stage_id);
-                let to_pop_key = stage.parent_pop_key.unwrap();
                 let to_name = to_pop_key.full_id(to_stage_id);
                 fprint!(file, "    {} -> {};\n", from_name, to_name);
             }
@@ -183,18 +220,24 @@ impl Stage {
                 let pathname = csv.pathname.split('/').last().unwrap_or(&csv.pathname);
                 //let mut projection = csv.projection.clone();
                 //projection.sort_by(|a, b| a.cmp(b));
-                let extrastr = format!("file: {}, input_projection: {:?}", pathname, &csv.input_projection).replace('{', "(").replace('}', ")");
+                let mut extrastr = format!("file: {}, input_projection: {:?}", pathname, &csv.input_projection).replace('{', "(").replace('}', ")");
+                if let Some(limit) = csv.limit {
+                    extrastr.push_str(&format!(", limit: {}", limit));
+                }
                 (String::from("CSV"), extrastr)
             }
             POP::Parquet(pq) => {
                 let pathname = pq.pathname.split('/').last().unwrap_or(&pq.pathname);
                 //let mut projection = csv.projection.clone();
                 //projection.sort_by(|a, b| a.cmp(b));
-                let extrastr = format!("file: {}, input_projection: {:?}", pathname, &pq.input_projection).replace('{', "(").replace('}', ")");
+                let mut extrastr = format!("file: {}, input_projection: {:?}", pathname, &pq.input_projection).replace('{', "(").replace('}', ")");
+                if let Some(limit) = pq.limit {
+                    extrastr.push_str(&format!(", limit: {}", limit));
+                }
                 (String::from("Parquet"), extrastr)
             }
-            POP::HashMatch { .. } => {
-                let extrastr = String::new();
+            POP::HashMatch(hash_match) => {
+                let extrastr = if hash_match.join_type == JoinType::Inner { String::new() } else { format!("{:?}", hash_match.join_type) };
                 (String::from("HashMatch"), extrastr)
             }
             POP::HashAgg { .. } => {
@@ -209,6 +252,26 @@ impl Stage {
                 let extrastr = String::new();
                 (String::from("RepartitionRead"), extrastr)
             }
+            POP::Sort(sort) => {
+                let mut extrastr = if sort.merge_link.is_some() { String::from("merge") } else { String::new() };
+                if let Some(limit) = sort.limit {
+                    extrastr.push_str(&format!(" limit: {}", limit));
+                }
+                (String::from("Sort"), extrastr)
+            }
+            POP::Expand(expand) => {
+                let extrastr = format!("key_len: {}, sets: {:?}", expand.key_len, expand.grouping_sets);
+                (String::from("Expand"), extrastr)
+            }
+            POP::Limit(limit) => {
+                let extrastr = format!("offset: {}, limit: {}", limit.offset, limit.limit);
+                (String::from("Limit"), extrastr)
+            }
+            POP::UnionAll(_) => (String::from("UnionAll"), String::new()),
+            POP::Window(window) => {
+                let func_types = window.window_cols.iter().map(|window_col| window_col.func_type).collect::<Vec<_>>();
+                (String::from("Window"), format!("{:?}", func_types))
+            }
         };
 
         let label = label.replace(['"', '{', '}'], "");
@@ -297,6 +360,21 @@ impl QueryBlock {
             fprint!(file, "    \"{}_having_clause\"[label=\"having_clause\",shape=box,style=filled];\n", self.name());
             fprint!(file, "}}\n");
         }
+
+        // Write ON clauses of ANSI joins
+        for (ix, join) in self.joins.iter().enumerate() {
+            fprint!(file, "  subgraph cluster_on_clause{}_{} {{\n", self.name(), ix);
+
+            for &expr_key in join.on_clause.iter() {
+                QGM::write_expr_to_graphvis(qgm, expr_key, file, None)?;
+
+                let id = expr_key.to_string();
+                fprint!(file, "    exprnode{} -> {}_on_clause_{};\n", id, self.name(), ix);
+            }
+            let label = f!("{:?} JOIN {:?} / {:?}", join.join_type, join.lhs_quns, join.rhs_quns);
+            fprint!(file, "    \"{}_on_clause_{}\"[label=\"{}\",shape=box,style=filled];\n", self.name(), ix, label);
+            fprint!(file, "}}\n");
+        }
         fprint!(file, "    label = \"{} type={:?}\";\n", self.name(), self.qbtype);
 
         fprint!(file, "}}\n");
@@ -375,6 +453,11 @@ pub fn describe_virtcols(preds: &Vec<VirtCol>, qgm: &QGM, do_escape: bool) -> St
     predstring
 }
 
+pub fn describe_sort_keys(sort_keys: &[(ExprKey, Ordering)], qgm: &QGM) -> String {
+    let sort_keys = sort_keys.iter().map(|(expr_key, ordering)| format!("{} {:?}", expr_key.describe(&qgm.expr_graph, true), ordering)).collect::<Vec<_>>();
+    sort_keys.join(", ")
+}
+
 impl POPKey {
     pub fn full_id(&self, stage_id: StageId) -> String {
         format!("stage{}_popkey{}", self.id(), stage_id)
@@ -3,7 +3,7 @@
 use std::{collections::HashMap, fmt, io::Write, process::Command, rc::Rc};
 
 use crate::{
-    expr::ExprGraph,
+    expr::{Expr, ExprGraph, FrameBound, FrameUnits, WindowFrame, WindowFunc, WindowFuncType},
     graph::{ExprKey, Graph, QueryBlockKey},
     includes::*,
     metadata::TableDesc,
@@ -14,9 +14,22 @@ pub type QueryBlockGraph = Graph<QueryBlockKey, QueryBlock, ()>;
 #[derive(Default)]
 pub struct QGMMetadata {
     tabledescmap: HashMap<QunId, Rc<dyn TableDesc>>,
+    ctes: Vec<(String, QueryBlockKey, Rc<dyn TableDesc>)>, // Resolved CTEs, in order of definition
 }
 
 impl QGMMetadata {
+    pub fn add_cte(&mut self, name: String, qbkey: QueryBlockKey, tabledesc: Rc<dyn TableDesc>) {
+        self.ctes.push((name, qbkey, tabledesc));
+    }
+
+    pub fn get_cte(&self, name: &String) -> Option<QueryBlockKey> {
+        self.ctes.iter().find(|(cte_name, ..)| cte_name == name).map(|(_, qbkey, _)| *qbkey)
+    }
+
+    pub fn get_cte_tabledesc(&self, qbkey: QueryBlockKey) -> Option<Rc<dyn TableDesc>> {
+        self.ctes.iter().find(|(_, cte_qbkey, _)| *cte_qbkey == qbkey).map(|(.., tabledesc)| Rc::clone(tabledesc))
+    }
+
     pub fn add_tabledesc(&mut self, qunid: QunId, tabledesc: Rc<dyn TableDesc>) {
         self.tabledescmap.insert(qunid, tabledesc);
     }
@@ -101,12 +114,15 @@ pub enum QuantifierSource {
     AnsiJoin(AnsiJoin),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum JoinType {
     Inner,
     Left,
     Right,
     Full,
+    Semi, // EXISTS / IN subqueries
+    Anti,   // NOT EXISTS / NOT IN subqueries
+    Scalar, // Uncorrelated scalar subqueries: LEFT join against a subquery that must not return more than one row
 }
 
 #[derive(Serialize, Deserialize)]
@@ -117,6 +133,33 @@ pub struct AnsiJoin {
     pub on_clause: ExprKey,
 }
 
+// JoinDesc: ANSI join flattened during resolution, or a semi/anti join unnested from a subquery predicate. The join's inputs are
+// identified by the leaf quantifiers on either side.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinDesc {
+    pub join_type: JoinType,
+    pub lhs_quns: Vec<QunId>,
+    pub rhs_quns: Vec<QunId>,
+    pub on_clause: Vec<ExprKey>,
+    pub null_aware_pred: Option<ExprKey>, // NOT IN equality (also part of the ON clause). Rows match unless it is FALSE.
+}
+
+impl JoinDesc {
+    // Quantifiers whose columns are NULL-extended by this join
+    pub fn null_producing_quns(&self) -> Vec<QunId> {
+        match self.join_type {
+            JoinType::Inner | JoinType::Semi | JoinType::Anti => vec![],
+            JoinType::Left | JoinType::Scalar => self.rhs_quns.clone(),
+            JoinType::Right => self.lhs_quns.clone(),
+            JoinType::Full => self.lhs_quns.iter().chain(self.rhs_quns.iter()).cloned().collect(),
+        }
+    }
+
+    pub fn quns(&self) -> impl Iterator<Item = &QunId> {
+        self.lhs_quns.iter().chain(self.rhs_quns.iter())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Quantifier {
     pub id: QunId,
@@ -159,6 +202,14 @@ impl Quantifier {
         Quantifier::new(id, source, alias)
     }
 
+    pub fn into_ansi_join(self) -> Result<AnsiJoin, Quantifier> {
+        if let QuantifierSource::AnsiJoin(ansi_join) = self.source {
+            Ok(ansi_join)
+        } else {
+            Err(self)
+        }
+    }
+
     pub fn is_base_table(&self) -> bool {
         matches!(self.source, QuantifierSource::Basename(_))
     }
@@ -183,6 +234,11 @@ impl Quantifier {
         }
     }
 
+    // set_qblock_key: Point a base table reference at the query block of a CTE with the same name
+    pub fn set_qblock_key(&mut self, qblock_key: QueryBlockKey) {
+        self.source = QuantifierSource::QueryBlock(qblock_key)
+    }
+
     pub fn get_qblock_key(&self) -> Option<QueryBlockKey> {
         if let QuantifierSource::QueryBlock(qblock_key) = self.source {
             Some(qblock_key)
@@ -191,6 +247,14 @@ impl Quantifier {
         }
     }
 
+    pub fn get_ansi_join(&self) -> Option<&AnsiJoin> {
+        if let QuantifierSource::AnsiJoin(ansi_join) = &self.source {
+            Some(ansi_join)
+        } else {
+            None
+        }
+    }
+
     pub fn get_qblock<'a>(&self, qgraph: &'a QueryBlockGraph) -> Option<&'a QueryBlock> {
         if let Some(qblock_key) = self.get_qblock_key() {
             Some(qgraph.get_value(qblock_key))
@@ -204,7 +268,7 @@ impl Quantifier {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Ordering {
     Asc,
     Desc,
@@ -223,21 +287,43 @@ pub struct QueryBlock {
     pub qbtype: QueryBlockType,
     pub select_list: Vec<NamedExpr>,
     pub quns: Vec<Quantifier>,
+    pub joins: Vec<JoinDesc>, // ANSI joins, innermost first
     pub pred_list: Option<Vec<ExprKey>>,
     pub group_by: Option<Vec<ExprKey>>,
+    pub grouping_sets: Option<Vec<Vec<usize>>>, // ROLLUP/CUBE/GROUPING SETS: GROUP-BY positions grouped on by each set
     pub having_clause: Option<Vec<ExprKey>>,
     pub order_by: Option<Vec<(ExprKey, Ordering)>>,
     pub distinct: DistinctProperty,
-    pub top_n: Option<usize>,
+    pub top_n: Option<usize>,  // TOP n or LIMIT n
+    pub offset: Option<usize>, // LIMIT n OFFSET m
+    pub nhidden: usize, // # of trailing select-list entries only referenced by ORDER BY
+    pub recursive: bool, // CTE of a WITH RECURSIVE clause. Only stays set if the CTE refers to itself.
 }
 
 impl QueryBlock {
     pub fn new(
         id: QBId, name: Option<String>, qbtype: QueryBlockType, select_list: Vec<NamedExpr>, quns: Vec<Quantifier>, pred_list: Option<Vec<ExprKey>>,
         group_by: Option<Vec<ExprKey>>, having_clause: Option<Vec<ExprKey>>, order_by: Option<Vec<(ExprKey, Ordering)>>, distinct: DistinctProperty,
-        top_n: Option<usize>,
+        top_n: Option<usize>, offset: Option<usize>,
     ) -> Self {
-        QueryBlock { id, name, qbtype, select_list, quns, pred_list, group_by, having_clause, order_by, distinct, top_n }
+        QueryBlock {
+            id,
+            name,
+            qbtype,
+            select_list,
+            quns,
+            joins: vec![],
+            pred_list,
+            group_by,
+            grouping_sets: None,
+            having_clause,
+            order_by,
+            distinct,
+            top_n,
+            offset,
+            nhidden: 0,
+            recursive: false,
+        }
     }
 
     pub fn new0(id: QBId, qbtype: QueryBlockType) -> Self {
@@ -247,12 +333,17 @@ impl QueryBlock {
             qbtype,
             select_list: vec![],
             quns: vec![],
+            joins: vec![],
             pred_list: None,
             group_by: None,
+            grouping_sets: None,
             having_clause: None,
             order_by: None,
             distinct: DistinctProperty::All,
             top_n: None,
+            offset: None,
+            nhidden: 0,
+            recursive: false,
         }
     }
 
@@ -274,12 +365,80 @@ impl QueryBlock {
     }
 }
 
+// WindowSpec: Parsed OVER clause
+pub struct WindowSpec {
+    pub partition_by: Vec<ExprKey>,
+    pub order_by: Vec<(ExprKey, Ordering)>,
+    pub frame: Option<WindowFrame>,
+}
+
 #[derive(Default)]
 pub struct ParserState {
     pub qblock_graph: QueryBlockGraph,
     pub expr_graph: ExprGraph,
 }
 
+impl ParserState {
+    // build_grouping_sets: Cross the grouping sets of every GROUP-BY element. The expressions of all sets are gathered into one GROUP-BY
+    // list, and each distinct set turns into the sorted positions of its expressions in that list.
+    pub fn build_grouping_sets(&self, elements: Vec<Vec<Vec<ExprKey>>>) -> (Vec<ExprKey>, Vec<Vec<usize>>) {
+        let expr_graph = &self.expr_graph;
+        let sets = elements.into_iter().fold(vec![vec![]], |sets: Vec<Vec<ExprKey>>, element_sets| {
+            sets.iter().flat_map(|set| element_sets.iter().map(move |element_set| set.iter().chain(element_set.iter()).cloned().collect())).collect()
+        });
+
+        let mut group_by: Vec<ExprKey> = vec![];
+        let mut grouping_sets = vec![];
+        for set in sets {
+            let mut positions = vec![];
+            for expr_key in set {
+                let pos = if let Some(pos) = group_by.iter().position(|&gb_key| Expr::isomorphic(expr_graph, gb_key, expr_key)) {
+                    pos
+                } else {
+                    group_by.push(expr_key);
+                    group_by.len() - 1
+                };
+                positions.push(pos);
+            }
+            positions.sort_unstable();
+            positions.dedup();
+            // Repeated sets are kept: each of them returns its own copy of the groups
+            grouping_sets.push(positions);
+        }
+        (group_by, grouping_sets)
+    }
+
+    pub fn add_window_function(&mut self, func_type: WindowFuncType, args: Vec<ExprKey>, spec: WindowSpec) -> Result<ExprKey, &'static str> {
+        let frame = spec.frame.unwrap_or_default();
+
+        // Bounds must not run backwards, e.g. CURRENT ROW to 1 PRECEDING
+        let bound_rank = |bound: FrameBound| match bound {
+            FrameBound::UnboundedPreceding => 0,
+            FrameBound::Preceding(_) => 1,
+            FrameBound::CurrentRow => 2,
+            FrameBound::Following(_) => 3,
+            FrameBound::UnboundedFollowing => 4,
+        };
+        if frame.start == FrameBound::UnboundedFollowing || frame.end == FrameBound::UnboundedPreceding || bound_rank(frame.start) > bound_rank(frame.end) {
+            return Err("Invalid window frame.");
+        }
+        let has_offset = |bound: FrameBound| matches!(bound, FrameBound::Preceding(_) | FrameBound::Following(_));
+        if frame.units == FrameUnits::RANGE && (has_offset(frame.start) || has_offset(frame.end)) && spec.order_by.len() != 1 {
+            return Err("RANGE frames with an offset require exactly one ORDER BY key.");
+        }
+
+        let window_func = WindowFunc {
+            func_type,
+            nargs: args.len(),
+            npartition_keys: spec.partition_by.len(),
+            orderings: spec.order_by.iter().map(|(_, ordering)| *ordering).collect(),
+            frame,
+        };
+        let children = args.into_iter().chain(spec.partition_by).chain(spec.order_by.into_iter().map(|(expr_key, _)| expr_key)).collect();
+        Ok(self.expr_graph.add_node(Expr::WindowFunction(window_func), Some(children)))
+    }
+}
+
 impl QueryBlockGraph {
     pub fn foo(&self) {
         println!("Hello")
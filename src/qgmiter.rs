@@ -91,6 +91,11 @@ impl QueryBlockKey {
                 iter = Box::new(iter.chain(expr_list.iter().copied()));
             }
         }
+
+        // Append ON clauses of ANSI joins
+        for join in qblock.joins.iter() {
+            iter = Box::new(iter.chain(join.on_clause.iter().copied()));
+        }
         iter
     }
 
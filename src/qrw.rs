@@ -0,0 +1,284 @@
+// qrw: Query rewrites over the QGM that run ahead of logical planning. Predicates implied by column equalities get added to query
+// blocks, and predicates on derived tables and aggregations get pushed into the query blocks that compute them.
+
+use crate::{
+    expr::{Expr, Expr::*, ExprGraph, LogOp, RelOp},
+    graph::{ExprKey, QueryBlockKey},
+    includes::*,
+    qgm::{JoinType, QueryBlock, QueryBlockGraph, QueryBlockType, QGM},
+};
+
+// ColumnClass: Columns known to be equal, along with an expression referencing each one
+type ColumnClass = Vec<(QunCol, ExprKey)>;
+
+/***************************************************************************************************/
+impl QGM {
+    // qrw_pushdown_preds: Infer predicates and push them down, one query block at a time. Query blocks are rewritten ahead of the ones
+    // they read from, so that predicates pushed into a query block can be pushed further down.
+    pub fn qrw_pushdown_preds(&mut self) {
+        let mut shared = HashSet::new();
+        for &cte_key in self.cte_list.iter() {
+            let nrefs = self.iter_qblocks().flat_map(|qbkey| self.qblock_graph.get_value(qbkey).quns.iter()).filter(|qun| qun.get_qblock_key() == Some(cte_key)).count();
+            if nrefs > 1 || self.qblock_graph.get_value(cte_key).recursive {
+                shared.insert(cte_key);
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut worklist = self.cte_list.clone();
+        worklist.push(self.main_qblock_key);
+        while let Some(qbkey) = worklist.pop() {
+            if !visited.insert(qbkey) {
+                continue;
+            }
+            let (qblock_graph, expr_graph, _) = self.borrow_parts();
+            match qblock_graph.get_value(qbkey).qbtype {
+                QueryBlockType::Select => {
+                    QueryBlock::infer_preds(&mut qblock_graph.get_mut(qbkey).value, expr_graph);
+                    QueryBlock::pushdown_into_derived_tables(qbkey, qblock_graph, expr_graph, &shared);
+                }
+                QueryBlockType::GroupBy => QueryBlock::pushdown_having_preds(qbkey, qblock_graph, expr_graph),
+                _ => {}
+            }
+            worklist.extend(qblock_graph.get_value(qbkey).quns.iter().filter_map(|qun| qun.get_qblock_key()));
+        }
+    }
+}
+
+/***************************************************************************************************/
+impl QueryBlock {
+    // infer_preds: Column equalities in the WHERE clause and in ON clauses of inner joins hold for every row of a query block, so that a
+    // predicate on one of the columns holds for all of them. Such predicates get added to the WHERE clause. An outer/semi/anti join also
+    // gets them added to its ON clause for the columns of the side it matches rows against, which is then filtered ahead of the join.
+    // Only predicates that are never TRUE on NULLs get copied, since they also reject the rows that joins NULL-extend.
+    fn infer_preds(qblock: &mut QueryBlock, expr_graph: &mut ExprGraph) {
+        let mut where_preds = qblock.pred_list.clone().unwrap_or_default();
+        let inner_on_preds = qblock.joins.iter().filter(|join| join.join_type == JoinType::Inner).flat_map(|join| join.on_clause.iter());
+        let equalities = where_preds.iter().chain(inner_on_preds).filter_map(|&pred_key| Self::column_equality(expr_graph, pred_key)).collect::<Vec<_>>();
+
+        let mut classes: Vec<ColumnClass> = vec![];
+        for (lhs, rhs) in equalities {
+            let find = |classes: &[ColumnClass], quncol: QunCol| classes.iter().position(|class| class.iter().any(|&(other, _)| other == quncol));
+            match (find(&classes, lhs.0), find(&classes, rhs.0)) {
+                (Some(lhs_ix), Some(rhs_ix)) if lhs_ix != rhs_ix => {
+                    let rhs_class = classes.remove(rhs_ix);
+                    let lhs_ix = if rhs_ix < lhs_ix { lhs_ix - 1 } else { lhs_ix };
+                    classes[lhs_ix].extend(rhs_class);
+                }
+                (Some(_), Some(_)) => {}
+                (Some(lhs_ix), None) => classes[lhs_ix].push(rhs),
+                (None, Some(rhs_ix)) => classes[rhs_ix].push(lhs),
+                (None, None) => classes.push(vec![lhs, rhs]),
+            }
+        }
+
+        // a.x = b.x AND a.x > 5 => b.x > 5
+        for pred_key in where_preds.clone() {
+            let Some(quncol) = Self::strict_column_pred(expr_graph, pred_key) else { continue };
+            let Some(class) = classes.iter().find(|class| class.iter().any(|&(other, _)| other == quncol)) else { continue };
+            for &(other, column_key) in class.iter().filter(|&&(other, _)| other != quncol) {
+                let new_pred_key = Self::copy_expr(expr_graph, pred_key, &|col| (col == quncol).then_some(column_key));
+                if !Self::contains_pred(expr_graph, &where_preds, new_pred_key) {
+                    where_preds.push(new_pred_key);
+                }
+            }
+        }
+
+        // a LEFT JOIN b ON a.x = b.x WHERE a.x > 5 => a LEFT JOIN b ON a.x = b.x AND b.x > 5
+        for join in qblock.joins.iter_mut() {
+            let (preserved_quns, matched_quns) = match join.join_type {
+                JoinType::Left | JoinType::Semi | JoinType::Anti => (&join.lhs_quns, &join.rhs_quns),
+                JoinType::Right => (&join.rhs_quns, &join.lhs_quns),
+                JoinType::Inner | JoinType::Full | JoinType::Scalar => continue,
+            };
+            let on_equalities = join.on_clause.iter().filter(|&&pred_key| join.null_aware_pred != Some(pred_key));
+            let on_equalities = on_equalities.filter_map(|&pred_key| Self::column_equality(expr_graph, pred_key)).collect::<Vec<_>>();
+
+            let mut on_clause = join.on_clause.clone();
+            for ((lhs_quncol, lhs_key), (rhs_quncol, rhs_key)) in on_equalities {
+                let (preserved_quncol, matched_key) = if preserved_quns.contains(&lhs_quncol.0) && matched_quns.contains(&rhs_quncol.0) {
+                    (lhs_quncol, rhs_key)
+                } else if preserved_quns.contains(&rhs_quncol.0) && matched_quns.contains(&lhs_quncol.0) {
+                    (rhs_quncol, lhs_key)
+                } else {
+                    continue;
+                };
+                for &pred_key in where_preds.iter().chain(join.on_clause.iter()) {
+                    if Self::strict_column_pred(expr_graph, pred_key) == Some(preserved_quncol) {
+                        let new_pred_key = Self::copy_expr(expr_graph, pred_key, &|col| (col == preserved_quncol).then_some(matched_key));
+                        if !Self::contains_pred(expr_graph, &on_clause, new_pred_key) {
+                            on_clause.push(new_pred_key);
+                        }
+                    }
+                }
+            }
+            join.on_clause = on_clause;
+        }
+
+        if qblock.pred_list.is_some() {
+            qblock.pred_list = Some(where_preds);
+        }
+    }
+
+    // pushdown_into_derived_tables: WHERE predicates on a single derived table, and ON-clause predicates on one that its join filters
+    // ahead of matching, move into the query block of the derived table. Its select-list expressions stand in for its columns.
+    fn pushdown_into_derived_tables(qbkey: QueryBlockKey, qblock_graph: &mut QueryBlockGraph, expr_graph: &mut ExprGraph, shared: &HashSet<QueryBlockKey>) {
+        let moves = Self::find_derived_table_preds(qbkey, qblock_graph, expr_graph, shared);
+        for (pred_key, join_ix, qunid, child_qbkey) in moves {
+            let select_list = qblock_graph.get_value(child_qbkey).select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
+            let new_pred_key = Self::copy_expr(expr_graph, pred_key, &|QunCol(other, colid)| (other == qunid).then(|| select_list[colid]));
+            qblock_graph.get_mut(child_qbkey).value.pred_list.get_or_insert_with(Vec::new).push(new_pred_key);
+
+            let qblock = &mut qblock_graph.get_mut(qbkey).value;
+            match join_ix {
+                Some(join_ix) => qblock.joins[join_ix].on_clause.retain(|&other| other != pred_key),
+                None => qblock.pred_list.iter_mut().for_each(|pred_list| pred_list.retain(|&other| other != pred_key)),
+            }
+            debug!("Pushed predicate on derived table {} into query block {}", qunid, qblock_graph.get_value(child_qbkey).id);
+        }
+    }
+
+    // find_derived_table_preds: Predicates to move into derived tables, along with the ON clause they come from (if any), the derived
+    // table and its query block
+    fn find_derived_table_preds(
+        qbkey: QueryBlockKey, qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, shared: &HashSet<QueryBlockKey>,
+    ) -> Vec<(ExprKey, Option<usize>, QunId, QueryBlockKey)> {
+        let qblock = qblock_graph.get_value(qbkey);
+
+        // Predicates on a NULL-producing side of an outer join only apply once that join is done
+        let nullable_quns = qblock.joins.iter().flat_map(|join| join.null_producing_quns()).collect::<Vec<_>>();
+        let where_preds = qblock.pred_list.iter().flatten().map(|&pred_key| (pred_key, None));
+        let where_preds = where_preds.filter(|&(pred_key, _)| pred_key.iter_quns(expr_graph).all(|qunid| !nullable_quns.contains(&qunid)));
+
+        // ON-clause predicates on the side a join matches rows against, unless a join nested in that side NULL-extends them
+        let on_preds = qblock.joins.iter().enumerate().flat_map(|(join_ix, join)| {
+            let matched_quns = match join.join_type {
+                JoinType::Inner => join.quns().cloned().collect::<Vec<_>>(),
+                JoinType::Left | JoinType::Semi | JoinType::Anti => join.rhs_quns.clone(),
+                JoinType::Right => join.lhs_quns.clone(),
+                JoinType::Full | JoinType::Scalar => vec![],
+            };
+            let nested_joins = qblock.joins[..join_ix].iter().filter(|nested_join| nested_join.quns().all(|qunid| join.quns().any(|other| other == qunid)));
+            let nullable_quns = nested_joins.flat_map(|nested_join| nested_join.null_producing_quns()).collect::<Vec<_>>();
+            let pushable_quns = matched_quns.into_iter().filter(|qunid| !nullable_quns.contains(qunid)).collect::<Vec<_>>();
+            join.on_clause.iter().filter(move |&&pred_key| pred_key.iter_quns(expr_graph).all(|qunid| pushable_quns.contains(&qunid))).map(move |&pred_key| (pred_key, Some(join_ix)))
+        });
+
+        let mut moves = vec![];
+        for (pred_key, join_ix) in where_preds.chain(on_preds) {
+            let quns = pred_key.iter_quns(expr_graph).collect::<HashSet<_>>();
+            let Some(&qunid) = quns.iter().next().filter(|_| quns.len() == 1) else { continue };
+            let Some(child_qbkey) = qblock.quns.iter().find(|qun| qun.id == qunid).and_then(|qun| qun.get_qblock_key()) else { continue };
+            let child_qblock = qblock_graph.get_value(child_qbkey);
+            if shared.contains(&child_qbkey) || !Self::can_push_into(child_qblock, expr_graph) || !Self::is_pushable(expr_graph, pred_key) {
+                continue;
+            }
+
+            // Aggregations only take predicates on their grouping keys
+            let select_exprs = pred_key.iter_quncols(expr_graph).map(|QunCol(_, colid)| child_qblock.select_list[colid].expr_key).collect::<Vec<_>>();
+            if select_exprs.iter().any(|&expr_key| expr_graph.true_iter(expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), AggFunction(..)))) {
+                continue;
+            }
+            moves.push((pred_key, join_ix, qunid, child_qbkey));
+        }
+
+        moves
+    }
+
+    // pushdown_having_preds: HAVING predicates on grouping keys alone filter the rows of a group as much as the group itself, and move
+    // into the WHERE clause of the aggregation's input. Grouping sets don't group every row on every key, so they keep theirs.
+    fn pushdown_having_preds(qbkey: QueryBlockKey, qblock_graph: &mut QueryBlockGraph, expr_graph: &mut ExprGraph) {
+        let qblock = qblock_graph.get_value(qbkey);
+        if qblock.grouping_sets.is_some() {
+            return;
+        }
+        let key_len = qblock.group_by.as_ref().map(|group_by| group_by.len()).unwrap_or(0);
+        let agg_qun = &qblock.quns[0];
+        let (agg_qunid, child_qbkey) = (agg_qun.id, agg_qun.get_qblock_key().unwrap());
+
+        let is_key_pred = |pred_key: ExprKey| {
+            let nodes = expr_graph.true_iter(pred_key).map(|expr_key| expr_graph.get_value(expr_key));
+            let (has_keys, has_others) = nodes.fold((false, false), |(has_keys, has_others), expr| match expr {
+                CID(qunid, cid) if *qunid == agg_qunid && *cid < key_len => (true, has_others),
+                CID(..) | Column { .. } | AggFunction(..) => (has_keys, true),
+                _ => (has_keys, has_others),
+            });
+            has_keys && !has_others && Self::is_pushable(expr_graph, pred_key)
+        };
+        let (key_preds, having_preds): (Vec<_>, Vec<_>) = qblock.pred_list.iter().flatten().partition(|&&pred_key| is_key_pred(pred_key));
+        if key_preds.is_empty() {
+            return;
+        }
+
+        let select_list = qblock_graph.get_value(child_qbkey).select_list.iter().map(|ne| ne.expr_key).collect::<Vec<_>>();
+        let new_preds = key_preds.iter().map(|&pred_key| Self::copy_expr(expr_graph, pred_key, &|QunCol(qunid, cid)| (qunid == agg_qunid).then(|| select_list[cid])));
+        let new_preds = new_preds.collect::<Vec<_>>();
+        qblock_graph.get_mut(child_qbkey).value.pred_list.get_or_insert_with(Vec::new).extend(new_preds);
+        qblock_graph.get_mut(qbkey).value.pred_list = Some(having_preds);
+    }
+
+    // can_push_into: Can predicates on the output of a query block be evaluated by its WHERE clause (or for aggregations, by HAVING)?
+    // Window functions are computed over the rows that pass the WHERE clause, and set operations have no WHERE clause of their own.
+    fn can_push_into(qblock: &QueryBlock, expr_graph: &ExprGraph) -> bool {
+        match qblock.qbtype {
+            QueryBlockType::Select => {
+                let has_window_fns = qblock.select_list.iter().any(|ne| expr_graph.true_iter(ne.expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), WindowFunction(..))));
+                !qblock.quns.is_empty() && !qblock.recursive && !has_window_fns
+            }
+            QueryBlockType::GroupBy => qblock.grouping_sets.is_none() && !qblock.recursive,
+            _ => false,
+        }
+    }
+
+    // is_pushable: Predicates that can be evaluated anywhere their columns are available
+    fn is_pushable(expr_graph: &ExprGraph, pred_key: ExprKey) -> bool {
+        !expr_graph.true_iter(pred_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), Subquery(_) | ExistsExpr | InSubqExpr | AggFunction(..) | WindowFunction(..)))
+    }
+
+    // strict_column_pred: Column of a predicate on a single column, as long as the predicate is never TRUE when that column is NULL.
+    // Functions aren't known to be strict in their arguments.
+    fn strict_column_pred(expr_graph: &ExprGraph, pred_key: ExprKey) -> Option<QunCol> {
+        let is_strict = !expr_graph.true_iter(pred_key).any(|expr_key| {
+            let expr = expr_graph.get_value(expr_key);
+            matches!(expr, IsNullExpr { .. } | CoalesceExpr | CaseExpr { .. } | NullIfExpr | ScalarFunction(_) | LogExpr(LogOp::Or) | CID(..))
+        });
+        let quncols = pred_key.iter_quncols(expr_graph).collect::<HashSet<_>>();
+        if is_strict && quncols.len() == 1 && Self::is_pushable(expr_graph, pred_key) && Self::column_equality(expr_graph, pred_key).is_none() {
+            quncols.into_iter().next()
+        } else {
+            None
+        }
+    }
+
+    // column_equality: Columns of an equality between two distinct columns of the same type
+    fn column_equality(expr_graph: &ExprGraph, pred_key: ExprKey) -> Option<((QunCol, ExprKey), (QunCol, ExprKey))> {
+        let (expr, _, children) = expr_graph.get3(pred_key);
+        let (RelExpr(RelOp::Eq), Some(children)) = (expr, children) else { return None };
+        let column = |expr_key: ExprKey| match expr_graph.get_value(expr_key) {
+            Column { qunid, colid, .. } => Some((QunCol(*qunid, *colid), expr_key)),
+            _ => None,
+        };
+        let (lhs, rhs) = (column(children[0])?, column(children[1])?);
+        (lhs.0 != rhs.0 && lhs.1.get_data_type(expr_graph) == rhs.1.get_data_type(expr_graph)).then_some((lhs, rhs))
+    }
+
+    // contains_pred: Is the same predicate over the same columns already in the list?
+    fn contains_pred(expr_graph: &ExprGraph, pred_list: &[ExprKey], pred_key: ExprKey) -> bool {
+        pred_list.iter().any(|&other| Expr::isomorphic(expr_graph, other, pred_key) && other.iter_quncols(expr_graph).eq(pred_key.iter_quncols(expr_graph)))
+    }
+
+    // copy_expr: Copy an expression, replacing column references with copies of the expressions `substitute` maps them to
+    fn copy_expr(expr_graph: &mut ExprGraph, expr_key: ExprKey, substitute: &dyn Fn(QunCol) -> Option<ExprKey>) -> ExprKey {
+        let node = expr_graph.get(expr_key);
+        let quncol = match node.value {
+            Column { qunid, colid, .. } | CID(qunid, colid) => Some(QunCol(qunid, colid)),
+            _ => None,
+        };
+        if let Some(new_expr_key) = quncol.and_then(substitute) {
+            return Self::copy_expr(expr_graph, new_expr_key, &|_| None);
+        }
+        let (value, props, children) = (node.value.clone(), node.properties.clone(), node.children.clone());
+        let children = children.map(|children| children.into_iter().map(|child_key| Self::copy_expr(expr_graph, child_key, substitute)).collect());
+        expr_graph.add_node_with_props(value, props, children)
+    }
+}
@@ -7,16 +7,32 @@ use arrow2::compute::cast::can_cast_types;
 use itertools::Itertools;
 
 use crate::{
-    datum::{get_rank, is_numeric},
-    expr::{AggType, ArithOp, Expr, Expr::*, ExprGraph, ExprProp},
+    datum::{get_rank, is_numeric, DATATYPE_PROPS, F64},
+    expr::{AggType, ArithOp, Expr, Expr::*, ExprGraph, ExprProp, FrameBound, FrameUnits, LogOp, RelOp, WindowFuncType},
     graph::{ExprKey, Node, QueryBlockKey},
     includes::*,
     metadata::{QueryDesc, TableDesc},
-    qgm::{NamedExpr, QGMMetadata, Quantifier, QueryBlock, QueryBlockGraph, QueryBlockType, QGM},
+    qgm::{AnsiJoin, DistinctProperty, JoinDesc, JoinType, NamedExpr, Ordering, QGMMetadata, Quantifier, QueryBlock, QueryBlockGraph, QueryBlockType, QGM},
+    scalar::ScalarFunc,
 };
 
 impl QGM {
     pub fn resolve(&mut self, env: &Env) -> Result<(), String> {
+        // Resolve CTEs in order of definition. Each CTE can refer to the ones defined before it.
+        for cte_key in self.cte_list.clone() {
+            let name = self.qblock_graph.get_value(cte_key).name.clone().unwrap();
+            if self.metadata.get_cte(&name).is_some() {
+                return Err(f!("CTE {} is defined more than once.", enquote(&name)));
+            }
+            if self.qblock_graph.get_value(cte_key).recursive && QueryBlock::refers_to(&self.qblock_graph, cte_key, &name) {
+                QueryBlock::resolve_recursive_cte(cte_key, name, env, self)?;
+            } else {
+                self.qblock_graph.get_mut(cte_key).value.recursive = false;
+                let qdesc = QueryBlock::resolve(cte_key, env, self)?;
+                self.metadata.add_cte(name, cte_key, qdesc);
+            }
+        }
+
         // Resolve top-level QB
         let qbkey = self.main_qblock_key;
         QueryBlock::resolve(qbkey, env, self)?;
@@ -31,32 +47,94 @@ impl QGM {
 
 impl QueryBlock {
     pub fn resolve(qbkey: QueryBlockKey, env: &Env, qgm: &mut QGM) -> Result<Rc<dyn TableDesc>, String> {
+        Self::resolve_in_scope(qbkey, &[], env, qgm)
+    }
+
+    // resolve_in_scope: Resolve a query block nested inside the query blocks of `outer_scope` (innermost first). Correlated column
+    // references bind to the innermost enclosing query block that has them.
+    fn resolve_in_scope(qbkey: QueryBlockKey, outer_scope: &[&QueryBlock], env: &Env, qgm: &mut QGM) -> Result<Rc<dyn TableDesc>, String> {
         // Resolve group-by/having clauses, if they exist
         // If a GROUP BY is present, all select_list expressions must either by included in the group_by, or they must be aggregate functions
-        let qblock = &mut qgm.qblock_graph.get_mut(qbkey).value;
+        let is_main_qblock = qgm.is_main_qblock(qbkey);
+        let (qblock_graph, expr_graph, _) = qgm.borrow_parts();
+        let qblock = &mut qblock_graph.get_mut(qbkey).value;
         //let qbid = qblock.id;
 
+        // ORDER BY only affects the final output, so it is ignored in nested query blocks
+        if !is_main_qblock {
+            qblock.order_by = None;
+            if qblock.top_n.is_some() {
+                return Err(String::from("TOP/LIMIT is only supported in the outermost query block."));
+            }
+        }
+
+        // Aggregate functions without a GROUP BY aggregate all rows into one
+        let exprs = qblock.select_list.iter().map(|ne| ne.expr_key).chain(qblock.having_clause.iter().flatten().cloned());
+        let has_aggs = exprs.into_iter().any(|expr_key| expr_graph.true_iter(expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), AggFunction(..))));
+        if qblock.group_by.is_none() && has_aggs {
+            qblock.group_by = Some(vec![]);
+        }
+
+        // Window functions are computed over the rows left after joins and predicates, and only in the select-list
+        let has_window_fns = |expr_key: ExprKey| expr_graph.true_iter(expr_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), WindowFunction(..)));
+        let clauses = qblock.pred_list.iter().flatten().chain(qblock.group_by.iter().flatten()).chain(qblock.having_clause.iter().flatten());
+        if clauses.cloned().any(has_window_fns) {
+            return Err(String::from("Window functions are only allowed in the select-list."));
+        }
+        if qblock.group_by.is_some() && qblock.select_list.iter().any(|ne| has_window_fns(ne.expr_key)) {
+            return Err(String::from("Window functions cannot be combined with GROUP BY or aggregate functions."));
+        }
+
+        if matches!(qblock.qbtype, QueryBlockType::Union | QueryBlockType::UnionAll | QueryBlockType::Intersect | QueryBlockType::Except) {
+            return Self::resolve_setop(qbkey, env, qgm);
+        }
+
+        let mut order_by_positions = None;
         if qblock.group_by.is_some() {
+            // Map ORDER BY expressions to select-list entries before the select-list gets rewritten
+            order_by_positions = qblock.resolve_order_by(expr_graph)?;
             Self::split_groupby(qbkey, qgm)?;
         }
 
         //let qgm_postsplit_pathname = format!("{}/QB_{}-{}", env.output_dir, qbid, "qgm_postsplit.dot");
         //qgm.write_qgm_to_graphviz(&qgm_postsplit_pathname, false)?;
 
-        let qblock = &mut qgm.qblock_graph.get_mut(qbkey).value;
+        let (qblock_graph, _, metadata) = qgm.borrow_parts();
+        let qblock = &mut qblock_graph.get_mut(qbkey).value;
         let is_group_by = qblock.qbtype == QueryBlockType::GroupBy;
 
+        // ANSI joins are flattened: their leaf quantifiers become quantifiers of this qblock
+        qblock.flatten_ansi_joins();
+
         // Ensure that every quantifier in this qblock is uniquely identifiable
         let qun_aliases = qblock.quns.iter().filter_map(|qun| qun.get_alias().cloned()).collect::<Vec<_>>();
         if has_duplicates(&qun_aliases) {
             return Err("Query has two or more quantifiers with the same aliases.".to_owned());
         }
 
-        // Resolve nested query blocks first
+        // References to CTEs become quantifiers over their query blocks
+        for qun in qblock.quns.iter_mut() {
+            if let Some(cte_key) = qun.get_basename().and_then(|name| metadata.get_cte(name)) {
+                qun.set_qblock_key(cte_key);
+            }
+        }
+
+        // Resolve nested query blocks first. The input of an aggregation shares its scope; derived tables don't see this qblock.
+        // CTEs have already been resolved.
         let qbkey_children: Vec<(QunId, QueryBlockKey)> = qblock.quns.iter().filter_map(|qun| qun.get_qblock_key().map(|qbkey| (qun.id, qbkey))).collect();
-        for (qunid, qbkey) in qbkey_children {
-            let qdesc = Self::resolve(qbkey, env, qgm)?;
-            qgm.metadata.add_tabledesc(qunid, qdesc);
+        for (qunid, child_qbkey) in qbkey_children {
+            let qdesc = if let Some(qdesc) = qgm.metadata.get_cte_tabledesc(child_qbkey) {
+                qdesc
+            } else if is_group_by {
+                Self::resolve_in_scope(child_qbkey, outer_scope, env, qgm)?
+            } else {
+                Self::resolve(child_qbkey, env, qgm)?
+            };
+            qgm.metadata.add_tabledesc(qunid, Rc::clone(&qdesc));
+            if !is_group_by {
+                let qblock = &mut qgm.qblock_graph.get_mut(qbkey).value;
+                qblock.quns.iter_mut().find(|qun| qun.id == qunid).unwrap().tabledesc = Some(qdesc);
+            }
         }
 
         let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
@@ -79,26 +157,68 @@ impl QueryBlock {
         // Resolve any stars (*)
         qblock.resolve_star(env, expr_graph)?;
 
+        if !is_group_by {
+            order_by_positions = qblock.resolve_order_by(expr_graph)?;
+
+            // Scalar subqueries become columns of derived tables joined with this qblock
+            Self::unnest_scalar_subqueries(qbkey, outer_scope, env, qgm)?;
+        }
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let qblock = &mut qblock_graph.get_mut(qbkey).value;
+
         // Resolve select list
         for ne in qblock.select_list.iter() {
             let expr_key = ne.expr_key;
-            qblock.resolve_expr(env, expr_graph, metadata, expr_key, is_group_by)?;
+            qblock.resolve_expr(outer_scope, env, expr_graph, metadata, expr_key, is_group_by)?;
         }
 
-        // Resolve predicates
+        // Point ORDER BY entries at their resolved select-list expressions
+        if let Some(order_by_positions) = order_by_positions {
+            let order_by = order_by_positions.into_iter().map(|(ix, ordering)| (qblock.select_list[ix].expr_key, ordering)).collect();
+            qblock.order_by = Some(order_by);
+        }
+
+        // Resolve predicates. EXISTS/IN subquery predicates are set aside, to be unnested once everything else has been resolved.
+        let mut subquery_preds = vec![];
         if let Some(pred_list) = qblock.pred_list.as_ref() {
-            let mut boolean_factors = vec![];
+            let mut unresolved_factors = vec![];
             for &expr_key in pred_list {
-                qblock.resolve_expr(env, expr_graph, metadata, expr_key, is_group_by)?;
-                expr_key.get_boolean_factors(expr_graph, &mut boolean_factors)
+                expr_key.get_boolean_factors(expr_graph, &mut unresolved_factors)
+            }
+
+            let mut boolean_factors = vec![];
+            for expr_key in unresolved_factors {
+                if !is_group_by && Self::parse_subquery_pred(expr_graph, expr_key).is_some() {
+                    subquery_preds.push(expr_key);
+                } else {
+                    qblock.resolve_expr(outer_scope, env, expr_graph, metadata, expr_key, is_group_by)?;
+                    boolean_factors.push(expr_key);
+                }
             }
             qblock.pred_list = Some(boolean_factors);
         }
 
+        // Resolve ON clauses. Each clause can only refer to quantifiers that participate in its join.
+        let mut on_clauses = vec![];
+        for join in qblock.joins.iter() {
+            let mut boolean_factors = vec![];
+            for &expr_key in join.on_clause.iter() {
+                qblock.resolve_expr(outer_scope, env, expr_graph, metadata, expr_key, false)?;
+                if expr_key.iter_quns(expr_graph).any(|qunid| !join.quns().contains(&qunid)) {
+                    return Err("ON clause references a table that is not part of its join.".to_string());
+                }
+                expr_key.get_boolean_factors(expr_graph, &mut boolean_factors)
+            }
+            on_clauses.push(boolean_factors);
+        }
+        for (join, on_clause) in qblock.joins.iter_mut().zip(on_clauses) {
+            join.on_clause = on_clause;
+        }
+
         // Resolve group-by
         if let Some(group_by) = qblock.group_by.as_ref() {
             for &expr_key in group_by.iter() {
-                qblock.resolve_expr(env, expr_graph, metadata, expr_key, false)?;
+                qblock.resolve_expr(outer_scope, env, expr_graph, metadata, expr_key, false)?;
             }
         }
 
@@ -106,26 +226,608 @@ impl QueryBlock {
         if let Some(having_clause) = qblock.having_clause.as_ref() {
             let mut boolean_factors = vec![];
             for &expr_key in having_clause {
-                qblock.resolve_expr(env, expr_graph, metadata, expr_key, is_group_by)?;
+                qblock.resolve_expr(outer_scope, env, expr_graph, metadata, expr_key, is_group_by)?;
                 expr_key.get_boolean_factors(expr_graph, &mut boolean_factors)
             }
             qblock.having_clause = Some(boolean_factors);
         }
 
+        // Unnest subquery predicates into semi/anti joins
+        if !subquery_preds.is_empty() {
+            Self::unnest_subquery_preds(qbkey, outer_scope, subquery_preds, env, qgm)?;
+        }
+
         info!("Resolved qblock id: {}", qblock_id);
 
-        let qdesc = qblock.get_projection(expr_graph);
+        let qblock = qgm.qblock_graph.get_value(qbkey);
+        let qdesc = qblock.get_projection(&qgm.expr_graph);
 
         Ok(qdesc)
     }
 
+    // resolve_setop: Resolve the branches of a UNION/INTERSECT/EXCEPT and harmonize the types of their columns. The set operation
+    // outputs its columns by position, under the names of the first branch.
+    fn resolve_setop(qbkey: QueryBlockKey, env: &Env, qgm: &mut QGM) -> Result<Rc<dyn TableDesc>, String> {
+        let qblock = qgm.qblock_graph.get_value(qbkey);
+        let branches = qblock.quns.iter().map(|qun| qun.get_qblock_key().unwrap()).collect::<Vec<_>>();
+        for branch_key in branches {
+            Self::resolve(branch_key, env, qgm)?;
+        }
+        Self::harmonize_setop_branches(qbkey, qgm)
+    }
+
+    // resolve_recursive_cte: A recursive CTE is the UNION ALL of an anchor query block, and of a recursive query block that refers to
+    // the CTE once. The anchor is resolved first and fixes the names and types of the CTE columns, which the recursive query block sees.
+    fn resolve_recursive_cte(qbkey: QueryBlockKey, name: String, env: &Env, qgm: &mut QGM) -> Result<(), String> {
+        let errstr = f!("Recursive CTE {} must be a UNION ALL of an anchor query block and of a recursive query block that refers to it once.", enquote(&name));
+        let qblock = qgm.qblock_graph.get_value(qbkey);
+        if qblock.qbtype != QueryBlockType::UnionAll {
+            return Err(errstr);
+        }
+        let (anchor_key, recursive_key) = (qblock.quns[0].get_qblock_key().unwrap(), qblock.quns[1].get_qblock_key().unwrap());
+        if Self::refers_to(&qgm.qblock_graph, anchor_key, &name) {
+            return Err(errstr);
+        }
+
+        let anchor_desc = Self::resolve(anchor_key, env, qgm)?;
+        qgm.metadata.add_cte(name.clone(), qbkey, Rc::clone(&anchor_desc));
+        Self::resolve(recursive_key, env, qgm)?;
+
+        // The one reference has to be a quantifier of the recursive query block itself, which can't aggregate
+        let recursive_qblock = qgm.qblock_graph.get_value(recursive_key);
+        let nrefs = qgm.iter_qblocks().flat_map(|qbkey| qgm.qblock_graph.get_value(qbkey).quns.iter()).filter(|qun| qun.get_qblock_key() == Some(qbkey)).count();
+        if recursive_qblock.qbtype != QueryBlockType::Select || nrefs != 1 || !recursive_qblock.quns.iter().any(|qun| qun.get_qblock_key() == Some(qbkey)) {
+            return Err(errstr);
+        }
+
+        let qdesc = Self::harmonize_setop_branches(qbkey, qgm)?;
+        for (colid, (field, anchor_field)) in qdesc.fields().iter().zip(anchor_desc.fields().iter()).enumerate() {
+            if field.data_type() != anchor_field.data_type() {
+                return Err(f!(
+                    "Column {} of recursive CTE {} has type {:?} in its anchor query block but {:?} in its recursive query block.",
+                    colid + 1,
+                    enquote(&name),
+                    anchor_field.data_type(),
+                    field.data_type()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // refers_to: Does a query block (or any of its derived tables) read a table with the given name?
+    fn refers_to(qblock_graph: &QueryBlockGraph, qbkey: QueryBlockKey, name: &String) -> bool {
+        fn qun_refers_to(qblock_graph: &QueryBlockGraph, qun: &Quantifier, name: &String) -> bool {
+            if let Some(ansi_join) = qun.get_ansi_join() {
+                qun_refers_to(qblock_graph, &ansi_join.left, name) || qun_refers_to(qblock_graph, &ansi_join.right, name)
+            } else if let Some(qbkey) = qun.get_qblock_key() {
+                QueryBlock::refers_to(qblock_graph, qbkey, name)
+            } else {
+                qun.get_basename() == Some(name)
+            }
+        }
+        qblock_graph.get_value(qbkey).quns.iter().any(|qun| qun_refers_to(qblock_graph, qun, name))
+    }
+
+    // harmonize_setop_branches: Harmonize the types of the columns of resolved set operation branches
+    fn harmonize_setop_branches(qbkey: QueryBlockKey, qgm: &mut QGM) -> Result<Rc<dyn TableDesc>, String> {
+        let qblock = qgm.qblock_graph.get_value(qbkey);
+        let branches = qblock.quns.iter().map(|qun| (qun.id, qun.get_qblock_key().unwrap())).collect::<Vec<_>>();
+
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let (lhs_key, rhs_key) = (branches[0].1, branches[1].1);
+        let ncols = qblock_graph.get_value(lhs_key).select_list.len();
+        if qblock_graph.get_value(rhs_key).select_list.len() != ncols {
+            return Err("Query blocks of a set operation must have the same number of columns.".to_string());
+        }
+
+        for colid in 0..ncols {
+            let children = vec![qblock_graph.get_value(lhs_key).select_list[colid].expr_key, qblock_graph.get_value(rhs_key).select_list[colid].expr_key];
+            let children_datatypes = children.iter().map(|expr_key| expr_key.get_data_type(expr_graph).clone()).collect::<Vec<_>>();
+            if children_datatypes[0] == children_datatypes[1] {
+                continue;
+            }
+            let numeric = |datatype: &DataType| DATATYPE_PROPS.contains_key(datatype) && is_numeric(datatype);
+            if !(numeric(&children_datatypes[0]) && numeric(&children_datatypes[1])) {
+                return Err(f!("Set operation column {} has mismatched types: {:?} vs {:?}", colid + 1, children_datatypes[0], children_datatypes[1]));
+            }
+
+            // The branch with the lower ranked type gets upcast. Literals are cast right away.
+            let to_datatype = children_datatypes.iter().max_by_key(|datatype| get_rank(datatype)).unwrap();
+            for ((&(_, branch_key), &expr_key), from_datatype) in branches.iter().zip(children.iter()).zip(children_datatypes.iter()) {
+                if from_datatype != to_datatype {
+                    let props = ExprProp::new(to_datatype.clone());
+                    let cast_key = if let Literal(_) = expr_graph.get_value(expr_key) {
+                        let value = Self::resolve_cast(expr_graph.get_value(expr_key), to_datatype)?;
+                        expr_graph.add_node_with_props(Literal(value), props, None)
+                    } else {
+                        expr_graph.add_node_with_props(Cast, props, Some(vec![expr_key]))
+                    };
+                    qblock_graph.get_mut(branch_key).value.select_list[colid].expr_key = cast_key;
+                }
+            }
+        }
+
+        for &(qunid, branch_key) in branches.iter() {
+            metadata.add_tabledesc(qunid, qblock_graph.get_value(branch_key).get_projection(expr_graph));
+        }
+
+        // Unaliased columns of the first branch keep their own name, so that enclosing blocks can refer to them
+        let lhs_qunid = branches[0].0;
+        let select_list = (qblock_graph.get_value(lhs_key).select_list.iter().enumerate())
+            .map(|(colid, ne)| {
+                let alias = match (&ne.alias, expr_graph.get_value(ne.expr_key)) {
+                    (None, Column { colname, .. }) => Some(colname.clone()),
+                    _ => ne.alias.clone(),
+                };
+                let props = ExprProp::new(ne.expr_key.get_data_type(expr_graph).clone());
+                NamedExpr::new(alias, expr_graph.add_node_with_props(CID(lhs_qunid, colid), props, None))
+            })
+            .collect::<Vec<_>>();
+
+        let qblock = &mut qblock_graph.get_mut(qbkey).value;
+        qblock.select_list = select_list;
+        Ok(qblock.get_projection(expr_graph))
+    }
+
+    // parse_subquery_pred: Decompose `[NOT] EXISTS (subquery)` and `expr [NOT] IN (subquery)` into (negated, IN-expression, subquery)
+    fn parse_subquery_pred(expr_graph: &ExprGraph, expr_key: ExprKey) -> Option<(bool, Option<ExprKey>, QueryBlockKey)> {
+        let (expr, _, children) = expr_graph.get3(expr_key);
+        match expr {
+            LogExpr(LogOp::Not) => {
+                let child_key = children.unwrap()[0];
+                Self::parse_subquery_pred(expr_graph, child_key).map(|(negated, in_expr_key, subq_key)| (!negated, in_expr_key, subq_key))
+            }
+            ExistsExpr => {
+                let children = children.unwrap();
+                if let Subquery(subq_key) = expr_graph.get_value(children[0]) {
+                    Some((false, None, *subq_key))
+                } else {
+                    None
+                }
+            }
+            InSubqExpr => {
+                let children = children.unwrap();
+                if let Subquery(subq_key) = expr_graph.get_value(children[1]) {
+                    Some((false, Some(children[0]), *subq_key))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // unnest_subquery_preds: Decorrelate EXISTS/IN subquery predicates into semi-joins, and their negations into anti-joins. All subqueries
+    // are resolved against this query block before any of them is pulled up into it.
+    fn unnest_subquery_preds(qbkey: QueryBlockKey, outer_scope: &[&QueryBlock], subquery_preds: Vec<ExprKey>, env: &Env, qgm: &mut QGM) -> Result<(), String> {
+        // Take this query block out of the graph while its subqueries are resolved against it
+        let mut qblock = replace(&mut qgm.qblock_graph.get_mut(qbkey).value, QueryBlock::new0(0, QueryBlockType::Select));
+
+        let mut subqueries = vec![];
+        for pred_key in subquery_preds {
+            let (negated, in_expr_key, subq_key) = Self::parse_subquery_pred(&qgm.expr_graph, pred_key).unwrap();
+            let subq = qgm.qblock_graph.get_value(subq_key);
+            let has_aggs = subq.select_list.iter().any(|ne| qgm.expr_graph.true_iter(ne.expr_key).any(|expr_key| matches!(qgm.expr_graph.get_value(expr_key), AggFunction(..))));
+
+            // Without a GROUP BY or HAVING clause, an aggregate subquery returns exactly one row. EXISTS always holds.
+            if has_aggs && subq.group_by.is_none() && subq.having_clause.is_none() && !negated && in_expr_key.is_none() {
+                qgm.qblock_graph.get_mut(subq_key).value = QueryBlock::new0(subq.id, QueryBlockType::Select);
+                continue;
+            }
+
+            if let Some(in_expr_key) = in_expr_key {
+                qblock.resolve_expr(outer_scope, env, &mut qgm.expr_graph, &mut qgm.metadata, in_expr_key, false)?;
+            }
+            let scope = std::iter::once(&qblock).chain(outer_scope.iter().cloned()).collect::<Vec<_>>();
+            Self::resolve_in_scope(subq_key, &scope, env, qgm)?;
+            subqueries.push((negated, in_expr_key, subq_key));
+        }
+
+        for (negated, in_expr_key, subq_key) in subqueries {
+            if qgm.qblock_graph.get_value(subq_key).qbtype == QueryBlockType::GroupBy {
+                qblock.unnest_grouped_subquery(negated, in_expr_key, subq_key, qgm)?;
+            } else {
+                qblock.unnest_subquery(negated, in_expr_key, subq_key, qgm)?;
+            }
+        }
+
+        qgm.qblock_graph.get_mut(qbkey).value = qblock;
+        Ok(())
+    }
+
+    // unnest_subquery: Pull up the quantifiers of a resolved subquery into this query block. Subquery predicates that are local to it
+    // become WHERE predicates while predicates that reference this query block become the ON clause of a semi/anti join. IN compares the
+    // IN-expression with the subquery's only column; for NOT IN, that comparison has to be NULL-aware.
+    fn unnest_subquery(&mut self, negated: bool, in_expr_key: Option<ExprKey>, subq_key: QueryBlockKey, qgm: &mut QGM) -> Result<(), String> {
+        let (qblock_graph, expr_graph, _) = qgm.borrow_parts();
+        let subq_id = qblock_graph.get_value(subq_key).id;
+        let subq = replace(&mut qblock_graph.get_mut(subq_key).value, QueryBlock::new0(subq_id, QueryBlockType::Select));
+
+        let subq_quns = subq.quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+        let visible_quns = self.quns.iter().map(|qun| qun.id).chain(subq_quns.iter().cloned()).collect::<Vec<_>>();
+
+        let mut local_preds = vec![];
+        let mut on_clause = vec![];
+        for &pred_key in subq.pred_list.iter().flatten() {
+            if pred_key.iter_quns(expr_graph).all(|qunid| subq_quns.contains(&qunid)) {
+                local_preds.push(pred_key);
+            } else if pred_key.iter_quns(expr_graph).all(|qunid| visible_quns.contains(&qunid)) {
+                on_clause.push(pred_key);
+            } else {
+                return Err("Subqueries can only reference columns of their immediately enclosing query block.".to_string());
+            }
+        }
+
+        let mut null_aware_pred = None;
+        if let Some(in_expr_key) = in_expr_key {
+            if subq.select_list.len() != 1 {
+                return Err("IN subquery must return exactly one column.".to_string());
+            }
+            let eq_pred_key = Self::in_subquery_eq_pred(in_expr_key, subq.select_list[0].expr_key, expr_graph)?;
+            on_clause.push(eq_pred_key);
+            if negated {
+                null_aware_pred = Some(eq_pred_key);
+            }
+        }
+
+        // Semi/anti joins are stacked in order: each one's LHS covers everything pulled up before it
+        let join_type = if negated { JoinType::Anti } else { JoinType::Semi };
+        let lhs_quns = self.quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+        self.quns.extend(subq.quns);
+        self.joins.extend(subq.joins);
+        self.joins.push(JoinDesc { join_type, lhs_quns, rhs_quns: subq_quns, on_clause, null_aware_pred });
+        self.pred_list.get_or_insert_with(Vec::new).extend(local_preds);
+        Ok(())
+    }
+
+    // unnest_grouped_subquery: Join an aggregating subquery as a derived table under the semi/anti join, since its quantifiers cannot be
+    // pulled up past the aggregation. Correlation predicates must be equalities, as in scalar subqueries: their inner sides become extra
+    // grouping keys, which the ON clause compares with their outer sides.
+    fn unnest_grouped_subquery(&mut self, negated: bool, in_expr_key: Option<ExprKey>, subq_key: QueryBlockKey, qgm: &mut QGM) -> Result<(), String> {
+        let (qblock_graph, expr_graph, _) = qgm.borrow_parts();
+        let subq = qblock_graph.get_value(subq_key);
+        let (agg_qunid, select_qbkey) = (subq.quns[0].id, subq.quns[0].get_qblock_key().unwrap());
+        let self_quns = self.quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+
+        // The select-list and HAVING clause only see the aggregation
+        let exprs = subq.select_list.iter().map(|ne| ne.expr_key).chain(subq.pred_list.iter().flatten().cloned()).collect::<Vec<_>>();
+        let is_local = exprs.into_iter().all(|expr_key| expr_key.iter_quns(expr_graph).all(|qunid| qunid == agg_qunid));
+        let correlation_preds = match Self::correlation_preds(select_qbkey, &self_quns, qblock_graph, expr_graph)? {
+            Some(correlation_preds) if is_local => correlation_preds,
+            _ => {
+                let errstr = "Subqueries with GROUP BY or aggregate functions can only reference columns of enclosing query blocks in their WHERE clause.";
+                return Err(errstr.to_string());
+            }
+        };
+        let nvalues = subq.select_list.len();
+        if in_expr_key.is_some() && nvalues != 1 {
+            return Err("IN subquery must return exactly one column.".to_string());
+        }
+
+        let qunid = expr_graph.next_id();
+        let derived_column = |colid: ColId, datatype: DataType, expr_graph: &mut ExprGraph| {
+            let expr = Column { prefix: None, colname: f!("${}.{}", qunid, colid), qunid, colid };
+            expr_graph.add_node_with_props(expr, ExprProp::new(datatype), None)
+        };
+
+        let mut on_clause = vec![];
+        if !correlation_preds.is_empty() {
+            // Without a GROUP BY, an aggregate over no rows still returns a row. It would be lost once grouped on the correlation keys.
+            if subq.group_by.as_ref().map_or(true, Vec::is_empty) {
+                return Err("Correlated subqueries with aggregate functions must have a GROUP BY clause.".to_string());
+            }
+            if subq.grouping_sets.is_some() {
+                return Err("Correlated subqueries with grouping sets are not supported.".to_string());
+            }
+            let correlation_keys = Self::group_by_correlation_keys(subq_key, &correlation_preds, &self_quns, qgm)?;
+
+            // Outer sides get compared with the grouping keys, which follow the subquery's own columns in the derived table
+            let expr_graph = &mut qgm.expr_graph;
+            for (cid, &(inner_key, outer_key)) in correlation_keys.iter().enumerate() {
+                let derived_key = derived_column(nvalues + cid, inner_key.get_data_type(expr_graph).clone(), expr_graph);
+                on_clause.push(expr_graph.add_node_with_props(RelExpr(RelOp::Eq), ExprProp::new(DataType::Boolean), Some(vec![outer_key, derived_key])));
+            }
+        }
+
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let qun = Self::new_derived_qun(qunid, subq_key, qblock_graph, expr_graph, metadata);
+
+        // IN compares the IN-expression with the derived table's first column; for NOT IN, that comparison has to be NULL-aware
+        let mut null_aware_pred = None;
+        if let Some(in_expr_key) = in_expr_key {
+            let datatype = qblock_graph.get_value(subq_key).select_list[0].expr_key.get_data_type(expr_graph).clone();
+            let subq_expr_key = derived_column(0, datatype, expr_graph);
+            let eq_pred_key = Self::in_subquery_eq_pred(in_expr_key, subq_expr_key, expr_graph)?;
+            on_clause.push(eq_pred_key);
+            if negated {
+                null_aware_pred = Some(eq_pred_key);
+            }
+        }
+
+        let join_type = if negated { JoinType::Anti } else { JoinType::Semi };
+        self.quns.push(qun);
+        self.joins.push(JoinDesc { join_type, lhs_quns: self_quns, rhs_quns: vec![qunid], on_clause, null_aware_pred });
+        Ok(())
+    }
+
+    // in_subquery_eq_pred: Equality between the IN-expression and the column returned by its subquery
+    fn in_subquery_eq_pred(in_expr_key: ExprKey, subq_expr_key: ExprKey, expr_graph: &mut ExprGraph) -> Result<ExprKey, String> {
+        let (lhs_datatype, rhs_datatype) = (in_expr_key.get_data_type(expr_graph), subq_expr_key.get_data_type(expr_graph));
+        if lhs_datatype != rhs_datatype {
+            return Err(f!("Datatype mismatch: {:?} vs {:?}  ({}:{})", lhs_datatype, rhs_datatype, file!(), line!()));
+        }
+        Ok(expr_graph.add_node_with_props(RelExpr(RelOp::Eq), ExprProp::new(DataType::Boolean), Some(vec![in_expr_key, subq_expr_key])))
+    }
+
+    // unnest_scalar_subqueries: Replace scalar subqueries in the select-list and WHERE clause by references to derived tables that get
+    // joined with this query block. EXISTS/IN subqueries are left alone; they are unnested once the WHERE clause has been resolved.
+    fn unnest_scalar_subqueries(qbkey: QueryBlockKey, outer_scope: &[&QueryBlock], env: &Env, qgm: &mut QGM) -> Result<(), String> {
+        let qblock = qgm.qblock_graph.get_value(qbkey);
+        let mut subq_expr_keys = vec![];
+        for expr_key in qblock.select_list.iter().map(|ne| ne.expr_key).chain(qblock.pred_list.iter().flatten().cloned()) {
+            Self::collect_scalar_subqueries(&qgm.expr_graph, expr_key, &mut subq_expr_keys);
+        }
+        if subq_expr_keys.is_empty() {
+            return Ok(());
+        }
+
+        // Take this query block out of the graph while its subqueries are resolved against it
+        let mut qblock = replace(&mut qgm.qblock_graph.get_mut(qbkey).value, QueryBlock::new0(0, QueryBlockType::Select));
+        for subq_expr_key in subq_expr_keys {
+            let Subquery(subq_key) = *qgm.expr_graph.get_value(subq_expr_key) else { panic!("ugh") };
+            // Look for COUNT before AVG gets rewritten into SUM/COUNT
+            let subq = qgm.qblock_graph.get_value(subq_key);
+            let is_count = |expr_key: ExprKey| matches!(qgm.expr_graph.get_value(expr_key), AggFunction(AggType::COUNT, ..));
+            let has_count = subq.select_list.iter().any(|ne| qgm.expr_graph.true_iter(ne.expr_key).any(is_count));
+            let counts = has_count.then(|| subq.select_list.first().is_some_and(|ne| is_count(ne.expr_key)));
+
+            let scope = std::iter::once(&qblock).chain(outer_scope.iter().cloned()).collect::<Vec<_>>();
+            Self::resolve_in_scope(subq_key, &scope, env, qgm)?;
+            qblock.unnest_scalar_subquery(subq_expr_key, subq_key, counts, qgm)?;
+        }
+
+        qgm.qblock_graph.get_mut(qbkey).value = qblock;
+        Ok(())
+    }
+
+    fn collect_scalar_subqueries(expr_graph: &ExprGraph, expr_key: ExprKey, subq_expr_keys: &mut Vec<ExprKey>) {
+        let (expr, _, children) = expr_graph.get3(expr_key);
+        match expr {
+            Subquery(_) => {
+                if !subq_expr_keys.contains(&expr_key) {
+                    subq_expr_keys.push(expr_key)
+                }
+            }
+            ExistsExpr | InSubqExpr => {}
+            _ => {
+                for &child_key in children.into_iter().flatten() {
+                    Self::collect_scalar_subqueries(expr_graph, child_key, subq_expr_keys)
+                }
+            }
+        }
+    }
+
+    // unnest_scalar_subquery: Turn a resolved scalar subquery into a derived table whose first column replaces the subquery expression.
+    // An uncorrelated subquery is joined as-is, and it is a runtime error for it to return more than one row. A correlated subquery
+    // must be an aggregation whose correlation predicates are equalities: their inner sides become grouping keys of the aggregation and
+    // their outer sides get LEFT joined to those keys. `counts` is set if the subquery uses COUNT, and tells whether COUNT is its value.
+    fn unnest_scalar_subquery(&mut self, subq_expr_key: ExprKey, subq_key: QueryBlockKey, counts: Option<bool>, qgm: &mut QGM) -> Result<(), String> {
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let subq = qblock_graph.get_value(subq_key);
+        if subq.select_list.len() != 1 {
+            return Err("Scalar subquery must return exactly one column.".to_string());
+        }
+
+        // Correlation predicates sit in the WHERE clause, below the aggregation if there is one
+        let is_agg = subq.qbtype == QueryBlockType::GroupBy;
+        let select_qbkey = if is_agg { subq.quns[0].get_qblock_key().unwrap() } else { subq_key };
+        let self_quns = self.quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+        let Some(correlation_preds) = Self::correlation_preds(select_qbkey, &self_quns, qblock_graph, expr_graph)? else {
+            return Err("Scalar subqueries can only reference columns of enclosing query blocks in their WHERE clause.".to_string());
+        };
+
+        let qunid = expr_graph.next_id();
+        let derived_column = |colid: ColId, datatype: DataType, expr_graph: &mut ExprGraph| {
+            let expr = Column { prefix: None, colname: f!("${}.{}", qunid, colid), qunid, colid };
+            expr_graph.add_node_with_props(expr, ExprProp::new(datatype), None)
+        };
+
+        let (join_type, on_clause) = if correlation_preds.is_empty() {
+            (JoinType::Scalar, vec![])
+        } else {
+            if !is_agg {
+                return Err("Correlated scalar subqueries must compute an aggregate.".to_string());
+            }
+            if subq.group_by.as_ref().is_some_and(|group_by| !group_by.is_empty()) {
+                return Err("Correlated scalar subqueries with a GROUP BY clause are not supported.".to_string());
+            }
+            // COUNT over no rows is 0, not the NULL an outer join produces. A COUNT value gets fixed up below, but not one inside an expression.
+            if counts == Some(false) {
+                return Err("COUNT is only supported as the value of a correlated scalar subquery, not inside an expression.".to_string());
+            }
+
+            let correlation_keys = Self::group_by_correlation_keys(subq_key, &correlation_preds, &self_quns, qgm)?;
+
+            // Outer sides get joined to the grouping keys, which follow the subquery's value in the derived table
+            let expr_graph = &mut qgm.expr_graph;
+            let mut on_clause = vec![];
+            for (cid, &(inner_key, outer_key)) in correlation_keys.iter().enumerate() {
+                let derived_key = derived_column(1 + cid, inner_key.get_data_type(expr_graph).clone(), expr_graph);
+                on_clause.push(expr_graph.add_node_with_props(RelExpr(RelOp::Eq), ExprProp::new(DataType::Boolean), Some(vec![outer_key, derived_key])));
+            }
+            (JoinType::Left, on_clause)
+        };
+
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let qun = Self::new_derived_qun(qunid, subq_key, qblock_graph, expr_graph, metadata);
+        let subq = qblock_graph.get_value(subq_key);
+
+        // The subquery expression itself turns into the derived table's first column. Outer rows without a match get a NULL value,
+        // which COALESCE(value, 0) turns into the count of no rows.
+        let datatype = subq.select_list[0].expr_key.get_data_type(expr_graph).clone();
+        let value_expr = Column { prefix: None, colname: f!("${}.0", qunid), qunid, colid: 0 };
+        let (value_expr, children) = if join_type == JoinType::Left && counts.is_some() {
+            let value_key = expr_graph.add_node_with_props(value_expr, ExprProp::new(datatype.clone()), None);
+            let zero_key = expr_graph.add_node_with_props(Literal(Datum::Int64(0)), ExprProp::new(DataType::Int64), None);
+            (CoalesceExpr, Some(vec![value_key, zero_key]))
+        } else {
+            (value_expr, None)
+        };
+        let node = expr_graph.get_mut(subq_expr_key);
+        node.value = value_expr;
+        node.properties = ExprProp::new(datatype);
+        node.children = children;
+
+        self.quns.push(qun);
+        self.joins.push(JoinDesc { join_type, lhs_quns: self_quns, rhs_quns: vec![qunid], on_clause, null_aware_pred: None });
+        Ok(())
+    }
+
+    // correlation_preds: Predicates of a subquery's WHERE clause that reference the enclosing query block, or None if the subquery
+    // references it anywhere else
+    fn correlation_preds(
+        select_qbkey: QueryBlockKey, self_quns: &[QunId], qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph,
+    ) -> Result<Option<Vec<ExprKey>>, String> {
+        let select_qb = qblock_graph.get_value(select_qbkey);
+        let subq_quns = select_qb.quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+        let is_inner = |expr_key: ExprKey| expr_key.iter_quns(expr_graph).all(|qunid| subq_quns.contains(&qunid));
+
+        if select_qb.select_list.iter().any(|ne| !is_inner(ne.expr_key)) {
+            return Ok(None);
+        }
+
+        let mut correlation_preds = vec![];
+        for &pred_key in select_qb.pred_list.iter().flatten() {
+            if is_inner(pred_key) {
+                continue;
+            } else if pred_key.iter_quns(expr_graph).all(|qunid| subq_quns.contains(&qunid) || self_quns.contains(&qunid)) {
+                correlation_preds.push(pred_key);
+            } else {
+                return Err("Subqueries can only reference columns of their immediately enclosing query block.".to_string());
+            }
+        }
+        Ok(Some(correlation_preds))
+    }
+
+    // group_by_correlation_keys: Decorrelate the aggregation of a subquery whose WHERE clause has correlation predicates. Each one must be an
+    // equality whose inner side becomes a leading grouping key, appended to the subquery's select-list too. Returns the (inner, outer)
+    // sides of the predicates, in the order of those keys.
+    fn group_by_correlation_keys(
+        subq_key: QueryBlockKey, correlation_preds: &[ExprKey], self_quns: &[QunId], qgm: &mut QGM,
+    ) -> Result<Vec<(ExprKey, ExprKey)>, String> {
+        let (qblock_graph, expr_graph, metadata) = qgm.borrow_parts();
+        let agg_qun = &qblock_graph.get_value(subq_key).quns[0];
+        let (select_qbkey, agg_qunid) = (agg_qun.get_qblock_key().unwrap(), agg_qun.id);
+        let subq_quns = qblock_graph.get_value(select_qbkey).quns.iter().map(|qun| qun.id).collect::<Vec<_>>();
+        let is_inner = |expr_graph: &ExprGraph, expr_key: ExprKey| expr_key.iter_quns(expr_graph).all(|qunid| subq_quns.contains(&qunid));
+        let is_outer = |expr_graph: &ExprGraph, expr_key: ExprKey| expr_key.iter_quns(expr_graph).all(|qunid| self_quns.contains(&qunid));
+
+        // Split each correlation predicate into its (inner, outer) sides
+        let mut correlation_keys = vec![];
+        for &pred_key in correlation_preds.iter() {
+            let (expr, _, children) = expr_graph.get3(pred_key);
+            let (lhs_key, rhs_key) = match (expr, children) {
+                (RelExpr(RelOp::Eq), Some(children)) => (children[0], children[1]),
+                _ => return Err("Correlated subqueries only support equality predicates on outer columns.".to_string()),
+            };
+            if is_inner(expr_graph, lhs_key) && is_outer(expr_graph, rhs_key) {
+                correlation_keys.push((lhs_key, rhs_key));
+            } else if is_inner(expr_graph, rhs_key) && is_outer(expr_graph, lhs_key) {
+                correlation_keys.push((rhs_key, lhs_key));
+            } else {
+                return Err("Correlated subqueries only support equality predicates on outer columns.".to_string());
+            }
+        }
+        let nkeys = correlation_keys.len();
+
+        // Inner sides of the correlation predicates become leading grouping keys
+        let select_qb = &mut qblock_graph.get_mut(select_qbkey).value;
+        if let Some(pred_list) = select_qb.pred_list.as_mut() {
+            pred_list.retain(|pred_key| !correlation_preds.contains(pred_key));
+        }
+        select_qb.select_list.splice(0..0, correlation_keys.iter().map(|&(inner_key, _)| NamedExpr::new(None, inner_key)));
+        metadata.add_tabledesc(agg_qunid, select_qb.get_projection(expr_graph));
+
+        // Existing references to the aggregation's input, including any GROUP-BY keys, move past the new keys
+        let subq = &mut qblock_graph.get_mut(subq_key).value;
+        let exprs = subq.select_list.iter().map(|ne| ne.expr_key).chain(subq.pred_list.iter().flatten().cloned());
+        let cid_keys = (exprs.chain(subq.group_by.iter().flatten().cloned()))
+            .flat_map(|expr_key| expr_graph.true_iter(expr_key).collect::<Vec<_>>())
+            .filter(|&expr_key| matches!(expr_graph.get_value(expr_key), CID(..)))
+            .unique()
+            .collect::<Vec<_>>();
+        for expr_key in cid_keys {
+            if let CID(_, colid) = &mut expr_graph.get_mut(expr_key).value {
+                *colid += nkeys
+            }
+        }
+
+        let mut group_by = vec![];
+        for (cid, &(inner_key, _)) in correlation_keys.iter().enumerate() {
+            let props = ExprProp::new(inner_key.get_data_type(expr_graph).clone());
+            group_by.push(expr_graph.add_node_with_props(CID(agg_qunid, cid), props.clone(), None));
+            let select_expr_key = expr_graph.add_node_with_props(CID(agg_qunid, cid), props, None);
+            subq.select_list.push(NamedExpr::new(None, select_expr_key));
+        }
+        group_by.extend(subq.group_by.take().into_iter().flatten());
+        subq.group_by = Some(group_by);
+        Ok(correlation_keys)
+    }
+
+    // new_derived_qun: Quantifier that joins a subquery as a derived table. Its columns have synthetic names that cannot clash with
+    // user-visible ones.
+    fn new_derived_qun(
+        qunid: QunId, subq_key: QueryBlockKey, qblock_graph: &QueryBlockGraph, expr_graph: &ExprGraph, metadata: &mut QGMMetadata,
+    ) -> Quantifier {
+        let subq = qblock_graph.get_value(subq_key);
+        let fields = (subq.select_list.iter().enumerate())
+            .map(|(colid, ne)| Field::new(f!("${}.{}", qunid, colid), ne.expr_key.get_data_type(expr_graph).clone(), true))
+            .collect::<Vec<_>>();
+        let qdesc: Rc<dyn TableDesc> = Rc::new(QueryDesc::new(fields));
+        metadata.add_tabledesc(qunid, Rc::clone(&qdesc));
+        let mut qun = Quantifier::new_qblock(qunid, subq_key, None);
+        qun.tabledesc = Some(qdesc);
+        qun
+    }
+
+    // flatten_ansi_joins: Replace ANSI join quantifiers by their leaf quantifiers. Every join is recorded along with the leaves on
+    // either side of it, nested joins ahead of the joins that contain them.
+    pub fn flatten_ansi_joins(&mut self) {
+        let quns = replace(&mut self.quns, vec![]);
+        for qun in quns.into_iter() {
+            self.flatten_ansi_join(qun);
+        }
+    }
+
+    fn flatten_ansi_join(&mut self, qun: Quantifier) -> Vec<QunId> {
+        match qun.into_ansi_join() {
+            Ok(AnsiJoin { join_type, left, right, on_clause }) => {
+                let lhs_quns = self.flatten_ansi_join(*left);
+                let rhs_quns = self.flatten_ansi_join(*right);
+                let quns = lhs_quns.iter().chain(rhs_quns.iter()).cloned().collect();
+                self.joins.push(JoinDesc { join_type, lhs_quns, rhs_quns, on_clause: vec![on_clause], null_aware_pred: None });
+                quns
+            }
+            Err(qun) => {
+                let qunid = qun.id;
+                self.quns.push(qun);
+                vec![qunid]
+            }
+        }
+    }
+
     pub fn get_projection(&self, expr_graph: &ExprGraph) -> Rc<dyn TableDesc> {
         let fields = self
             .select_list
             .iter()
             .map(|ne| {
                 let expr_key = ne.expr_key;
-                let name = ne.get_name();
+                // Unaliased columns keep their own name so that enclosing blocks can refer to them
+                let name = match (&ne.alias, expr_graph.get_value(expr_key)) {
+                    (None, Expr::Column { colname, .. }) => colname.clone(),
+                    _ => ne.get_name(),
+                };
                 let typ = expr_key.get_data_type(expr_graph);
 
                 Field::new(name, typ.clone(), false)
@@ -135,6 +837,42 @@ impl QueryBlock {
         qdesc
     }
 
+    // resolve_order_by: Map every ORDER BY entry to a select-list position. An entry can be a 1-based position, a select-list alias or
+    // an arbitrary expression. Expressions that don't already appear in the select-list are appended to it as hidden columns.
+    pub fn resolve_order_by(&mut self, expr_graph: &ExprGraph) -> Result<Option<Vec<(usize, Ordering)>>, String> {
+        let Some(order_by) = self.order_by.as_ref() else { return Ok(None) };
+        let mut positions = vec![];
+
+        for &(expr_key, ordering) in order_by.iter() {
+            let expr = expr_graph.get_value(expr_key);
+            let ix = if let Literal(Int64(pos)) = expr {
+                let pos = *pos;
+                if pos < 1 || pos as usize > self.select_list.len() - self.nhidden {
+                    return Err(f!("ORDER BY position {pos} is not in the select-list."));
+                }
+                pos as usize - 1
+            } else {
+                let alias_ix = if let Column { prefix: None, colname, .. } = expr {
+                    self.select_list.iter().position(|ne| ne.alias.as_ref() == Some(colname))
+                } else {
+                    None
+                };
+                let select_ix = alias_ix.or_else(|| self.select_list.iter().position(|ne| Expr::isomorphic(expr_graph, expr_key, ne.expr_key)));
+                if let Some(select_ix) = select_ix {
+                    select_ix
+                } else if matches!(self.distinct, DistinctProperty::Distinct) {
+                    return Err(f!("ORDER BY expressions of a SELECT DISTINCT must appear in its select-list."));
+                } else {
+                    self.select_list.push(NamedExpr::new(None, expr_key));
+                    self.nhidden += 1;
+                    self.select_list.len() - 1
+                }
+            };
+            positions.push((ix, ordering));
+        }
+        Ok(Some(positions))
+    }
+
     pub fn split_groupby(qbkey: QueryBlockKey, qgm: &mut QGM) -> Result<(), String> {
         let (qblock_graph, expr_graph, ..) = qgm.borrow_parts();
 
@@ -148,16 +886,49 @@ impl QueryBlock {
 
         // Replace group_by expressions with references to child qun
         let group_by = replace(&mut outer_qb.group_by, None).unwrap();
-        let group_by_expr_count = group_by.len();
 
         let having_clause = replace(&mut outer_qb.having_clause, None);
 
         // Construct inner select-list by first adding GROUP-BY clause expressions
         let mut inner_select_list = group_by.iter().map(|&expr_key| NamedExpr::new(None, expr_key)).collect::<Vec<NamedExpr>>();
 
-        // Augment inner select-list by extracting parameters from `agg(parameter)` expressions
+        // Grouping sets are told apart by a grouping-id, which becomes the last grouping key. Its placeholder gets filled in once the
+        // input rows are expanded into every set.
+        let grouping_id = outer_qb.grouping_sets.as_ref().map(|_| group_by.len());
+        if grouping_id.is_some() {
+            let placeholder_key = expr_graph.add_node_with_props(ScalarFunction(String::from("$GROUPING_ID")), ExprProp::new(DataType::Int64), None);
+            inner_select_list.push(NamedExpr::new(None, placeholder_key));
+        }
+        let group_by_expr_count = inner_select_list.len();
+
+        // The argument of DISTINCT aggregates comes right after them, even if it's also a GROUP-BY expression. MIN/MAX ignore duplicates anyway.
+        let select_list_exprs = outer_qb.select_list.iter().map(|ne| ne.expr_key);
+        let distinct_aggs = select_list_exprs
+            .chain(having_clause.iter().flatten().cloned())
+            .flat_map(|expr_key| expr_graph.true_iter(expr_key))
+            .filter(|&expr_key| matches!(expr_graph.get_value(expr_key), AggFunction(_, true)))
+            .collect::<Vec<_>>();
+        for agg_key in distinct_aggs {
+            let node = expr_graph.get_mut(agg_key);
+            if let AggFunction(aggtype @ (AggType::MIN | AggType::MAX), true) = node.value {
+                node.value = AggFunction(aggtype, false);
+            } else {
+                let arg_key = node.children.as_ref().unwrap()[0];
+                if Self::find_distinct_arg(expr_graph, &inner_select_list, group_by_expr_count, arg_key).is_none() {
+                    inner_select_list.push(NamedExpr::new(None, arg_key));
+                }
+            }
+        }
+        if inner_select_list.len() > group_by_expr_count + 1 {
+            return Err(f!("Aggregates over more than one DISTINCT argument are not supported."));
+        }
+
+        // Augment inner select-list by extracting parameters from `agg(parameter)` expressions. Unaliased columns keep their names.
         for ne in outer_qb.select_list.iter_mut() {
-            Self::transform_groupby_expr(expr_graph, &mut inner_select_list, group_by_expr_count, agg_qun_id, &mut ne.expr_key)?;
+            if let (None, Expr::Column { colname, .. }) = (&ne.alias, expr_graph.get_value(ne.expr_key)) {
+                ne.alias = Some(colname.clone());
+            }
+            Self::transform_groupby_expr(expr_graph, &mut inner_select_list, group_by_expr_count, grouping_id, agg_qun_id, &mut ne.expr_key)?;
         }
 
         // Transform HAVING clause expressions -> outer qb predicates
@@ -165,7 +936,7 @@ impl QueryBlock {
             let mut new_having_clause = vec![];
             for having_pred in having_clause.iter() {
                 let mut new_pred_id = *having_pred;
-                Self::transform_groupby_expr(expr_graph, &mut inner_select_list, group_by_expr_count, agg_qun_id, &mut new_pred_id)?;
+                Self::transform_groupby_expr(expr_graph, &mut inner_select_list, group_by_expr_count, grouping_id, agg_qun_id, &mut new_pred_id)?;
                 new_having_clause.push(new_pred_id);
             }
             Some(new_having_clause)
@@ -173,10 +944,8 @@ impl QueryBlock {
             None
         };
 
-        let group_by = group_by
-            .iter()
-            .enumerate()
-            .map(|(cid, ..)| {
+        let group_by = (0..group_by_expr_count)
+            .map(|cid| {
                 let gbcol_expr_key = inner_select_list[cid].expr_key;
                 let gbcol_props = expr_graph.get_properties(gbcol_expr_key);
                 let gbcol_props = ExprProp { data_type: gbcol_props.data_type().clone() };
@@ -194,7 +963,8 @@ impl QueryBlock {
             None,
             None,
             None,
-            outer_qb.distinct,
+            DistinctProperty::All,
+            None,
             None,
         );
 
@@ -223,6 +993,11 @@ impl QueryBlock {
         None
     }
 
+    fn find_distinct_arg(graph: &ExprGraph, select_list: &[NamedExpr], group_by_expr_count: usize, expr_key: ExprKey) -> Option<usize> {
+        // DISTINCT aggregate arguments come right after the GROUP-BY expressions
+        select_list.iter().skip(group_by_expr_count).position(|ne| Expr::isomorphic(graph, expr_key, ne.expr_key)).map(|ix| ix + group_by_expr_count)
+    }
+
     fn append(expr_graph: &ExprGraph, select_list: &mut Vec<NamedExpr>, expr_key: ExprKey) -> (usize, DataType) {
         // Does this expression already exist in the select_list?
         let cid = if let Some(ix) = Self::find(expr_graph, select_list, select_list.len(), expr_key) {
@@ -244,21 +1019,31 @@ impl QueryBlock {
     //                                        |
     //                                        V
     //            Inner QB select list    => c1
+    // GROUPING(c1, ..) turns into GROUPING($qunid.grouping_id, bit#, ..), or 0 without grouping sets.
     pub fn transform_groupby_expr(
-        expr_graph: &mut ExprGraph, select_list: &mut Vec<NamedExpr>, group_by_expr_count: usize, qunid: QunId, expr_key: &mut ExprKey,
+        expr_graph: &mut ExprGraph, select_list: &mut Vec<NamedExpr>, group_by_expr_count: usize, grouping_id: Option<usize>, qunid: QunId,
+        expr_key: &mut ExprKey,
     ) -> Result<(), String> {
         //debug!("transform_groupby_expr: {:?}", expr_key.describe(&expr_graph, false));
 
         let node = expr_graph.get(*expr_key);
-        if let AggFunction(aggtype, _) = node.value {
-            // Aggregate-function: replace argument with CID reference to inner query-block
-            let child_key = node.children.as_ref().unwrap()[0];
-            let (cid, data_type) = Self::append(expr_graph, select_list, child_key);
+        if let AggFunction(aggtype, distinct) = node.value {
+            // Aggregate-function: replace argument with CID reference to inner query-block. COUNT(*) counts a constant, since a star
+            // would expand into all columns of the inner query-block.
+            let mut child_key = node.children.as_ref().unwrap()[0];
+            if let Star { .. } = expr_graph.get_value(child_key) {
+                child_key = expr_graph.add_node_with_props(Literal(Int64(1)), ExprProp::new(DataType::Int64), None);
+            }
+            let (cid, data_type) = if let (true, Some(cid)) = (distinct, Self::find_distinct_arg(expr_graph, select_list, group_by_expr_count, child_key)) {
+                (cid, expr_graph.get_properties(child_key).data_type().clone())
+            } else {
+                Self::append(expr_graph, select_list, child_key)
+            };
             let new_child_key = if aggtype == AggType::AVG {
                 // AVG -> SUM / COUNT
                 let cid = expr_graph.add_node_with_props(CID(qunid, cid), ExprProp::new(data_type.clone()), None);
-                let sum = expr_graph.add_node_with_props(AggFunction(AggType::SUM, false), ExprProp::new(data_type), Some(vec![cid]));
-                let cnt = expr_graph.add_node_with_props(AggFunction(AggType::COUNT, false), ExprProp::new(DataType::Int64), Some(vec![cid]));
+                let sum = expr_graph.add_node_with_props(AggFunction(AggType::SUM, distinct), ExprProp::new(data_type), Some(vec![cid]));
+                let cnt = expr_graph.add_node_with_props(AggFunction(AggType::COUNT, distinct), ExprProp::new(DataType::Int64), Some(vec![cid]));
                 expr_graph.add_node_with_props(BinaryExpr(ArithOp::Div), ExprProp::new(DataType::Float64), Some(vec![sum, cnt]))
             } else {
                 expr_graph.add_node_with_props(CID(qunid, cid), ExprProp { data_type }, None)
@@ -268,6 +1053,25 @@ impl QueryBlock {
             if aggtype == AggType::AVG {
                 *expr_key = new_child_key;
             }
+        } else if matches!(&node.value, ScalarFunction(name) if name == "GROUPING") {
+            // GROUPING(): Each argument is a GROUP-BY expression, whose bit in the grouping-id is set in sets that don't group on it
+            let nkeys = grouping_id.unwrap_or(group_by_expr_count);
+            let args = node.children.clone().unwrap();
+            let mut children = vec![];
+            if let Some(grouping_id) = grouping_id {
+                children.push(expr_graph.add_node_with_props(CID(qunid, grouping_id), ExprProp::new(DataType::Int64), None));
+            }
+            for child_key in args {
+                let Some(cid) = Self::find(expr_graph, select_list, nkeys, child_key) else {
+                    return Err("Arguments of GROUPING() must be GROUP-BY expressions.".to_string());
+                };
+                children.push(expr_graph.add_node_with_props(Literal(Int64((nkeys - 1 - cid) as i64)), ExprProp::new(DataType::Int64), None));
+            }
+            if grouping_id.is_some() {
+                expr_graph.get_mut(*expr_key).children = Some(children);
+            } else {
+                *expr_key = expr_graph.add_node_with_props(Literal(Int64(0)), ExprProp::new(DataType::Int64), None);
+            }
         } else if let Some(cid) = Self::find(expr_graph, select_list, group_by_expr_count, *expr_key) {
             // Expression in GROUP-BY list, all good
             let cid_props = expr_graph.get_properties(select_list[cid].expr_key);
@@ -280,7 +1084,7 @@ impl QueryBlock {
         } else if let Some(mut children) = node.children.clone() {
             let mut children2 = vec![];
             for child_key in children.iter_mut() {
-                Self::transform_groupby_expr(expr_graph, select_list, group_by_expr_count, qunid, child_key)?;
+                Self::transform_groupby_expr(expr_graph, select_list, group_by_expr_count, grouping_id, qunid, child_key)?;
                 children2.push(*child_key);
             }
             let node = expr_graph.get_mut(*expr_key);
@@ -289,6 +1093,13 @@ impl QueryBlock {
         Ok(())
     }
 
+    fn has_column(&self, prefix: Option<&String>, colname: &String) -> bool {
+        self.quns.iter().any(|qun| {
+            let matches_prefix = prefix.map(|prefix| qun.matches_name_or_alias(prefix)).unwrap_or(true);
+            matches_prefix && qun.tabledesc.as_ref().map(|desc| desc.get_column(colname).is_some()).unwrap_or(false)
+        })
+    }
+
     pub fn resolve_column(&self, _env: &Env, prefix: Option<&String>, colname: &String) -> Result<(QunCol, DataType, ColId), String> {
         let mut retval = None;
         let colid = 0;
@@ -343,35 +1154,67 @@ impl QueryBlock {
             //    Expr vs Literal     (intcol vs 2.0) / (floatcol vs 2)
             //    Literal vs Expr     (same as above, but flipped)
             //    Expr vs Expr        (intcol = floatcol)
-            match (is_lhs_literal, is_rhs_literal) {
-                (false, false) => {
-                    let (lhs_rank, rhs_rank) = (get_rank(lhs_datatype), get_rank(rhs_datatype));
-                    if lhs_rank > rhs_rank {
-                        if !can_cast_types(rhs_datatype, lhs_datatype) {
-                            return Err(f!("Cannot cast {:?} to {:?}", rhs_datatype, lhs_datatype));
-                        }
-                        // Upcast RHS (e.g. floatcol = intcol) => floatcol = cast(intcol as float)
-                        let cast_props = ExprProp { data_type: lhs_datatype.clone() };
-                        let cast_node = expr_graph.add_node_with_props(Expr::Cast, cast_props, Some(vec![rhs_key]));
-                        return Ok((lhs_datatype.clone(), Some(vec![lhs_key, cast_node])));
-                    } else {
-                        // Upcast LHS (e.g. intcol = floatcol)
-                    }
-                }
-                _ => {
-                    let lhsstr = lhs_key.describe(&expr_graph, false);
-                    let rhsstr = rhs_key.describe(&expr_graph, false);
-                    panic!("Cannot harmonize {}: {:?} and {}: {:?}", lhsstr, lhs_datatype, rhsstr, rhs_datatype);
-                }
+            // The lower ranked side gets upcast. Literals are cast right away.
+            let (lhs_rank, rhs_rank) = (get_rank(lhs_datatype), get_rank(rhs_datatype));
+            let (from_key, is_from_literal, from_datatype, to_datatype) =
+                if lhs_rank > rhs_rank { (rhs_key, is_rhs_literal, rhs_datatype, lhs_datatype) } else { (lhs_key, is_lhs_literal, lhs_datatype, rhs_datatype) };
+            if !can_cast_types(from_datatype, to_datatype) {
+                return Err(f!("Cannot cast {:?} to {:?}", from_datatype, to_datatype));
             }
-            //return Err(f!("Binary operands don't have identical types: {:?} vs {:?}", children_datatypes[0], children_datatypes[1]));
-        } else {
+
+            let cast_props = ExprProp { data_type: to_datatype.clone() };
+            let cast_node = if is_from_literal {
+                let value = Self::resolve_cast(expr_graph.get_value(from_key), to_datatype)?;
+                expr_graph.add_node_with_props(Expr::Literal(value), cast_props, None)
+            } else {
+                expr_graph.add_node_with_props(Expr::Cast, cast_props, Some(vec![from_key]))
+            };
+            let children = if lhs_rank > rhs_rank { vec![lhs_key, cast_node] } else { vec![cast_node, rhs_key] };
+            return Ok((to_datatype.clone(), Some(children)));
         }
         Ok((children_datatypes[0].clone(), Some(children.clone())))
     }
 
+    // harmonize_expr_list: Upcast a list of expressions whose values end up in the same column, e.g. the results of a CASE, to the highest
+    // ranked type among them. NULL literals fit any type and are left as-is.
+    pub fn harmonize_expr_list(expr_graph: &mut ExprGraph, exprs: &[ExprKey], datatypes: &[DataType]) -> Result<(DataType, Vec<ExprKey>), String> {
+        let non_null_datatypes = datatypes.iter().filter(|&datatype| *datatype != DataType::Null).collect::<Vec<_>>();
+        let Some(&first_datatype) = non_null_datatypes.first() else {
+            return Err("At least one of the result expressions must be something other than NULL.".to_string());
+        };
+        let to_datatype = if non_null_datatypes.iter().all(|&datatype| datatype == first_datatype) {
+            first_datatype.clone()
+        } else if non_null_datatypes.iter().all(|&datatype| is_numeric(datatype)) {
+            non_null_datatypes.iter().max_by_key(|&&datatype| get_rank(datatype)).cloned().unwrap().clone()
+        } else {
+            return Err(f!("Datatype mismatch: {:?}", non_null_datatypes));
+        };
+
+        let mut harmonized = vec![];
+        for (&expr_key, from_datatype) in exprs.iter().zip(datatypes.iter()) {
+            if *from_datatype == DataType::Null || *from_datatype == to_datatype {
+                harmonized.push(expr_key);
+                continue;
+            }
+            if !can_cast_types(from_datatype, &to_datatype) {
+                return Err(f!("Cannot cast {:?} to {:?}", from_datatype, to_datatype));
+            }
+            let cast_props = ExprProp { data_type: to_datatype.clone() };
+            let cast_key = if let Expr::Literal(_) = expr_graph.get_value(expr_key) {
+                let value = Self::resolve_cast(expr_graph.get_value(expr_key), &to_datatype)?;
+                expr_graph.add_node_with_props(Expr::Literal(value), cast_props, None)
+            } else {
+                expr_graph.add_node_with_props(Expr::Cast, cast_props, Some(vec![expr_key]))
+            };
+            harmonized.push(cast_key);
+        }
+        Ok((to_datatype, harmonized))
+    }
+
     #[tracing::instrument(fields(expr = expr_key.to_string()), skip_all, parent = None)]
-    pub fn resolve_expr(&self, env: &Env, expr_graph: &mut ExprGraph, metadata: &mut QGMMetadata, expr_key: ExprKey, agg_fns_allowed: bool) -> Result<(), String> {
+    pub fn resolve_expr(
+        &self, outer_scope: &[&QueryBlock], env: &Env, expr_graph: &mut ExprGraph, metadata: &mut QGMMetadata, expr_key: ExprKey, agg_fns_allowed: bool,
+    ) -> Result<(), String> {
         debug!("Unresolved expression: {} ...", expr_key.describe(expr_graph, false));
 
         let children_agg_fns_allowed = if let AggFunction(_, _) = expr_graph.get(expr_key).value {
@@ -386,7 +1229,7 @@ impl QueryBlock {
         let children = expr_graph.get(expr_key).children.clone();
         if let Some(children) = children.clone() {
             for child_key in children {
-                self.resolve_expr(env, expr_graph, metadata, child_key, children_agg_fns_allowed)?;
+                self.resolve_expr(outer_scope, env, expr_graph, metadata, child_key, children_agg_fns_allowed)?;
                 let datatype = expr_graph.get(child_key).properties.data_type().clone();
                 children_datatypes.push(datatype);
             }
@@ -401,6 +1244,39 @@ impl QueryBlock {
                 let datatype = metadata.get_fieldtype(quncol).unwrap();
                 (None, datatype, children)
             }
+            RelExpr(RelOp::Like) => {
+                // Patterns get compiled into a matcher, so they must be known upfront
+                if children_datatypes[0] != DataType::Utf8 {
+                    return Err("LIKE is only supported on strings.".to_string());
+                } else if !matches!(expr_graph.get_value(children.as_ref().unwrap()[1]), Literal(Utf8(_))) {
+                    return Err("LIKE patterns must be string literals.".to_string());
+                }
+                (None, DataType::Boolean, children)
+            }
+            BetweenExpr => {
+                if children_datatypes.iter().any(|datatype| *datatype == DataType::Null) {
+                    return Err("BETWEEN operands cannot be NULL.".to_string());
+                }
+                let (datatype, children) = Self::harmonize_expr_list(expr_graph, &children.unwrap(), &children_datatypes)?;
+                // Constant bounds are compared against the argument's values directly
+                let is_constant = |expr_key: ExprKey| expr_graph.true_iter(expr_key).all(|expr_key| !matches!(expr_graph.get_value(expr_key), CID(..)));
+                let constant_bounds = children[1..].iter().all(|&child_key| is_constant(child_key));
+                let supported =
+                    matches!(datatype, DataType::Int32 | DataType::Date32 | DataType::Int64 | DataType::Float64 | DataType::Decimal(..) | DataType::Utf8);
+                if constant_bounds && !supported {
+                    return Err(f!("BETWEEN with constant bounds is not supported on {datatype:?} values."));
+                }
+                (None, DataType::Boolean, Some(children))
+            }
+            InListExpr => {
+                // IN-lists are compiled into a hash-set of their values
+                let children = children.unwrap();
+                if children.iter().skip(1).any(|&child_key| !matches!(expr_graph.get_value(child_key), Literal(_))) {
+                    return Err("IN-lists may only contain literals.".to_string());
+                }
+                let (_, children) = Self::harmonize_expr_list(expr_graph, &children, &children_datatypes)?;
+                (None, DataType::Boolean, Some(children))
+            }
             RelExpr(..) => {
                 // Check argument types
                 if children_datatypes[0] != children_datatypes[1] {
@@ -423,11 +1299,55 @@ impl QueryBlock {
                 }
             }
             Column { prefix, colname, .. } => {
-                let (quncol, datatype, ..) = self.resolve_column(env, prefix.as_ref(), colname)?;
+                // Bind to the innermost query block that has this column
+                let qblock = std::iter::once(self).chain(outer_scope.iter().cloned()).find(|qblock| qblock.has_column(prefix.as_ref(), colname)).unwrap_or(self);
+                let (quncol, datatype, ..) = qblock.resolve_column(env, prefix.as_ref(), colname)?;
                 let resolved_expr = Some(Column { prefix: prefix.clone(), colname: colname.clone(), qunid: quncol.0, colid: quncol.1 });
                 (resolved_expr, datatype, None)
             }
             LogExpr(..) => (None, DataType::Boolean, children),
+            IsNullExpr { .. } => (None, DataType::Boolean, children),
+            CaseExpr { has_operand, has_else } => {
+                let (has_operand, has_else) = (*has_operand as usize, *has_else);
+                let children = children.unwrap();
+                let nbranches = (children.len() - has_operand - has_else as usize) / 2;
+                for branch_ix in 0..nbranches {
+                    let when_datatype = &children_datatypes[has_operand + 2 * branch_ix];
+                    if has_operand == 1 && *when_datatype != children_datatypes[0] {
+                        return Err(f!("Datatype mismatch: {:?} vs {:?}  ({}:{})", children_datatypes[0], when_datatype, file!(), line!()));
+                    } else if has_operand == 0 && *when_datatype != DataType::Boolean {
+                        return Err("CASE WHEN conditions must be boolean expressions.".to_string());
+                    }
+                }
+
+                // THEN and ELSE results share a type
+                let mut result_ixs = (0..nbranches).map(|branch_ix| has_operand + 2 * branch_ix + 1).collect::<Vec<_>>();
+                if has_else {
+                    result_ixs.push(children.len() - 1);
+                }
+                let results = result_ixs.iter().map(|&ix| children[ix]).collect::<Vec<_>>();
+                let result_datatypes = result_ixs.iter().map(|&ix| children_datatypes[ix].clone()).collect::<Vec<_>>();
+                let (datatype, results) = Self::harmonize_expr_list(expr_graph, &results, &result_datatypes)?;
+
+                let mut children = children;
+                for (ix, result) in result_ixs.into_iter().zip(results) {
+                    children[ix] = result;
+                }
+                (None, datatype, Some(children))
+            }
+            CoalesceExpr => {
+                let (datatype, children) = Self::harmonize_expr_list(expr_graph, &children.unwrap(), &children_datatypes)?;
+                (None, datatype, Some(children))
+            }
+            NullIfExpr => {
+                if children_datatypes[0] == DataType::Null {
+                    return Err("The first argument of NULLIF cannot be NULL.".to_string());
+                } else if children_datatypes[1] != DataType::Null && children_datatypes[0] != children_datatypes[1] {
+                    return Err(f!("Datatype mismatch: {:?} vs {:?}  ({}:{})", children_datatypes[0], children_datatypes[1], file!(), line!()));
+                }
+                (None, children_datatypes[0].clone(), children)
+            }
+            Literal(Null) => (None, DataType::Null, children),
             Literal(Utf8(_)) => (None, DataType::Utf8, children),
             Literal(Int64(_)) => (None, DataType::Int64, children),
             Literal(Float64(_)) => (None, DataType::Float64, children),
@@ -447,6 +1367,41 @@ impl QueryBlock {
                         }
                     }
                     AggType::AVG => DataType::Float64,
+                    AggType::APPROX_COUNT_DISTINCT | AggType::APPROX_PERCENTILE(_) => {
+                        // Sketches are built from the datums of their argument, so other types can't be sketched
+                        let sketchable = matches!(
+                            children_datatypes[0],
+                            DataType::Boolean
+                                | DataType::Int32
+                                | DataType::Int64
+                                | DataType::Float64
+                                | DataType::Decimal(..)
+                                | DataType::Date32
+                                | DataType::Utf8
+                                | DataType::Binary
+                        );
+                        if *aggtype == AggType::APPROX_COUNT_DISTINCT && sketchable {
+                            DataType::Int64
+                        } else if sketchable && is_numeric(&children_datatypes[0]) {
+                            DataType::Float64
+                        } else {
+                            return Err(format!("{:?} not allowed for {:?} arguments.", aggtype, children_datatypes[0]));
+                        }
+                    }
+                    AggType::STDDEV_SAMP
+                    | AggType::STDDEV_POP
+                    | AggType::VAR_SAMP
+                    | AggType::VAR_POP
+                    | AggType::MEDIAN
+                    | AggType::PERCENTILE_CONT(_) => {
+                        if is_numeric(&children_datatypes[0]) {
+                            DataType::Float64
+                        } else {
+                            return Err(format!("{:?}() only allowed for numeric datatypes.", aggtype));
+                        }
+                    }
+                    AggType::SUM_SQUARES => DataType::Float64,
+                    AggType::HLL_SKETCH | AggType::QUANTILE_SKETCH | AggType::HLL_MERGE | AggType::QUANTILE_MERGE => DataType::Binary,
                 };
                 (None, datatype, children)
             }
@@ -465,6 +1420,64 @@ impl QueryBlock {
                 let datatype = new_value.datatype();
                 (Some(Literal(new_value)), datatype, None)
             }
+            ScalarFunction(name) if name == "GROUPING" => {
+                if !agg_fns_allowed {
+                    return Err("GROUPING() is only allowed in the select-list and HAVING clause of aggregations.".to_string());
+                }
+                (None, DataType::Int64, children)
+            }
+            ScalarFunction(name) if name == "$GROUPING_ID" => (None, DataType::Int64, children),
+            ScalarFunction(name) if env.scalar_udfs.contains_key(name) => {
+                let datatype = env.scalar_udfs[name].return_type(name, &children_datatypes)?;
+                (None, datatype, children)
+            }
+            ScalarFunction(name) => {
+                let func = ScalarFunc::new(expr_graph, name, children.as_deref().unwrap_or_default())?;
+                let datatype = func.return_type(&children_datatypes[ScalarFunc::takes_date_part(name) as usize..])?;
+                (None, datatype, children)
+            }
+            WindowFunction(window_func) => {
+                let window_func = window_func.clone();
+                let children_keys = children.as_ref().unwrap();
+                if children_keys.iter().any(|&child_key| expr_graph.true_iter(child_key).any(|expr_key| matches!(expr_graph.get_value(expr_key), WindowFunction(..)))) {
+                    return Err(String::from("Window functions cannot be nested."));
+                }
+                let datatype = match window_func.func_type {
+                    WindowFuncType::ROW_NUMBER | WindowFuncType::RANK | WindowFuncType::DENSE_RANK | WindowFuncType::AGG(AggType::COUNT) => DataType::Int64,
+                    WindowFuncType::LAG(_) | WindowFuncType::LEAD(_) => {
+                        if window_func.nargs == 2 && children_datatypes[1] != children_datatypes[0] {
+                            return Err(String::from("The default of LAG/LEAD must have the same datatype as its argument."));
+                        }
+                        children_datatypes[0].clone()
+                    }
+                    WindowFuncType::FIRST_VALUE | WindowFuncType::AGG(AggType::MIN | AggType::MAX) => children_datatypes[0].clone(),
+                    WindowFuncType::AGG(aggtype @ (AggType::SUM | AggType::AVG)) => {
+                        if !is_numeric(&children_datatypes[0]) {
+                            return Err(format!("{:?}() only allowed for numeric datatypes.", aggtype));
+                        }
+                        if aggtype == AggType::SUM {
+                            children_datatypes[0].clone()
+                        } else {
+                            DataType::Float64
+                        }
+                    }
+                    WindowFuncType::AGG(aggtype) => return Err(format!("Aggregate function {:?} cannot be used as a window function.", aggtype)),
+                };
+
+                // RANGE offsets are added to the value of the (only) ORDER BY key
+                let frame = window_func.frame;
+                let has_offset = |bound: FrameBound| matches!(bound, FrameBound::Preceding(_) | FrameBound::Following(_));
+                if frame.units == FrameUnits::RANGE && (has_offset(frame.start) || has_offset(frame.end)) {
+                    let order_key_datatype = &children_datatypes[window_func.nargs + window_func.npartition_keys];
+                    if !is_numeric(order_key_datatype) {
+                        return Err(String::from("RANGE frames with an offset require a numeric ORDER BY key."));
+                    }
+                }
+                (None, datatype, children)
+            }
+            Subquery(_) | ExistsExpr | InSubqExpr => {
+                return Err("Subqueries are only supported in the select-list and WHERE clause of query blocks without GROUP BY.".to_string());
+            }
             _ => {
                 panic!("Unexpected expression found: {:?}", &expr);
             }
@@ -494,6 +1507,7 @@ impl QueryBlock {
                     let date: i32 = chrono::Datelike::num_days_from_ce(&date) - arrow2::temporal_conversions::EPOCH_DAYS_FROM_CE;
                     Date32(date)
                 }
+                (Int64(i), DataType::Float64) => Float64(F64::from(*i as f64)),
                 _ => todo!(),
             };
             return Ok(to_value);
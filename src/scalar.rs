@@ -0,0 +1,357 @@
+// scalar: Built-in and user-defined scalar functions. Signatures are checked when expressions get resolved, and each function runs
+// over whole columns from PInstruction::Call.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use arrow2::{
+    array::Utf8Array,
+    compute::{
+        arity,
+        cast::{self, CastOptions},
+    },
+    temporal_conversions::{date32_to_date, EPOCH_DAYS_FROM_CE},
+};
+use chrono::{Datelike, NaiveDate};
+
+use crate::{
+    datum::is_numeric,
+    expr::{Expr, ExprGraph},
+    graph::ExprKey,
+    includes::*,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DatePart {
+    YEAR,
+    QUARTER,
+    MONTH,
+    WEEK,
+    DAY,
+}
+
+impl DatePart {
+    fn new(name: &str) -> Result<Self, String> {
+        let date_part = match name.to_uppercase().as_str() {
+            "YEAR" => DatePart::YEAR,
+            "QUARTER" => DatePart::QUARTER,
+            "MONTH" => DatePart::MONTH,
+            "WEEK" => DatePart::WEEK,
+            "DAY" => DatePart::DAY,
+            _ => return Err(f!("Invalid date part '{name}'.")),
+        };
+        Ok(date_part)
+    }
+
+    fn extract(&self, date: NaiveDate) -> i64 {
+        let value = match self {
+            DatePart::YEAR => date.year(),
+            DatePart::QUARTER => (date.month0() / 3 + 1) as i32,
+            DatePart::MONTH => date.month() as i32,
+            DatePart::WEEK => date.iso_week().week() as i32,
+            DatePart::DAY => date.day() as i32,
+        };
+        value as i64
+    }
+
+    // truncate: First day of the year/quarter/month/week holding `date`. Weeks start on Monday.
+    fn truncate(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            DatePart::YEAR => date.with_ordinal(1).unwrap(),
+            DatePart::QUARTER => date.with_day(1).unwrap().with_month(date.month0() / 3 * 3 + 1).unwrap(),
+            DatePart::MONTH => date.with_day(1).unwrap(),
+            DatePart::WEEK => date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64),
+            DatePart::DAY => date,
+        }
+    }
+}
+
+/***************************************************************************************************/
+pub type ScalarUdfFn = fn(&[Box<dyn Array>]) -> Box<dyn Array>;
+
+#[derive(Debug, Clone)]
+pub struct ScalarUdf {
+    pub arg_types: Vec<DataType>,
+    pub return_type: DataType,
+    pub func: ScalarUdfFn,
+}
+
+impl ScalarUdf {
+    // check_name: UDFs cannot shadow built-in or internally planned functions
+    pub fn check_name(name: &str) -> Result<(), String> {
+        if ScalarFunc::is_internal(name) || ScalarFunc::takes_date_part(name) || ScalarFunc::new(&ExprGraph::default(), name, &[]).is_ok() {
+            return Err(f!("Function {name}() is already defined."));
+        }
+        Ok(())
+    }
+
+    pub fn return_type(&self, name: &str, arg_types: &[DataType]) -> Result<DataType, String> {
+        if arg_types != self.arg_types {
+            return Err(format!("{}() expects arguments of type {:?}, not {:?}.", name, self.arg_types, arg_types));
+        }
+        Ok(self.return_type.clone())
+    }
+}
+
+thread_local! {
+    // UDFs of the Env that owns this worker thread. PCode only carries their names, so Env::register_scalar_udf() hands a copy to
+    // every worker of its scheduler.
+    static WORKER_UDFS: RefCell<HashMap<String, ScalarUdf>> = RefCell::new(HashMap::new());
+}
+
+pub fn add_worker_udf(name: String, udf: ScalarUdf) {
+    WORKER_UDFS.with(|udfs| udfs.borrow_mut().insert(name, udf));
+}
+
+/***************************************************************************************************/
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ScalarFunc {
+    UPPER,
+    LOWER,
+    SUBSTRING,
+    TRIM,
+    LENGTH,
+    CONCAT,
+    ABS,
+    ROUND,
+    FLOOR,
+    CEIL,
+    MOD,
+    EXTRACT(DatePart),
+    DATE_TRUNC(DatePart),
+    DATE_ADD,
+    UDF(String),
+}
+
+impl ScalarFunc {
+    // takes_date_part: EXTRACT(part FROM date) and DATE_TRUNC('part', date) carry their date part as a leading string literal.
+    // It is folded into the function rather than evaluated.
+    pub fn takes_date_part(name: &str) -> bool {
+        matches!(name, "EXTRACT" | "DATE_TRUNC")
+    }
+
+    // is_internal: Functions that are planned internally and evaluated outside the registry, e.g. GROUPING()
+    pub fn is_internal(name: &str) -> bool {
+        matches!(name, "$GROUPING_ID" | "GROUPING" | "STDDEV_SAMP" | "STDDEV_POP" | "VAR_SAMP" | "VAR_POP" | "APPROX_COUNT_DISTINCT" | "APPROX_PERCENTILE")
+    }
+
+    pub fn new(expr_graph: &ExprGraph, name: &str, args: &[ExprKey]) -> Result<Self, String> {
+        let date_part = || match args.first().map(|&arg| expr_graph.get_value(arg)) {
+            Some(Expr::Literal(Utf8(date_part))) => DatePart::new(date_part),
+            _ => Err(f!("{name}() expects a date part as its first argument.")),
+        };
+        let func = match name {
+            "UPPER" => ScalarFunc::UPPER,
+            "LOWER" => ScalarFunc::LOWER,
+            "SUBSTRING" | "SUBSTR" => ScalarFunc::SUBSTRING,
+            "TRIM" => ScalarFunc::TRIM,
+            "LENGTH" => ScalarFunc::LENGTH,
+            "CONCAT" => ScalarFunc::CONCAT,
+            "ABS" => ScalarFunc::ABS,
+            "ROUND" => ScalarFunc::ROUND,
+            "FLOOR" => ScalarFunc::FLOOR,
+            "CEIL" | "CEILING" => ScalarFunc::CEIL,
+            "MOD" => ScalarFunc::MOD,
+            "EXTRACT" => ScalarFunc::EXTRACT(date_part()?),
+            "YEAR" => ScalarFunc::EXTRACT(DatePart::YEAR),
+            "MONTH" => ScalarFunc::EXTRACT(DatePart::MONTH),
+            "DAY" => ScalarFunc::EXTRACT(DatePart::DAY),
+            "DATE_TRUNC" => ScalarFunc::DATE_TRUNC(date_part()?),
+            "DATE_ADD" => ScalarFunc::DATE_ADD,
+            _ => return Err(f!("Unknown function {name}().")),
+        };
+        Ok(func)
+    }
+
+    // return_type: Check the arguments against the signature of the function, and return the type of its result
+    pub fn return_type(&self, arg_types: &[DataType]) -> Result<DataType, String> {
+        let nargs = arg_types.len();
+        let arg_type = |ix: usize| &arg_types[ix];
+        let (min_args, max_args) = match self {
+            ScalarFunc::SUBSTRING => (2, 3),
+            ScalarFunc::ROUND => (1, 2),
+            ScalarFunc::CONCAT => (1, usize::MAX),
+            ScalarFunc::MOD | ScalarFunc::DATE_ADD => (2, 2),
+            _ => (1, 1),
+        };
+        if nargs < min_args || nargs > max_args {
+            return Err(format!("Wrong number of arguments to {:?}().", self));
+        } else if arg_types.contains(&DataType::Null) {
+            return Err(format!("Arguments of {:?}() cannot be NULL.", self));
+        }
+
+        let expect = |valid: bool, expected: &str| if valid { Ok(()) } else { Err(format!("{:?}() expects {} arguments.", self, expected)) };
+        let datatype = match self {
+            ScalarFunc::UPPER | ScalarFunc::LOWER | ScalarFunc::TRIM | ScalarFunc::CONCAT => {
+                expect(arg_types.iter().all(|datatype| *datatype == DataType::Utf8), "string")?;
+                DataType::Utf8
+            }
+            ScalarFunc::LENGTH => {
+                expect(*arg_type(0) == DataType::Utf8, "string")?;
+                DataType::Int64
+            }
+            ScalarFunc::SUBSTRING => {
+                expect(*arg_type(0) == DataType::Utf8 && arg_types[1..].iter().all(is_integer), "string and integer")?;
+                DataType::Utf8
+            }
+            ScalarFunc::ABS | ScalarFunc::FLOOR | ScalarFunc::CEIL | ScalarFunc::ROUND => {
+                expect(is_number(arg_type(0)) && arg_types[1..].iter().all(is_integer), "numeric")?;
+                numeric_result_type(&arg_types[..1])
+            }
+            ScalarFunc::MOD => {
+                expect(arg_types.iter().all(is_number), "numeric")?;
+                numeric_result_type(arg_types)
+            }
+            ScalarFunc::EXTRACT(_) => {
+                expect(*arg_type(0) == DataType::Date32, "date")?;
+                DataType::Int64
+            }
+            ScalarFunc::DATE_TRUNC(_) => {
+                expect(*arg_type(0) == DataType::Date32, "date")?;
+                DataType::Date32
+            }
+            ScalarFunc::DATE_ADD => {
+                expect(*arg_type(0) == DataType::Date32 && is_integer(arg_type(1)), "date and integer")?;
+                DataType::Date32
+            }
+            // UDFs are checked against their signature on the Env
+            ScalarFunc::UDF(_) => unreachable!(),
+        };
+        Ok(datatype)
+    }
+
+    // eval: Arguments are columns of the same length. A NULL argument yields NULL.
+    pub fn eval(&self, args: &[Box<dyn Array>]) -> Box<dyn Array> {
+        match self {
+            ScalarFunc::UPPER => map_strings(&*args[0], |s| s.to_uppercase()),
+            ScalarFunc::LOWER => map_strings(&*args[0], |s| s.to_lowercase()),
+            ScalarFunc::TRIM => map_strings(&*args[0], |s| s.trim().to_string()),
+            ScalarFunc::LENGTH => {
+                let strings = as_strings(&*args[0]);
+                let lengths = strings.values_iter().map(|s| s.chars().count() as i64).collect::<Vec<_>>();
+                Box::new(PrimitiveArray::<i64>::from_vec(lengths).with_validity(strings.validity().cloned()))
+            }
+            ScalarFunc::SUBSTRING => {
+                let (strings, starts) = (as_strings(&*args[0]), as_i64(&*args[1]));
+                let lengths = args.get(2).map(|arg| as_i64(&**arg));
+                let array = (0..strings.len())
+                    .map(|ix| {
+                        let length = match &lengths {
+                            Some(lengths) => lengths.get(ix)?,
+                            None => i64::MAX,
+                        };
+                        Some(substring(strings.get(ix)?, starts.get(ix)?, length))
+                    })
+                    .collect::<Utf8Array<i32>>();
+                Box::new(array)
+            }
+            ScalarFunc::CONCAT => {
+                // Like `||`, and unlike some dialects' CONCAT, any NULL argument makes the result NULL
+                let args = args.iter().map(|arg| as_strings(&**arg)).collect::<Vec<_>>();
+                let array = (0..args[0].len()).map(|ix| args.iter().map(|arg| arg.get(ix)).collect::<Option<String>>()).collect::<Utf8Array<i32>>();
+                Box::new(array)
+            }
+            ScalarFunc::ABS if is_integer(args[0].data_type()) => Box::new(arity::unary(&as_i64(&*args[0]), |value| value.abs(), DataType::Int64)),
+            ScalarFunc::ABS => Box::new(arity::unary(&as_f64(&*args[0]), |value| value.abs(), DataType::Float64)),
+            ScalarFunc::FLOOR | ScalarFunc::CEIL | ScalarFunc::ROUND if is_integer(args[0].data_type()) && args.len() == 1 => Box::new(as_i64(&*args[0])),
+            ScalarFunc::FLOOR | ScalarFunc::CEIL | ScalarFunc::ROUND => {
+                // Integers are only rounded to a negative # of digits, e.g. ROUND(1234, -2) = 1200
+                let values = as_f64(&*args[0]);
+                let digits = match args.get(1) {
+                    Some(digits) => as_i64(&**digits),
+                    None => PrimitiveArray::<i64>::from_vec(vec![0; values.len()]),
+                };
+                let op = match self {
+                    ScalarFunc::FLOOR => f64::floor,
+                    ScalarFunc::CEIL => f64::ceil,
+                    _ => f64::round,
+                };
+                let array = arity::binary(&values, &digits, DataType::Float64, |value, digits| {
+                    let scale = 10f64.powi(digits as i32);
+                    op(value * scale) / scale
+                });
+                if is_integer(args[0].data_type()) {
+                    cast::cast(&array, &DataType::Int64, CastOptions::default()).unwrap()
+                } else {
+                    Box::new(array)
+                }
+            }
+            ScalarFunc::MOD if is_integer(args[0].data_type()) && is_integer(args[1].data_type()) => {
+                // Remainders of a division by zero are NULL
+                Box::new(arithmetics::basic::checked_rem(&as_i64(&*args[0]), &as_i64(&*args[1])))
+            }
+            ScalarFunc::MOD => Box::new(arithmetics::basic::rem(&as_f64(&*args[0]), &as_f64(&*args[1]))),
+            ScalarFunc::EXTRACT(date_part) => {
+                let dates = args[0].as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+                Box::new(arity::unary(dates, |days| date_part.extract(date32_to_date(days)), DataType::Int64))
+            }
+            ScalarFunc::DATE_TRUNC(date_part) => {
+                let dates = args[0].as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+                Box::new(arity::unary(dates, |days| to_date32(date_part.truncate(date32_to_date(days))), DataType::Date32))
+            }
+            ScalarFunc::DATE_ADD => {
+                let dates = args[0].as_any().downcast_ref::<PrimitiveArray<i32>>().unwrap();
+                let dates = cast::primitive_to_primitive::<i32, i64>(dates, &DataType::Int64);
+                let array = arity::binary(&dates, &as_i64(&*args[1]), DataType::Int64, |days, ndays| days + ndays);
+                Box::new(cast::primitive_to_primitive::<i64, i32>(&array, &DataType::Date32))
+            }
+            ScalarFunc::UDF(name) => {
+                let func = WORKER_UDFS.with(|udfs| udfs.borrow().get(name).map(|udf| udf.func));
+                let func = func.unwrap_or_else(|| panic!("Function {}() is not registered with this worker.", name));
+                func(args)
+            }
+        }
+    }
+}
+
+fn is_integer(datatype: &DataType) -> bool {
+    matches!(
+        datatype,
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64 | DataType::UInt8 | DataType::UInt16 | DataType::UInt32 | DataType::UInt64
+    )
+}
+
+// is_number: Dates are numeric as far as casts go, but aren't arguments to math functions
+fn is_number(datatype: &DataType) -> bool {
+    is_numeric(datatype) && !matches!(datatype, DataType::Date32 | DataType::Date64)
+}
+
+// numeric_result_type: Integer arguments compute in Int64, all others in Float64
+fn numeric_result_type(arg_types: &[DataType]) -> DataType {
+    if arg_types.iter().all(is_integer) {
+        DataType::Int64
+    } else {
+        DataType::Float64
+    }
+}
+
+fn as_strings(array: &dyn Array) -> &Utf8Array<i32> {
+    array.as_any().downcast_ref::<Utf8Array<i32>>().unwrap()
+}
+
+fn as_i64(array: &dyn Array) -> PrimitiveArray<i64> {
+    let array = cast::cast(array, &DataType::Int64, CastOptions::default()).unwrap();
+    array.as_any().downcast_ref::<PrimitiveArray<i64>>().unwrap().clone()
+}
+
+fn as_f64(array: &dyn Array) -> PrimitiveArray<f64> {
+    let array = cast::cast(array, &DataType::Float64, CastOptions::default()).unwrap();
+    array.as_any().downcast_ref::<PrimitiveArray<f64>>().unwrap().clone()
+}
+
+fn map_strings(array: &dyn Array, func: impl Fn(&str) -> String) -> Box<dyn Array> {
+    let strings = as_strings(array);
+    let array = Utf8Array::<i32>::from_iter_values(strings.values_iter().map(func));
+    Box::new(array.with_validity(strings.validity().cloned()))
+}
+
+// substring: Characters `start` thru `start + length - 1`, counting from 1. Positions before the first character count towards the length.
+fn substring(s: &str, start: i64, length: i64) -> String {
+    let end = start.saturating_add(length.max(0));
+    let start = start.max(1);
+    s.chars().skip((start - 1) as usize).take((end - start).max(0) as usize).collect()
+}
+
+fn to_date32(date: NaiveDate) -> i32 {
+    date.num_days_from_ce() - EPOCH_DAYS_FROM_CE
+}
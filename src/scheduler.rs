@@ -4,16 +4,22 @@ use std::{sync::mpsc, thread, thread::JoinHandle};
 
 use crate::{
     includes::*,
-    stage::{Stage, StageContext},
+    pop_repartition::has_iteration_output,
+    scalar::{self, ScalarUdf},
+    stage::{Stage, StageContext, StageLink},
     task::Task,
     Flow,
 };
 
+pub const MAX_RECURSION: usize = 100;
+
 #[derive(Debug)]
 pub enum SchedulerMessage {
     ScheduleTask(Vec<u8>),
     TaskCompleted { stage_id: StageId, partition_id: usize },
+    TaskFailed { stage_id: StageId, partition_id: usize, errstr: String },
     StageCompleted { stage_id: StageId },
+    RegisterUdf { name: String, udf: ScalarUdf },
     EndThread,
 }
 
@@ -62,6 +68,7 @@ impl Scheduler {
                             debug!("End of thread");
                             break;
                         }
+                        SchedulerMessage::RegisterUdf { name, udf } => scalar::add_worker_udf(name, udf),
                         SchedulerMessage::ScheduleTask(encoded) => {
                             let (flow, stage, mut task): (Flow, Stage, Task) = bincode::deserialize(&encoded[..]).unwrap();
 
@@ -73,12 +80,15 @@ impl Scheduler {
                                 task.partition_id
                             );
                             */
-                            task.run(&flow, &stage).unwrap();
+                            let msg = match task.run(&flow, &stage) {
+                                Ok(_) => SchedulerMessage::TaskCompleted { stage_id: stage.stage_id, partition_id: task.partition_id },
+                                Err(errstr) => SchedulerMessage::TaskFailed { stage_id: stage.stage_id, partition_id: task.partition_id, errstr },
+                            };
 
                             // The following send may not succeed if the scheduler is gone
-                            t2s_channel_tx_clone.send(SchedulerMessage::TaskCompleted { stage_id: stage.stage_id, partition_id: task.partition_id }).unwrap_or_default()
+                            t2s_channel_tx_clone.send(msg).unwrap_or_default()
                         }
-                        SchedulerMessage::TaskCompleted { .. } => {
+                        SchedulerMessage::TaskCompleted { .. } | SchedulerMessage::TaskFailed { .. } => {
                             panic!("Invalid message")
                         }
                         SchedulerMessage::StageCompleted { .. } => {
@@ -99,7 +109,7 @@ impl Scheduler {
         let v = stages
             .iter()
             .zip(stage_status.iter())
-            .filter_map(|(stage, ss)| if stage.nchildren == ss.nchildren_completed && ss.npartitions_completed == 0 { Some(stage) } else { None })
+            .filter_map(|(stage, ss)| if stage.nchildren == ss.nchildren_completed && !ss.scheduled { Some(stage) } else { None })
             .collect();
         v
     }
@@ -120,20 +130,62 @@ impl Scheduler {
 
     pub fn set_stage_completed(flow: &Flow, stage_contexts: &mut [StageContext], stage_id: StageId) {
         let stage_graph = &flow.stage_graph;
-        if stage_id > 0 {
-            let parent_stage_id = stage_graph.stages[stage_id].parent_stage_id.unwrap();
+        for &parent_stage_id in stage_graph.stages[stage_id].parent_stage_ids.iter() {
             stage_contexts[parent_stage_id].nchildren_completed += 1;
         }
     }
 
-    pub fn schedule_stages(&self, env: &Env, flow: &Flow, stage_contexts: &[StageContext]) -> Result<usize, String> {
+    // restart_recursive_stage: Run another iteration of a recursive CTE unless its last one produced nothing
+    pub fn restart_recursive_stage(env: &Env, flow: &Flow, stage_contexts: &mut [StageContext], stage_id: StageId) -> Result<bool, String> {
         let stage_graph = &flow.stage_graph;
+        let stage = &stage_graph.stages[stage_id];
+        let recursive_stages = if let Some(recursive_stages) = &stage.recursive_stages { recursive_stages } else { return Ok(false) };
+
+        let iteration = stage_contexts[stage_id].iteration;
+        let stage_link = StageLink(stage_id, stage.parent_stage_ids[0]);
+        if !has_iteration_output(flow.id, stage_link, 1, iteration)? {
+            return Ok(false);
+        }
+
+        let max_recursion = env.settings.max_recursion.unwrap_or(MAX_RECURSION);
+        if iteration + 1 > max_recursion {
+            return Err(f!("Recursive CTE did not terminate within MAX_RECURSION = {max_recursion} iterations."));
+        }
+
+        // Rerun the stage along with the stages of its recursive query block. Their other children are done for good.
+        let group = recursive_stages.iter().cloned().chain(std::iter::once(stage_id)).collect::<Vec<_>>();
+        for &group_stage_id in group.iter() {
+            let group_stage = &stage_graph.stages[group_stage_id];
+            let nchildren_outside = stage_graph
+                .stages
+                .iter()
+                .filter(|child_stage| !group.contains(&child_stage.stage_id))
+                .map(|child_stage| child_stage.parent_stage_ids.iter().filter(|&&parent_stage_id| parent_stage_id == group_stage_id).count())
+                .sum();
+            stage_contexts[group_stage_id] = StageContext { nchildren_completed: nchildren_outside, npartitions_completed: 0, scheduled: false, iteration: iteration + 1 };
+
+            // The CTE's own output accumulates across iterations, but everything below it is recomputed
+            if group_stage_id != stage_id {
+                for &parent_stage_id in group_stage.parent_stage_ids.iter() {
+                    let dirname = get_pipeline_dir(flow.id, StageLink(group_stage_id, parent_stage_id));
+                    std::fs::remove_dir_all(&dirname).unwrap_or_default();
+                }
+            }
+        }
+        debug!("Stage {} starts iteration {}", stage_id, iteration + 1);
+        Ok(true)
+    }
 
+    pub fn schedule_stages(&self, env: &Env, flow: &Flow, stage_contexts: &mut [StageContext]) -> Result<usize, String> {
+        let stage_graph = &flow.stage_graph;
+
+        // Return the number of tasks scheduled
         let stages = Self::runnable(&stage_graph.stages, stage_contexts);
         for stage in stages.iter() {
-            stage.schedule(env, flow).map_err(stringify)?;
+            stage.schedule(env, flow, stage_contexts[stage.stage_id].iteration).map_err(stringify)?;
+            stage_contexts[stage.stage_id].scheduled = true;
         }
-        Ok(stages.len())
+        Ok(stages.iter().map(|stage| stage.npartitions).sum())
     }
 
     pub fn run_flow(&self, env: &Env, flow: &Flow) -> Result<(), String> {
@@ -142,12 +194,26 @@ impl Scheduler {
         let stage_graph = &flow.stage_graph;
         let mut stage_contexts = (0..stage_graph.stages.len()).map(|_| StageContext::default()).collect::<Vec<_>>();
 
-        self.schedule_stages(env, flow, &stage_contexts)?;
+        let mut ntasks_running = self.schedule_stages(env, flow, &mut stage_contexts)?;
+        let mut first_error = None;
 
         for msg in &self.t2s_channel_rx {
             debug!("run_flow message recv: {:?}", msg);
+            ntasks_running -= 1;
 
             match msg {
+                SchedulerMessage::TaskFailed { errstr, .. } => {
+                    // Stop scheduling but wait for running tasks to wind down, so that none of them report into a later flow
+                    first_error.get_or_insert(errstr);
+                    if ntasks_running == 0 {
+                        break;
+                    }
+                }
+                SchedulerMessage::TaskCompleted { .. } if first_error.is_some() => {
+                    if ntasks_running == 0 {
+                        break;
+                    }
+                }
                 SchedulerMessage::TaskCompleted { stage_id, .. } => {
                     let ss = &mut stage_contexts[stage_id];
                     let stage = &stage_graph.stages[stage_id];
@@ -155,13 +221,31 @@ impl Scheduler {
                     // If this was the last task in a stage, schedule any dependent stages
                     ss.npartitions_completed += 1;
                     if stage.npartitions == ss.npartitions_completed {
+                        match Self::restart_recursive_stage(env, flow, &mut stage_contexts, stage_id) {
+                            Ok(true) => {
+                                ntasks_running += self.schedule_stages(env, flow, &mut stage_contexts)?;
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(errstr) => {
+                                first_error.get_or_insert(errstr);
+                                if ntasks_running == 0 {
+                                    break;
+                                }
+                                continue;
+                            }
+                        }
+
                         debug!("Stage {} completed", stage_id);
                         Self::set_stage_completed(flow, &mut stage_contexts, stage_id);
 
                         debug!("Stage contexts: {:?}", &stage_contexts);
-                        if self.schedule_stages(env, flow, &stage_contexts)? == 0 {
+
+                        // The flow is done once its root stage completes
+                        if stage_id == 0 {
                             break;
                         }
+                        ntasks_running += self.schedule_stages(env, flow, &mut stage_contexts)?;
                     }
                 }
                 _ => {
@@ -169,6 +253,6 @@ impl Scheduler {
                 }
             }
         }
-        Ok(())
+        first_error.map_or(Ok(()), Err)
     }
 }
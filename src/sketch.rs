@@ -0,0 +1,155 @@
+// sketch: Mergeable sketches behind the approximate aggregates. Partial aggregates ship them between stages as binary columns.
+
+use std::collections::BTreeMap;
+
+use fasthash::xx;
+
+use crate::includes::*;
+
+/***************************************************************************************************/
+// HyperLogLog: Distinct-count sketch. 2^HLL_PRECISION one-byte registers give a standard error of ~1.6%.
+const HLL_PRECISION: u32 = 12;
+const HLL_NREGISTERS: usize = 1 << HLL_PRECISION;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog { registers: vec![0; HLL_NREGISTERS] }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn add(&mut self, datum: &Datum) {
+        // The leading bits pick a register, which remembers the longest run of leading zeros among the rest
+        let hash = hash_datum(datum);
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION).leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &other_register) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(other_register);
+        }
+    }
+
+    pub fn estimate(&self) -> i64 {
+        let m = HLL_NREGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self.registers.iter().map(|&rank| 2f64.powi(-(rank as i32))).sum::<f64>();
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities are better estimated by counting empty registers
+        let nzeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        let estimate = if estimate <= 2.5 * m && nzeros > 0 { m * (m / nzeros as f64).ln() } else { estimate };
+        estimate.round() as i64
+    }
+}
+
+fn hash_datum(datum: &Datum) -> u64 {
+    match datum {
+        Boolean(value) => xx::hash64([*value as u8]),
+        Int32(value) | Date32(value) => xx::hash64(value.to_le_bytes()),
+        Int64(value) => xx::hash64(value.to_le_bytes()),
+        Float64(value) => xx::hash64(f64::from(*value).to_bits().to_le_bytes()),
+        Decimal(value, ..) => xx::hash64(value.to_le_bytes()),
+        Utf8(value) => xx::hash64(value),
+        Binary(value) => xx::hash64(value),
+        Null => panic!("hash_datum: NULLs are not counted"),
+    }
+}
+
+/***************************************************************************************************/
+// QuantileSketch: Values are counted in logarithmically sized buckets, so that any quantile is returned within
+// QUANTILE_RELATIVE_ACCURACY of its true value.
+const QUANTILE_RELATIVE_ACCURACY: f64 = 0.01;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuantileSketch {
+    positive: BTreeMap<i32, u64>, // Bucket -> # of values
+    negative: BTreeMap<i32, u64>, // Bucket of the absolute value -> # of values
+    zeros: u64,
+}
+
+impl QuantileSketch {
+    pub fn new() -> Self {
+        QuantileSketch::default()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        bincode::deserialize(bytes).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn gamma() -> f64 {
+        (1.0 + QUANTILE_RELATIVE_ACCURACY) / (1.0 - QUANTILE_RELATIVE_ACCURACY)
+    }
+
+    fn bucket(value: f64) -> i32 {
+        (value.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    fn bucket_value(bucket: i32) -> f64 {
+        2.0 * Self::gamma().powi(bucket) / (Self::gamma() + 1.0)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value > 0.0 {
+            *self.positive.entry(Self::bucket(value)).or_insert(0) += 1;
+        } else if value < 0.0 {
+            *self.negative.entry(Self::bucket(-value)).or_insert(0) += 1;
+        } else if value == 0.0 {
+            self.zeros += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        for (&bucket, &count) in other.positive.iter() {
+            *self.positive.entry(bucket).or_insert(0) += count;
+        }
+        for (&bucket, &count) in other.negative.iter() {
+            *self.negative.entry(bucket).or_insert(0) += count;
+        }
+        self.zeros += other.zeros;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.positive.values().sum::<u64>() + self.negative.values().sum::<u64>() + self.zeros
+    }
+
+    // quantile: Value of the bucket holding the `fraction` quantile, NULL if the sketch is empty
+    pub fn quantile(&self, fraction: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (fraction * (count - 1) as f64).round() as u64;
+
+        // Walk the buckets in ascending order of their values
+        let negatives = self.negative.iter().rev().map(|(&bucket, &count)| (-Self::bucket_value(bucket), count));
+        let zeros = std::iter::once((0.0, self.zeros));
+        let positives = self.positive.iter().map(|(&bucket, &count)| (Self::bucket_value(bucket), count));
+
+        let mut seen = 0;
+        for (value, count) in negatives.chain(zeros).chain(positives) {
+            seen += count;
+            if seen > rank {
+                return Some(value);
+            }
+        }
+        unreachable!()
+    }
+}
@@ -16,12 +16,13 @@ use crate::{
 #[derive(Serialize, Deserialize)]
 pub struct Stage {
     pub stage_id: StageId,
-    pub parent_stage_id: Option<StageId>, // 0 == no stage depends on this
-    pub parent_pop_key: Option<POPKey>,   // POPKey that 'reads' this stage
+    pub parent_stage_ids: Vec<StageId>, // Stages that depend on this one. Only materialized CTEs have more than one.
+    pub parent_pop_keys: Vec<POPKey>,   // POPKeys that 'read' this stage, one per parent stage
     pub root_lop_key: LOPKey,
     pub root_pop_key: Option<POPKey>,
     pub nchildren: usize, // # of stages this stage depends on
     pub npartitions: usize,
+    pub recursive_stages: Option<Vec<StageId>>, // Stages rerun along with this one on every iteration of a recursive CTE
 
     #[derivative(Debug = "ignore")]
     pub pop_graph: POPGraph,
@@ -32,6 +33,8 @@ pub struct StageContext {
     // Runtime details
     pub nchildren_completed: usize,
     pub npartitions_completed: usize,
+    pub scheduled: bool,
+    pub iteration: usize,
 }
 
 /***************************************************************************************************/
@@ -40,16 +43,27 @@ impl Stage {
         debug!("New stage with root_lop_key: {:?}", root_lop_key);
         let pop_graph = Graph::default();
 
-        Stage { stage_id, parent_stage_id, parent_pop_key: None, root_lop_key, root_pop_key: None, nchildren: 0, npartitions: 0, pop_graph }
+        let parent_stage_ids = parent_stage_id.into_iter().collect();
+        Stage {
+            stage_id,
+            parent_stage_ids,
+            parent_pop_keys: vec![],
+            root_lop_key,
+            root_pop_key: None,
+            nchildren: 0,
+            npartitions: 0,
+            recursive_stages: None,
+            pop_graph,
+        }
     }
 
-    pub fn schedule(&self, env: &Env, flow: &Flow) -> Result<(), String> {
+    pub fn schedule(&self, env: &Env, flow: &Flow, iteration: usize) -> Result<(), String> {
         debug!("Schedule stage: {:?}", self.root_pop_key);
 
         let (_, props, ..) = self.pop_graph.get3(self.root_pop_key.unwrap());
         let npartitions = props.npartitions;
         for partition_id in 0..npartitions {
-            let task = Task::new(partition_id);
+            let task = Task::new(partition_id, iteration);
             //task.run(flow, self);
 
             let thread_id = partition_id % (env.scheduler.nthreads());
@@ -102,7 +116,22 @@ impl StageGraph {
 
     pub fn set_parent_pop_key(&mut self, stage_id: StageId, pop_key: POPKey) {
         let stage = &mut self.stages[stage_id];
-        stage.parent_pop_key = Some(pop_key)
+        stage.parent_pop_keys.push(pop_key)
+    }
+
+    // add_parent_stage: Make another stage dependent on an existing one, reading its output through `pop_key`
+    pub fn add_parent_stage(&mut self, stage_id: StageId, parent_stage_id: StageId, pop_key: POPKey) {
+        let stage = &mut self.stages[stage_id];
+        stage.parent_stage_ids.push(parent_stage_id);
+        stage.parent_pop_keys.push(pop_key);
+
+        let parent_stage = &mut self.stages[parent_stage_id];
+        parent_stage.nchildren += 1;
+    }
+
+    // find_child_stage: Find the stage that computes the output of a Repartition LOP
+    pub fn find_child_stage(&self, root_lop_key: LOPKey) -> Option<&Stage> {
+        self.stages.iter().find(|stage| stage.root_lop_key == root_lop_key && !stage.parent_stage_ids.is_empty())
     }
 
     pub fn print(&self) {
@@ -10,10 +10,15 @@ use crate::{
     includes::*,
     pop::{POPContext, POP},
     pop_csv::CSVContext,
+    pop_expand::ExpandContext,
     pop_hashagg::HashAggContext,
     pop_hashmatch::HashMatchContext,
+    pop_limit::LimitContext,
     pop_parquet::ParquetContext,
     pop_repartition::{RepartitionReadContext, RepartitionWriteContext},
+    pop_sort::SortContext,
+    pop_union::UnionAllContext,
+    pop_window::WindowContext,
     stage::Stage,
 };
 
@@ -21,6 +26,7 @@ use crate::{
 #[derive(Serialize, Deserialize)]
 pub struct Task {
     pub partition_id: PartitionId,
+    pub iteration: usize, // Only recursive stages run more than once
 
     #[serde(skip)]
     pub contexts: Vec<Box<dyn POPContext>>,
@@ -28,8 +34,8 @@ pub struct Task {
 
 // Tasks write to flow-id / top-id / dest-part-id / source-part-id
 impl Task {
-    pub fn new(partition_id: PartitionId) -> Task {
-        Task { partition_id, contexts: vec![] }
+    pub fn new(partition_id: PartitionId, iteration: usize) -> Task {
+        Task { partition_id, iteration, contexts: vec![] }
     }
 
     pub fn run(&mut self, flow: &Flow, stage: &Stage) -> Result<(), String> {
@@ -86,10 +92,15 @@ impl Task {
         let ctxt = match &pop {
             POP::CSV(csv) => CSVContext::try_new(popkey, csv, self.partition_id)?,
             POP::Parquet(pq) => ParquetContext::try_new(popkey, pq, stage.npartitions, self.partition_id)?,
-            POP::RepartitionWrite(rpw) => RepartitionWriteContext::try_new(popkey, rpw, child_contexts.unwrap(), self.partition_id)?,
-            POP::RepartitionRead(rpr) => RepartitionReadContext::try_new(flow.id, popkey, rpr, self.partition_id)?,
+            POP::RepartitionWrite(rpw) => RepartitionWriteContext::try_new(popkey, rpw, child_contexts.unwrap(), self.partition_id, self.iteration)?,
+            POP::RepartitionRead(rpr) => RepartitionReadContext::try_new(flow.id, popkey, rpr, self.partition_id, self.iteration)?,
             POP::HashMatch(hj) => HashMatchContext::try_new(popkey, hj, child_contexts.unwrap(), self.partition_id)?,
             POP::HashAgg(ha) => HashAggContext::try_new(popkey, ha, child_contexts.unwrap(), self.partition_id)?,
+            POP::Expand(expand) => ExpandContext::try_new(popkey, expand, child_contexts.unwrap(), self.partition_id)?,
+            POP::Sort(sort) => SortContext::try_new(popkey, sort, child_contexts.unwrap(), self.partition_id)?,
+            POP::Limit(limit) => LimitContext::try_new(popkey, limit, child_contexts.unwrap(), self.partition_id)?,
+            POP::UnionAll(union_all) => UnionAllContext::try_new(popkey, union_all, child_contexts.unwrap(), self.partition_id, self.iteration)?,
+            POP::Window(window) => WindowContext::try_new(popkey, window, child_contexts.unwrap(), self.partition_id)?,
         };
         Ok(ctxt)
     }